@@ -0,0 +1,2 @@
+DROP TABLE cover_requests;
+DROP TABLE occurrences;
@@ -0,0 +1,29 @@
+CREATE TABLE occurrences (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  subscription_id VARCHAR(36) NOT NULL REFERENCES subscriptions,
+  day DATE NOT NULL,
+  user_id VARCHAR(36) NOT NULL REFERENCES users,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE (subscription_id, day)
+);
+
+CREATE TABLE cover_requests (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  subscription_id VARCHAR(36) NOT NULL REFERENCES subscriptions,
+  day DATE NOT NULL,
+  requested_by VARCHAR(36) NOT NULL REFERENCES users,
+  claimed_by VARCHAR(36) REFERENCES users,
+  status VARCHAR(36) NOT NULL,
+  message VARCHAR(150),
+  expires_at TIMESTAMP NOT NULL,
+  escalated_at TIMESTAMP,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,8 @@
+ALTER TABLE places
+DROP COLUMN latitude,
+DROP COLUMN longitude;
+
+ALTER TABLE subscriptions
+DROP COLUMN child_id,
+DROP COLUMN start_time,
+DROP COLUMN end_time;
@@ -0,0 +1,8 @@
+ALTER TABLE subscriptions
+ADD COLUMN child_id VARCHAR(36) REFERENCES users,
+ADD COLUMN start_time TIME,
+ADD COLUMN end_time TIME;
+
+ALTER TABLE places
+ADD COLUMN latitude double precision,
+ADD COLUMN longitude double precision;
@@ -0,0 +1,6 @@
+DROP INDEX events_subscription_id_occurred_at_idx;
+
+ALTER TABLE events
+DROP COLUMN kind,
+DROP COLUMN actor_id,
+DROP COLUMN occurred_at;
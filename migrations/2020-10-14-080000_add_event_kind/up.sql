@@ -0,0 +1,8 @@
+ALTER TABLE events
+ADD COLUMN kind VARCHAR(36) NOT NULL DEFAULT 'note',
+ADD COLUMN actor_id VARCHAR(36) REFERENCES users,
+ADD COLUMN occurred_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+UPDATE events SET occurred_at = created_at;
+
+CREATE INDEX events_subscription_id_occurred_at_idx ON events (subscription_id, occurred_at);
@@ -0,0 +1,9 @@
+CREATE TABLE activities (
+  id BIGSERIAL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  kind VARCHAR(36) NOT NULL,
+  payload TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX activities_family_id_id_idx ON activities (family_id, id);
@@ -0,0 +1,6 @@
+DROP TABLE presences;
+
+ALTER TABLE places
+DROP COLUMN radius,
+DROP COLUMN polygon,
+DROP COLUMN address;
@@ -0,0 +1,12 @@
+ALTER TABLE places
+ADD COLUMN radius double precision,
+ADD COLUMN polygon TEXT,
+ADD COLUMN address VARCHAR(255);
+
+CREATE TABLE presences (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL REFERENCES users,
+  place_id VARCHAR(36) NOT NULL REFERENCES places ON DELETE CASCADE,
+  entered_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE (user_id, place_id)
+);
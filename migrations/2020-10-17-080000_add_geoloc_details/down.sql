@@ -0,0 +1,7 @@
+ALTER TABLE geolocs
+DROP COLUMN accuracy,
+DROP COLUMN speed,
+DROP COLUMN heading,
+DROP COLUMN altitude,
+DROP COLUMN battery,
+DROP COLUMN received_at;
@@ -0,0 +1,9 @@
+ALTER TABLE geolocs
+ADD COLUMN accuracy double precision,
+ADD COLUMN speed double precision,
+ADD COLUMN heading double precision,
+ADD COLUMN altitude double precision,
+ADD COLUMN battery INTEGER,
+ADD COLUMN received_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+UPDATE geolocs SET received_at = created_at;
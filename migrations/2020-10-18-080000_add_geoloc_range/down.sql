@@ -0,0 +1,4 @@
+DROP INDEX geolocs_user_id_created_at_idx;
+
+ALTER TABLE families
+DROP COLUMN timezone;
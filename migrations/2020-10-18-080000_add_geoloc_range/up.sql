@@ -0,0 +1,4 @@
+ALTER TABLE families
+ADD COLUMN timezone VARCHAR(64) NOT NULL DEFAULT 'UTC';
+
+CREATE INDEX geolocs_user_id_created_at_idx ON geolocs (user_id, created_at);
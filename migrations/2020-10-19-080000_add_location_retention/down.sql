@@ -0,0 +1,4 @@
+DROP INDEX activities_family_id_kind_created_at_idx;
+
+ALTER TABLE families
+DROP COLUMN location_retention_days;
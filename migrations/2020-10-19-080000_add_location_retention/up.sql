@@ -0,0 +1,4 @@
+ALTER TABLE families
+ADD COLUMN location_retention_days INTEGER;
+
+CREATE INDEX activities_family_id_kind_created_at_idx ON activities (family_id, kind, created_at);
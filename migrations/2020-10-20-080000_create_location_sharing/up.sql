@@ -0,0 +1,6 @@
+CREATE TABLE location_sharing (
+  user_id VARCHAR(36) NOT NULL PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+  mode VARCHAR(16) NOT NULL DEFAULT 'precise',
+  paused_until TIMESTAMP,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,32 @@
+CREATE TABLE emergency_contacts (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  name VARCHAR(100) NOT NULL,
+  phone VARCHAR(32) NOT NULL,
+  user_id VARCHAR(36) REFERENCES users ON DELETE SET NULL,
+  position INTEGER NOT NULL DEFAULT 0,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE escalations (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  subscription_id VARCHAR(36) NOT NULL REFERENCES subscriptions,
+  day DATE NOT NULL,
+  reason VARCHAR(16) NOT NULL,
+  level VARCHAR(16) NOT NULL,
+  notified_at TIMESTAMP NOT NULL,
+  acknowledged_by VARCHAR(36) REFERENCES users,
+  acknowledged_at TIMESTAMP,
+  resolved_at TIMESTAMP,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE (subscription_id, day)
+);
+
+CREATE INDEX escalations_family_id_idx ON escalations (family_id);
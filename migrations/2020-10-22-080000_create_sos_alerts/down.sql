@@ -0,0 +1 @@
+DROP TABLE sos_alerts;
@@ -0,0 +1,22 @@
+CREATE TABLE sos_alerts (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  user_id VARCHAR(36) NOT NULL REFERENCES users,
+  event_id VARCHAR(36) REFERENCES events ON DELETE SET NULL,
+  latitude DOUBLE PRECISION NOT NULL,
+  longitude DOUBLE PRECISION NOT NULL,
+  accuracy DOUBLE PRECISION,
+  message VARCHAR(150),
+  token VARCHAR(64) NOT NULL UNIQUE,
+  sampling_interval INTEGER NOT NULL,
+  expires_at TIMESTAMP NOT NULL,
+  resolved_by VARCHAR(36) REFERENCES users,
+  resolved_at TIMESTAMP,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX sos_alerts_family_id_idx ON sos_alerts (family_id);
+CREATE INDEX sos_alerts_user_id_idx ON sos_alerts (user_id) WHERE resolved_at IS NULL;
@@ -0,0 +1,2 @@
+DROP TABLE handover_codes;
+DROP TABLE pickup_persons;
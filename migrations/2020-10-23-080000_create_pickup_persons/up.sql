@@ -0,0 +1,34 @@
+CREATE TABLE pickup_persons (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  child_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  phone VARCHAR(32),
+  photo_url VARCHAR(255),
+  user_id VARCHAR(36) REFERENCES users ON DELETE SET NULL,
+  valid_from DATE,
+  valid_until DATE,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX pickup_persons_child_id_idx ON pickup_persons (child_id);
+
+CREATE TABLE handover_codes (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  subscription_id VARCHAR(36) NOT NULL REFERENCES subscriptions,
+  day DATE NOT NULL,
+  child_id VARCHAR(36) NOT NULL REFERENCES users,
+  pickup_person_id VARCHAR(36) REFERENCES pickup_persons ON DELETE SET NULL,
+  pickup_name VARCHAR(100) NOT NULL,
+  code VARCHAR(16) NOT NULL UNIQUE,
+  expires_at TIMESTAMP NOT NULL,
+  used_at TIMESTAMP,
+  confirmed_by VARCHAR(36),
+  event_id VARCHAR(36) REFERENCES events ON DELETE SET NULL,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
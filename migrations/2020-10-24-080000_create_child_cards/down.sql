@@ -0,0 +1,3 @@
+DROP TABLE child_card_views;
+DROP TABLE child_cards;
+ALTER TABLE emergency_contacts DROP COLUMN child_id;
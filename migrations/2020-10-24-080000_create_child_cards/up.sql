@@ -0,0 +1,28 @@
+-- Contacts of one child only, the others are for the whole family
+ALTER TABLE emergency_contacts ADD COLUMN child_id VARCHAR(36) REFERENCES users ON DELETE CASCADE;
+
+CREATE TABLE child_cards (
+  child_id VARCHAR(36) NOT NULL PRIMARY KEY REFERENCES users ON DELETE CASCADE,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  birth_date DATE,
+  blood_type VARCHAR(8),
+  allergies TEXT,
+  medications TEXT,
+  conditions TEXT,
+  doctor_name VARCHAR(100),
+  doctor_phone VARCHAR(32),
+  notes TEXT,
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE child_card_views (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  child_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  viewer_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  format VARCHAR(16) NOT NULL,
+  viewed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX child_card_views_child_id_idx ON child_card_views (child_id, viewed_at);
@@ -0,0 +1,32 @@
+CREATE TABLE chat_threads (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  title VARCHAR(100),
+  event_id VARCHAR(36) REFERENCES events ON DELETE SET NULL,
+  subscription_id VARCHAR(36) REFERENCES subscriptions ON DELETE SET NULL,
+  place_id VARCHAR(36) REFERENCES places ON DELETE SET NULL,
+  last_message_at TIMESTAMP,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX chat_threads_family_id_idx ON chat_threads (family_id);
+
+CREATE TABLE chat_messages (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  thread_id VARCHAR(36) NOT NULL REFERENCES chat_threads ON DELETE CASCADE,
+  user_id VARCHAR(36) NOT NULL REFERENCES users,
+  body TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX chat_messages_thread_id_idx ON chat_messages (thread_id, created_at);
+
+CREATE TABLE chat_reads (
+  thread_id VARCHAR(36) NOT NULL REFERENCES chat_threads ON DELETE CASCADE,
+  user_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  last_read_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (thread_id, user_id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE mandatory_acknowledgements;
+DROP TABLE acknowledgements;
@@ -0,0 +1,21 @@
+CREATE TABLE acknowledgements (
+  event_id VARCHAR(36) NOT NULL REFERENCES events ON DELETE CASCADE,
+  user_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  acknowledged_at TIMESTAMP,
+  notified_at TIMESTAMP NOT NULL,
+  reminders INTEGER NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (event_id, user_id)
+);
+
+CREATE INDEX acknowledgements_family_id_idx ON acknowledgements (family_id, notified_at);
+
+-- Event kinds of a family that must be acknowledged
+CREATE TABLE mandatory_acknowledgements (
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  kind VARCHAR(16) NOT NULL,
+  updated_by VARCHAR(36) NOT NULL,
+  updated_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (family_id, kind)
+);
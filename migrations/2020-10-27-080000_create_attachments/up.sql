@@ -0,0 +1,19 @@
+CREATE TABLE attachments (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  event_id VARCHAR(36) REFERENCES events ON DELETE CASCADE,
+  message_id VARCHAR(36) REFERENCES chat_messages ON DELETE CASCADE,
+  filename VARCHAR(255) NOT NULL,
+  content_type VARCHAR(100) NOT NULL,
+  size BIGINT NOT NULL,
+  storage_key VARCHAR(255) NOT NULL,
+  thumbnail_key VARCHAR(255),
+  width INTEGER,
+  height INTEGER,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  CHECK ((event_id IS NULL) <> (message_id IS NULL))
+);
+
+CREATE INDEX attachments_event_id_idx ON attachments (event_id);
+CREATE INDEX attachments_message_id_idx ON attachments (message_id);
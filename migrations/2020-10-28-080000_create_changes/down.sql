@@ -0,0 +1,4 @@
+DROP TABLE changes;
+ALTER TABLE places DROP COLUMN deleted_at, DROP COLUMN deleted_by;
+ALTER TABLE subscriptions DROP COLUMN deleted_at, DROP COLUMN deleted_by;
+ALTER TABLE events DROP COLUMN deleted_at, DROP COLUMN deleted_by;
@@ -0,0 +1,17 @@
+ALTER TABLE events ADD COLUMN deleted_at TIMESTAMP, ADD COLUMN deleted_by VARCHAR(36);
+ALTER TABLE subscriptions ADD COLUMN deleted_at TIMESTAMP, ADD COLUMN deleted_by VARCHAR(36);
+ALTER TABLE places ADD COLUMN deleted_at TIMESTAMP, ADD COLUMN deleted_by VARCHAR(36);
+
+CREATE TABLE changes (
+  id BIGSERIAL PRIMARY KEY,
+  family_id VARCHAR(36) NOT NULL REFERENCES families,
+  entity VARCHAR(20) NOT NULL,
+  entity_id VARCHAR(36) NOT NULL,
+  action VARCHAR(20) NOT NULL,
+  before TEXT,
+  after TEXT,
+  changed_by VARCHAR(36) NOT NULL,
+  changed_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX changes_entity_idx ON changes (entity, entity_id, id);
@@ -0,0 +1,26 @@
+-- No foreign keys, entries outlive the users and families they are about
+CREATE TABLE audit_entries (
+  id BIGSERIAL PRIMARY KEY,
+  family_id VARCHAR(36),
+  actor_id VARCHAR(36),
+  action VARCHAR(50) NOT NULL,
+  target VARCHAR(255),
+  details TEXT,
+  ip_address VARCHAR(64),
+  user_agent VARCHAR(255),
+  created_at TIMESTAMP NOT NULL,
+  prev_hash VARCHAR(64) NOT NULL,
+  hash VARCHAR(64) NOT NULL
+);
+
+CREATE INDEX audit_entries_family_id_idx ON audit_entries (family_id, id);
+
+CREATE FUNCTION audit_entries_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit entries are append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_entries_append_only
+  BEFORE UPDATE OR DELETE ON audit_entries
+  FOR EACH ROW EXECUTE PROCEDURE audit_entries_append_only();
@@ -0,0 +1,4 @@
+DROP INDEX activities_family_id_user_id_idx;
+
+ALTER TABLE activities
+DROP COLUMN user_id;
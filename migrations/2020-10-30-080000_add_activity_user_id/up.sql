@@ -0,0 +1,9 @@
+ALTER TABLE activities
+ADD COLUMN user_id VARCHAR;
+
+-- Positions carry the user they belong to in their payload
+UPDATE activities
+SET user_id = payload::json->>'user_id'
+WHERE kind = 'geoloc_created';
+
+CREATE INDEX activities_family_id_user_id_idx ON activities (family_id, user_id);
@@ -0,0 +1,7 @@
+CREATE TABLE place_staff (
+  place_id VARCHAR(36) NOT NULL REFERENCES places ON DELETE CASCADE,
+  user_id VARCHAR(36) NOT NULL REFERENCES users ON DELETE CASCADE,
+  created_by VARCHAR(36) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY (place_id, user_id)
+);
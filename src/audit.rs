@@ -0,0 +1,15 @@
+//! Recording of security-sensitive actions, see `models::audit`
+
+use crate::database::PoolType;
+use crate::models::audit::{append, NewAuditEntry};
+use actix_web::web::{block, Data};
+
+/// Append an entry to the audit log
+///
+/// Failures are only logged, the action itself has already been taken.
+pub async fn record(pool: &Data<PoolType>, new_entry: NewAuditEntry) {
+    let pool = pool.clone();
+    if let Err(error) = block(move || append(&pool, new_entry)).await {
+        log::warn!("Could not record audit entry: {:?}", error);
+    }
+}
@@ -8,21 +8,18 @@ use redis_async::resp::{FromResp, RespValue};
 pub type Cache = Data<Addr<RedisActor>>;
 
 /// Retrieve an entry in redis
-#[allow(dead_code)]
 pub async fn get<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
     let command = resp_array!["GET", key];
     send(redis, command).await
 }
 
 /// Insert or update an entry in redis
-#[allow(dead_code)]
 pub async fn set<'a>(redis: Cache, key: &'a str, value: &'a str) -> Result<String, ApiError> {
     let command = resp_array!["SET", key, value];
     send(redis, command).await
 }
 
 /// Delete an entry in redis
-#[allow(dead_code)]
 pub async fn delete<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
     let command = resp_array!["DEL", key];
     send(redis, command).await
@@ -48,6 +45,15 @@ pub fn add_cache(cfg: &mut ServiceConfig) {
     }
 }
 
+/// A redis actor of its own for code running outside of requests, such as
+/// jobs, if the URL is set
+pub fn start_cache() -> Option<Cache> {
+    if CONFIG.redis_url.is_empty() {
+        return None;
+    }
+    Some(Data::new(RedisActor::start(&CONFIG.redis_url)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
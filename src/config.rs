@@ -27,6 +27,129 @@ pub struct Config {
     pub session_name: String,
     pub session_secure: bool,
     pub session_timeout: i64,
+    /// Address of the reverse proxy whose Forwarded/X-Forwarded-For headers
+    /// name the client, other peers are taken at their word
+    #[serde(default)]
+    pub trusted_proxy: Option<String>,
+    #[serde(default)]
+    pub fcm_key: Option<String>,
+    /// Gateway text messages are posted to, as JSON `{"to", "body"}`
+    #[serde(default)]
+    pub sms_url: Option<String>,
+    #[serde(default)]
+    pub sms_key: Option<String>,
+    #[serde(default = "default_cover_request_ttl")]
+    pub cover_request_ttl: i64,
+    #[serde(default = "default_cover_request_escalation")]
+    pub cover_request_escalation: i64,
+    #[serde(default = "default_travel_speed")]
+    pub travel_speed: f64,
+    #[serde(default = "default_location_retention_days")]
+    pub location_retention_days: i64,
+    #[serde(default = "default_escalation_grace")]
+    pub escalation_grace: i64,
+    #[serde(default = "default_escalation_adults_delay")]
+    pub escalation_adults_delay: i64,
+    #[serde(default = "default_escalation_contacts_delay")]
+    pub escalation_contacts_delay: i64,
+    #[serde(default = "default_sos_duration")]
+    pub sos_duration: i64,
+    #[serde(default = "default_sos_sampling_interval")]
+    pub sos_sampling_interval: i32,
+    #[serde(default = "default_ack_reminder_interval")]
+    pub ack_reminder_interval: i64,
+    #[serde(default = "default_ack_max_reminders")]
+    pub ack_max_reminders: i32,
+    /// local or s3
+    #[serde(default = "default_attachment_storage")]
+    pub attachment_storage: String,
+    #[serde(default = "default_attachment_dir")]
+    pub attachment_dir: String,
+    #[serde(default = "default_attachment_max_size")]
+    pub attachment_max_size: usize,
+    #[serde(default)]
+    pub s3_endpoint: Option<String>,
+    #[serde(default)]
+    pub s3_bucket: Option<String>,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    #[serde(default)]
+    pub s3_access_key: Option<String>,
+    #[serde(default)]
+    pub s3_secret_key: Option<String>,
+}
+
+/// Minutes a cover request stays open when the client doesn't set an expiry
+fn default_cover_request_ttl() -> i64 {
+    120
+}
+
+/// Minutes before expiry at which an unclaimed cover request is escalated
+fn default_cover_request_escalation() -> i64 {
+    30
+}
+
+/// Average km/h used to tell if two places can be reached in time
+fn default_travel_speed() -> f64 {
+    30.0
+}
+
+/// Days positions are kept for families that didn't set their own retention
+fn default_location_retention_days() -> i64 {
+    90
+}
+
+/// Minutes into or after a window before a pickup is escalated
+fn default_escalation_grace() -> i64 {
+    10
+}
+
+/// Minutes the assigned adult has to acknowledge before the other adults are alerted
+fn default_escalation_adults_delay() -> i64 {
+    10
+}
+
+/// Minutes the adults have to acknowledge before emergency contacts are alerted
+fn default_escalation_contacts_delay() -> i64 {
+    15
+}
+
+/// Minutes an emergency alert is live tracked, unless an adult extends it
+fn default_sos_duration() -> i64 {
+    60
+}
+
+/// Seconds between two positions devices are asked for while live tracked
+fn default_sos_sampling_interval() -> i32 {
+    5
+}
+
+/// Minutes before an event nobody acknowledged is notified again
+fn default_ack_reminder_interval() -> i64 {
+    10
+}
+
+/// Times an event nobody acknowledged is notified again before giving up
+fn default_ack_max_reminders() -> i32 {
+    3
+}
+
+fn default_attachment_storage() -> String {
+    "local".into()
+}
+
+/// Where attachments are kept with the local storage
+fn default_attachment_dir() -> String {
+    "./attachments".into()
+}
+
+/// Bytes an attachment may weigh, 10 MB
+fn default_attachment_max_size() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".into()
 }
 
 // Throw the Config struct into a CONFIG lazy_static to avoid multiple processing
@@ -0,0 +1,220 @@
+//! Conflict analysis over the expanded schedule of a family
+//!
+//! Two occurrences of the same day conflict when they involve the same
+//! person (the responsible adult or the child) and either their windows
+//! overlap, or the gap between them is too short to travel from one place
+//! to the other.
+
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::geo::Point;
+use crate::models::occurrence::{expand, get_assignments, ScheduledOccurrence};
+use crate::models::place::find_all_by_family_id as find_places;
+use crate::models::subscription::find_all_by_family_id as find_subscriptions;
+use chrono::NaiveDate;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    AdultOverlap,
+    ChildOverlap,
+    AdultTravel,
+    ChildTravel,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub kind: ConflictKind,
+    pub day: NaiveDate,
+    pub user_id: String,
+    pub subscription_ids: (String, String),
+    /// Minutes needed to travel between the two places, for travel conflicts
+    pub travel_minutes: Option<i64>,
+    /// Minutes between the end of the first and the start of the second
+    pub gap_minutes: Option<i64>,
+}
+
+impl Conflict {
+    pub fn involves(&self, subscription_id: &str) -> bool {
+        self.subscription_ids.0 == subscription_id || self.subscription_ids.1 == subscription_id
+    }
+}
+
+/// Minutes needed to travel a distance in meters at the configured speed
+fn travel_minutes(distance: f64, speed: f64) -> i64 {
+    (distance / 1000.0 / speed * 60.0).ceil() as i64
+}
+
+/// Detect the conflicts between occurrences
+///
+/// Occurrences without a time window can't be compared and are skipped.
+pub fn detect(
+    occurrences: &[ScheduledOccurrence],
+    locations: &HashMap<String, Point>,
+    speed: f64,
+) -> Vec<Conflict> {
+    let mut by_person: HashMap<(NaiveDate, String, bool), Vec<&ScheduledOccurrence>> = HashMap::new();
+    for occurrence in occurrences.iter().filter(|occurrence| occurrence.window().is_some()) {
+        by_person
+            .entry((occurrence.day, occurrence.user_id.clone(), false))
+            .or_insert_with(Vec::new)
+            .push(occurrence);
+        if let Some(child_id) = &occurrence.subscription.child_id {
+            by_person
+                .entry((occurrence.day, child_id.clone(), true))
+                .or_insert_with(Vec::new)
+                .push(occurrence);
+        }
+    }
+
+    let mut conflicts = vec![];
+    for ((day, user_id, is_child), mut group) in by_person {
+        group.sort_by_key(|occurrence| occurrence.window());
+        for (index, first) in group.iter().enumerate() {
+            let (_, first_end) = first.window().unwrap();
+            for (offset, second) in group.iter().enumerate().skip(index + 1) {
+                let (second_start, _) = second.window().unwrap();
+                let subscription_ids = (first.subscription.id.clone(), second.subscription.id.clone());
+
+                if second_start < first_end {
+                    conflicts.push(Conflict {
+                        kind: if is_child { ConflictKind::ChildOverlap } else { ConflictKind::AdultOverlap },
+                        day,
+                        user_id: user_id.clone(),
+                        subscription_ids,
+                        travel_minutes: None,
+                        gap_minutes: None,
+                    });
+                    continue;
+                }
+
+                // Travel is only checked between back to back occurrences
+                if offset != index + 1 || first.subscription.place_id == second.subscription.place_id {
+                    continue;
+                }
+                let from = locations.get(&first.subscription.place_id);
+                let to = locations.get(&second.subscription.place_id);
+                if let (Some(from), Some(to)) = (from, to) {
+                    let needed = travel_minutes(from.distance(to), speed);
+                    let gap = (second_start - first_end).num_minutes();
+                    if gap < needed {
+                        conflicts.push(Conflict {
+                            kind: if is_child { ConflictKind::ChildTravel } else { ConflictKind::AdultTravel },
+                            day,
+                            user_id: user_id.clone(),
+                            subscription_ids,
+                            travel_minutes: Some(needed),
+                            gap_minutes: Some(gap),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    conflicts.sort_by(|a, b| (a.day, &a.subscription_ids).cmp(&(b.day, &b.subscription_ids)));
+    conflicts
+}
+
+/// Find the conflicts in the schedule of a family between two dates
+pub fn find_conflicts(
+    pool: &PoolType,
+    family_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Conflict>, ApiError> {
+    let subscriptions = find_subscriptions(pool, family_id)?;
+    let assignments = get_assignments(pool, family_id, from, to)?;
+    let locations = find_places(pool, family_id)?
+        .into_iter()
+        .filter_map(|place| place.location().map(|location| (place.id, location)))
+        .collect();
+
+    let occurrences = expand(&subscriptions, &assignments, from, to);
+    Ok(detect(&occurrences, &locations, CONFIG.travel_speed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::subscription::Subscription;
+    use chrono::{NaiveTime, Utc};
+
+    fn occurrence(place_id: &str, user_id: &str, child_id: Option<&str>, start: (u32, u32), end: (u32, u32)) -> ScheduledOccurrence {
+        ScheduledOccurrence {
+            subscription: Subscription {
+                id: format!("{}-{}-{}", place_id, start.0, start.1),
+                family_id: "family".into(),
+                place_id: place_id.into(),
+                user_id: user_id.into(),
+                days: "lundi".into(),
+                created_by: "".into(),
+                created_at: Utc::now().naive_utc(),
+                updated_by: "".into(),
+                updated_at: Utc::now().naive_utc(),
+                child_id: child_id.map(Into::into),
+                start_time: Some(NaiveTime::from_hms(start.0, start.1, 0)),
+                end_time: Some(NaiveTime::from_hms(end.0, end.1, 0)),
+                deleted_at: None,
+                deleted_by: None,
+            },
+            day: NaiveDate::from_ymd(2020, 10, 12),
+            user_id: user_id.into(),
+        }
+    }
+
+    fn locations() -> HashMap<String, Point> {
+        let mut locations = HashMap::new();
+        locations.insert("school".to_string(), Point::new(48.8566, 2.3522));
+        // about 10 km away
+        locations.insert("pool".to_string(), Point::new(48.9466, 2.3522));
+        locations
+    }
+
+    #[test]
+    fn it_detects_overlaps_for_the_same_adult() {
+        let occurrences = vec![
+            occurrence("school", "mom", None, (8, 0), (8, 30)),
+            occurrence("pool", "mom", None, (8, 15), (9, 0)),
+        ];
+        let conflicts = detect(&occurrences, &locations(), 30.0);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::AdultOverlap);
+    }
+
+    #[test]
+    fn it_detects_overlaps_for_the_same_child() {
+        let occurrences = vec![
+            occurrence("school", "mom", Some("kid"), (8, 0), (8, 30)),
+            occurrence("pool", "dad", Some("kid"), (8, 15), (9, 0)),
+        ];
+        let conflicts = detect(&occurrences, &locations(), 30.0);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::ChildOverlap);
+        assert_eq!(conflicts[0].user_id, "kid");
+    }
+
+    #[test]
+    fn it_detects_unfeasible_travel() {
+        let occurrences = vec![
+            occurrence("school", "mom", None, (8, 0), (8, 30)),
+            occurrence("pool", "mom", None, (8, 40), (9, 0)),
+        ];
+        let conflicts = detect(&occurrences, &locations(), 30.0);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kind, ConflictKind::AdultTravel);
+        assert_eq!(conflicts[0].travel_minutes, Some(21));
+        assert_eq!(conflicts[0].gap_minutes, Some(10));
+    }
+
+    #[test]
+    fn it_accepts_a_feasible_schedule() {
+        let occurrences = vec![
+            occurrence("school", "mom", None, (8, 0), (8, 30)),
+            occurrence("pool", "mom", None, (9, 0), (10, 0)),
+            occurrence("school", "dad", None, (8, 0), (8, 30)),
+        ];
+        assert!(detect(&occurrences, &locations(), 30.0).is_empty());
+    }
+}
@@ -1,6 +1,7 @@
 //! Database-related functions
 use crate::config::{Config, CONFIG};
-use actix_web::web;
+use actix_web::web::{self, Data};
+use std::any::Any;
 use diesel::{
     mysql::MysqlConnection,
     pg::PgConnection,
@@ -37,6 +38,18 @@ pub type PoolType = PostgresPool;
 #[cfg(feature = "sqlite")]
 pub type PoolType = SqlitePool;
 
+#[cfg(feature = "cockroach")]
+pub type ConnectionType = PgConnection;
+
+#[cfg(feature = "mysql")]
+pub type ConnectionType = MysqlConnection;
+
+#[cfg(feature = "postgres")]
+pub type ConnectionType = PgConnection;
+
+#[cfg(feature = "sqlite")]
+pub type ConnectionType = SqliteConnection;
+
 #[derive(Clone)]
 pub enum InferPool {
     Cockroach(CockroachPool),
@@ -71,12 +84,32 @@ where
     Pool::builder().build(manager)
 }
 
+lazy_static! {
+    /// Created once, every worker of the server and the background jobs share
+    /// its connections
+    static ref POOL: InferPool =
+        InferPool::init_pool(CONFIG.clone()).expect("Failed to create connection pool");
+}
+
 pub fn add_pool(cfg: &mut web::ServiceConfig) {
-    let pool = InferPool::init_pool(CONFIG.clone()).expect("Failed to create connection pool");
-    match pool {
+    match POOL.clone() {
         InferPool::Cockroach(cockroach_pool) => cfg.data(cockroach_pool),
         InferPool::Mysql(mysql_pool) => cfg.data(mysql_pool),
         InferPool::Postgres(postgres_pool) => cfg.data(postgres_pool),
         InferPool::Sqlite(sqlite_pool) => cfg.data(sqlite_pool),
     };
 }
+
+/// The shared pool as handlers get it, for the background jobs
+pub fn shared_pool() -> Data<PoolType> {
+    let pool: Box<dyn Any> = match POOL.clone() {
+        InferPool::Cockroach(cockroach_pool) => Box::new(cockroach_pool),
+        InferPool::Mysql(mysql_pool) => Box::new(mysql_pool),
+        InferPool::Postgres(postgres_pool) => Box::new(postgres_pool),
+        InferPool::Sqlite(sqlite_pool) => Box::new(sqlite_pool),
+    };
+    let pool = pool
+        .downcast::<PoolType>()
+        .expect("The configured database doesn't match the one the server was built for");
+    Data::new(*pool)
+}
@@ -0,0 +1,321 @@
+//! Escalation of pickups going wrong
+//!
+//! An occurrence escalates when its adult isn't at the place a grace period
+//! into the window, or when the window is over and the child was neither
+//! picked up nor gone from the place. The assigned adult is alerted first,
+//! then the other adults, then the emergency contacts, each after a delay
+//! unless someone acknowledged in between. Escalations close by themselves
+//! once the pickup is confirmed or the adult shows up.
+
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::emergency_contact::{find_all_by_family_id as find_contacts, EmergencyContact};
+use crate::models::event::{get_all_by_occurrence, status_of, Event, EventKind, OccurrenceStatus};
+use crate::models::escalation::{
+    advance, create, find_all_by_day, resolve, resolve_before, restart, Escalation, Level, Reason,
+};
+use crate::models::family::Family;
+use crate::models::occurrence::{expand, get_assignments, ScheduledOccurrence};
+use crate::models::place::{find_place, Place};
+use crate::models::presence::{find_all_by_user_ids, Presence};
+use crate::models::subscription::find_all_by_family_id as find_subscriptions;
+use crate::models::user::{find_user, get_adults_by_family_id, User};
+use crate::scheduling::SCHEDULER;
+use chrono::{Duration, NaiveDateTime};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Delays {
+    /// Into the window for lateness, after it for missed pickups
+    pub grace: Duration,
+    /// From the assigned adult to the other adults
+    pub adults: Duration,
+    /// From the other adults to the emergency contacts
+    pub contacts: Duration,
+}
+
+impl Default for Delays {
+    fn default() -> Self {
+        Delays {
+            grace: Duration::minutes(CONFIG.escalation_grace),
+            adults: Duration::minutes(CONFIG.escalation_adults_delay),
+            contacts: Duration::minutes(CONFIG.escalation_contacts_delay),
+        }
+    }
+}
+
+/// Where an occurrence stands as far as escalation is concerned
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Situation {
+    /// UTC window
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub status: OccurrenceStatus,
+    /// The adult on duty is inside the place
+    pub adult_present: bool,
+    /// The child was seen leaving the place and isn't back
+    pub child_gone: bool,
+}
+
+/// Why an occurrence should be escalated right now, if at all
+pub fn reason(situation: &Situation, now: NaiveDateTime, delays: &Delays) -> Option<Reason> {
+    match situation.status {
+        OccurrenceStatus::Scheduled | OccurrenceStatus::DroppedOff => {}
+        OccurrenceStatus::PickedUp | OccurrenceStatus::Absent => return None,
+    }
+    if now >= situation.end + delays.grace {
+        if situation.child_gone {
+            return None;
+        }
+        return Some(Reason::Missed);
+    }
+    if now >= situation.start + delays.grace && !situation.adult_present {
+        return Some(Reason::Late);
+    }
+    None
+}
+
+/// The level an open escalation should reach now, if further than its own
+pub fn next_level(escalation: &Escalation, now: NaiveDateTime, delays: &Delays) -> Option<Level> {
+    match escalation.level() {
+        Level::Assigned if now >= escalation.notified_at + delays.adults => Some(Level::Adults),
+        Level::Adults if now >= escalation.notified_at + delays.contacts => Some(Level::Contacts),
+        _ => None,
+    }
+}
+
+/// Whether the child left the place of an occurrence, from the crossings
+/// detected and the places they are currently inside
+fn child_gone(occurrence: &ScheduledOccurrence, events: &[Event], presences: &[Presence]) -> bool {
+    let child_id = match &occurrence.subscription.child_id {
+        Some(child_id) => child_id,
+        None => return false,
+    };
+    let inside = presences
+        .iter()
+        .any(|presence| &presence.user_id == child_id && presence.place_id == occurrence.subscription.place_id);
+    let departed = events
+        .iter()
+        .any(|event| event.kind() == EventKind::Departure && event.actor_id.as_ref() == Some(child_id));
+    departed && !inside
+}
+
+/// An escalation that reached a new level, with who to alert
+#[derive(Clone, Debug)]
+pub struct Step {
+    pub escalation: Escalation,
+    pub occurrence: ScheduledOccurrence,
+    pub place: Place,
+    /// The adult on duty
+    pub assigned: User,
+    pub users: Vec<User>,
+    /// Only on the last level, for the adults to call them, those without
+    /// an account get a text message
+    pub contacts: Vec<EmergencyContact>,
+}
+
+/// Start, advance or close the escalations of a family's day
+pub fn escalate(pool: &PoolType, family: &Family, now: NaiveDateTime, delays: &Delays) -> Result<Vec<Step>, ApiError> {
+    let today = family.today();
+    resolve_before(pool, &family.id, today, SCHEDULER)?;
+
+    let subscriptions = find_subscriptions(pool, &family.id)?;
+    let assignments = get_assignments(pool, &family.id, today, today)?;
+    let occurrences = expand(&subscriptions, &assignments, today, today);
+    let mut escalations: HashMap<String, Escalation> = find_all_by_day(pool, &family.id, today)?
+        .into_iter()
+        .map(|escalation| (escalation.subscription_id.clone(), escalation))
+        .collect();
+
+    let mut user_ids: Vec<String> = occurrences.iter().map(|occurrence| occurrence.user_id.clone()).collect();
+    user_ids.extend(occurrences.iter().filter_map(|occurrence| occurrence.subscription.child_id.clone()));
+    let conn = pool.get()?;
+    let presences = find_all_by_user_ids(&conn, &user_ids)?;
+
+    let mut steps = vec![];
+    let mut resolved = vec![];
+    for occurrence in occurrences {
+        let (start, end) = match occurrence.window() {
+            Some((start, end)) => (family.to_utc(start), family.to_utc(end)),
+            None => continue,
+        };
+        let existing = escalations.remove(&occurrence.subscription.id);
+        if now < start + delays.grace && existing.is_none() {
+            continue;
+        }
+
+        let events = get_all_by_occurrence(pool, family, &occurrence.subscription.id, occurrence.day)?;
+        let situation = Situation {
+            start,
+            end,
+            status: status_of(&events),
+            adult_present: presences.iter().any(|presence| {
+                presence.user_id == occurrence.user_id && presence.place_id == occurrence.subscription.place_id
+            }),
+            child_gone: child_gone(&occurrence, &events, &presences),
+        };
+
+        let escalation = match (existing, reason(&situation, now, delays)) {
+            (None, None) => continue,
+            (None, Some(reason)) => create(
+                pool,
+                &family.id,
+                &occurrence.subscription.id,
+                occurrence.day,
+                reason,
+                SCHEDULER,
+            )?,
+            (Some(escalation), None) => {
+                if escalation.resolved_at.is_none() {
+                    resolved.push(escalation.id);
+                }
+                continue;
+            }
+            // Someone made it or said they were coming, yet nobody confirmed the pickup
+            (Some(escalation), Some(Reason::Missed))
+                if escalation.reason() == Reason::Late && !escalation.is_open() =>
+            {
+                restart(pool, &escalation.id, Reason::Missed, SCHEDULER)?
+            }
+            (Some(escalation), Some(_)) if escalation.is_open() => match next_level(&escalation, now, delays) {
+                Some(level) => match advance(pool, &escalation.id, level, SCHEDULER)? {
+                    Some(escalation) => escalation,
+                    None => continue,
+                },
+                None => continue,
+            },
+            (Some(_), Some(_)) => continue,
+        };
+        steps.push(step(pool, family, escalation, occurrence)?);
+    }
+    resolve(pool, &resolved, SCHEDULER)?;
+    Ok(steps)
+}
+
+/// Who to alert for the level an escalation just reached
+fn step(
+    pool: &PoolType,
+    family: &Family,
+    escalation: Escalation,
+    occurrence: ScheduledOccurrence,
+) -> Result<Step, ApiError> {
+    let place = find_place(pool, &occurrence.subscription.place_id)?;
+    let assigned = find_user(pool, &occurrence.user_id)?;
+    let (users, contacts) = match escalation.level() {
+        Level::Assigned => (vec![assigned.clone()], vec![]),
+        Level::Adults => {
+            let adults = get_adults_by_family_id(pool, &family.id)?;
+            let others = adults.into_iter().filter(|adult| adult.id != occurrence.user_id).collect();
+            (others, vec![])
+        }
+        Level::Contacts => {
+            let child_id = occurrence.subscription.child_id.as_deref();
+            let contacts: Vec<EmergencyContact> = find_contacts(pool, &family.id)?
+                .into_iter()
+                .filter(|contact| contact.is_for(child_id))
+                .collect();
+            let mut users = get_adults_by_family_id(pool, &family.id)?;
+            for contact in contacts.iter() {
+                if let Some(user_id) = &contact.user_id {
+                    if users.iter().all(|user| &user.id != user_id) {
+                        users.push(find_user(pool, user_id)?);
+                    }
+                }
+            }
+            (users, contacts)
+        }
+    };
+    Ok(Step {
+        escalation,
+        occurrence,
+        place,
+        assigned,
+        users,
+        contacts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 10, 21).and_hms(hour, minute, 0)
+    }
+
+    fn delays() -> Delays {
+        Delays {
+            grace: Duration::minutes(10),
+            adults: Duration::minutes(10),
+            contacts: Duration::minutes(15),
+        }
+    }
+
+    fn situation(status: OccurrenceStatus, adult_present: bool, child_gone: bool) -> Situation {
+        Situation {
+            start: at(16, 30),
+            end: at(17, 0),
+            status,
+            adult_present,
+            child_gone,
+        }
+    }
+
+    #[test]
+    fn it_escalates_an_adult_not_there_after_the_grace() {
+        let late = situation(OccurrenceStatus::Scheduled, false, false);
+        assert_eq!(reason(&late, at(16, 35), &delays()), None);
+        assert_eq!(reason(&late, at(16, 40), &delays()), Some(Reason::Late));
+        let there = situation(OccurrenceStatus::Scheduled, true, false);
+        assert_eq!(reason(&there, at(16, 40), &delays()), None);
+    }
+
+    #[test]
+    fn it_escalates_a_missed_pickup() {
+        let missed = situation(OccurrenceStatus::DroppedOff, true, false);
+        assert_eq!(reason(&missed, at(17, 5), &delays()), None);
+        assert_eq!(reason(&missed, at(17, 10), &delays()), Some(Reason::Missed));
+        let gone = situation(OccurrenceStatus::DroppedOff, true, true);
+        assert_eq!(reason(&gone, at(17, 10), &delays()), None);
+    }
+
+    #[test]
+    fn it_leaves_confirmed_occurrences_alone() {
+        let picked_up = situation(OccurrenceStatus::PickedUp, false, false);
+        assert_eq!(reason(&picked_up, at(18, 0), &delays()), None);
+        let absent = situation(OccurrenceStatus::Absent, false, false);
+        assert_eq!(reason(&absent, at(18, 0), &delays()), None);
+    }
+
+    #[test]
+    fn it_reaches_further_after_each_delay() {
+        let mut escalation = Escalation {
+            id: "escalation".into(),
+            family_id: "family".into(),
+            subscription_id: "subscription".into(),
+            day: at(0, 0).date(),
+            reason: Reason::Missed.as_str().into(),
+            level: Level::Assigned.as_str().into(),
+            notified_at: at(17, 10),
+            acknowledged_by: None,
+            acknowledged_at: None,
+            resolved_at: None,
+            created_by: SCHEDULER.into(),
+            created_at: at(17, 10),
+            updated_by: SCHEDULER.into(),
+            updated_at: at(17, 10),
+        };
+        assert_eq!(next_level(&escalation, at(17, 15), &delays()), None);
+        assert_eq!(next_level(&escalation, at(17, 20), &delays()), Some(Level::Adults));
+
+        escalation.level = Level::Adults.as_str().into();
+        escalation.notified_at = at(17, 20);
+        assert_eq!(next_level(&escalation, at(17, 30), &delays()), None);
+        assert_eq!(next_level(&escalation, at(17, 35), &delays()), Some(Level::Contacts));
+
+        escalation.level = Level::Contacts.as_str().into();
+        assert_eq!(next_level(&escalation, at(20, 0), &delays()), None);
+    }
+}
@@ -0,0 +1,421 @@
+//! Estimated time of arrival of the adult on duty at an occurrence's place
+//!
+//! Speed and heading come from the adult's recent geolocs, the distance and
+//! travel time from a router. The default router goes in a straight line;
+//! another one, backed by a routing service, can be passed instead.
+
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::geo::{bearing_difference, Geofence, Point};
+use crate::handlers::event::EventResponse;
+use crate::models::event::{create, get_all_by_occurrence, status_of, Event, EventKind, NewEvent, OccurrenceStatus};
+use crate::models::family::{find_family, Family};
+use crate::models::geoloc::{find_between, find_latest, Geoloc};
+use crate::models::occurrence::{assigned_user_id, expand, get_assignments, ScheduledOccurrence};
+use crate::models::place::{find_place, Place};
+use crate::models::sharing::{visibility_for, Visibility};
+use crate::models::subscription::{day_name, find_all_by_family_id as find_subscriptions, find_subscription};
+use crate::scheduling::SCHEDULER;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use uuid::Uuid;
+
+/// Minutes of geolocs telling speed and heading
+pub const RECENT: i64 = 10;
+
+/// Minutes after which the last position no longer tells where someone is
+const STALE: i64 = 30;
+
+/// Minutes before its window an occurrence is checked for lateness
+const LOOKAHEAD: i64 = 60;
+
+/// Meters per second under which someone isn't considered on the move
+const MIN_SPEED: f64 = 0.5;
+
+/// Meters to move before a heading can be told from positions alone
+const MIN_HEADING_DISTANCE: f64 = 20.0;
+
+/// Degrees off the target beyond which someone is heading away from it
+const HEADING_AWAY: f64 = 90.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Route {
+    /// Meters
+    pub distance: f64,
+    pub duration: Duration,
+}
+
+/// Tells how far a place is, and how long it takes to get there
+pub trait Router {
+    /// Travel between two points at a speed in meters per second
+    fn route(&self, from: &Point, to: &Point, speed: f64) -> Result<Route, ApiError>;
+}
+
+/// Straight line, as the crow flies
+pub struct Haversine;
+
+impl Router for Haversine {
+    fn route(&self, from: &Point, to: &Point, speed: f64) -> Result<Route, ApiError> {
+        let distance = from.distance(to);
+        Ok(Route {
+            distance,
+            duration: Duration::seconds((distance / speed).round() as i64),
+        })
+    }
+}
+
+/// Where a target is, as far as estimates are concerned
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub center: Point,
+    pub fence: Geofence,
+}
+
+impl Target {
+    pub fn of_place(place: &Place) -> Option<Self> {
+        let fence = place.geofence()?;
+        let center = place.location().unwrap_or_else(|| {
+            let ring = fence.ring();
+            let count = ring.len() as f64;
+            Point::new(
+                ring.iter().map(|vertex| vertex.latitude).sum::<f64>() / count,
+                ring.iter().map(|vertex| vertex.longitude).sum::<f64>() / count,
+            )
+        });
+        Some(Target { center, fence })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    pub position: Point,
+    pub position_at: NaiveDateTime,
+    /// Meters per second observed recently, None when not moving
+    pub speed: Option<f64>,
+    /// Degrees clockwise from north, None when not moving
+    pub heading: Option<f64>,
+    pub heading_away: bool,
+    /// Meters left to travel
+    pub distance: f64,
+    pub duration: Duration,
+    pub arrival_at: NaiveDateTime,
+    /// Already inside the target
+    pub arrived: bool,
+}
+
+/// Average speed over positions, as reported or from the distance covered
+fn observed_speed(recent: &[Geoloc]) -> Option<f64> {
+    let reported: Vec<f64> = recent.iter().filter_map(|geoloc| geoloc.speed).collect();
+    let speed = if !reported.is_empty() {
+        reported.iter().sum::<f64>() / reported.len() as f64
+    } else {
+        let (first, last) = (recent.first()?, recent.last()?);
+        let seconds = (last.created_at - first.created_at).num_seconds();
+        if seconds <= 0 {
+            return None;
+        }
+        let distance: f64 = recent
+            .windows(2)
+            .map(|pair| point(&pair[0]).distance(&point(&pair[1])))
+            .sum();
+        distance / seconds as f64
+    };
+    if speed < MIN_SPEED {
+        None
+    } else {
+        Some(speed)
+    }
+}
+
+/// Heading of the latest position, as reported or from the way covered
+fn observed_heading(recent: &[Geoloc]) -> Option<f64> {
+    let last = recent.last()?;
+    if let Some(heading) = last.heading {
+        return Some(heading);
+    }
+    let first = recent.first()?;
+    if point(first).distance(&point(last)) < MIN_HEADING_DISTANCE {
+        return None;
+    }
+    Some(point(first).bearing(&point(last)))
+}
+
+fn point(geoloc: &Geoloc) -> Point {
+    Point::new(geoloc.latitude, geoloc.longitude)
+}
+
+/// Estimate when someone reaches a target from their recent positions,
+/// oldest first
+///
+/// The observed speed is kept while heading towards the target. Someone
+/// standing still or going the other way is expected to travel at
+/// `default_speed`, in meters per second, once they set off now.
+pub fn estimate(
+    recent: &[Geoloc],
+    target: &Target,
+    now: NaiveDateTime,
+    default_speed: f64,
+    router: &dyn Router,
+) -> Result<Option<Estimate>, ApiError> {
+    let last = match recent.last() {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+    let position = point(last);
+    let speed = observed_speed(recent);
+    let heading = speed.and(observed_heading(recent));
+    let heading_away = heading.map_or(false, |heading| {
+        bearing_difference(heading, position.bearing(&target.center)) > HEADING_AWAY
+    });
+
+    if target.fence.contains(&position, 0.0) {
+        return Ok(Some(Estimate {
+            position,
+            position_at: last.created_at,
+            speed,
+            heading,
+            heading_away,
+            distance: 0.0,
+            duration: Duration::zero(),
+            arrival_at: last.created_at,
+            arrived: true,
+        }));
+    }
+
+    let (travel_speed, departure) = match speed {
+        Some(speed) if !heading_away => (speed, last.created_at),
+        _ => (default_speed, now.max(last.created_at)),
+    };
+    let route = router.route(&position, &target.center, travel_speed)?;
+    Ok(Some(Estimate {
+        position,
+        position_at: last.created_at,
+        speed,
+        heading,
+        heading_away,
+        distance: route.distance,
+        duration: route.duration,
+        arrival_at: (departure + route.duration).max(now),
+        arrived: false,
+    }))
+}
+
+/// An occurrence with what it takes to tell if its adult is on time
+pub struct OccurrenceEstimate {
+    pub occurrence: ScheduledOccurrence,
+    pub place: Place,
+    /// UTC start of the window, when the adult is expected at the place
+    pub scheduled_at: Option<NaiveDateTime>,
+    pub events: Vec<Event>,
+    pub status: OccurrenceStatus,
+    /// None without positions, or when the viewer may not see them
+    pub estimate: Option<Estimate>,
+}
+
+impl OccurrenceEstimate {
+    /// How late the adult will be, if late at all
+    pub fn late_by(&self) -> Option<Duration> {
+        let estimate = self.estimate.as_ref()?;
+        let scheduled_at = self.scheduled_at?;
+        if estimate.arrived || estimate.arrival_at <= scheduled_at {
+            return None;
+        }
+        Some(estimate.arrival_at - scheduled_at)
+    }
+}
+
+/// Estimate the arrival of the adult on duty for an occurrence, as seen by
+/// a viewer
+pub fn estimate_occurrence(
+    pool: &PoolType,
+    subscription_id: &str,
+    day: NaiveDate,
+    viewer_id: &str,
+    router: &dyn Router,
+) -> Result<OccurrenceEstimate, ApiError> {
+    let subscription = find_subscription(pool, subscription_id)?;
+    if !subscription.runs_on(day) {
+        let message = format!("subscription {} doesn't run on {}", subscription_id, day);
+        return Err(ApiError::BadRequest(message));
+    }
+    let user_id = assigned_user_id(pool, &subscription, day)?;
+    let family = find_family(pool, &subscription.family_id)?;
+    let occurrence = ScheduledOccurrence {
+        subscription,
+        day,
+        user_id,
+    };
+    estimate_scheduled(pool, &family, occurrence, viewer_id, router)
+}
+
+/// Estimate the arrival of the adult on duty for an expanded occurrence
+pub fn estimate_scheduled(
+    pool: &PoolType,
+    family: &Family,
+    occurrence: ScheduledOccurrence,
+    viewer_id: &str,
+    router: &dyn Router,
+) -> Result<OccurrenceEstimate, ApiError> {
+    let place = find_place(pool, &occurrence.subscription.place_id)?;
+    let events = get_all_by_occurrence(pool, family, &occurrence.subscription.id, occurrence.day)?;
+    let status = status_of(&events);
+    let scheduled_at = occurrence.window().map(|(start, _)| family.to_utc(start));
+
+    let now = Utc::now().naive_utc();
+    let target = Target::of_place(&place);
+    let visible = visibility_for(pool, &occurrence.user_id, viewer_id)? == Visibility::Precise;
+    let estimate = match target {
+        Some(target) if visible => {
+            let mut recent = find_between(pool, &occurrence.user_id, now - Duration::minutes(RECENT), now, None)?;
+            if recent.is_empty() {
+                let fresh = now - Duration::minutes(STALE);
+                recent.extend(find_latest(pool, &occurrence.user_id)?.filter(|geoloc| geoloc.created_at >= fresh));
+            }
+            // Geolocs come most recent first
+            recent.reverse();
+            estimate(&recent, &target, now, CONFIG.travel_speed / 3.6, router)?
+        }
+        _ => None,
+    };
+
+    Ok(OccurrenceEstimate {
+        occurrence,
+        place,
+        scheduled_at,
+        events,
+        status,
+        estimate,
+    })
+}
+
+/// Record a late event for each occurrence of a family's day whose adult
+/// won't make it in time
+///
+/// Only occurrences starting within the lookahead, or already started and
+/// not over, are checked. Each gets at most one late event, and none once
+/// the child was picked up. Adults who don't share their location precisely
+/// are never estimated.
+pub fn report_late(
+    pool: &PoolType,
+    family: &Family,
+    now: NaiveDateTime,
+    router: &dyn Router,
+) -> Result<Vec<(EventResponse, Duration)>, ApiError> {
+    let today = family.today();
+    let subscriptions = find_subscriptions(pool, &family.id)?;
+    let assignments = get_assignments(pool, &family.id, today, today)?;
+    let due: Vec<ScheduledOccurrence> = expand(&subscriptions, &assignments, today, today)
+        .into_iter()
+        .filter(|occurrence| match occurrence.window() {
+            Some((start, end)) => {
+                family.to_utc(start) - Duration::minutes(LOOKAHEAD) <= now && now <= family.to_utc(end)
+            }
+            None => false,
+        })
+        .collect();
+
+    let mut reported = vec![];
+    for occurrence in due {
+        let estimated = estimate_scheduled(pool, family, occurrence, SCHEDULER, router)?;
+        let pending = estimated.status == OccurrenceStatus::Scheduled || estimated.status == OccurrenceStatus::DroppedOff;
+        let already = estimated.events.iter().any(|event| event.kind() == EventKind::Late);
+        let (late_by, estimate) = match (estimated.late_by(), &estimated.estimate) {
+            (Some(late_by), Some(estimate)) if pending && !already => (late_by, estimate),
+            _ => continue,
+        };
+
+        let expected_at = family.to_local(estimate.arrival_at);
+        let event: Event = NewEvent {
+            id: Uuid::new_v4().to_string(),
+            family_id: family.id.clone(),
+            subscription_id: estimated.occurrence.subscription.id.clone(),
+            place_id: estimated.place.id.clone(),
+            user_id: estimated.occurrence.user_id.clone(),
+            day: day_name(estimated.occurrence.day).into(),
+            message: format!("Running late, expected at {}", expected_at.format("%H:%M")),
+            created_by: SCHEDULER.into(),
+            updated_by: SCHEDULER.into(),
+            kind: EventKind::Late,
+            actor_id: None,
+            occurred_at: now,
+        }
+        .into();
+        reported.push((create(pool, &event)?, late_by));
+    }
+    Ok(reported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 10, 20).and_hms(16, 0, 0) + Duration::minutes(minutes)
+    }
+
+    fn geoloc(minutes: i64, latitude: f64, speed: Option<f64>) -> Geoloc {
+        Geoloc {
+            id: "00000000-0000-0000-0000-000000000000".into(),
+            user_id: "00000000-0000-0000-0000-000000000001".into(),
+            latitude,
+            longitude: 2.0,
+            created_at: at(minutes),
+            accuracy: None,
+            speed,
+            heading: None,
+            altitude: None,
+            battery: None,
+            received_at: at(minutes),
+        }
+    }
+
+    /// A school 0.1 degree north, about 11 km
+    fn school() -> Target {
+        let center = Point::new(48.1, 2.0);
+        Target {
+            center,
+            fence: Geofence::Circle(center, 100.0),
+        }
+    }
+
+    #[test]
+    fn it_keeps_the_observed_speed_towards_the_target() {
+        // 10 m/s north, 1.1 km covered in the last two minutes
+        let recent = vec![geoloc(0, 48.0, Some(10.0)), geoloc(2, 48.01, Some(10.0))];
+        let estimate = estimate(&recent, &school(), at(2), 5.0, &Haversine).unwrap().unwrap();
+        assert!(!estimate.heading_away);
+        assert!((estimate.distance - 10_008.0).abs() < 10.0);
+        assert_eq!(estimate.duration, Duration::seconds(1001));
+        assert_eq!(estimate.arrival_at, at(2) + Duration::seconds(1001));
+    }
+
+    #[test]
+    fn it_falls_back_on_the_default_speed_when_heading_away() {
+        let recent = vec![geoloc(0, 48.01, None), geoloc(2, 48.0, None)];
+        let estimate = estimate(&recent, &school(), at(3), 5.0, &Haversine).unwrap().unwrap();
+        assert!(estimate.heading_away);
+        assert_eq!(estimate.heading.map(f64::round), Some(180.0));
+        assert_eq!(estimate.arrival_at, at(3) + Duration::seconds(2224));
+    }
+
+    #[test]
+    fn it_sets_off_now_when_standing_still() {
+        let recent = vec![geoloc(0, 48.0, None), geoloc(5, 48.0, None)];
+        let estimate = estimate(&recent, &school(), at(10), 5.0, &Haversine).unwrap().unwrap();
+        assert_eq!(estimate.speed, None);
+        assert_eq!(estimate.arrival_at, at(10) + Duration::seconds(2224));
+    }
+
+    #[test]
+    fn it_tells_when_already_there() {
+        let recent = vec![geoloc(0, 48.1, None)];
+        let estimate = estimate(&recent, &school(), at(1), 5.0, &Haversine).unwrap().unwrap();
+        assert!(estimate.arrived);
+        assert_eq!(estimate.distance, 0.0);
+    }
+
+    #[test]
+    fn it_needs_a_position() {
+        assert_eq!(estimate(&[], &school(), at(0), 5.0, &Haversine).unwrap(), None);
+    }
+}
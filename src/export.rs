@@ -0,0 +1,306 @@
+//! Location history as GeoJSON, GPX or KML
+//!
+//! Documents are written piece by piece, a header, then geolocs in as many
+//! chunks as needed, then a footer, so a long range can be streamed without
+//! holding it in memory.
+
+use crate::errors::ApiError;
+use crate::geo::{Geofence, Point};
+use crate::models::geoloc::Geoloc;
+use chrono::NaiveDateTime;
+use serde_json::json;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// A FeatureCollection, one Point feature per geoloc
+    Geojson,
+    /// A track of timed points, places as waypoints
+    Gpx,
+    /// A LineString placemark, places as polygons
+    Kml,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Geojson => "geojson",
+            Format::Gpx => "gpx",
+            Format::Kml => "kml",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Geojson => "application/geo+json",
+            Format::Gpx => "application/gpx+xml",
+            Format::Kml => "application/vnd.google-earth.kml+xml",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Format {
+    type Err = ApiError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "geojson" => Ok(Format::Geojson),
+            "gpx" => Ok(Format::Gpx),
+            "kml" => Ok(Format::Kml),
+            _ => Err(ApiError::BadRequest(format!("unknown export format {}", format))),
+        }
+    }
+}
+
+/// A place drawn next to the track
+#[derive(Clone, Debug)]
+pub struct Area {
+    pub name: String,
+    pub fence: Geofence,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn timestamp(time: NaiveDateTime) -> String {
+    time.format("%Y-%m-%dT%H:%M:%S%.fZ").to_string()
+}
+
+/// GeoJSON and KML want longitude first
+fn coordinates(point: &Point) -> String {
+    format!("{},{}", point.longitude, point.latitude)
+}
+
+/// A ring closed on its first vertex
+fn closed(fence: &Geofence) -> Vec<Point> {
+    let mut ring = fence.ring();
+    if let Some(first) = ring.first().copied() {
+        ring.push(first);
+    }
+    ring
+}
+
+/// Writes one document, keeping track of what was already written
+pub struct Writer {
+    format: Format,
+    /// Nothing written between the brackets of the GeoJSON features yet
+    empty: bool,
+}
+
+impl Writer {
+    pub fn new(format: Format) -> Self {
+        Writer { format, empty: true }
+    }
+
+    /// A GeoJSON feature, comma separated from the previous one
+    fn feature(&mut self, feature: serde_json::Value) -> String {
+        let separator = if self.empty { "" } else { "," };
+        self.empty = false;
+        format!("{}{}", separator, feature)
+    }
+
+    /// Everything before the first geoloc, places included
+    pub fn header(&mut self, title: &str, areas: &[Area]) -> String {
+        match self.format {
+            Format::Geojson => {
+                let mut header = String::from("{\"type\":\"FeatureCollection\",\"features\":[");
+                for area in areas {
+                    let ring: Vec<_> = closed(&area.fence)
+                        .iter()
+                        .map(|point| vec![point.longitude, point.latitude])
+                        .collect();
+                    header.push_str(&self.feature(json!({
+                        "type": "Feature",
+                        "properties": { "name": area.name, "kind": "place" },
+                        "geometry": { "type": "Polygon", "coordinates": [ring] },
+                    })));
+                }
+                header
+            }
+            Format::Gpx => {
+                let mut header = String::from(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                     <gpx version=\"1.1\" creator=\"mama-server\" xmlns=\"http://www.topografix.com/GPX/1/1\">\n",
+                );
+                // GPX has no areas, places are marked by their center
+                for area in areas {
+                    let center = match &area.fence {
+                        Geofence::Circle(center, _) => *center,
+                        Geofence::Polygon(vertices) => {
+                            let count = vertices.len() as f64;
+                            Point::new(
+                                vertices.iter().map(|vertex| vertex.latitude).sum::<f64>() / count,
+                                vertices.iter().map(|vertex| vertex.longitude).sum::<f64>() / count,
+                            )
+                        }
+                    };
+                    header.push_str(&format!(
+                        "<wpt lat=\"{}\" lon=\"{}\"><name>{}</name></wpt>\n",
+                        center.latitude,
+                        center.longitude,
+                        escape(&area.name)
+                    ));
+                }
+                header.push_str(&format!("<trk><name>{}</name><trkseg>\n", escape(title)));
+                header
+            }
+            Format::Kml => {
+                let mut header = format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                     <kml xmlns=\"http://www.opengis.net/kml/2.2\"><Document><name>{}</name>\n",
+                    escape(title)
+                );
+                for area in areas {
+                    let ring: Vec<_> = closed(&area.fence).iter().map(coordinates).collect();
+                    header.push_str(&format!(
+                        "<Placemark><name>{}</name><Polygon><outerBoundaryIs><LinearRing><coordinates>{}\
+                         </coordinates></LinearRing></outerBoundaryIs></Polygon></Placemark>\n",
+                        escape(&area.name),
+                        ring.join(" ")
+                    ));
+                }
+                header.push_str(&format!(
+                    "<Placemark><name>{}</name><LineString><coordinates>\n",
+                    escape(title)
+                ));
+                header
+            }
+        }
+    }
+
+    pub fn geolocs(&mut self, geolocs: &[Geoloc]) -> String {
+        let mut chunk = String::new();
+        for geoloc in geolocs {
+            let point = Point::new(geoloc.latitude, geoloc.longitude);
+            match self.format {
+                Format::Geojson => {
+                    let mut position = vec![geoloc.longitude, geoloc.latitude];
+                    position.extend(geoloc.altitude);
+                    chunk.push_str(&self.feature(json!({
+                        "type": "Feature",
+                        "properties": {
+                            "time": timestamp(geoloc.created_at),
+                            "accuracy": geoloc.accuracy,
+                            "speed": geoloc.speed,
+                            "heading": geoloc.heading,
+                            "battery": geoloc.battery,
+                        },
+                        "geometry": { "type": "Point", "coordinates": position },
+                    })));
+                }
+                Format::Gpx => {
+                    let elevation = geoloc
+                        .altitude
+                        .map_or_else(String::new, |altitude| format!("<ele>{}</ele>", altitude));
+                    chunk.push_str(&format!(
+                        "<trkpt lat=\"{}\" lon=\"{}\">{}<time>{}</time></trkpt>\n",
+                        point.latitude,
+                        point.longitude,
+                        elevation,
+                        timestamp(geoloc.created_at)
+                    ));
+                }
+                Format::Kml => {
+                    chunk.push_str(&coordinates(&point));
+                    chunk.push('\n');
+                }
+            }
+        }
+        chunk
+    }
+
+    pub fn footer(&self) -> String {
+        match self.format {
+            Format::Geojson => "]}".into(),
+            Format::Gpx => "</trkseg></trk>\n</gpx>\n".into(),
+            Format::Kml => "</coordinates></LineString></Placemark>\n</Document></kml>\n".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn geoloc(minute: u32, latitude: f64) -> Geoloc {
+        let at = NaiveDate::from_ymd(2020, 10, 19).and_hms(8, minute, 0);
+        Geoloc {
+            id: "00000000-0000-0000-0000-000000000000".into(),
+            user_id: "00000000-0000-0000-0000-000000000001".into(),
+            latitude,
+            longitude: 2.35,
+            created_at: at,
+            accuracy: Some(5.0),
+            speed: None,
+            heading: None,
+            altitude: None,
+            battery: None,
+            received_at: at,
+        }
+    }
+
+    fn school() -> Area {
+        Area {
+            name: "École & co".into(),
+            fence: Geofence::Circle(Point::new(48.85, 2.35), 100.0),
+        }
+    }
+
+    fn write(format: Format, chunks: &[Vec<Geoloc>]) -> String {
+        let mut writer = Writer::new(format);
+        let mut document = writer.header("Trip", &[school()]);
+        for chunk in chunks {
+            document.push_str(&writer.geolocs(chunk));
+        }
+        document.push_str(&writer.footer());
+        document
+    }
+
+    #[test]
+    fn it_writes_a_feature_collection_across_chunks() {
+        let document = write(Format::Geojson, &[vec![geoloc(0, 48.85)], vec![], vec![geoloc(1, 48.86)]]);
+        let collection: serde_json::Value = serde_json::from_str(&document).unwrap();
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[0]["geometry"]["type"], "Polygon");
+        assert_eq!(features[0]["properties"]["name"], "École & co");
+        assert_eq!(features[2]["geometry"]["coordinates"], json!([2.35, 48.86]));
+        assert_eq!(features[2]["properties"]["time"], "2020-10-19T08:01:00Z");
+    }
+
+    #[test]
+    fn it_writes_an_empty_feature_collection() {
+        let mut writer = Writer::new(Format::Geojson);
+        let document = format!("{}{}", writer.header("Trip", &[]), writer.footer());
+        assert_eq!(document, "{\"type\":\"FeatureCollection\",\"features\":[]}");
+    }
+
+    #[test]
+    fn it_writes_a_gpx_track() {
+        let document = write(Format::Gpx, &[vec![geoloc(0, 48.85)]]);
+        assert!(document.contains("<wpt lat=\"48.85\" lon=\"2.35\"><name>École &amp; co</name></wpt>"));
+        assert!(document.contains("<trkpt lat=\"48.85\" lon=\"2.35\"><time>2020-10-19T08:00:00Z</time></trkpt>"));
+        assert!(document.ends_with("</gpx>\n"));
+    }
+
+    #[test]
+    fn it_writes_a_kml_line() {
+        let document = write(Format::Kml, &[vec![geoloc(0, 48.85), geoloc(1, 48.86)]]);
+        assert!(document.contains("<LineString><coordinates>\n2.35,48.85\n2.35,48.86\n</coordinates>"));
+        assert!(document.contains("<Polygon>"));
+    }
+}
@@ -1,13 +1,17 @@
-use crate::auth::{decode_jwt, PrivateClaim};
+use crate::auth::decode_jwt;
+use crate::config::CONFIG;
+use crate::models::audit::ClientInfo;
 use crate::models::user::AuthUser;
 use actix_identity::RequestIdentity;
 use actix_web::{
-    dev::Payload,
+    dev::{ConnectionInfo, Payload},
+    http::{header::USER_AGENT, HeaderMap},
     web::{HttpRequest, HttpResponse},
     Error,
     FromRequest,
 };
 use futures::future::{ok, err, Ready};
+use std::net::SocketAddr;
 
 /// Extractor for pulling the identity out of a request.
 ///
@@ -19,13 +23,105 @@ impl FromRequest for AuthUser {
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
         let identity = RequestIdentity::get_identity(req);
-        if let Some(identity) = identity {
-            let private_claim: PrivateClaim = decode_jwt(&identity).unwrap();
-            return ok(AuthUser {
+        // An expired or forged session is no session, `Option<AuthUser>` gets None
+        let private_claim = identity.and_then(|identity| decode_jwt(&identity).ok());
+        match private_claim {
+            Some(private_claim) => ok(AuthUser {
                 id: private_claim.user_id.to_string(),
                 email: private_claim.email,
-            });
+            }),
+            None => err(HttpResponse::Unauthorized().into()),
         }
-        err(HttpResponse::Unauthorized().into())
+    }
+}
+
+/// Extractor for the address and user agent of the client of a request.
+///
+/// Never fails, both are missing when the client did not give them.
+impl FromRequest for ClientInfo {
+    type Error = Error;
+    type Config = ();
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ok(client_info(req.peer_addr(), &req.connection_info(), req.headers()))
+    }
+}
+
+/// The client of a request, from its connection and headers
+///
+/// Forwarded headers are anyone's to set, they are only believed when the
+/// peer is the configured proxy.
+pub fn client_info(
+    peer: Option<SocketAddr>,
+    connection: &ConnectionInfo,
+    headers: &HeaderMap,
+) -> ClientInfo {
+    ClientInfo {
+        ip_address: remote_address(peer, connection, CONFIG.trusted_proxy.as_deref()),
+        user_agent: headers
+            .get(USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(str::to_string),
+    }
+}
+
+fn remote_address(
+    peer: Option<SocketAddr>,
+    connection: &ConnectionInfo,
+    trusted_proxy: Option<&str>,
+) -> Option<String> {
+    let peer = peer.map(|peer| peer.ip().to_string());
+    match (&peer, trusted_proxy) {
+        (Some(peer), Some(proxy)) if peer == proxy => connection.remote().map(strip_port),
+        _ => peer,
+    }
+}
+
+/// The remote address may come with a port, "[::1]:8080" or "127.0.0.1:8080"
+fn strip_port(remote: &str) -> String {
+    if let Some(end) = remote.find(']') {
+        return remote[..end].trim_start_matches('[').to_string();
+    }
+    match remote.rfind(':') {
+        Some(colon) if remote.find(':') == Some(colon) => remote[..colon].to_string(),
+        _ => remote.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn it_strips_the_port_of_a_remote_address() {
+        assert_eq!(strip_port("127.0.0.1:8080"), "127.0.0.1");
+        assert_eq!(strip_port("127.0.0.1"), "127.0.0.1");
+        assert_eq!(strip_port("[::1]:8080"), "::1");
+        assert_eq!(strip_port("2001:db8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn it_only_believes_forwarded_headers_from_the_proxy() {
+        let req = TestRequest::default()
+            .header("x-forwarded-for", "203.0.113.7")
+            .to_http_request();
+        let connection = req.connection_info();
+        let proxy: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        let stranger: SocketAddr = "198.51.100.9:40000".parse().unwrap();
+
+        assert_eq!(
+            remote_address(Some(proxy), &connection, Some("10.0.0.2")),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(
+            remote_address(Some(stranger), &connection, Some("10.0.0.2")),
+            Some("198.51.100.9".to_string())
+        );
+        assert_eq!(
+            remote_address(Some(proxy), &connection, None),
+            Some("10.0.0.2".to_string())
+        );
     }
 }
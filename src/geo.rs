@@ -0,0 +1,282 @@
+//! Geographic helpers working on WGS84 coordinates
+
+use crate::errors::ApiError;
+
+/// Mean earth radius in meters
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// Vertices of the polygon standing for a circle
+const CIRCLE_VERTICES: usize = 32;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Point {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Point {
+    pub fn new(latitude: f64, longitude: f64) -> Self {
+        Point { latitude, longitude }
+    }
+
+    /// Great-circle distance to another point in meters (haversine)
+    pub fn distance(&self, other: &Point) -> f64 {
+        let d_latitude = (other.latitude - self.latitude).to_radians();
+        let d_longitude = (other.longitude - self.longitude).to_radians();
+        let a = (d_latitude / 2.0).sin().powi(2)
+            + self.latitude.to_radians().cos()
+                * other.latitude.to_radians().cos()
+                * (d_longitude / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Initial bearing towards another point, in degrees clockwise from north
+    pub fn bearing(&self, other: &Point) -> f64 {
+        let (latitude, other_latitude) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let d_longitude = (other.longitude - self.longitude).to_radians();
+        let y = d_longitude.sin() * other_latitude.cos();
+        let x = latitude.cos() * other_latitude.sin() - latitude.sin() * other_latitude.cos() * d_longitude.cos();
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
+}
+
+/// Smallest angle between two bearings, in degrees
+pub fn bearing_difference(first: f64, second: f64) -> f64 {
+    let difference = (first - second).abs() % 360.0;
+    difference.min(360.0 - difference)
+}
+
+/// The area of a place, used to tell when someone arrives or leaves
+#[derive(Clone, Debug, PartialEq)]
+pub enum Geofence {
+    /// A disc around a center, radius in meters
+    Circle(Point, f64),
+    /// A closed ring of at least three vertices
+    Polygon(Vec<Point>),
+}
+
+impl Geofence {
+    /// Is the point inside the fence, or within `margin` meters of it
+    pub fn contains(&self, point: &Point, margin: f64) -> bool {
+        match self {
+            Geofence::Circle(center, radius) => center.distance(point) <= radius + margin,
+            Geofence::Polygon(vertices) => {
+                inside_polygon(vertices, point) || (margin > 0.0 && distance_to_ring(vertices, point) <= margin)
+            }
+        }
+    }
+
+    /// The outline of the fence, circles approximated by a polygon of
+    /// `CIRCLE_VERTICES` vertices, for formats that only know polygons
+    pub fn ring(&self) -> Vec<Point> {
+        match self {
+            Geofence::Circle(center, radius) => {
+                let d_latitude = (radius / EARTH_RADIUS).to_degrees();
+                let d_longitude = d_latitude / center.latitude.to_radians().cos();
+                (0..CIRCLE_VERTICES)
+                    .map(|index| {
+                        let angle = 2.0 * std::f64::consts::PI * index as f64 / CIRCLE_VERTICES as f64;
+                        Point::new(
+                            center.latitude + d_latitude * angle.cos(),
+                            center.longitude + d_longitude * angle.sin(),
+                        )
+                    })
+                    .collect()
+            }
+            Geofence::Polygon(vertices) => vertices.clone(),
+        }
+    }
+}
+
+/// Ray casting, good enough for the few hundred meters a place spans
+fn inside_polygon(vertices: &[Point], point: &Point) -> bool {
+    let mut inside = false;
+    let mut previous = vertices[vertices.len() - 1];
+    for vertex in vertices {
+        if (vertex.latitude > point.latitude) != (previous.latitude > point.latitude) {
+            let crossing = (previous.longitude - vertex.longitude) * (point.latitude - vertex.latitude)
+                / (previous.latitude - vertex.latitude)
+                + vertex.longitude;
+            if point.longitude < crossing {
+                inside = !inside;
+            }
+        }
+        previous = *vertex;
+    }
+    inside
+}
+
+/// Distance in meters from a point to the closest edge of a ring
+fn distance_to_ring(vertices: &[Point], point: &Point) -> f64 {
+    let mut closest = f64::MAX;
+    let mut previous = &vertices[vertices.len() - 1];
+    for vertex in vertices {
+        closest = closest.min(distance_to_segment(point, previous, vertex));
+        previous = vertex;
+    }
+    closest
+}
+
+/// Distance in meters from a point to a segment
+///
+/// Uses an equirectangular projection around the point, accurate at the
+/// scale of a neighbourhood.
+fn distance_to_segment(point: &Point, start: &Point, end: &Point) -> f64 {
+    let scale = point.latitude.to_radians().cos();
+    let project = |other: &Point| {
+        (
+            (other.longitude - point.longitude).to_radians() * scale * EARTH_RADIUS,
+            (other.latitude - point.latitude).to_radians() * EARTH_RADIUS,
+        )
+    };
+    let (start, end) = (project(start), project(end));
+    let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+    let length = dx * dx + dy * dy;
+    let t = if length == 0.0 {
+        0.0
+    } else {
+        (-(start.0 * dx + start.1 * dy) / length).max(0.0).min(1.0)
+    };
+    let (x, y) = (start.0 + t * dx, start.1 + t * dy);
+    (x * x + y * y).sqrt()
+}
+
+/// Indices of the points kept by Douglas-Peucker simplification
+///
+/// Points closer than `tolerance` meters to the simplified line are dropped,
+/// the first and last are always kept.
+pub fn simplify(points: &[Point], tolerance: f64) -> Vec<usize> {
+    if points.len() < 3 {
+        return (0..points.len()).collect();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    let mut ranges = vec![(0, points.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let farthest = (first + 1..last)
+            .map(|index| (index, distance_to_segment(&points[index], &points[first], &points[last])))
+            .fold(None, |farthest: Option<(usize, f64)>, candidate| match farthest {
+                Some(farthest) if farthest.1 >= candidate.1 => Some(farthest),
+                _ => Some(candidate),
+            });
+        if let Some((index, distance)) = farthest {
+            if distance > tolerance {
+                keep[index] = true;
+                ranges.push((first, index));
+                ranges.push((index, last));
+            }
+        }
+    }
+    (0..points.len()).filter(|index| keep[*index]).collect()
+}
+
+/// Optional coordinates must be given together and within WGS84 bounds
+pub fn validate_location(latitude: Option<f64>, longitude: Option<f64>) -> Result<(), ApiError> {
+    let valid = match (latitude, longitude) {
+        (None, None) => true,
+        (Some(latitude), Some(longitude)) => {
+            (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+        }
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(vec![
+            "latitude and longitude must be set together, within -90..90 and -180..180".into(),
+        ]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_measures_a_distance() {
+        let paris = Point::new(48.8566, 2.3522);
+        let lyon = Point::new(45.7640, 4.8357);
+        let distance = paris.distance(&lyon);
+        assert!((distance - 391_500.0).abs() < 1_000.0);
+    }
+
+    #[test]
+    fn it_measures_no_distance_to_itself() {
+        let paris = Point::new(48.8566, 2.3522);
+        assert_eq!(paris.distance(&paris), 0.0);
+    }
+
+    #[test]
+    fn it_validates_a_location() {
+        assert!(validate_location(None, None).is_ok());
+        assert!(validate_location(Some(48.8), Some(2.3)).is_ok());
+        assert!(validate_location(Some(48.8), None).is_err());
+        assert!(validate_location(Some(91.0), Some(2.3)).is_err());
+    }
+
+    fn square() -> Geofence {
+        // roughly 110 m on each side
+        Geofence::Polygon(vec![
+            Point::new(48.0, 2.0),
+            Point::new(48.0, 2.0015),
+            Point::new(48.001, 2.0015),
+            Point::new(48.001, 2.0),
+        ])
+    }
+
+    #[test]
+    fn it_tells_if_a_circle_contains_a_point() {
+        let fence = Geofence::Circle(Point::new(48.8566, 2.3522), 100.0);
+        assert!(fence.contains(&Point::new(48.8570, 2.3522), 0.0));
+        assert!(!fence.contains(&Point::new(48.8577, 2.3522), 0.0));
+        assert!(fence.contains(&Point::new(48.8577, 2.3522), 50.0));
+    }
+
+    #[test]
+    fn it_tells_if_a_polygon_contains_a_point() {
+        assert!(square().contains(&Point::new(48.0005, 2.0007), 0.0));
+        assert!(!square().contains(&Point::new(48.0015, 2.0007), 0.0));
+    }
+
+    #[test]
+    fn it_measures_a_bearing() {
+        let origin = Point::new(48.0, 2.0);
+        assert!((origin.bearing(&Point::new(48.1, 2.0)) - 0.0).abs() < 0.01);
+        assert!((origin.bearing(&Point::new(48.0, 2.1)) - 90.0).abs() < 0.1);
+        assert!((origin.bearing(&Point::new(47.9, 2.0)) - 180.0).abs() < 0.01);
+        assert_eq!(bearing_difference(350.0, 10.0), 20.0);
+    }
+
+    #[test]
+    fn it_outlines_a_circle() {
+        let center = Point::new(48.85, 2.35);
+        let ring = Geofence::Circle(center, 100.0).ring();
+        assert_eq!(ring.len(), CIRCLE_VERTICES);
+        assert!(ring.iter().all(|vertex| (center.distance(vertex) - 100.0).abs() < 0.5));
+    }
+
+    #[test]
+    fn it_simplifies_a_line() {
+        // A straight line north with a 10 m bump in the middle, then a turn east
+        let points = vec![
+            Point::new(48.0, 2.0),
+            Point::new(48.001, 2.0),
+            Point::new(48.002, 2.00013),
+            Point::new(48.003, 2.0),
+            Point::new(48.004, 2.0),
+            Point::new(48.004, 2.005),
+        ];
+        assert_eq!(simplify(&points, 20.0), vec![0, 4, 5]);
+        assert_eq!(simplify(&points, 5.0), vec![0, 2, 4, 5]);
+    }
+
+    #[test]
+    fn it_applies_a_margin_around_a_polygon() {
+        // about 33 m north of the square
+        let point = Point::new(48.0013, 2.0007);
+        assert!(!square().contains(&point, 20.0));
+        assert!(square().contains(&point, 40.0));
+    }
+}
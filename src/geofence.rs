@@ -0,0 +1,286 @@
+//! Arrival and departure detection from geolocs
+//!
+//! A user enters a place when a geoloc falls inside its geofence, and only
+//! leaves it once a geoloc falls beyond the fence plus a margin, so GPS
+//! jitter along the edge doesn't produce a stream of crossings. Crossings
+//! are recorded as events of the matching subscription occurrence.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::geo::{Geofence, Point};
+use crate::models::event::{insert, Event, EventKind, NewEvent};
+use crate::models::family::{find_family, Family};
+use crate::models::occurrence::{expand, get_assignments, ScheduledOccurrence};
+use crate::models::place::find_all_by_family_id as find_places;
+use crate::models::presence::{enter, find_all_by_user_id, leave};
+use crate::models::subscription::{day_name, find_all_by_family_id as find_subscriptions};
+use crate::models::user::User;
+use chrono::NaiveDateTime;
+use diesel::Connection;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Meters beyond a geofence before someone is considered gone
+pub const HYSTERESIS: f64 = 50.0;
+
+/// Minutes around its window a crossing still belongs to an occurrence
+const MATCH_SLACK: i64 = 60;
+
+/// Actor recorded in `created_by` for events detected from geolocs
+pub const GEOFENCE: &str = "geofence";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Crossing {
+    Arrival,
+    Departure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub place_id: String,
+    pub crossing: Crossing,
+}
+
+/// Compare a position with the places a user was inside
+pub fn detect(fences: &[(String, Geofence)], inside: &HashSet<String>, point: &Point) -> Vec<Transition> {
+    fences
+        .iter()
+        .filter_map(|(place_id, fence)| {
+            let was_inside = inside.contains(place_id);
+            let crossing = if !was_inside && fence.contains(point, 0.0) {
+                Crossing::Arrival
+            } else if was_inside && !fence.contains(point, HYSTERESIS) {
+                Crossing::Departure
+            } else {
+                return None;
+            };
+            Some(Transition {
+                place_id: place_id.clone(),
+                crossing,
+            })
+        })
+        .collect()
+}
+
+/// Pick the occurrence a crossing belongs to
+///
+/// The user must be the child or the adult responsible that day. The
+/// closest window wins, occurrences without a window only as a last resort.
+/// `at` is on the family's clock, like the windows.
+pub fn match_occurrence<'a>(
+    occurrences: &'a [ScheduledOccurrence],
+    user_id: &str,
+    at: NaiveDateTime,
+) -> Option<&'a ScheduledOccurrence> {
+    occurrences
+        .iter()
+        .filter(|occurrence| {
+            occurrence.user_id == user_id || occurrence.subscription.child_id.as_deref() == Some(user_id)
+        })
+        .filter_map(|occurrence| {
+            let minutes_away = match occurrence.window() {
+                Some((start, _)) if at < start => (start - at).num_minutes(),
+                Some((_, end)) if at > end => (at - end).num_minutes(),
+                Some(_) => 0,
+                None => MATCH_SLACK + 1,
+            };
+            if occurrence.window().is_some() && minutes_away > MATCH_SLACK {
+                return None;
+            }
+            Some((minutes_away, occurrence))
+        })
+        .min_by_key(|(minutes_away, _)| *minutes_away)
+        .map(|(_, occurrence)| occurrence)
+}
+
+/// Pick the occurrence at a place a crossing at a UTC time belongs to, on
+/// the day it happened for the family
+pub fn match_crossing(
+    family: &Family,
+    occurrences: &[ScheduledOccurrence],
+    place_id: &str,
+    user_id: &str,
+    at: NaiveDateTime,
+) -> Option<ScheduledOccurrence> {
+    let local = family.to_local(at);
+    let at_place: Vec<_> = occurrences
+        .iter()
+        .filter(|occurrence| occurrence.day == local.date() && occurrence.subscription.place_id == place_id)
+        .cloned()
+        .collect();
+    match_occurrence(&at_place, user_id, local).cloned()
+}
+
+/// Update the places a user is inside from new positions, oldest first, and
+/// record an event for each crossing that matches an occurrence of its day
+pub fn track(pool: &PoolType, user: &User, positions: &[(Point, NaiveDateTime)]) -> Result<Vec<Event>, ApiError> {
+    let family_id = match &user.family_id {
+        Some(family_id) => family_id,
+        None => return Ok(vec![]),
+    };
+    let family = find_family(pool, family_id)?;
+    let (first, last) = match (positions.first(), positions.last()) {
+        (Some(first), Some(last)) => (family.to_local(first.1).date(), family.to_local(last.1).date()),
+        _ => return Ok(vec![]),
+    };
+    let places = find_places(pool, family_id)?;
+    let fences: Vec<_> = places
+        .iter()
+        .filter_map(|place| place.geofence().map(|fence| (place.id.clone(), fence)))
+        .collect();
+    if fences.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let subscriptions = find_subscriptions(pool, family_id)?;
+    let assignments = get_assignments(pool, family_id, first, last)?;
+    let occurrences = expand(&subscriptions, &assignments, first, last);
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let mut inside: HashSet<String> = find_all_by_user_id(&conn, &user.id)?
+            .into_iter()
+            .map(|presence| presence.place_id)
+            .collect();
+
+        let mut events = vec![];
+        for (point, at) in positions {
+            let at = *at;
+            for transition in detect(&fences, &inside, point) {
+                let place = places.iter().find(|place| place.id == transition.place_id).unwrap();
+                let (kind, message) = match transition.crossing {
+                    Crossing::Arrival => {
+                        enter(&conn, &user.id, &place.id, at)?;
+                        inside.insert(place.id.clone());
+                        (EventKind::Arrival, format!("Arrived at {}", place.name))
+                    }
+                    Crossing::Departure => {
+                        leave(&conn, &user.id, &place.id)?;
+                        inside.remove(&place.id);
+                        (EventKind::Departure, format!("Left {}", place.name))
+                    }
+                };
+
+                if let Some(occurrence) = match_crossing(&family, &occurrences, &place.id, &user.id, at) {
+                    let event: Event = NewEvent {
+                        id: Uuid::new_v4().to_string(),
+                        family_id: family_id.clone(),
+                        subscription_id: occurrence.subscription.id.clone(),
+                        place_id: place.id.clone(),
+                        user_id: user.id.clone(),
+                        day: day_name(occurrence.day).into(),
+                        message,
+                        created_by: GEOFENCE.into(),
+                        updated_by: GEOFENCE.into(),
+                        kind,
+                        actor_id: Some(user.id.clone()),
+                        occurred_at: at,
+                    }
+                    .into();
+                    insert(&conn, &event)?;
+                    events.push(event);
+                }
+            }
+        }
+        Ok(events)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::subscription::Subscription;
+    use chrono::{NaiveDate, NaiveTime, Utc};
+
+    fn school() -> (String, Geofence) {
+        ("school".to_string(), Geofence::Circle(Point::new(48.8566, 2.3522), 100.0))
+    }
+
+    fn occurrence(user_id: &str, child_id: Option<&str>, window: Option<((u32, u32), (u32, u32))>) -> ScheduledOccurrence {
+        ScheduledOccurrence {
+            subscription: Subscription {
+                id: Uuid::new_v4().to_string(),
+                family_id: "family".into(),
+                place_id: "school".into(),
+                user_id: user_id.into(),
+                days: "lundi".into(),
+                created_by: "".into(),
+                created_at: Utc::now().naive_utc(),
+                updated_by: "".into(),
+                updated_at: Utc::now().naive_utc(),
+                child_id: child_id.map(Into::into),
+                start_time: window.map(|(start, _)| NaiveTime::from_hms(start.0, start.1, 0)),
+                end_time: window.map(|(_, end)| NaiveTime::from_hms(end.0, end.1, 0)),
+                deleted_at: None,
+                deleted_by: None,
+            },
+            day: NaiveDate::from_ymd(2020, 10, 12),
+            user_id: user_id.into(),
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 10, 12).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn it_detects_an_arrival() {
+        let transitions = detect(&[school()], &HashSet::new(), &Point::new(48.8567, 2.3522));
+        assert_eq!(transitions.len(), 1);
+        assert_eq!(transitions[0].crossing, Crossing::Arrival);
+    }
+
+    #[test]
+    fn it_waits_past_the_margin_before_a_departure() {
+        let mut inside = HashSet::new();
+        inside.insert("school".to_string());
+        // about 130 m from the center, within the margin
+        assert!(detect(&[school()], &inside, &Point::new(48.8578, 2.3522)).is_empty());
+        // about 230 m from the center
+        let transitions = detect(&[school()], &inside, &Point::new(48.8587, 2.3522));
+        assert_eq!(transitions[0].crossing, Crossing::Departure);
+    }
+
+    #[test]
+    fn it_matches_the_closest_occurrence_of_the_user() {
+        let occurrences = vec![
+            occurrence("mom", Some("kid"), Some(((8, 0), (8, 30)))),
+            occurrence("mom", Some("kid"), Some(((16, 30), (17, 0)))),
+            occurrence("dad", None, Some(((8, 0), (8, 30)))),
+        ];
+        let matched = match_occurrence(&occurrences, "kid", at(16, 20)).unwrap();
+        assert_eq!(matched, &occurrences[1]);
+        assert!(match_occurrence(&occurrences, "kid", at(12, 0)).is_none());
+        assert!(match_occurrence(&occurrences, "grandma", at(8, 0)).is_none());
+    }
+
+    #[test]
+    fn it_matches_crossings_on_the_family_clock() {
+        let family = Family {
+            id: "family".into(),
+            nom: "".into(),
+            code: "".into(),
+            created_by: "".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "".into(),
+            updated_at: Utc::now().naive_utc(),
+            timezone: "America/Los_Angeles".into(),
+            location_retention_days: None,
+        };
+        let occurrences = vec![occurrence("mom", Some("kid"), Some(((16, 30), (17, 0))))];
+        // 16:40 on the 12th in Los Angeles is 23:40 UTC
+        let matched = match_crossing(&family, &occurrences, "school", "kid", at(23, 40));
+        assert_eq!(matched.as_ref(), Some(&occurrences[0]));
+        // 16:40 UTC is 09:40 in Los Angeles, far from the window
+        assert!(match_crossing(&family, &occurrences, "school", "kid", at(16, 40)).is_none());
+        // 00:10 UTC on the 13th is still 17:10 on the 12th there
+        let next_day = NaiveDate::from_ymd(2020, 10, 13).and_hms(0, 10, 0);
+        assert!(match_crossing(&family, &occurrences, "school", "kid", next_day).is_some());
+    }
+
+    #[test]
+    fn it_falls_back_on_occurrences_without_a_window() {
+        let occurrences = vec![occurrence("mom", None, None)];
+        assert!(match_occurrence(&occurrences, "mom", at(12, 0)).is_some());
+    }
+}
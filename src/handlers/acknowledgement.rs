@@ -0,0 +1,204 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::event::EventResponse;
+use crate::helpers::respond_json;
+use crate::models::acknowledgement::{
+    acknowledge, find_all_by_event_id, find_mandatory_kinds, request, save_mandatory_kinds, Acknowledgement,
+};
+use crate::models::activity::ActivityKind;
+use crate::models::event::{find_event, Event, EventKind};
+use crate::models::user::{find_user, get_adults_by_family_id, AuthUser, Membership, User};
+use crate::notify::{send_to_users, Notification, Priority};
+use crate::stream::publish;
+use actix_web::web::{block, Data, Json, Path};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AcknowledgementResponse {
+    pub event_id: Uuid,
+    pub user_id: Uuid,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    pub notified_at: NaiveDateTime,
+    pub reminders: i32,
+}
+
+/// Who has and hasn't acknowledged an event
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EventAcknowledgementsResponse {
+    pub event_id: Uuid,
+    pub mandatory: bool,
+    pub acknowledged: Vec<AcknowledgementResponse>,
+    pub pending: Vec<AcknowledgementResponse>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MandatoryKindsResponse {
+    pub family_id: Uuid,
+    pub kinds: Vec<EventKind>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateMandatoryKindsRequest {
+    pub kinds: Vec<EventKind>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByEventID {
+    event_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get who has and hasn't acknowledged an event
+pub async fn get_acknowledgements(
+    user: AuthUser,
+    path: Path<PathByEventID>,
+    pool: Data<PoolType>,
+) -> Result<Json<EventAcknowledgementsResponse>, ApiError> {
+    let (event, mandatory, all) = block(move || {
+        let event = find_event(&pool, &path.event_id.to_string())?;
+        Membership::Member.of(&pool, &user, &event.family_id)?;
+        let mandatory = find_mandatory_kinds(&pool, &event.family_id)?.contains(&event.kind());
+        let all = find_all_by_event_id(&pool, &event.id)?;
+        Ok::<_, ApiError>((event, mandatory, all))
+    })
+    .await?;
+
+    let (acknowledged, pending): (Vec<_>, Vec<_>) = all
+        .into_iter()
+        .partition(|acknowledgement| acknowledgement.acknowledged_at.is_some());
+    respond_json(EventAcknowledgementsResponse {
+        event_id: Uuid::parse_str(&event.id)?,
+        mandatory,
+        acknowledged: acknowledged.into_iter().map(|acknowledgement| acknowledgement.into()).collect(),
+        pending: pending.into_iter().map(|acknowledgement| acknowledgement.into()).collect(),
+    })
+}
+
+/// Acknowledge an event the caller was sent
+pub async fn acknowledge_event(
+    user: AuthUser,
+    path: Path<PathByEventID>,
+    pool: Data<PoolType>,
+) -> Result<Json<AcknowledgementResponse>, ApiError> {
+    let pool_ack = pool.clone();
+    let acknowledgement = block(move || {
+        let event = find_event(&pool_ack, &path.event_id.to_string())?;
+        Membership::Member.of(&pool_ack, &user, &event.family_id)?;
+        acknowledge(&pool_ack, &event.id, &user.id)
+    })
+    .await?;
+    let response: AcknowledgementResponse = acknowledgement.clone().into();
+    publish(&pool, &acknowledgement.family_id, ActivityKind::EventAcknowledged, &response).await;
+    respond_json(response)
+}
+
+/// Get the event kinds a family must acknowledge
+pub async fn get_mandatory_kinds(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<MandatoryKindsResponse>, ApiError> {
+    let family_id = path.family_id;
+    let kinds = block(move || {
+        Membership::Member.of(&pool, &user, &family_id.to_string())?;
+        find_mandatory_kinds(&pool, &family_id.to_string())
+    })
+    .await?;
+    respond_json(MandatoryKindsResponse { family_id, kinds })
+}
+
+/// Choose the event kinds a family must acknowledge, only an adult can
+pub async fn update_mandatory_kinds(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+    params: Json<UpdateMandatoryKindsRequest>,
+) -> Result<Json<MandatoryKindsResponse>, ApiError> {
+    let family_id = path.family_id;
+    let kinds = block(move || {
+        let adult = Membership::Member.of(&pool, &user, &family_id.to_string())?;
+        if !adult.is_adult() {
+            return Err(ApiError::Unauthorized("Only an adult of the family can do this".into()));
+        }
+        save_mandatory_kinds(&pool, &family_id.to_string(), &params.kinds, &adult.id)
+    })
+    .await?;
+    respond_json(MandatoryKindsResponse { family_id, kinds })
+}
+
+/// Record who should acknowledge a new event: the adults of the family but
+/// whoever posted it. They are asked to when its kind is mandatory.
+pub async fn request_acknowledgements(pool: &Data<PoolType>, event: &EventResponse) {
+    let pool_request = pool.clone();
+    let event_id = event.id.to_string();
+    let actor_id = event.actor_id.map(|actor_id| actor_id.to_string());
+    let requested = block(move || {
+        let event = find_event(&pool_request, &event_id)?;
+        let recipients: Vec<User> = get_adults_by_family_id(&pool_request, &event.family_id)?
+            .into_iter()
+            .filter(|adult| actor_id.as_ref() != Some(&adult.id))
+            .collect();
+        let user_ids: Vec<String> = recipients.iter().map(|recipient| recipient.id.clone()).collect();
+        request(&pool_request, &event, &user_ids)?;
+        let mandatory = find_mandatory_kinds(&pool_request, &event.family_id)?.contains(&event.kind());
+        Ok::<_, ApiError>((event, recipients, mandatory))
+    })
+    .await;
+    match requested {
+        Ok((event, recipients, true)) => send_to_users(&recipients, acknowledgement_notification(&event, 0)).await,
+        Ok(_) => {}
+        Err(error) => log::warn!("Could not request acknowledgements of event {}: {:?}", event.id, error),
+    }
+}
+
+/// Notify the recipients of an event nobody acknowledged again
+pub async fn notify_reminder(pool: &Data<PoolType>, event: &Event, recipients: &[Acknowledgement]) {
+    let pool = pool.clone();
+    let user_ids: Vec<String> = recipients.iter().map(|recipient| recipient.user_id.clone()).collect();
+    let reminders = recipients.iter().map(|recipient| recipient.reminders).max().unwrap_or(0) + 1;
+    let users = block(move || {
+        user_ids
+            .iter()
+            .map(|user_id| find_user(&pool, user_id))
+            .collect::<Result<Vec<User>, ApiError>>()
+    })
+    .await;
+    match users {
+        Ok(users) => send_to_users(&users, acknowledgement_notification(event, reminders)).await,
+        Err(error) => log::warn!("Could not remind about event {}: {:?}", event.id, error),
+    }
+}
+
+fn acknowledgement_notification(event: &Event, reminders: i32) -> Notification {
+    let title = if reminders == 0 { "Please acknowledge" } else { "Still waiting for an acknowledgement" };
+    let body = if event.message.is_empty() { event.kind().to_string() } else { event.message.clone() };
+    let notification = Notification::new(title, &body).data(json!({
+        "event_id": event.id,
+        "kind": event.kind,
+        "acknowledge": true,
+    }));
+    if reminders == 0 {
+        notification
+    } else {
+        notification.priority(Priority::High)
+    }
+}
+
+impl From<Acknowledgement> for AcknowledgementResponse {
+    fn from(acknowledgement: Acknowledgement) -> Self {
+        AcknowledgementResponse {
+            event_id: Uuid::parse_str(&acknowledgement.event_id).unwrap(),
+            user_id: Uuid::parse_str(&acknowledgement.user_id).unwrap(),
+            acknowledged_at: acknowledgement.acknowledged_at,
+            notified_at: acknowledgement.notified_at,
+            reminders: acknowledgement.reminders,
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::media::{is_image, sniff, strip_location, thumbnail};
+use crate::models::attachment::{
+    create, delete, find, find_all_by_event_id, find_all_by_message_id, storage_key, thumbnail_key, Attachment,
+};
+use crate::models::chat::{self, find_message};
+use crate::models::event::find_event;
+use crate::models::user::{AuthUser, Membership, User};
+use crate::multipart::{boundary, parse};
+use crate::storage::STORAGE;
+use actix_web::web::{block, Data, Json, Path, Payload};
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use futures::StreamExt;
+use rayon::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Room for the multipart headers and delimiters around the file
+const MULTIPART_OVERHEAD: usize = 16 * 1024;
+/// The form field carrying the file
+const FILE_FIELD: &str = "file";
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AttachmentResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub event_id: Option<Uuid>,
+    pub message_id: Option<Uuid>,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// Where members of the family download the file
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AttachmentsResponse(pub Vec<AttachmentResponse>);
+
+impl From<Attachment> for AttachmentResponse {
+    fn from(attachment: Attachment) -> Self {
+        let parse = |id: Option<String>| id.map(|id| Uuid::parse_str(&id).unwrap());
+        let url = format!("/api/v1/attachment/{}/download", attachment.id);
+        let thumbnail_url = attachment
+            .thumbnail_key
+            .as_ref()
+            .map(|_| format!("/api/v1/attachment/{}/thumbnail", attachment.id));
+        AttachmentResponse {
+            id: Uuid::parse_str(&attachment.id).unwrap(),
+            family_id: Uuid::parse_str(&attachment.family_id).unwrap(),
+            event_id: parse(attachment.event_id),
+            message_id: parse(attachment.message_id),
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+            width: attachment.width,
+            height: attachment.height,
+            url,
+            thumbnail_url,
+            created_by: Uuid::parse_str(&attachment.created_by).unwrap(),
+            created_at: attachment.created_at,
+        }
+    }
+}
+
+impl From<Vec<Attachment>> for AttachmentsResponse {
+    fn from(attachments: Vec<Attachment>) -> Self {
+        AttachmentsResponse(attachments.into_par_iter().map(|attachment| attachment.into()).collect())
+    }
+}
+
+/// A file as uploaded
+struct Upload {
+    filename: String,
+    data: Vec<u8>,
+}
+
+/// Get the attachments of an event
+pub async fn get_event_attachments(
+    user: AuthUser,
+    event_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<AttachmentsResponse>, ApiError> {
+    let attachments = block(move || {
+        let event = find_event(&pool, &event_id.to_string())?;
+        Membership::Member.of(&pool, &user, &event.family_id)?;
+        find_all_by_event_id(&pool, &event.id)
+    })
+    .await?;
+    respond_json(attachments.into())
+}
+
+/// Attach a file to an event, any member of its family can
+pub async fn upload_event_attachment(
+    user: AuthUser,
+    event_id: Path<Uuid>,
+    request: HttpRequest,
+    payload: Payload,
+    pool: Data<PoolType>,
+) -> Result<Json<AttachmentResponse>, ApiError> {
+    let upload = read_upload(&request, payload).await?;
+    let attachment = block(move || {
+        let event = find_event(&pool, &event_id.to_string())?;
+        let member = Membership::Member.of(&pool, &user, &event.family_id)?;
+        store(&pool, &member, &event.family_id, Some(event.id.clone()), None, upload)
+    })
+    .await?;
+    respond_json(attachment.into())
+}
+
+/// Get the attachments of a chat message
+pub async fn get_message_attachments(
+    user: AuthUser,
+    message_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<AttachmentsResponse>, ApiError> {
+    let attachments = block(move || {
+        let message = find_message(&pool, *message_id)?;
+        let thread = chat::find(&pool, Uuid::parse_str(&message.thread_id).unwrap())?;
+        Membership::Member.of(&pool, &user, &thread.family_id)?;
+        find_all_by_message_id(&pool, &message.id)
+    })
+    .await?;
+    respond_json(attachments.into())
+}
+
+/// Attach a file to a chat message, only its author can
+pub async fn upload_message_attachment(
+    user: AuthUser,
+    message_id: Path<Uuid>,
+    request: HttpRequest,
+    payload: Payload,
+    pool: Data<PoolType>,
+) -> Result<Json<AttachmentResponse>, ApiError> {
+    let upload = read_upload(&request, payload).await?;
+    let attachment = block(move || {
+        let message = find_message(&pool, *message_id)?;
+        let thread = chat::find(&pool, Uuid::parse_str(&message.thread_id).unwrap())?;
+        let member = Membership::Member.of(&pool, &user, &thread.family_id)?;
+        if message.user_id != member.id {
+            return Err(ApiError::Unauthorized("Only the author of a message can attach files to it".into()));
+        }
+        store(&pool, &member, &thread.family_id, None, Some(message.id.clone()), upload)
+    })
+    .await?;
+    respond_json(attachment.into())
+}
+
+/// Get an attachment
+pub async fn get_attachment(
+    user: AuthUser,
+    attachment_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<AttachmentResponse>, ApiError> {
+    let attachment = block(move || {
+        let attachment = find(&pool, *attachment_id)?;
+        Membership::Member.of(&pool, &user, &attachment.family_id)?;
+        Ok::<_, ApiError>(attachment)
+    })
+    .await?;
+    respond_json(attachment.into())
+}
+
+/// Download the content of an attachment, only members of its family can
+pub async fn download_attachment(
+    user: AuthUser,
+    attachment_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let (attachment, data) = block(move || {
+        let attachment = find(&pool, *attachment_id)?;
+        Membership::Member.of(&pool, &user, &attachment.family_id)?;
+        let data = STORAGE.get(&attachment.storage_key)?;
+        Ok::<_, ApiError>((attachment, data))
+    })
+    .await?;
+
+    let disposition = if is_image(&attachment.content_type) { "inline" } else { "attachment" };
+    let disposition = format!("{}; filename=\"{}\"", disposition, attachment.filename);
+    Ok(file_response(&attachment.content_type, Some(disposition), data))
+}
+
+/// Download the thumbnail of an image attachment
+pub async fn download_thumbnail(
+    user: AuthUser,
+    attachment_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let data = block(move || {
+        let attachment = find(&pool, *attachment_id)?;
+        Membership::Member.of(&pool, &user, &attachment.family_id)?;
+        let not_found = format!("attachment {} has no thumbnail", attachment.id);
+        let key = attachment.thumbnail_key.ok_or(ApiError::NotFound(not_found))?;
+        STORAGE.get(&key)
+    })
+    .await?;
+    Ok(file_response("image/jpeg", None, data))
+}
+
+/// Delete an attachment, its uploader or an adult of the family can
+pub async fn delete_attachment(
+    user: AuthUser,
+    attachment_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    block(move || {
+        let attachment = find(&pool, *attachment_id)?;
+        let member = Membership::Member.of(&pool, &user, &attachment.family_id)?;
+        if attachment.created_by != member.id && !member.is_adult() {
+            return Err(ApiError::Unauthorized(
+                "Only the uploader or an adult of the family can delete an attachment".into(),
+            ));
+        }
+        delete(&pool, &attachment.id)?;
+        remove_content(&attachment);
+        Ok::<_, ApiError>(())
+    })
+    .await?;
+    respond_ok()
+}
+
+/// Read the file of a multipart upload, within the size limit
+async fn read_upload(request: &HttpRequest, mut payload: Payload) -> Result<Upload, ApiError> {
+    let content_type = request
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let boundary = boundary(content_type)?;
+    let too_large = || ApiError::BadRequest(format!("files are limited to {} bytes", CONFIG.attachment_max_size));
+
+    let mut body = vec![];
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|error| ApiError::BadRequest(format!("could not read upload: {}", error)))?;
+        if body.len() + chunk.len() > CONFIG.attachment_max_size + MULTIPART_OVERHEAD {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let part = parse(&body, &boundary)?
+        .into_iter()
+        .find(|part| part.name == FILE_FIELD)
+        .ok_or_else(|| ApiError::ValidationError(vec![format!("a {} field is required", FILE_FIELD)]))?;
+    if part.data.is_empty() {
+        return Err(ApiError::ValidationError(vec!["the file is empty".into()]));
+    }
+    if part.data.len() > CONFIG.attachment_max_size {
+        return Err(too_large());
+    }
+    Ok(Upload {
+        filename: clean_filename(part.filename.as_deref().unwrap_or_default()),
+        data: part.data,
+    })
+}
+
+/// Check, clean up and keep an uploaded file
+fn store(
+    pool: &PoolType,
+    uploader: &User,
+    family_id: &str,
+    event_id: Option<String>,
+    message_id: Option<String>,
+    upload: Upload,
+) -> Result<Attachment, ApiError> {
+    let content_type = sniff(&upload.data)
+        .ok_or_else(|| ApiError::ValidationError(vec!["only JPEG, PNG, GIF and PDF files can be attached".into()]))?;
+    let data = strip_location(content_type, upload.data)
+        .ok_or_else(|| ApiError::ValidationError(vec!["the image is damaged and can't be attached".into()]))?;
+
+    let id = Uuid::new_v4().to_string();
+    let mut attachment = Attachment {
+        id: id.clone(),
+        family_id: family_id.to_string(),
+        event_id,
+        message_id,
+        filename: upload.filename,
+        content_type: content_type.to_string(),
+        size: data.len() as i64,
+        storage_key: storage_key(family_id, &id),
+        thumbnail_key: None,
+        width: None,
+        height: None,
+        created_by: uploader.id.clone(),
+        created_at: Utc::now().naive_utc(),
+    };
+
+    STORAGE.put(&attachment.storage_key, &data, content_type)?;
+    if is_image(content_type) {
+        if let Some((thumbnail, width, height)) = thumbnail(&data) {
+            let key = thumbnail_key(family_id, &id);
+            match STORAGE.put(&key, &thumbnail, "image/jpeg") {
+                Ok(()) => attachment.thumbnail_key = Some(key),
+                Err(error) => log::warn!("Could not store thumbnail of {}: {:?}", id, error),
+            }
+            attachment.width = Some(width as i32);
+            attachment.height = Some(height as i32);
+        }
+    }
+
+    create(pool, &attachment).map_err(|error| {
+        remove_content(&attachment);
+        error
+    })
+}
+
+/// Remove the content of an attachment from the storage, leftovers are
+/// only logged
+fn remove_content(attachment: &Attachment) {
+    let keys = std::iter::once(&attachment.storage_key).chain(attachment.thumbnail_key.as_ref());
+    for key in keys {
+        if let Err(error) = STORAGE.delete(key) {
+            log::warn!("Could not delete {} from the storage: {:?}", key, error);
+        }
+    }
+}
+
+/// Stored content as a response, browsers must not guess another type
+fn file_response(content_type: &str, disposition: Option<String>, data: Vec<u8>) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .header("X-Content-Type-Options", "nosniff")
+        .header("Cache-Control", "private, max-age=3600");
+    if let Some(disposition) = disposition {
+        response.header("Content-Disposition", disposition);
+    }
+    response.body(data)
+}
+
+/// The name of an uploaded file without any path or characters that would
+/// break the Content-Disposition header
+fn clean_filename(filename: &str) -> String {
+    let name = filename.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        "attachment".into()
+    } else {
+        name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_cleans_filenames() {
+        assert_eq!(clean_filename("park.jpg"), "park.jpg");
+        assert_eq!(clean_filename("C:\\photos\\park.jpg"), "park.jpg");
+        assert_eq!(clean_filename("../../etc/passwd"), "passwd");
+        assert_eq!(clean_filename("a\"b\r\n.pdf"), "ab.pdf");
+        assert_eq!(clean_filename(""), "attachment");
+    }
+}
@@ -0,0 +1,137 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::respond_json;
+use crate::models::audit::{find_all_by_family_id, find_chain, verify, AuditAction, AuditEntry, AuditFilter};
+use crate::models::user::{AuthUser, Membership};
+use actix_web::web::{block, Data, Json, Path, Query};
+use chrono::NaiveDateTime;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Entries returned per page by default, and at most
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// One security-sensitive action
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEntryResponse {
+    pub id: i64,
+    pub family_id: Option<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: Value,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub hash: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEntriesResponse(pub Vec<AuditEntryResponse>);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditVerificationResponse {
+    pub family_id: Uuid,
+    pub entries: usize,
+    pub valid: bool,
+    /// The first entry that was altered, or follows a removed one
+    pub first_invalid_id: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    pub actor_id: Option<Uuid>,
+    /// UTC
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AuditQuery {
+    fn filter(&self) -> AuditFilter {
+        AuditFilter {
+            action: self.action,
+            actor_id: self.actor_id.map(|actor_id| actor_id.to_string()),
+            from: self.from,
+            to: self.to,
+        }
+    }
+
+    fn page(&self) -> (i64, i64) {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+        (self.offset.unwrap_or(0).max(0), limit)
+    }
+}
+
+/// Get the audit log of a family, newest first, only a parent can
+pub async fn get_audit_entries(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    query: Query<AuditQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<AuditEntriesResponse>, ApiError> {
+    let entries = block(move || {
+        let family_id = path.family_id.to_string();
+        Membership::Parent.of(&pool, &user, &family_id)?;
+        let (offset, limit) = query.page();
+        find_all_by_family_id(&pool, &family_id, &query.filter(), offset, limit)
+    })
+    .await?;
+    respond_json(entries.into())
+}
+
+/// Check that the audit log of a family was not tampered with, only a
+/// parent can
+pub async fn verify_audit_entries(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<AuditVerificationResponse>, ApiError> {
+    let family_id = path.family_id;
+    let entries = block(move || {
+        Membership::Parent.of(&pool, &user, &family_id.to_string())?;
+        find_chain(&pool, &family_id.to_string())
+    })
+    .await?;
+    let first_invalid_id = verify(&entries).err();
+    respond_json(AuditVerificationResponse {
+        family_id,
+        entries: entries.len(),
+        valid: first_invalid_id.is_none(),
+        first_invalid_id,
+    })
+}
+
+impl From<AuditEntry> for AuditEntryResponse {
+    fn from(entry: AuditEntry) -> Self {
+        let parse = |id: &Option<String>| id.as_ref().and_then(|id| Uuid::parse_str(id).ok());
+        AuditEntryResponse {
+            id: entry.id,
+            family_id: parse(&entry.family_id),
+            actor_id: parse(&entry.actor_id),
+            details: entry.details(),
+            action: entry.action,
+            target: entry.target,
+            ip_address: entry.ip_address,
+            user_agent: entry.user_agent,
+            created_at: entry.created_at,
+            hash: entry.hash,
+        }
+    }
+}
+
+impl From<Vec<AuditEntry>> for AuditEntriesResponse {
+    fn from(entries: Vec<AuditEntry>) -> Self {
+        AuditEntriesResponse(entries.into_par_iter().map(|entry| entry.into()).collect())
+    }
+}
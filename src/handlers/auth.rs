@@ -1,9 +1,11 @@
+use crate::audit::record;
 use crate::auth::{create_jwt, hash, PrivateClaim};
 use crate::database::PoolType;
 use crate::errors::ApiError;
 use crate::handlers::user::UserResponse;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::find_by_auth;
+use crate::models::audit::{AuditAction, ClientInfo, NewAuditEntry};
+use crate::models::user::{find_by_auth, find_by_email};
 use crate::validate::validate;
 use actix_identity::Identity;
 use actix_web::web::{block, Data, HttpResponse, Json};
@@ -23,8 +25,12 @@ pub struct LoginRequest {
 }
 /// Login a user
 /// Create and remember their JWT
+///
+/// Both successful and failed attempts are audited, a failed one in the
+/// family of the user the email belongs to, if any.
 pub async fn login(
     id: Identity,
+    client: ClientInfo,
     pool: Data<PoolType>,
     params: Json<LoginRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
@@ -32,7 +38,21 @@ pub async fn login(
 
     // Validate that the email + hashed password matches
     let hashed = hash(&params.password);
-    let user = block(move || find_by_auth(&pool, &params.email, &hashed)).await?;
+    let email = params.email.clone();
+    let auth_pool = pool.clone();
+    let user = match block(move || find_by_auth(&auth_pool, &params.email, &hashed)).await {
+        Ok(user) => user,
+        Err(error) => {
+            record(&pool, login_failed(&pool, &client, Some(email)).await).await;
+            return Err(error.into());
+        }
+    };
+
+    let user_id = user.id.to_string();
+    let entry = NewAuditEntry::new(AuditAction::Login, Some(&user_id), &client)
+        .family(user.family_id.as_deref())
+        .target(&user_id);
+    record(&pool, entry).await;
 
     // Create a JWT
     let private_claim = PrivateClaim::new(user.id, user.email.clone());
@@ -43,6 +63,23 @@ pub async fn login(
     respond_json(user.into())
 }
 
+/// Audit entry for a failed login, in the family of the user the email
+/// belongs to, if any
+pub async fn login_failed(pool: &Data<PoolType>, client: &ClientInfo, email: Option<String>) -> NewAuditEntry {
+    let entry = NewAuditEntry::new(AuditAction::LoginFailed, None, client);
+    let email = match email {
+        Some(email) => email,
+        None => return entry,
+    };
+    let lookup_pool = pool.clone();
+    let lookup_email = email.clone();
+    let family_id = block(move || find_by_email(&lookup_pool, &lookup_email))
+        .await
+        .ok()
+        .and_then(|user| user.family_id);
+    entry.family(family_id.as_deref()).target(&email)
+}
+
 /// Logout a user
 /// Forget their user_id
 pub async fn logout(id: Identity) -> Result<HttpResponse, ApiError> {
@@ -73,7 +110,7 @@ pub mod tests {
             password: "123456".into(),
         };
         let identity = get_identity().await;
-        login(identity, get_data_pool(), Json(params)).await
+        login(identity, ClientInfo::default(), get_data_pool(), Json(params)).await
     }
 
     async fn logout_user() -> Result<HttpResponse, ApiError> {
@@ -0,0 +1,46 @@
+use crate::models::change::{Change, ChangeAction, Entity};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+/// One write to an event, subscription or place
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChangeResponse {
+    pub id: i64,
+    pub entity: Entity,
+    pub entity_id: Uuid,
+    pub action: ChangeAction,
+    pub changed_fields: Vec<String>,
+    /// The row before the change, None for a creation
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+    pub changed_by: String,
+    pub changed_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChangesResponse(pub Vec<ChangeResponse>);
+
+impl From<Change> for ChangeResponse {
+    fn from(change: Change) -> Self {
+        let parse = |row: &Option<String>| row.as_ref().and_then(|row| serde_json::from_str(row).ok());
+        ChangeResponse {
+            id: change.id,
+            entity: change.entity(),
+            entity_id: Uuid::parse_str(&change.entity_id).unwrap(),
+            action: change.action(),
+            changed_fields: change.changed_fields(),
+            before: parse(&change.before),
+            after: parse(&change.after),
+            changed_by: change.changed_by,
+            changed_at: change.changed_at,
+        }
+    }
+}
+
+impl From<Vec<Change>> for ChangesResponse {
+    fn from(changes: Vec<Change>) -> Self {
+        ChangesResponse(changes.into_iter().map(|change| change.into()).collect())
+    }
+}
@@ -0,0 +1,392 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::respond_json;
+use crate::models::activity::ActivityKind;
+use crate::models::chat::{
+    create, find, find_all_by_family_id, find_message, find_messages, find_reads, is_read_by, mark_read, post,
+    unread_counts, unread_counts_by_member, Message, Read, Thread, Topic,
+};
+use crate::models::event::find_event;
+use crate::models::place::find_place;
+use crate::models::subscription::find_subscription;
+use crate::models::user::{get_members_by_family_id, AuthUser, Membership, User};
+use crate::notify::{send_to_users, Notification};
+use crate::stream::publish;
+use crate::validate::validate;
+use actix_web::web::{block, Data, Json, Path, Query};
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 200;
+/// Characters of a message shown in its push notification
+const PREVIEW_LENGTH: usize = 100;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ThreadResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub title: Option<String>,
+    pub event_id: Option<Uuid>,
+    pub subscription_id: Option<Uuid>,
+    pub place_id: Option<Uuid>,
+    pub last_message_at: Option<NaiveDateTime>,
+    /// Messages of others the caller hasn't read
+    pub unread_count: i64,
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ThreadsResponse(pub Vec<ThreadResponse>);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MessageResponse {
+    pub id: Uuid,
+    pub thread_id: Uuid,
+    pub user_id: Uuid,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+    /// Members who read the message, its author included
+    pub read_by: Vec<Uuid>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MessagesResponse(pub Vec<MessageResponse>);
+
+/// Where a member is in a thread
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReadReceiptResponse {
+    pub thread_id: Uuid,
+    pub user_id: Uuid,
+    /// None when the member never opened the thread
+    pub last_read_at: Option<NaiveDateTime>,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ReadReceiptsResponse(pub Vec<ReadReceiptResponse>);
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadQuery {
+    pub event_id: Option<Uuid>,
+    pub subscription_id: Option<Uuid>,
+    pub place_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageQuery {
+    /// The oldest message received, to get the page before it
+    pub before: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+
+impl MessageQuery {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreateThreadRequest {
+    pub family_id: Uuid,
+
+    #[validate(length(min = 1, max = 100, message = "title must be between 1 and 100 characters"))]
+    pub title: Option<String>,
+
+    pub event_id: Option<Uuid>,
+    pub subscription_id: Option<Uuid>,
+    pub place_id: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct PostMessageRequest {
+    #[validate(length(min = 1, max = 2000, message = "body is required and must be at most 2000 characters"))]
+    pub body: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReadRequest {
+    /// UTC, defaults to now
+    pub until: Option<NaiveDateTime>,
+}
+
+fn topic(event_id: Option<Uuid>, subscription_id: Option<Uuid>, place_id: Option<Uuid>) -> Result<Topic, ApiError> {
+    let to_string = |id: Option<Uuid>| id.map(|id| id.to_string());
+    Topic::from_ids(to_string(event_id), to_string(subscription_id), to_string(place_id))
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get the threads of a family, most recently active first, with what the
+/// caller hasn't read
+pub async fn get_threads_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    query: Query<ThreadQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<ThreadsResponse>, ApiError> {
+    let topic = topic(query.event_id, query.subscription_id, query.place_id)?;
+    let threads = block(move || {
+        let family_id = path.family_id.to_string();
+        Membership::Member.of(&pool, &user, &family_id)?;
+        let threads = find_all_by_family_id(&pool, &family_id, &topic)?;
+        let thread_ids: Vec<String> = threads.iter().map(|thread| thread.id.clone()).collect();
+        let counts = unread_counts(&pool, &thread_ids, &user.id)?;
+        let threads: Vec<ThreadResponse> = threads
+            .into_iter()
+            .map(|thread| {
+                let count = counts.get(&thread.id).cloned().unwrap_or(0);
+                thread_response(thread, count)
+            })
+            .collect();
+        Ok::<_, ApiError>(threads)
+    })
+    .await?;
+    respond_json(ThreadsResponse(threads))
+}
+
+/// Get a thread
+pub async fn get_thread(user: AuthUser, thread_id: Path<Uuid>, pool: Data<PoolType>) -> Result<Json<ThreadResponse>, ApiError> {
+    let thread = block(move || {
+        let thread = find(&pool, *thread_id)?;
+        Membership::Member.of(&pool, &user, &thread.family_id)?;
+        let count = unread_counts(&pool, &[thread.id.clone()], &user.id)?.remove(&thread.id).unwrap_or(0);
+        Ok::<_, ApiError>(thread_response(thread, count))
+    })
+    .await?;
+    respond_json(thread)
+}
+
+/// Start a thread, about the family or one of its events, subscriptions or places
+pub async fn create_thread(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<CreateThreadRequest>,
+) -> Result<Json<ThreadResponse>, ApiError> {
+    validate(&params)?;
+    let topic = topic(params.event_id, params.subscription_id, params.place_id)?;
+
+    let thread = block(move || {
+        let family_id = params.family_id.to_string();
+        let member = Membership::Member.of(&pool, &user, &family_id)?;
+        let topic_family_id = match &topic {
+            Topic::Family => family_id.clone(),
+            Topic::Event(event_id) => find_event(&pool, event_id)?.family_id,
+            Topic::Subscription(subscription_id) => find_subscription(&pool, subscription_id)?.family_id,
+            Topic::Place(place_id) => find_place(&pool, place_id)?.family_id,
+        };
+        if topic_family_id != family_id {
+            return Err(ApiError::ValidationError(vec![
+                "a thread can only be about something of its family".into(),
+            ]));
+        }
+
+        let now = Utc::now().naive_utc();
+        let params = params.into_inner();
+        create(
+            &pool,
+            &Thread {
+                id: Uuid::new_v4().to_string(),
+                family_id,
+                title: params.title,
+                event_id: params.event_id.map(|id| id.to_string()),
+                subscription_id: params.subscription_id.map(|id| id.to_string()),
+                place_id: params.place_id.map(|id| id.to_string()),
+                last_message_at: None,
+                created_by: member.id.clone(),
+                created_at: now,
+                updated_by: member.id,
+                updated_at: now,
+            },
+        )
+    })
+    .await?;
+    respond_json(thread_response(thread, 0))
+}
+
+/// Get a page of the messages of a thread, most recent first
+///
+/// Pages go by cursor, messages posted meanwhile don't shift them.
+pub async fn get_messages(
+    user: AuthUser,
+    thread_id: Path<Uuid>,
+    query: Query<MessageQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<MessagesResponse>, ApiError> {
+    let limit = query.limit();
+    let messages = block(move || {
+        let thread = find(&pool, *thread_id)?;
+        Membership::Member.of(&pool, &user, &thread.family_id)?;
+        let before = match query.before {
+            Some(before) => Some(find_message(&pool, before)?).filter(|before| before.thread_id == thread.id),
+            None => None,
+        };
+        if query.before.is_some() && before.is_none() {
+            return Err(ApiError::BadRequest("before must be a message of the thread".into()));
+        }
+        let messages = find_messages(&pool, &thread.id, before.as_ref(), limit)?;
+        let reads = find_reads(&pool, &thread.id)?;
+        let messages: Vec<MessageResponse> = messages
+            .into_iter()
+            .map(|message| message_response(message, &reads))
+            .collect();
+        Ok::<_, ApiError>(messages)
+    })
+    .await?;
+    respond_json(MessagesResponse(messages))
+}
+
+/// Post a message to a thread, pushed to the other members
+pub async fn post_message(
+    user: AuthUser,
+    thread_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<PostMessageRequest>,
+) -> Result<Json<MessageResponse>, ApiError> {
+    validate(&params)?;
+
+    let pool_post = pool.clone();
+    let (thread, author, message) = block(move || {
+        let thread = find(&pool_post, *thread_id)?;
+        let author = Membership::Member.of(&pool_post, &user, &thread.family_id)?;
+        let message = post(&pool_post, &Message::new(&thread.id, &author.id, &params.body))?;
+        Ok::<_, ApiError>((thread, author, message))
+    })
+    .await?;
+
+    let response = message_response(message.clone(), &[]);
+    publish(&pool, &thread.family_id, ActivityKind::ChatMessageCreated, &response).await;
+    notify_members(&pool, &thread, &author, &message).await;
+    respond_json(response)
+}
+
+/// Mark a thread as read by the caller, up to now by default
+pub async fn read_thread(
+    user: AuthUser,
+    thread_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<ReadRequest>,
+) -> Result<Json<ReadReceiptResponse>, ApiError> {
+    let pool_read = pool.clone();
+    let (thread, receipt) = block(move || {
+        let thread = find(&pool_read, *thread_id)?;
+        Membership::Member.of(&pool_read, &user, &thread.family_id)?;
+        let now = Utc::now().naive_utc();
+        let until = params.until.map_or(now, |until| until.min(now));
+        let read = mark_read(&pool_read, &thread.id, &user.id, until)?;
+        let count = unread_counts(&pool_read, &[thread.id.clone()], &user.id)?.remove(&thread.id).unwrap_or(0);
+        let receipt = receipt_response(&thread.id, &user.id, Some(read.last_read_at), count);
+        Ok::<_, ApiError>((thread, receipt))
+    })
+    .await?;
+    publish(&pool, &thread.family_id, ActivityKind::ChatRead, &receipt).await;
+    respond_json(receipt)
+}
+
+/// Get where each member of the family is in a thread
+pub async fn get_read_receipts(
+    user: AuthUser,
+    thread_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<ReadReceiptsResponse>, ApiError> {
+    let receipts = block(move || {
+        let thread = find(&pool, *thread_id)?;
+        Membership::Member.of(&pool, &user, &thread.family_id)?;
+        let reads = find_reads(&pool, &thread.id)?;
+        let members = get_members_by_family_id(&pool, &thread.family_id)?;
+        let member_ids: Vec<String> = members.iter().map(|member| member.id.clone()).collect();
+        let counts = unread_counts_by_member(&pool, &thread.id, &member_ids)?;
+        let receipts: Vec<ReadReceiptResponse> = members
+            .iter()
+            .map(|member| {
+                let last_read_at = reads
+                    .iter()
+                    .find(|read| read.user_id == member.id)
+                    .map(|read| read.last_read_at);
+                let count = counts.get(&member.id).cloned().unwrap_or(0);
+                receipt_response(&thread.id, &member.id, last_read_at, count)
+            })
+            .collect();
+        Ok::<_, ApiError>(receipts)
+    })
+    .await?;
+    respond_json(ReadReceiptsResponse(receipts))
+}
+
+/// Push a message to every member of the family but its author
+async fn notify_members(pool: &Data<PoolType>, thread: &Thread, author: &User, message: &Message) {
+    let pool = pool.clone();
+    let family_id = thread.family_id.clone();
+    match block(move || get_members_by_family_id(&pool, &family_id)).await {
+        Ok(members) => {
+            let others: Vec<_> = members.into_iter().filter(|member| member.id != author.id).collect();
+            let mut preview: String = message.body.chars().take(PREVIEW_LENGTH).collect();
+            if message.body.chars().count() > PREVIEW_LENGTH {
+                preview.push('…');
+            }
+            let title = thread.title.as_deref().unwrap_or("Family chat");
+            let notification = Notification::new(title, &format!("{}: {}", author.first_name, preview)).data(json!({
+                "thread_id": thread.id,
+                "message_id": message.id,
+            }));
+            send_to_users(&others, notification).await;
+        }
+        Err(error) => log::warn!("Could not notify members: {:?}", error),
+    }
+}
+
+fn thread_response(thread: Thread, unread_count: i64) -> ThreadResponse {
+    let parse = |id: Option<String>| id.map(|id| Uuid::parse_str(&id).unwrap());
+    ThreadResponse {
+        id: Uuid::parse_str(&thread.id).unwrap(),
+        family_id: Uuid::parse_str(&thread.family_id).unwrap(),
+        title: thread.title,
+        event_id: parse(thread.event_id),
+        subscription_id: parse(thread.subscription_id),
+        place_id: parse(thread.place_id),
+        last_message_at: thread.last_message_at,
+        unread_count,
+        created_by: Uuid::parse_str(&thread.created_by).unwrap(),
+        created_at: thread.created_at,
+    }
+}
+
+fn receipt_response(
+    thread_id: &str,
+    user_id: &str,
+    last_read_at: Option<NaiveDateTime>,
+    unread_count: i64,
+) -> ReadReceiptResponse {
+    ReadReceiptResponse {
+        thread_id: Uuid::parse_str(thread_id).unwrap(),
+        user_id: Uuid::parse_str(user_id).unwrap(),
+        last_read_at,
+        unread_count,
+    }
+}
+
+fn message_response(message: Message, reads: &[Read]) -> MessageResponse {
+    let mut read_by = vec![Uuid::parse_str(&message.user_id).unwrap()];
+    read_by.extend(
+        reads
+            .iter()
+            .filter(|read| read.user_id != message.user_id)
+            .filter(|read| is_read_by(&message, &read.user_id, Some(read.last_read_at)))
+            .map(|read| Uuid::parse_str(&read.user_id).unwrap()),
+    );
+    MessageResponse {
+        id: Uuid::parse_str(&message.id).unwrap(),
+        thread_id: Uuid::parse_str(&message.thread_id).unwrap(),
+        user_id: Uuid::parse_str(&message.user_id).unwrap(),
+        body: message.body,
+        created_at: message.created_at,
+        read_by,
+    }
+}
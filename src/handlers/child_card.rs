@@ -0,0 +1,274 @@
+use crate::audit::record;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::emergency_contact::EmergencyContactResponse;
+use crate::helpers::respond_json;
+use crate::models::audit::{AuditAction, ClientInfo, NewAuditEntry};
+use crate::models::child_card::{find_card, find_views, on_duty, record_view, save, summary, CardView, ChildCard};
+use crate::models::emergency_contact::{find_all_for_child, EmergencyContact};
+use crate::models::family::find_family;
+use crate::models::occurrence::{expand, get_assignments};
+use crate::models::subscription::find_all_by_family_id as find_subscriptions;
+use crate::models::user::{find_user, AuthUser, Membership, User};
+use crate::validate::validate;
+use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ChildCardResponse {
+    pub child_id: Uuid,
+    pub family_id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub birth_date: Option<NaiveDate>,
+    pub blood_type: Option<String>,
+    pub allergies: Option<String>,
+    pub medications: Option<String>,
+    pub conditions: Option<String>,
+    pub doctor_name: Option<String>,
+    pub doctor_phone: Option<String>,
+    pub notes: Option<String>,
+    /// The family's contacts and the child's own, in the order they are reached
+    pub emergency_contacts: Vec<EmergencyContactResponse>,
+    pub updated_by: Uuid,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CardViewResponse {
+    pub viewer_id: Uuid,
+    pub format: String,
+    pub viewed_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CardViewsResponse(pub Vec<CardViewResponse>);
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct UpdateChildCardRequest {
+    pub birth_date: Option<NaiveDate>,
+
+    #[validate(length(max = 8, message = "blood_type must be at most 8 characters"))]
+    pub blood_type: Option<String>,
+
+    #[validate(length(max = 2000, message = "allergies must be at most 2000 characters"))]
+    pub allergies: Option<String>,
+
+    #[validate(length(max = 2000, message = "medications must be at most 2000 characters"))]
+    pub medications: Option<String>,
+
+    #[validate(length(max = 2000, message = "conditions must be at most 2000 characters"))]
+    pub conditions: Option<String>,
+
+    #[validate(length(max = 100, message = "doctor_name must be at most 100 characters"))]
+    pub doctor_name: Option<String>,
+
+    #[validate(length(max = 32, message = "doctor_phone must be at most 32 characters"))]
+    pub doctor_phone: Option<String>,
+
+    #[validate(length(max = 2000, message = "notes must be at most 2000 characters"))]
+    pub notes: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByChild {
+    child_id: Uuid,
+}
+
+/// Get the card of a child, for its parents or the adult on duty for it
+pub async fn get_child_card(
+    user: AuthUser,
+    client: ClientInfo,
+    path: Path<PathByChild>,
+    pool: Data<PoolType>,
+) -> Result<Json<ChildCardResponse>, ApiError> {
+    let viewer_id = user.id.clone();
+    let card_pool = pool.clone();
+    let (child, family_id, card, contacts) = block(move || {
+        let (child, family_id) = child_of_family(&card_pool, &path.child_id.to_string())?;
+        let viewer = reader_of(&card_pool, &user, &child, &family_id)?;
+        let card = find_card(&card_pool, &child, &family_id)?;
+        let contacts = find_all_for_child(&card_pool, &family_id, &child.id)?;
+        record_view(&card_pool, &view(&viewer, &child, &family_id, "card"))?;
+        Ok::<_, ApiError>((child, family_id, card, contacts))
+    })
+    .await?;
+    audit_view(&pool, &viewer_id, &client, &child, &family_id, "card").await;
+    respond_json(card_response(child, card, contacts))
+}
+
+/// Fill in the card of a child, only a parent can
+pub async fn update_child_card(
+    user: AuthUser,
+    path: Path<PathByChild>,
+    pool: Data<PoolType>,
+    params: Json<UpdateChildCardRequest>,
+) -> Result<Json<ChildCardResponse>, ApiError> {
+    validate(&params)?;
+
+    let (child, card, contacts) = block(move || {
+        let (child, family_id) = child_of_family(&pool, &path.child_id.to_string())?;
+        let parent = Membership::Parent.of(&pool, &user, &family_id)?;
+        let params = params.into_inner();
+        let card = save(
+            &pool,
+            &ChildCard {
+                child_id: child.id.clone(),
+                family_id: family_id.clone(),
+                birth_date: params.birth_date,
+                blood_type: params.blood_type,
+                allergies: params.allergies,
+                medications: params.medications,
+                conditions: params.conditions,
+                doctor_name: params.doctor_name,
+                doctor_phone: params.doctor_phone,
+                notes: params.notes,
+                updated_by: parent.id,
+                updated_at: Utc::now().naive_utc(),
+            },
+        )?;
+        let contacts = find_all_for_child(&pool, &family_id, &child.id)?;
+        Ok::<_, ApiError>((child, card, contacts))
+    })
+    .await?;
+    respond_json(card_response(child, card, contacts))
+}
+
+/// Download the card of a child as plain text, to print or hand over
+pub async fn get_child_card_summary(
+    user: AuthUser,
+    client: ClientInfo,
+    path: Path<PathByChild>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let viewer_id = user.id.clone();
+    let card_pool = pool.clone();
+    let (child, family_id, text) = block(move || {
+        let (child, family_id) = child_of_family(&card_pool, &path.child_id.to_string())?;
+        let viewer = reader_of(&card_pool, &user, &child, &family_id)?;
+        let card = find_card(&card_pool, &child, &family_id)?;
+        let contacts = find_all_for_child(&card_pool, &family_id, &child.id)?;
+        record_view(&card_pool, &view(&viewer, &child, &family_id, "summary"))?;
+        let text = summary(&child, &card, &contacts);
+        Ok::<_, ApiError>((child, family_id, text))
+    })
+    .await?;
+    audit_view(&pool, &viewer_id, &client, &child, &family_id, "summary").await;
+
+    let filename = format!("card-{}.txt", child.first_name.to_lowercase());
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .body(text))
+}
+
+/// Get who looked at the card of a child, most recent first, only a parent can
+pub async fn get_child_card_views(
+    user: AuthUser,
+    path: Path<PathByChild>,
+    pool: Data<PoolType>,
+) -> Result<Json<CardViewsResponse>, ApiError> {
+    let views = block(move || {
+        let (child, family_id) = child_of_family(&pool, &path.child_id.to_string())?;
+        Membership::Parent.of(&pool, &user, &family_id)?;
+        find_views(&pool, &child.id)
+    })
+    .await?;
+    respond_json(CardViewsResponse(views.into_par_iter().map(|view| view.into()).collect()))
+}
+
+/// Audit a view of the card of a child, alongside the views parents see
+async fn audit_view(
+    pool: &Data<PoolType>,
+    viewer_id: &str,
+    client: &ClientInfo,
+    child: &User,
+    family_id: &str,
+    format: &str,
+) {
+    let entry = NewAuditEntry::new(AuditAction::ChildCardViewed, Some(viewer_id), client)
+        .family(Some(family_id))
+        .target(&child.id)
+        .details(json!({ "format": format }));
+    record(pool, entry).await;
+}
+
+/// A child and the family it belongs to
+fn child_of_family(pool: &PoolType, child_id: &str) -> Result<(User, String), ApiError> {
+    let child = find_user(pool, child_id)?;
+    if child.is_adult() {
+        return Err(ApiError::BadRequest(format!("user {} isn't a child", child.id)));
+    }
+    let family_id = child
+        .family_id
+        .clone()
+        .ok_or_else(|| ApiError::BadRequest(format!("user {} doesn't belong to a family", child.id)))?;
+    Ok((child, family_id))
+}
+
+/// Parents read the card any time, other adults while on duty for the child
+fn reader_of(pool: &PoolType, user: &AuthUser, child: &User, family_id: &str) -> Result<User, ApiError> {
+    let user = find_user(pool, &user.id)?;
+    if user.belongs_to(family_id) && user.is_parent() {
+        return Ok(user);
+    }
+    if user.belongs_to(family_id) && user.is_adult() {
+        let family = find_family(pool, family_id)?;
+        let today = family.today();
+        let subscriptions = find_subscriptions(pool, family_id)?;
+        let assignments = get_assignments(pool, family_id, today, today)?;
+        let occurrences = expand(&subscriptions, &assignments, today, today);
+        if on_duty(&occurrences, &user.id, &child.id, family.to_local(Utc::now().naive_utc())) {
+            return Ok(user);
+        }
+    }
+    Err(ApiError::Unauthorized(
+        "Only a parent or the adult on duty for the child can see its card".into(),
+    ))
+}
+
+fn view(viewer: &User, child: &User, family_id: &str, format: &str) -> CardView {
+    CardView {
+        id: Uuid::new_v4().to_string(),
+        family_id: family_id.to_string(),
+        child_id: child.id.clone(),
+        viewer_id: viewer.id.clone(),
+        format: format.to_string(),
+        viewed_at: Utc::now().naive_utc(),
+    }
+}
+
+fn card_response(child: User, card: ChildCard, contacts: Vec<EmergencyContact>) -> ChildCardResponse {
+    ChildCardResponse {
+        child_id: Uuid::parse_str(&card.child_id).unwrap(),
+        family_id: Uuid::parse_str(&card.family_id).unwrap(),
+        first_name: child.first_name,
+        last_name: child.last_name,
+        birth_date: card.birth_date,
+        blood_type: card.blood_type,
+        allergies: card.allergies,
+        medications: card.medications,
+        conditions: card.conditions,
+        doctor_name: card.doctor_name,
+        doctor_phone: card.doctor_phone,
+        notes: card.notes,
+        emergency_contacts: contacts.into_iter().map(|contact| contact.into()).collect(),
+        updated_by: Uuid::parse_str(&card.updated_by).unwrap(),
+        updated_at: card.updated_at,
+    }
+}
+
+impl From<CardView> for CardViewResponse {
+    fn from(view: CardView) -> Self {
+        CardViewResponse {
+            viewer_id: Uuid::parse_str(&view.viewer_id).unwrap(),
+            format: view.format,
+            viewed_at: view.viewed_at,
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::respond_json;
+use crate::models::cover_request::{
+    cancel, claim, create, find, find_open, get_all_by_family_id, CoverRequest, NewCoverRequest,
+};
+use crate::models::occurrence::assigned_user_id;
+use crate::models::subscription::find_subscription;
+use crate::models::user::{find_user, get_adults_by_family_id, AuthUser, Membership};
+use crate::models::activity::ActivityKind;
+use crate::notify::{send_to_users, Notification, Priority};
+use crate::stream::publish;
+use crate::validate::validate;
+use actix_web::web::{block, Data, Json, Path};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CoverRequestResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    pub requested_by: Uuid,
+    pub claimed_by: Option<Uuid>,
+    pub status: String,
+    pub message: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub escalated_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub updated_by: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CoverRequestsResponse(pub Vec<CoverRequestResponse>);
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreateCoverRequestRequest {
+    #[validate(length(
+        min = 3,
+        message = "subscription_id is required and must be at least 3 characters"
+    ))]
+    pub subscription_id: String,
+
+    pub day: NaiveDate,
+
+    #[validate(length(max = 150, message = "message must be at most 150 characters"))]
+    pub message: Option<String>,
+
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get all cover requests of a family
+pub async fn get_cover_requests_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<CoverRequestsResponse>, ApiError> {
+    let cover_requests = block(move || {
+        Membership::Member.of(&pool, &user, &path.family_id.to_string())?;
+        get_all_by_family_id(&pool, path.family_id)
+    })
+    .await?;
+    respond_json(cover_requests)
+}
+
+/// Get a cover request
+pub async fn get_cover_request(
+    user: AuthUser,
+    cover_request_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<CoverRequestResponse>, ApiError> {
+    let cover_request = block(move || {
+        let cover_request = find(&pool, *cover_request_id)?;
+        Membership::Member.of(&pool, &user, &cover_request.family_id)?;
+        Ok(cover_request)
+    })
+    .await?;
+    respond_json(cover_request.into())
+}
+
+/// Ask the other adults of the family to cover an occurrence
+///
+/// Only the adult responsible for the occurrence can ask for cover.
+pub async fn create_cover_request(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<CreateCoverRequestRequest>,
+) -> Result<Json<CoverRequestResponse>, ApiError> {
+    validate(&params)?;
+
+    let now = Utc::now().naive_utc();
+    let end_of_day = (params.day + Duration::days(1)).and_hms(0, 0, 0);
+    let expires_at = params
+        .expires_at
+        .unwrap_or_else(|| now + Duration::minutes(CONFIG.cover_request_ttl))
+        .min(end_of_day);
+    if expires_at <= now {
+        return Err(ApiError::ValidationError(vec![
+            "expires_at must be in the future and on or before the day to cover".into(),
+        ]));
+    }
+
+    let pool_check = pool.clone();
+    let params_check = params.clone();
+    let user_id = user.id.clone();
+    let subscription = block(move || {
+        let subscription = find_subscription(&pool_check, &params_check.subscription_id)?;
+        if !subscription.runs_on(params_check.day) {
+            let message = format!("subscription {} does not take place on {}", subscription.id, params_check.day);
+            return Err(ApiError::BadRequest(message));
+        }
+        if assigned_user_id(&pool_check, &subscription, params_check.day)? != user_id {
+            return Err(ApiError::Unauthorized("Only the assigned adult can ask for cover".into()));
+        }
+        if find_open(&pool_check, &subscription.id, params_check.day)?.is_some() {
+            return Err(ApiError::BadRequest("A cover request is already open for this occurrence".into()));
+        }
+        Ok(subscription)
+    })
+    .await?;
+
+    let cover_request_id = Uuid::new_v4();
+    let new_cover_request: CoverRequest = NewCoverRequest {
+        id: cover_request_id.to_string(),
+        family_id: subscription.family_id.clone(),
+        subscription_id: subscription.id.clone(),
+        day: params.day,
+        requested_by: user.id.to_string(),
+        message: params.message.clone(),
+        expires_at,
+        created_by: user.id.to_string(),
+        updated_by: user.id.to_string(),
+    }
+    .into();
+    let pool_create = pool.clone();
+    let cover_request = block(move || create(&pool_create, &new_cover_request)).await?;
+
+    let notification = Notification::new(
+        "Cover needed",
+        &format!("Can someone cover the pickup of {}?", cover_request.day),
+    );
+    notify_adults(&pool, &cover_request, notification).await;
+    respond_json(cover_request.into())
+}
+
+/// Claim a cover request, the first adult to claim it gets the occurrence
+pub async fn claim_cover_request(
+    user: AuthUser,
+    cover_request_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<CoverRequestResponse>, ApiError> {
+    let pool_claim = pool.clone();
+    let cover_request = block(move || {
+        let cover_request = find(&pool_claim, *cover_request_id)?;
+        let claimer = find_user(&pool_claim, &user.id)?;
+        if !claimer.belongs_to(&cover_request.family_id) || !claimer.is_adult() {
+            return Err(ApiError::Unauthorized("Only an adult of the family can claim".into()));
+        }
+        if claimer.id == cover_request.requested_by {
+            return Err(ApiError::BadRequest("You can't claim your own cover request".into()));
+        }
+        claim(&pool_claim, *cover_request_id, &claimer.id)
+    })
+    .await?;
+
+    let assignment = json!({
+        "subscription_id": cover_request.subscription_id,
+        "day": cover_request.day,
+        "user_id": cover_request.claimed_by,
+    });
+    publish(&pool, &cover_request.family_id, ActivityKind::AssignmentChanged, &assignment).await;
+
+    let pool_requester = pool.clone();
+    let requested_by = cover_request.requested_by.clone();
+    if let Ok(requester) = block(move || find_user(&pool_requester, &requested_by)).await {
+        let notification = Notification::new(
+            "Cover found",
+            &format!("Your pickup of {} is covered", cover_request.day),
+        )
+        .data(json!({ "cover_request_id": cover_request.id }));
+        send_to_users(&[requester], notification).await;
+    }
+    respond_json(cover_request.into())
+}
+
+/// Cancel a cover request, only its requester can
+pub async fn cancel_cover_request(
+    user: AuthUser,
+    cover_request_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<CoverRequestResponse>, ApiError> {
+    let cover_request = block(move || {
+        let cover_request = find(&pool, *cover_request_id)?;
+        if cover_request.requested_by != user.id {
+            return Err(ApiError::Unauthorized("Only the requester can cancel".into()));
+        }
+        cancel(&pool, *cover_request_id, &user.id)
+    })
+    .await?;
+    respond_json(cover_request.into())
+}
+
+/// Notify every adult of the family but the requester about a cover request
+pub async fn notify_adults(pool: &Data<PoolType>, cover_request: &CoverRequest, notification: Notification) {
+    let pool = pool.clone();
+    let family_id = cover_request.family_id.clone();
+    let requested_by = cover_request.requested_by.clone();
+    let adults = block(move || get_adults_by_family_id(&pool, &family_id)).await;
+    match adults {
+        Ok(adults) => {
+            let others: Vec<_> = adults.into_iter().filter(|adult| adult.id != requested_by).collect();
+            let notification = notification.data(json!({ "cover_request_id": cover_request.id }));
+            send_to_users(&others, notification).await;
+        }
+        Err(error) => log::warn!("Could not notify adults: {:?}", error),
+    }
+}
+
+/// Let adults know an unclaimed cover request is about to expire
+pub async fn notify_escalated(pool: &Data<PoolType>, cover_request: &CoverRequest) {
+    let notification = Notification::new(
+        "Cover still needed",
+        &format!("Nobody has covered the pickup of {} yet", cover_request.day),
+    )
+    .priority(Priority::High);
+    notify_adults(pool, cover_request, notification).await;
+}
+
+/// Let the requester know nobody claimed their cover request in time
+pub async fn notify_expired(pool: &Data<PoolType>, cover_request: &CoverRequest) {
+    let pool = pool.clone();
+    let requested_by = cover_request.requested_by.clone();
+    if let Ok(requester) = block(move || find_user(&pool, &requested_by)).await {
+        let notification = Notification::new(
+            "Cover request expired",
+            &format!("Nobody covered the pickup of {}, it is still yours", cover_request.day),
+        )
+        .data(json!({ "cover_request_id": cover_request.id }))
+        .priority(Priority::High);
+        send_to_users(&[requester], notification).await;
+    }
+}
+
+impl From<CoverRequest> for CoverRequestResponse {
+    fn from(cover_request: CoverRequest) -> Self {
+        CoverRequestResponse {
+            id: Uuid::parse_str(&cover_request.id).unwrap(),
+            family_id: Uuid::parse_str(&cover_request.family_id).unwrap(),
+            subscription_id: Uuid::parse_str(&cover_request.subscription_id).unwrap(),
+            day: cover_request.day,
+            requested_by: Uuid::parse_str(&cover_request.requested_by).unwrap(),
+            claimed_by: cover_request
+                .claimed_by
+                .map(|claimed_by| Uuid::parse_str(&claimed_by).unwrap()),
+            status: cover_request.status,
+            message: cover_request.message,
+            expires_at: cover_request.expires_at,
+            escalated_at: cover_request.escalated_at,
+            created_by: cover_request.created_by,
+            updated_by: cover_request.updated_by,
+        }
+    }
+}
+
+impl From<Vec<CoverRequest>> for CoverRequestsResponse {
+    fn from(cover_requests: Vec<CoverRequest>) -> Self {
+        CoverRequestsResponse(cover_requests.into_par_iter().map(|cover_request| cover_request.into()).collect())
+    }
+}
@@ -0,0 +1,226 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::models::emergency_contact::{
+    create, delete, find, find_all_by_family_id, update, EmergencyContact, UpdateEmergencyContact,
+};
+use crate::models::user::{find_user, AuthUser, Membership};
+use crate::validate::validate;
+use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use chrono::{NaiveDateTime, Utc};
+use rayon::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EmergencyContactResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub name: String,
+    pub phone: String,
+    pub user_id: Option<Uuid>,
+    pub position: i32,
+    /// None when the contact is for the whole family
+    pub child_id: Option<Uuid>,
+    pub updated_at: NaiveDateTime,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EmergencyContactsResponse(pub Vec<EmergencyContactResponse>);
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreateEmergencyContactRequest {
+    pub family_id: Uuid,
+
+    #[validate(length(min = 1, max = 100, message = "name is required and must be at most 100 characters"))]
+    pub name: String,
+
+    #[validate(length(min = 3, max = 32, message = "phone is required and must be at most 32 characters"))]
+    pub phone: String,
+
+    /// When the contact has an account, to notify them
+    pub user_id: Option<Uuid>,
+
+    #[serde(default)]
+    pub position: i32,
+
+    /// To reach the contact about one child only
+    pub child_id: Option<Uuid>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct UpdateEmergencyContactRequest {
+    #[validate(length(min = 1, max = 100, message = "name is required and must be at most 100 characters"))]
+    pub name: String,
+
+    #[validate(length(min = 3, max = 32, message = "phone is required and must be at most 32 characters"))]
+    pub phone: String,
+
+    pub user_id: Option<Uuid>,
+
+    #[serde(default)]
+    pub position: i32,
+
+    pub child_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get the emergency contacts of a family, in the order they are reached
+pub async fn get_emergency_contacts_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<EmergencyContactsResponse>, ApiError> {
+    let contacts = block(move || {
+        let family_id = path.family_id.to_string();
+        Membership::Adult.of(&pool, &user, &family_id)?;
+        find_all_by_family_id(&pool, &family_id)
+    })
+    .await?;
+    respond_json(EmergencyContactsResponse(
+        contacts.into_par_iter().map(|contact| contact.into()).collect(),
+    ))
+}
+
+/// Get an emergency contact
+pub async fn get_emergency_contact(
+    user: AuthUser,
+    contact_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<EmergencyContactResponse>, ApiError> {
+    let contact = block(move || {
+        let contact = find(&pool, *contact_id)?;
+        Membership::Adult.of(&pool, &user, &contact.family_id)?;
+        Ok::<_, ApiError>(contact)
+    })
+    .await?;
+    respond_json(contact.into())
+}
+
+/// Add an emergency contact, only a parent can
+pub async fn create_emergency_contact(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<CreateEmergencyContactRequest>,
+) -> Result<Json<EmergencyContactResponse>, ApiError> {
+    validate(&params)?;
+
+    let contact = block(move || {
+        let family_id = params.family_id.to_string();
+        let parent = Membership::Parent.of(&pool, &user, &family_id)?;
+        check_references(&pool, &family_id, params.user_id, params.child_id)?;
+        let now = Utc::now().naive_utc();
+        let params = params.into_inner();
+        create(
+            &pool,
+            &EmergencyContact {
+                id: Uuid::new_v4().to_string(),
+                family_id,
+                name: params.name,
+                phone: params.phone,
+                user_id: params.user_id.map(|user_id| user_id.to_string()),
+                position: params.position,
+                created_by: parent.id.clone(),
+                created_at: now,
+                updated_by: parent.id,
+                updated_at: now,
+                child_id: params.child_id.map(|child_id| child_id.to_string()),
+            },
+        )
+    })
+    .await?;
+    respond_json(contact.into())
+}
+
+/// Change an emergency contact, only a parent can
+pub async fn update_emergency_contact(
+    user: AuthUser,
+    contact_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<UpdateEmergencyContactRequest>,
+) -> Result<Json<EmergencyContactResponse>, ApiError> {
+    validate(&params)?;
+
+    let contact = block(move || {
+        let contact = find(&pool, *contact_id)?;
+        let parent = Membership::Parent.of(&pool, &user, &contact.family_id)?;
+        check_references(&pool, &contact.family_id, params.user_id, params.child_id)?;
+        let params = params.into_inner();
+        update(
+            &pool,
+            &UpdateEmergencyContact {
+                id: contact.id,
+                name: params.name,
+                phone: params.phone,
+                user_id: params.user_id.map(|user_id| user_id.to_string()),
+                position: params.position,
+                child_id: params.child_id.map(|child_id| child_id.to_string()),
+                updated_by: parent.id,
+                updated_at: Utc::now().naive_utc(),
+            },
+        )
+    })
+    .await?;
+    respond_json(contact.into())
+}
+
+/// Remove an emergency contact, only a parent can
+pub async fn delete_emergency_contact(
+    user: AuthUser,
+    contact_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    block(move || {
+        let contact = find(&pool, *contact_id)?;
+        Membership::Parent.of(&pool, &user, &contact.family_id)?;
+        delete(&pool, *contact_id)
+    })
+    .await?;
+    respond_ok()
+}
+
+/// The account of a contact must exist, their child must be of the family
+fn check_references(
+    pool: &PoolType,
+    family_id: &str,
+    user_id: Option<Uuid>,
+    child_id: Option<Uuid>,
+) -> Result<(), ApiError> {
+    if let Some(user_id) = user_id {
+        find_user(pool, &user_id.to_string())?;
+    }
+    if let Some(child_id) = child_id {
+        let child = find_user(pool, &child_id.to_string())?;
+        if !child.belongs_to(family_id) {
+            return Err(ApiError::ValidationError(vec![format!(
+                "user {} isn't a child of the family",
+                child_id
+            )]));
+        }
+    }
+    Ok(())
+}
+
+impl From<EmergencyContact> for EmergencyContactResponse {
+    fn from(contact: EmergencyContact) -> Self {
+        EmergencyContactResponse {
+            id: Uuid::parse_str(&contact.id).unwrap(),
+            family_id: Uuid::parse_str(&contact.family_id).unwrap(),
+            name: contact.name,
+            phone: contact.phone,
+            user_id: contact.user_id.map(|user_id| Uuid::parse_str(&user_id).unwrap()),
+            position: contact.position,
+            child_id: contact.child_id.map(|child_id| Uuid::parse_str(&child_id).unwrap()),
+            updated_at: contact.updated_at,
+            created_by: Uuid::parse_str(&contact.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&contact.updated_by).unwrap(),
+        }
+    }
+}
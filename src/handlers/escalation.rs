@@ -0,0 +1,196 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::escalation::Step;
+use crate::helpers::respond_json;
+use crate::models::activity::ActivityKind;
+use crate::models::emergency_contact::find_all_by_family_id as find_contacts;
+use crate::models::escalation::{acknowledge, find, get_all_by_family_id, Escalation, Level, Reason};
+use crate::models::user::{find_user, get_adults_by_family_id, AuthUser, Membership};
+use crate::notify::{send_to_phones, send_to_users, Notification, Priority};
+use crate::stream::publish;
+use actix_web::web::{block, Data, Json, Path};
+use chrono::{NaiveDate, NaiveDateTime};
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EscalationResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    pub reason: Reason,
+    pub level: Level,
+    pub notified_at: NaiveDateTime,
+    pub acknowledged_by: Option<Uuid>,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    pub resolved_at: Option<NaiveDateTime>,
+    /// A user id, or the scheduler for the escalations it raises
+    pub created_by: String,
+    pub updated_by: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EscalationsResponse(pub Vec<EscalationResponse>);
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get all escalations of a family
+pub async fn get_escalations_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<EscalationsResponse>, ApiError> {
+    let escalations = block(move || {
+        Membership::Member.of(&pool, &user, &path.family_id.to_string())?;
+        get_all_by_family_id(&pool, path.family_id)
+    })
+    .await?;
+    respond_json(escalations)
+}
+
+/// Get an escalation
+pub async fn get_escalation(
+    user: AuthUser,
+    escalation_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<EscalationResponse>, ApiError> {
+    let escalation = block(move || {
+        let escalation = find(&pool, *escalation_id)?;
+        Membership::Member.of(&pool, &user, &escalation.family_id)?;
+        Ok(escalation)
+    })
+    .await?;
+    respond_json(escalation.into())
+}
+
+/// Let everyone alerted know the caller takes care of it, which stops the
+/// escalation
+///
+/// Adults of the family and emergency contacts with an account can.
+pub async fn acknowledge_escalation(
+    user: AuthUser,
+    escalation_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<EscalationResponse>, ApiError> {
+    let pool_ack = pool.clone();
+    let (escalation, name) = block(move || {
+        let escalation = find(&pool_ack, *escalation_id)?;
+        let user = match Membership::Adult.of(&pool_ack, &user, &escalation.family_id) {
+            Err(ApiError::Unauthorized(_)) => {
+                let contact = find_contacts(&pool_ack, &escalation.family_id)?
+                    .iter()
+                    .any(|contact| contact.user_id.as_ref() == Some(&user.id));
+                if !contact {
+                    return Err(ApiError::Unauthorized(
+                        "Only an adult or an emergency contact of the family can acknowledge".into(),
+                    ));
+                }
+                find_user(&pool_ack, &user.id)?
+            }
+            adult => adult?,
+        };
+        let escalation = acknowledge(&pool_ack, *escalation_id, &user.id)?;
+        Ok((escalation, user.first_name))
+    })
+    .await?;
+
+    let response: EscalationResponse = escalation.clone().into();
+    publish(&pool, &escalation.family_id, ActivityKind::EscalationChanged, &response).await;
+
+    let pool_adults = pool.clone();
+    let family_id = escalation.family_id.clone();
+    if let Ok(adults) = block(move || get_adults_by_family_id(&pool_adults, &family_id)).await {
+        let others: Vec<_> = adults
+            .into_iter()
+            .filter(|adult| Some(&adult.id) != escalation.acknowledged_by.as_ref())
+            .collect();
+        let notification = Notification::new("Pickup handled", &format!("{} is taking care of it", name))
+            .data(json!({ "escalation_id": escalation.id }));
+        send_to_users(&others, notification).await;
+    }
+    respond_json(response)
+}
+
+/// Alert the people an escalation just reached
+pub async fn notify_step(pool: &Data<PoolType>, step: &Step) {
+    let response: EscalationResponse = step.escalation.clone().into();
+    publish(pool, &step.escalation.family_id, ActivityKind::EscalationChanged, &response).await;
+
+    let place = &step.place.name;
+    let (title, body) = match (step.escalation.level(), step.escalation.reason()) {
+        (Level::Assigned, Reason::Late) => ("Pickup waiting", format!("You are expected at {}", place)),
+        (Level::Assigned, Reason::Missed) => ("Pickup missed", format!("Nobody confirmed the pickup at {}", place)),
+        (Level::Adults, _) => (
+            "Pickup needs help",
+            format!("{} hasn't answered about the pickup at {}", step.assigned.first_name, place),
+        ),
+        (Level::Contacts, _) => {
+            let contacts: Vec<String> = step
+                .contacts
+                .iter()
+                .map(|contact| format!("{} ({})", contact.name, contact.phone))
+                .collect();
+            let mut body = format!("Nobody in the family answered about the pickup at {}", place);
+            if !contacts.is_empty() {
+                body = format!("{}, emergency contacts: {}", body, contacts.join(", "));
+            }
+            ("Pickup emergency", body)
+        }
+    };
+    let notification = Notification::new(title, &body)
+        .data(json!({
+            "escalation_id": step.escalation.id,
+            "subscription_id": step.escalation.subscription_id,
+            "day": step.escalation.day,
+        }))
+        .priority(Priority::High);
+    send_to_users(&step.users, notification).await;
+
+    // Contacts with an account were notified along with the adults
+    let phones: Vec<String> = step
+        .contacts
+        .iter()
+        .filter(|contact| contact.user_id.is_none())
+        .map(|contact| contact.phone.clone())
+        .collect();
+    if !phones.is_empty() {
+        let body = format!(
+            "Pickup emergency: nobody in the family of {} {} answered about the pickup at {}",
+            step.assigned.first_name, step.assigned.last_name, place
+        );
+        send_to_phones(&phones, &body).await;
+    }
+}
+
+impl From<Escalation> for EscalationResponse {
+    fn from(escalation: Escalation) -> Self {
+        EscalationResponse {
+            id: Uuid::parse_str(&escalation.id).unwrap(),
+            family_id: Uuid::parse_str(&escalation.family_id).unwrap(),
+            subscription_id: Uuid::parse_str(&escalation.subscription_id).unwrap(),
+            day: escalation.day,
+            reason: escalation.reason(),
+            level: escalation.level(),
+            notified_at: escalation.notified_at,
+            acknowledged_by: escalation
+                .acknowledged_by
+                .map(|acknowledged_by| Uuid::parse_str(&acknowledged_by).unwrap()),
+            acknowledged_at: escalation.acknowledged_at,
+            resolved_at: escalation.resolved_at,
+            created_by: escalation.created_by,
+            updated_by: escalation.updated_by,
+        }
+    }
+}
+
+impl From<Vec<Escalation>> for EscalationsResponse {
+    fn from(escalations: Vec<Escalation>) -> Self {
+        EscalationsResponse(escalations.into_par_iter().map(|escalation| escalation.into()).collect())
+    }
+}
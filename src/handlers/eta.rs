@@ -0,0 +1,105 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::eta::{estimate_occurrence, Haversine};
+use crate::handlers::event::EventResponse;
+use crate::helpers::respond_json;
+use crate::models::event::OccurrenceStatus;
+use crate::models::user::{get_adults_by_family_id, AuthUser};
+use crate::notify::{send_to_users, Notification, Priority};
+use actix_web::web::{block, Data, Json, Path};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EtaResponse {
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    /// Adult on duty
+    pub user_id: Uuid,
+    pub place_id: Uuid,
+    pub status: OccurrenceStatus,
+    /// UTC start of the window
+    pub scheduled_at: Option<NaiveDateTime>,
+    /// Whether the adult's position could be used, the fields below are
+    /// empty otherwise
+    pub available: bool,
+    pub position_at: Option<NaiveDateTime>,
+    /// Meters left to travel
+    pub distance: Option<f64>,
+    /// Meters per second
+    pub speed: Option<f64>,
+    /// Degrees clockwise from north
+    pub heading: Option<f64>,
+    pub heading_away: bool,
+    /// Seconds left to travel
+    pub duration: Option<i64>,
+    pub arrival_at: Option<NaiveDateTime>,
+    /// Seconds after the start of the window the adult is expected
+    pub late_by: Option<i64>,
+    pub arrived: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PathByOccurrence {
+    subscription_id: Uuid,
+    day: NaiveDate,
+}
+
+/// Estimate when the adult on duty reaches the place of an occurrence
+///
+/// Positions the adult doesn't share precisely with the caller are left out.
+pub async fn get_eta(
+    viewer: AuthUser,
+    path: Path<PathByOccurrence>,
+    pool: Data<PoolType>,
+) -> Result<Json<EtaResponse>, ApiError> {
+    let subscription_id = path.subscription_id;
+    let day = path.day;
+    let estimated =
+        block(move || estimate_occurrence(&pool, &subscription_id.to_string(), day, &viewer.id, &Haversine)).await?;
+
+    let late_by = estimated.late_by().map(|late_by| late_by.num_seconds());
+    let estimate = estimated.estimate;
+    respond_json(EtaResponse {
+        subscription_id,
+        day,
+        user_id: Uuid::parse_str(&estimated.occurrence.user_id).unwrap(),
+        place_id: Uuid::parse_str(&estimated.place.id).unwrap(),
+        status: estimated.status,
+        scheduled_at: estimated.scheduled_at,
+        available: estimate.is_some(),
+        position_at: estimate.as_ref().map(|estimate| estimate.position_at),
+        distance: estimate.as_ref().map(|estimate| estimate.distance),
+        speed: estimate.as_ref().and_then(|estimate| estimate.speed),
+        heading: estimate.as_ref().and_then(|estimate| estimate.heading),
+        heading_away: estimate.as_ref().map_or(false, |estimate| estimate.heading_away),
+        duration: estimate.as_ref().map(|estimate| estimate.duration.num_seconds()),
+        arrival_at: estimate.as_ref().map(|estimate| estimate.arrival_at),
+        late_by,
+        arrived: estimate.as_ref().map_or(false, |estimate| estimate.arrived),
+    })
+}
+
+/// Let the adults of the family know the adult on duty is running late
+pub async fn notify_running_late(pool: &Data<PoolType>, event: &EventResponse, late_by: Duration) {
+    let pool = pool.clone();
+    let family_id = event.family_id.to_string();
+    match block(move || get_adults_by_family_id(&pool, &family_id)).await {
+        Ok(adults) => {
+            let notification = Notification::new(
+                "Running late",
+                &format!("{} (about {} min late)", event.message, late_by.num_minutes().max(1)),
+            )
+            .data(json!({
+                "event_id": event.id,
+                "subscription_id": event.subscription_id,
+                "user_id": event.user_id,
+            }))
+            .priority(Priority::High);
+            send_to_users(&adults, notification).await;
+        }
+        Err(error) => log::warn!("Could not notify adults: {:?}", error),
+    }
+}
@@ -1,9 +1,18 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::handlers::acknowledgement::request_acknowledgements;
+use crate::handlers::change::ChangesResponse;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::event::{ get_all_by_family_user_place_sub, create, delete, find, get_all_by_family_id, get_all, update, NewEvent, UpdateEvent, Event};
+use crate::models::event::{ get_all_by_family_user_place_sub, create, delete, find, get_all_by_family_id, get_all, update, NewEvent, UpdateEvent, Event, EventKind, OccurrenceStatus, get_all_by_occurrence, status_of, find_event, find_with_deleted, restore};
+use crate::models::activity::ActivityKind;
+use crate::models::change::{find_all as find_changes, Entity};
+use crate::models::family::find_family;
+use crate::models::subscription::find_subscription;
+use crate::models::user::{AuthUser, Membership, ANONYMOUS};
+use crate::stream::publish;
 use crate::validate::validate;
-use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use actix_web::web::{block, Data, HttpResponse, Json, Path, Query};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
 use serde::Serialize;
 use uuid::Uuid;
 use validator::Validate;
@@ -19,11 +28,32 @@ pub struct EventResponse {
     pub user_id: Uuid,
     pub day: String,
     pub message: String,
+    pub kind: EventKind,
+    pub actor_id: Option<Uuid>,
+    pub occurred_at: NaiveDateTime,
+    /// A user id, or the job that wrote the event: scheduler, geofence, handover,
+    /// or anonymous on the public route
+    pub created_by: String,
+    pub updated_by: String,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct EventsResponse(pub Vec<EventResponse>);
 
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct OccurrenceStatusResponse {
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    pub status: OccurrenceStatus,
+    pub events: Vec<EventResponse>,
+}
+
+/// Optional filters on event lists
+#[derive(Debug, Deserialize)]
+pub struct EventFilter {
+    pub kind: Option<EventKind>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
 pub struct CreateEventRequest {
     #[validate(length(
@@ -50,10 +80,8 @@ pub struct CreateEventRequest {
     ))]
     pub place_id: String,
 
-    #[validate(length(
-        min = 1,
-        message = "message is required and must be at least 3 characters"
-    ))]
+    #[serde(default)]
+    #[validate(length(max = 150, message = "message must be at most 150 characters"))]
     pub message: String,
 
     #[validate(length(
@@ -61,6 +89,13 @@ pub struct CreateEventRequest {
         message = "day is required and must be at least 3 characters"
     ))]
     pub day: String,
+
+    /// Defaults to a note, the only kind older clients know about
+    #[serde(default)]
+    pub kind: Option<EventKind>,
+
+    /// Defaults to now
+    pub occurred_at: Option<NaiveDateTime>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
@@ -89,10 +124,8 @@ pub struct UpdateEventRequest {
     ))]
     pub subscription_id: String,
 
-    #[validate(length(
-        min = 1,
-        message = "message is required and must be at least 3 characters"
-    ))]
+    #[serde(default)]
+    #[validate(length(max = 150, message = "message must be at most 150 characters"))]
     pub message: String,
 
     #[validate(length(
@@ -100,6 +133,13 @@ pub struct UpdateEventRequest {
         message = "day is required and must be at least 3 characters"
     ))]
     pub day: String,
+
+    /// Defaults to the current kind, older clients don't send it
+    #[serde(default)]
+    pub kind: Option<EventKind>,
+
+    /// Defaults to the current time of the event
+    pub occurred_at: Option<NaiveDateTime>,
 }
 
 pub async fn get_event(
@@ -110,8 +150,8 @@ pub async fn get_event(
     respond_json(event)
 }
 
-pub async fn get_events(pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
-    let events = block(move || get_all(&pool)).await?;
+pub async fn get_events(filter: Query<EventFilter>, pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
+    let events = block(move || get_all(&pool, filter.kind)).await?;
     respond_json(events)
 }
 
@@ -121,8 +161,8 @@ pub struct PathByFamilyID {
     day: String,
 }
 
-pub async fn get_events_by_family_id(path: Path<PathByFamilyID>, pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
-    let events = block(move || get_all_by_family_id(&pool, path.family_id, &path.day)).await?;
+pub async fn get_events_by_family_id(path: Path<PathByFamilyID>, filter: Query<EventFilter>, pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
+    let events = block(move || get_all_by_family_id(&pool, path.family_id, &path.day, filter.kind)).await?;
     respond_json(events)
 }
 
@@ -134,13 +174,47 @@ pub struct PathByFamilyPlaceUserSub {
     user_id: Uuid,
 }
 
-pub async fn get_events_by_family_place_user_user(path: Path<PathByFamilyPlaceUserSub>, pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
-    let events = block(move || get_all_by_family_user_place_sub(&pool, path.family_id, path.place_id, path.user_id, path.subscription_id)).await?;
+pub async fn get_events_by_family_place_user_user(path: Path<PathByFamilyPlaceUserSub>, filter: Query<EventFilter>, pool: Data<PoolType>) -> Result<Json<EventsResponse>, ApiError> {
+    let events = block(move || get_all_by_family_user_place_sub(&pool, path.family_id, path.place_id, path.user_id, path.subscription_id, filter.kind)).await?;
     println!("events {:?}", events);
     respond_json(events)
 }
 
+#[derive(Deserialize)]
+pub struct PathByOccurrence {
+    subscription_id: Uuid,
+    day: NaiveDate,
+}
+
+/// Get the status of an occurrence along with its events
+pub async fn get_occurrence_status(path: Path<PathByOccurrence>, pool: Data<PoolType>) -> Result<Json<OccurrenceStatusResponse>, ApiError> {
+    let day = path.day;
+    let subscription_id = path.subscription_id;
+    let events = block(move || {
+        let subscription = find_subscription(&pool, &subscription_id.to_string())?;
+        let family = find_family(&pool, &subscription.family_id)?;
+        get_all_by_occurrence(&pool, &family, &subscription.id, day)
+    })
+    .await?;
+    let status = status_of(&events);
+    respond_json(OccurrenceStatusResponse {
+        subscription_id,
+        day,
+        status,
+        events: events.into_iter().map(|event| event.into()).collect(),
+    })
+}
+
+/// Notes carry their whole meaning in the message, other kinds may omit it
+fn validate_message(kind: EventKind, message: &str) -> Result<(), ApiError> {
+    if kind == EventKind::Note && message.is_empty() {
+        return Err(ApiError::ValidationError(vec!["message is required for a note".into()]));
+    }
+    Ok(())
+}
+
 pub async fn create_event(
+    user: Option<AuthUser>,
     pool: Data<PoolType>,
     params: Json<CreateEventRequest>,
 ) -> Result<Json<EventResponse>, ApiError> {
@@ -148,7 +222,33 @@ pub async fn create_event(
     //println!("{:?}", params);
 
     validate(&params)?;
+    let kind = params.kind.unwrap_or(EventKind::Note);
+    validate_message(kind, &params.message)?;
+
+    // Anyone can leave a note, the lifecycle of an occurrence is up to the
+    // family
+    if kind != EventKind::Note {
+        let user = user
+            .clone()
+            .ok_or_else(|| ApiError::Unauthorized("Only members of the family can do this".into()))?;
+        let pool = pool.clone();
+        let (family_id, subscription_id) = (params.family_id.to_string(), params.subscription_id.to_string());
+        block(move || {
+            Membership::Member.of(&pool, &user, &family_id)?;
+            let subscription = find_subscription(&pool, &subscription_id)?;
+            if subscription.family_id != family_id {
+                return Err(ApiError::BadRequest(format!(
+                    "subscription {} doesn't belong to family {}",
+                    subscription_id, family_id
+                )));
+            }
+            Ok(())
+        })
+        .await?;
+    }
 
+    let actor_id = user.map(|user| user.id);
+    let actor = actor_id.clone().unwrap_or_else(|| ANONYMOUS.to_string());
     let event_id = Uuid::new_v4();
     let new_event: Event = NewEvent {
         id: event_id.to_string(),
@@ -158,44 +258,103 @@ pub async fn create_event(
         place_id: params.place_id.to_string(),
         day: params.day.to_string(),
         message: params.message.to_string(),
-        created_by: event_id.to_string(),
-        updated_by: event_id.to_string(),
+        created_by: actor.clone(),
+        updated_by: actor,
+        kind,
+        actor_id,
+        occurred_at: params.occurred_at.unwrap_or_else(|| Utc::now().naive_utc()),
     }
     .into();
-    let event = block(move || create(&pool, &new_event)).await?;
-    respond_json(event.into())
+    let pool_create = pool.clone();
+    let event = block(move || create(&pool_create, &new_event)).await?;
+    publish(&pool, &event.family_id.to_string(), ActivityKind::EventCreated, &event).await;
+    request_acknowledgements(&pool, &event).await;
+    respond_json(event)
 }
 
 pub async fn update_event(
+    user: AuthUser,
     event_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdateEventRequest>,
 ) -> Result<Json<EventResponse>, ApiError> {
     validate(&params)?;
 
-    let update_event= UpdateEvent{
-        id: event_id.to_string(),
-        subscription_id: params.subscription_id.to_string(),
-        family_id: params.family_id.to_string(),
-        user_id: params.user_id.to_string(),
-        place_id: params.place_id.to_string(),
-        day: params.day.to_string(),
-        message: params.message.to_string(),
-        updated_by: event_id.to_string(),
-    };
-    let event = block(move || update(&pool, &update_event)).await?;
-    respond_json(event.into())
+    let pool_update = pool.clone();
+    let event = block(move || {
+        let pool = pool_update;
+        let current = find_event(&pool, &event_id.to_string())?;
+        Membership::Member.of(&pool, &user, &current.family_id)?;
+        Membership::Member.of(&pool, &user, &params.family_id)?;
+        let kind = params.kind.unwrap_or_else(|| current.kind());
+        validate_message(kind, &params.message)?;
+        let update_event = UpdateEvent {
+            id: event_id.to_string(),
+            subscription_id: params.subscription_id.to_string(),
+            family_id: params.family_id.to_string(),
+            user_id: params.user_id.to_string(),
+            place_id: params.place_id.to_string(),
+            day: params.day.to_string(),
+            message: params.message.to_string(),
+            updated_by: user.id.clone(),
+            kind: kind.as_str().into(),
+            actor_id: current.actor_id.clone(),
+            occurred_at: params.occurred_at.unwrap_or(current.occurred_at),
+        };
+        update(&pool, &update_event)
+    })
+    .await?;
+    publish(&pool, &event.family_id.to_string(), ActivityKind::EventUpdated, &event).await;
+    respond_json(event)
 }
 
-/// Delete a user
+/// Delete an event, it can be restored
 pub async fn delete_event(
+    user: AuthUser,
     event_id: Path<Uuid>,
     pool: Data<PoolType>,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *event_id)).await?;
+    block(move || {
+        let event = find_event(&pool, &event_id.to_string())?;
+        Membership::Member.of(&pool, &user, &event.family_id)?;
+        delete(&pool, *event_id, &user.id)
+    })
+    .await?;
     respond_ok()
 }
 
+/// Restore a deleted event
+pub async fn restore_event(
+    user: AuthUser,
+    event_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<EventResponse>, ApiError> {
+    let pool_restore = pool.clone();
+    let event = block(move || {
+        let event = find_with_deleted(&pool_restore, &event_id.to_string())?;
+        Membership::Member.of(&pool_restore, &user, &event.family_id)?;
+        restore(&pool_restore, *event_id, &user.id)
+    })
+    .await?;
+    publish(&pool, &event.family_id.to_string(), ActivityKind::EventUpdated, &event).await;
+    respond_json(event)
+}
+
+/// Get every change made to an event, oldest first, deleted events included
+pub async fn get_event_history(
+    user: AuthUser,
+    event_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<ChangesResponse>, ApiError> {
+    let changes = block(move || {
+        let event = find_with_deleted(&pool, &event_id.to_string())?;
+        Membership::Member.of(&pool, &user, &event.family_id)?;
+        find_changes(&pool, Entity::Event, &event.id)
+    })
+    .await?;
+    respond_json(changes.into())
+}
+
 impl From<Event> for EventResponse {
     fn from(event: Event) -> Self {
         println!("//////////////////////////////");
@@ -211,6 +370,11 @@ impl From<Event> for EventResponse {
             place_id: Uuid::parse_str(&event.place_id).unwrap(),
             day: event.day.to_string(),
             message: event.message.to_string(),
+            kind: event.kind(),
+            actor_id: event.actor_id.as_ref().map(|actor_id| Uuid::parse_str(actor_id).unwrap()),
+            occurred_at: event.occurred_at,
+            created_by: event.created_by,
+            updated_by: event.updated_by,
         }
     }
 }
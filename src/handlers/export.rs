@@ -0,0 +1,159 @@
+use crate::audit::record;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::export::{Area, Format, Writer};
+use crate::handlers::geoloc::resolve_range;
+use crate::models::audit::{AuditAction, ClientInfo, NewAuditEntry};
+use crate::models::family::find_family;
+use crate::models::geoloc::{find_chunk, Cursor};
+use crate::models::place::find_all_by_family_id as find_places;
+use crate::models::sharing::{visibility_for, Visibility};
+use crate::models::user::{find_user, AuthUser};
+use actix_web::web::{block, Bytes, Data, HttpResponse, Path, Query};
+use chrono::{NaiveDate, NaiveDateTime};
+use futures::stream::unfold;
+use serde_json::json;
+use uuid::Uuid;
+
+/// Positions read from the database at once while exporting
+const CHUNK: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub format: Format,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub date: Option<NaiveDate>,
+    /// Also draw the geofences of the family's places
+    #[serde(default)]
+    pub places: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PathByUser {
+    user_id: Uuid,
+}
+
+#[derive(Clone)]
+enum Stage {
+    Header,
+    /// Positions after the last one written
+    Geolocs(Option<Cursor>),
+    Footer,
+    Done,
+}
+
+/// An export being streamed, one chunk of positions at a time
+struct Export {
+    pool: Data<PoolType>,
+    user_id: String,
+    title: String,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    areas: Vec<Area>,
+    /// Whether the viewer may see the positions, otherwise only places are written
+    visible: bool,
+    writer: Writer,
+    stage: Stage,
+}
+
+impl Export {
+    async fn next(&mut self) -> Option<Result<Bytes, ApiError>> {
+        let text = match self.stage.clone() {
+            Stage::Header => {
+                self.stage = if self.visible { Stage::Geolocs(None) } else { Stage::Footer };
+                self.writer.header(&self.title, &self.areas)
+            }
+            Stage::Geolocs(after) => {
+                let pool = self.pool.clone();
+                let user_id = self.user_id.clone();
+                let (from, to) = (self.from, self.to);
+                let geolocs = match block(move || find_chunk(&pool, &user_id, from, to, after, CHUNK)).await {
+                    Ok(geolocs) => geolocs,
+                    Err(error) => {
+                        self.stage = Stage::Done;
+                        return Some(Err(error.into()));
+                    }
+                };
+                self.stage = match geolocs.last() {
+                    Some(last) if geolocs.len() as i64 == CHUNK => {
+                        Stage::Geolocs(Some((last.created_at, last.id.clone())))
+                    }
+                    _ => Stage::Footer,
+                };
+                self.writer.geolocs(&geolocs)
+            }
+            Stage::Footer => {
+                self.stage = Stage::Done;
+                self.writer.footer()
+            }
+            Stage::Done => return None,
+        };
+        Some(Ok(Bytes::from(text)))
+    }
+}
+
+/// Download the positions of a user over a range, the current day by default
+///
+/// The document is streamed as positions are read, so ranges of any length
+/// are exported in constant memory. Users who don't share their location
+/// precisely export as an empty track. Every export is audited.
+pub async fn export_geolocs(
+    viewer: AuthUser,
+    client: ClientInfo,
+    path: Path<PathByUser>,
+    query: Query<ExportQuery>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let format = query.format;
+    let viewer_id = viewer.id.clone();
+    let pool_find = pool.clone();
+    let user_id = path.user_id.to_string();
+    let (user, from, to, areas, visible) = block(move || {
+        let user = find_user(&pool_find, &user_id)?;
+        let visible = visibility_for(&pool_find, &user.id, &viewer.id)? == Visibility::Precise;
+        let family = match &user.family_id {
+            Some(family_id) => Some(find_family(&pool_find, family_id)?),
+            None => None,
+        };
+        let (from, to) = resolve_range(query.from, query.to, query.date, family.as_ref())?;
+        let areas = match &user.family_id {
+            Some(family_id) if query.places => find_places(&pool_find, family_id)?
+                .into_iter()
+                .filter_map(|place| place.geofence().map(|fence| Area { name: place.name, fence }))
+                .collect(),
+            _ => vec![],
+        };
+        Ok::<_, ApiError>((user, from, to, areas, visible))
+    })
+    .await?;
+
+    let entry = NewAuditEntry::new(AuditAction::LocationExported, Some(&viewer_id), &client)
+        .family(user.family_id.as_deref())
+        .target(&user.id)
+        .details(json!({ "from": from, "to": to, "format": format.to_string(), "precise": visible }));
+    record(&pool, entry).await;
+
+    let filename = format!("locations-{}.{}", from.format("%Y%m%d-%H%M"), format);
+    let export = Export {
+        pool,
+        user_id: user.id,
+        title: format!("{} {}, {} - {}", user.first_name, user.last_name, from, to),
+        from,
+        to,
+        areas,
+        visible,
+        writer: Writer::new(format),
+        stage: Stage::Header,
+    };
+    let body = unfold(export, |mut export| async move {
+        let chunk = export.next().await?;
+        Some((chunk, export))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .header("Content-Disposition", format!("attachment; filename=\"{}\"", filename))
+        .streaming(body))
+}
@@ -1,20 +1,27 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::family::{find_by_code, create, delete, find, get_all, update, NewFamily, UpdateFamily, Family};
+use crate::models::family::{find_by_code, create, delete, find, get_all, update, NewFamily, UpdateFamily, Family, DEFAULT_TIMEZONE};
+use crate::models::user::AuthUser;
 use crate::validate::validate;
 use actix_web::web::{block, Data, HttpResponse, Json, Path};
 use rayon::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use uuid::Uuid;
 use validator::Validate;
 use rand::Rng;
+use chrono_tz::Tz;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct FamilyResponse {
     pub id: Uuid,
     pub nom: String,
     pub code: String,
+    pub timezone: String,
+    /// Days positions are kept, the system default when null
+    pub location_retention_days: Option<i32>,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -26,6 +33,10 @@ impl From<Family> for FamilyResponse {
             id: Uuid::parse_str(&family.id).unwrap(),
             nom: family.nom.to_string(),
             code: family.code.to_string(),
+            timezone: family.timezone.to_string(),
+            location_retention_days: family.location_retention_days,
+            created_by: Uuid::parse_str(&family.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&family.updated_by).unwrap(),
         }
     }
 }
@@ -43,6 +54,35 @@ pub struct CreateFamilyRequest {
         message = "nom is required and must be at least 3 characters"
     ))]
     pub nom: String,
+
+    /// IANA name such as Europe/Paris, defaults to UTC
+    pub timezone: Option<String>,
+
+    /// Days positions are kept, defaults to the system retention
+    pub location_retention_days: Option<i32>,
+}
+
+/// Timezones must be IANA names
+fn validate_timezone(timezone: &Option<String>) -> Result<(), ApiError> {
+    match timezone {
+        Some(timezone) if timezone.parse::<Tz>().is_err() => Err(ApiError::ValidationError(vec![
+            format!("timezone {} is not a known IANA timezone", timezone),
+        ])),
+        _ => Ok(()),
+    }
+}
+
+/// Longest retention a family can pick, in days
+const MAX_RETENTION_DAYS: i32 = 3650;
+
+fn validate_retention(days: Option<i32>) -> Result<(), ApiError> {
+    match days {
+        Some(days) if days < 1 || days > MAX_RETENTION_DAYS => Err(ApiError::ValidationError(vec![format!(
+            "location_retention_days must be between 1 and {}",
+            MAX_RETENTION_DAYS
+        )])),
+        _ => Ok(()),
+    }
 }
 
 /// Get a family
@@ -75,10 +115,13 @@ pub async fn get_families(pool: Data<PoolType>) -> Result<Json<FamiliesResponse>
 
 /// Create a family
 pub async fn create_family(
+    user: AuthUser,
     pool: Data<PoolType>,
     params: Json<CreateFamilyRequest>,
 ) -> Result<Json<FamilyResponse>, ApiError> {
     validate(&params)?;
+    validate_timezone(&params.timezone)?;
+    validate_retention(params.location_retention_days)?;
 
     let mut rng = rand::thread_rng();
     let number: u32 = rng.gen_range(0, 999999);
@@ -89,8 +132,10 @@ pub async fn create_family(
         id: family_id.to_string(),
         nom: params.nom.to_string(),
         code: code,
-        created_by: family_id.to_string(),
-        updated_by: family_id.to_string(),
+        created_by: user.id.clone(),
+        updated_by: user.id,
+        timezone: params.timezone.clone().unwrap_or_else(|| DEFAULT_TIMEZONE.into()),
+        location_retention_days: params.location_retention_days,
     }
     .into();
     let family = block(move || create(&pool, &new_family)).await?;
@@ -111,21 +156,38 @@ pub struct UpdateFamilyRequest {
         message = "code is required and must be at least 3 characters"
     ))]
     pub code: String,
+
+    /// Left unchanged when missing
+    pub timezone: Option<String>,
+
+    /// Left unchanged when missing, back to the system retention when null
+    #[serde(default, deserialize_with = "present")]
+    pub location_retention_days: Option<Option<i32>>,
+}
+
+/// Tell a null field from a missing one, which serde maps to None as well
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Option<i32>>, D::Error> {
+    Option::deserialize(deserializer).map(Some)
 }
 
 /// Update a family
 pub async fn update_family(
+    user: AuthUser,
     family_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdateFamilyRequest>,
 ) -> Result<Json<FamilyResponse>, ApiError> {
     validate(&params)?;
+    validate_timezone(&params.timezone)?;
+    validate_retention(params.location_retention_days.flatten())?;
 
     let update_family= UpdateFamily {
         id: family_id.to_string(),
         nom: params.nom.to_string(),
         code: params.code.to_string(),
-        updated_by: family_id.to_string(),
+        updated_by: user.id,
+        timezone: params.timezone.clone(),
+        location_retention_days: params.location_retention_days,
     };
     let family = block(move || update(&pool, &update_family)).await?;
     respond_json(family.into())
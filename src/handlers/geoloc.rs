@@ -1,14 +1,32 @@
+use crate::cache::Cache;
 use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::handlers::acknowledgement::request_acknowledgements;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::geoloc::{find_today, create, NewGeoloc, UpdateGeoloc, Geoloc};
+use crate::models::family::{day_bounds, find_family, Family};
+use crate::models::geoloc::{dedupe, delete_batch, find_at, find_between, find_downsampled, find_latest_at, create_batch, NewGeoloc, UpdateGeoloc, Geoloc, PURGE_BATCH};
+use crate::geo::{validate_location, Point};
+use crate::geofence::track;
+use crate::handlers::event::EventResponse;
+use crate::latest::{forget, latest, remember};
+use crate::models::activity::{delete_geolocs_of_user, ActivityKind};
+use crate::models::place::find_all_by_family_id as find_places;
+use crate::models::presence::find_all_by_user_ids as find_presences;
+use crate::models::sos::{find_tracked_user_ids, sampling_interval};
+use crate::models::sharing::{find_all_by_user_ids as find_sharings, find_sharing, visibility_for, Visibility};
+use crate::models::user::{find_user, get_members_by_family_id, AuthUser, Membership};
+use crate::stream::{publish, publish_of};
 use crate::validate::validate;
-use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use actix_web::web::{block, Data, HttpResponse, Json, Path, Query};
 use rayon::prelude::*;
 use serde::Serialize;
 use validator::Validate;
 use uuid::Uuid;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+
+/// How far ahead of the server a device clock may be, in minutes
+const MAX_CLOCK_SKEW: i64 = 5;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct GeolocResponse {
@@ -17,16 +35,131 @@ pub struct GeolocResponse {
     pub longitude: f64,
     pub user_id: Uuid,
     pub created_at: NaiveDateTime,
+    pub accuracy: Option<f64>,
+    pub speed: Option<f64>,
+    pub heading: Option<f64>,
+    pub altitude: Option<f64>,
+    pub battery: Option<i32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct GeolocsResponse(pub Vec<GeolocResponse>);
 
-#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
-pub struct CreateGeolocRequest {
+/// An uploaded position, with what the device should do next
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CreatedGeolocResponse {
+    #[serde(flatten)]
+    pub geoloc: GeolocResponse,
+    /// Same as in `GeolocBatchResponse`
+    pub sampling_interval: Option<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct GeolocBatchResponse {
+    pub received: usize,
+    pub inserted: usize,
+    /// Nothing is inserted unless sharing is precise
+    pub visibility: Visibility,
+    /// Seconds between positions the device should switch to, set while an
+    /// emergency alert of the user is tracked
+    pub sampling_interval: Option<i32>,
+    pub geolocs: GeolocsResponse,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct GeolocWipeResponse {
+    pub deleted: usize,
+}
+
+/// Where a member of a family was last seen
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MemberLocationResponse {
+    pub user_id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    /// None until the member sends a position
+    pub geoloc: Option<GeolocResponse>,
+    /// Seconds since the position was taken
+    pub age: Option<i64>,
+    pub accuracy: Option<f64>,
+    /// The place the member is inside, the most recently entered one
+    pub place_id: Option<Uuid>,
+    pub place_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MemberLocationsResponse(pub Vec<MemberLocationResponse>);
+
+/// A position as sent by a device
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GeolocPoint {
     pub latitude: f64,
     pub longitude: f64,
-    pub user_id: String
+    /// Device time of the position, defaults to now
+    pub recorded_at: Option<NaiveDateTime>,
+    pub accuracy: Option<f64>,
+    pub speed: Option<f64>,
+    pub heading: Option<f64>,
+    pub altitude: Option<f64>,
+    pub battery: Option<i32>,
+}
+
+impl GeolocPoint {
+    pub fn errors(&self) -> Vec<String> {
+        let mut errors = vec![];
+        if validate_location(Some(self.latitude), Some(self.longitude)).is_err() {
+            errors.push("latitude and longitude must be within -90..90 and -180..180".into());
+        }
+        if self.accuracy.map_or(false, |accuracy| accuracy < 0.0) {
+            errors.push("accuracy must be positive".into());
+        }
+        if self.speed.map_or(false, |speed| speed < 0.0) {
+            errors.push("speed must be positive".into());
+        }
+        if self.heading.map_or(false, |heading| heading < 0.0 || heading >= 360.0) {
+            errors.push("heading must be within 0..360".into());
+        }
+        if self.battery.map_or(false, |battery| battery < 0 || battery > 100) {
+            errors.push("battery must be within 0..100".into());
+        }
+        let latest = Utc::now().naive_utc() + Duration::minutes(MAX_CLOCK_SKEW);
+        if self.recorded_at.map_or(false, |recorded_at| recorded_at > latest) {
+            errors.push("recorded_at can't be in the future".into());
+        }
+        errors
+    }
+
+    pub fn into_geoloc(self, user_id: &str) -> Geoloc {
+        NewGeoloc {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            latitude: self.latitude,
+            longitude: self.longitude,
+            recorded_at: self.recorded_at,
+            accuracy: self.accuracy,
+            speed: self.speed,
+            heading: self.heading,
+            altitude: self.altitude,
+            battery: self.battery,
+        }
+        .into()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreateGeolocRequest {
+    #[serde(flatten)]
+    pub point: GeolocPoint,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreateGeolocBatchRequest {
+    #[validate(length(
+        min = 1,
+        max = 1000,
+        message = "points must hold between 1 and 1000 positions"
+    ))]
+    pub points: Vec<GeolocPoint>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
@@ -42,27 +175,366 @@ pub struct PathByDay {
     user_id: Uuid
 }
 
-pub async fn get_geolocs_by_day(path: Path<PathByDay>, pool: Data<PoolType>) -> Result<Json<GeolocsResponse>, ApiError> {
-    let geolocs = block(move || find_today(&pool, path.user_id)).await?;
-    respond_json(geolocs)
+/// Positions returned per page by default, and at most
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 5000;
+
+/// Longest range of positions that can be asked for at once, in days
+const MAX_RANGE_DAYS: i64 = 31;
+
+#[derive(Debug, Deserialize)]
+pub struct GeolocQuery {
+    /// UTC, defaults to a day before `to`
+    pub from: Option<NaiveDateTime>,
+    /// UTC, defaults to now
+    pub to: Option<NaiveDateTime>,
+    /// A day in the family's timezone, used when neither from nor to are set
+    pub date: Option<NaiveDate>,
+    /// Spread the positions of the range over at most this many points
+    pub max_points: Option<usize>,
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
 }
 
+impl GeolocQuery {
+    pub fn range(&self, family: Option<&Family>) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+        resolve_range(self.from, self.to, self.date, family)
+    }
+
+    fn page(&self) -> (i64, i64) {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT).max(1).min(MAX_LIMIT);
+        (self.offset.unwrap_or(0).max(0), limit)
+    }
+}
+
+/// The UTC range asked for, the family's current day by default
+pub fn resolve_range(
+    from: Option<NaiveDateTime>,
+    to: Option<NaiveDateTime>,
+    date: Option<NaiveDate>,
+    family: Option<&Family>,
+) -> Result<(NaiveDateTime, NaiveDateTime), ApiError> {
+    let (from, to) = match (from, to) {
+        (None, None) => {
+            let tz = family.map_or(Tz::UTC, Family::tz);
+            let today = || family.map_or_else(|| Utc::now().naive_utc().date(), Family::today);
+            day_bounds(tz, date.unwrap_or_else(today))
+        }
+        (from, to) => {
+            let to = to.unwrap_or_else(|| Utc::now().naive_utc());
+            (from.unwrap_or(to - Duration::days(1)), to)
+        }
+    };
+    if from >= to || to - from > Duration::days(MAX_RANGE_DAYS) {
+        let message = format!("from must be before to, at most {} days apart", MAX_RANGE_DAYS);
+        return Err(ApiError::ValidationError(vec![message]));
+    }
+    Ok((from, to))
+}
+
+/// Get the positions of a user over a range, the current day by default
+///
+/// With `max_points` the whole range is downsampled before being paginated.
+pub async fn get_geolocs_by_day(
+    viewer: AuthUser,
+    path: Path<PathByDay>,
+    query: Query<GeolocQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<GeolocsResponse>, ApiError> {
+    let geolocs = block(move || {
+        let user_id = path.user_id.to_string();
+        let user = find_user(&pool, &user_id)?;
+        if visibility_for(&pool, &user_id, &viewer.id)? != Visibility::Precise {
+            return Ok(vec![]);
+        }
+        let family = match &user.family_id {
+            Some(family_id) => Some(find_family(&pool, family_id)?),
+            None => None,
+        };
+        let (from, to) = query.range(family.as_ref())?;
+        let (offset, limit) = query.page();
+        match query.max_points {
+            Some(max_points) => {
+                let geolocs = find_downsampled(&pool, &user_id, from, to, max_points)?;
+                Ok(geolocs.into_iter().skip(offset as usize).take(limit as usize).collect())
+            }
+            None => find_between(&pool, &user_id, from, to, Some((offset, limit))),
+        }
+    })
+    .await?;
+    respond_json(geolocs.into())
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamily {
+    family_id: Uuid,
+}
+
+/// Get the latest position of every member of a family, to draw them on a map
+///
+/// Only members of the family can see it. Members sharing only places show
+/// up with their place and no position, members not sharing show up with
+/// neither.
+pub async fn get_latest_by_family(
+    viewer: AuthUser,
+    path: Path<PathByFamily>,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<Json<MemberLocationsResponse>, ApiError> {
+    let pool_find = pool.clone();
+    let family_id = path.family_id.to_string();
+    let viewer_check = viewer.clone();
+    let (members, presences, places, sharings, tracked) = block(move || {
+        Membership::Member.of(&pool_find, &viewer_check, &family_id)?;
+        let members = get_members_by_family_id(&pool_find, &family_id)?;
+        let member_ids: Vec<String> = members.iter().map(|member| member.id.clone()).collect();
+        let conn = pool_find.get()?;
+        let presences = find_presences(&conn, &member_ids)?;
+        let places = find_places(&pool_find, &family_id)?;
+        let sharings = find_sharings(&pool_find, &member_ids)?;
+        let tracked = find_tracked_user_ids(&pool_find, &member_ids)?;
+        Ok::<_, ApiError>((members, presences, places, sharings, tracked))
+    })
+    .await?;
+
+    let now = Utc::now().naive_utc();
+    let mut locations = vec![];
+    for member in members {
+        let visibility = match sharings.get(&member.id) {
+            Some(sharing) if member.id != viewer.id && !tracked.contains(&member.id) => sharing.visibility(),
+            _ => Visibility::Precise,
+        };
+        let geoloc = match visibility {
+            Visibility::Precise => latest(&cache, &pool, &member.id).await?,
+            _ => None,
+        };
+        let place = presences
+            .iter()
+            .filter(|presence| presence.user_id == member.id && visibility != Visibility::Hidden)
+            .max_by_key(|presence| presence.entered_at)
+            .and_then(|presence| places.iter().find(|place| place.id == presence.place_id));
+        locations.push(MemberLocationResponse {
+            user_id: Uuid::parse_str(&member.id)?,
+            first_name: member.first_name,
+            last_name: member.last_name,
+            age: geoloc.as_ref().map(|geoloc| (now - geoloc.created_at).num_seconds().max(0)),
+            accuracy: geoloc.as_ref().and_then(|geoloc| geoloc.accuracy),
+            geoloc: geoloc.map(|geoloc| geoloc.into()),
+            place_id: place.map(|place| Uuid::parse_str(&place.id)).transpose()?,
+            place_name: place.map(|place| place.name.clone()),
+        });
+    }
+    respond_json(MemberLocationsResponse(locations))
+}
+
+/// Upload a position of the caller, one already stored for the same time is
+/// returned as is
 pub async fn create_geoloc(
+    user: AuthUser,
     pool: Data<PoolType>,
+    cache: Option<Cache>,
     params: Json<CreateGeolocRequest>,
-) -> Result<Json<GeolocResponse>, ApiError> {
+) -> Result<Json<CreatedGeolocResponse>, ApiError> {
     validate(&params)?;
+    let errors = params.point.errors();
+    if !errors.is_empty() {
+        return Err(ApiError::ValidationError(errors));
+    }
 
-    let geoloc_id = Uuid::new_v4();
-    let new_geoloc: Geoloc = NewGeoloc{
-        id: geoloc_id.to_string(),
-        latitude: params.latitude,
-        longitude: params.longitude,
-        user_id: params.user_id.to_string(),
+    let user_id = user.id;
+    let new_geoloc = params.into_inner().point.into_geoloc(&user_id);
+    let recorded_at = new_geoloc.created_at;
+    let ingested = ingest(&pool, &cache, &user_id, vec![new_geoloc]).await?;
+    let sampling_interval = ingested.sampling_interval;
+
+    let geoloc = match (ingested.geolocs.into_iter().next(), ingested.visibility) {
+        (Some(geoloc), _) => geoloc,
+        // A retry of a position already stored gets it back, like in a batch
+        (None, Visibility::Precise) => block(move || find_at(&pool, &user_id, recorded_at)).await?,
+        (None, _) => {
+            return Err(ApiError::BadRequest("Location sharing isn't precise, the position was not stored".into()))
+        }
+    };
+    respond_json(CreatedGeolocResponse {
+        geoloc: geoloc.into(),
+        sampling_interval,
+    })
+}
+
+/// Upload many positions of the caller at once, typically what a device
+/// recorded offline
+///
+/// Positions are deduplicated by time, within the batch and against the
+/// ones already stored.
+pub async fn create_geoloc_batch(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<CreateGeolocBatchRequest>,
+) -> Result<Json<GeolocBatchResponse>, ApiError> {
+    validate(&params)?;
+    let errors: Vec<String> = params
+        .points
+        .iter()
+        .enumerate()
+        .flat_map(|(index, point)| {
+            let mut errors = point.errors();
+            if point.recorded_at.is_none() {
+                errors.push("recorded_at is required".into());
+            }
+            errors.into_iter().map(move |error| format!("points[{}]: {}", index, error))
+        })
+        .collect();
+    if !errors.is_empty() {
+        return Err(ApiError::ValidationError(errors));
+    }
+
+    let params = params.into_inner();
+    let received = params.points.len();
+    let user_id = user.id;
+    let batch: Vec<Geoloc> = params
+        .points
+        .into_iter()
+        .map(|point| point.into_geoloc(&user_id))
+        .collect();
+    let ingested = ingest(&pool, &cache, &user_id, batch).await?;
+
+    respond_json(GeolocBatchResponse {
+        received,
+        inserted: ingested.geolocs.len(),
+        visibility: ingested.visibility,
+        sampling_interval: ingested.sampling_interval,
+        geolocs: ingested.geolocs.into(),
+    })
+}
+
+/// Delete all the positions of the caller right away, including the ones
+/// the family's streams would replay
+pub async fn wipe_geolocs(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<Json<GeolocWipeResponse>, ApiError> {
+    let pool_wipe = pool.clone();
+    let user_id = user.id.clone();
+    let deleted = block(move || {
+        let user = find_user(&pool_wipe, &user_id)?;
+        let mut deleted = 0;
+        loop {
+            let batch = delete_batch(&pool_wipe, &user.id, None)?;
+            deleted += batch;
+            if batch < PURGE_BATCH as usize {
+                break;
+            }
+        }
+        if let Some(family_id) = &user.family_id {
+            delete_geolocs_of_user(&pool_wipe, family_id, &user.id)?;
+        }
+        Ok::<_, ApiError>(deleted)
+    })
+    .await?;
+    forget(&cache, &user.id).await;
+    respond_json(GeolocWipeResponse { deleted })
+}
+
+/// What became of uploaded positions
+pub struct Ingested {
+    pub visibility: Visibility,
+    /// Positions actually inserted, duplicates left out
+    pub geolocs: Vec<Geoloc>,
+    /// Seconds between positions the device should use while live tracked
+    pub sampling_interval: Option<i32>,
+}
+
+/// Store the positions of a user, then track them
+///
+/// Nothing is kept while the user's sharing is off or paused. With
+/// place-only sharing positions only serve to detect arrivals and
+/// departures and are never stored. An emergency alert being tracked
+/// overrides sharing, its positions are always stored.
+pub async fn ingest(
+    pool: &Data<PoolType>,
+    cache: &Option<Cache>,
+    user_id: &str,
+    batch: Vec<Geoloc>,
+) -> Result<Ingested, ApiError> {
+    let pool_create = pool.clone();
+    let user_create = user_id.to_string();
+    let (visibility, sampling_interval, previous, geolocs) = block(move || {
+        let sampling_interval = sampling_interval(&pool_create, &user_create)?;
+        let visibility = match sampling_interval {
+            Some(_) => Visibility::Precise,
+            None => find_sharing(&pool_create, &user_create)?.visibility(),
+        };
+        let previous = find_latest_at(&pool_create, &user_create)?;
+        let geolocs = match visibility {
+            Visibility::Hidden => vec![],
+            Visibility::Place => dedupe(batch),
+            Visibility::Precise => create_batch(&pool_create, &user_create, batch)?,
+        };
+        Ok::<_, ApiError>((visibility, sampling_interval, previous, geolocs))
+    })
+    .await?;
+    let stored = visibility == Visibility::Precise;
+    ingested(pool, cache, user_id, &geolocs, previous, stored).await;
+    Ok(Ingested {
+        visibility,
+        geolocs: if stored { geolocs } else { vec![] },
+        sampling_interval,
+    })
+}
+
+/// Detect arrivals and departures from new positions, then cache the
+/// latest one and push it to the family's streams if it was stored
+///
+/// Positions older than the previous latest one come late from an offline
+/// device: presences already moved past them, so they are only stored.
+/// These are side effects, failures are logged and never fail the upload.
+async fn ingested(
+    pool: &Data<PoolType>,
+    cache: &Option<Cache>,
+    user_id: &str,
+    geolocs: &[Geoloc],
+    previous: Option<NaiveDateTime>,
+    stored: bool,
+) {
+    let positions: Vec<_> = geolocs
+        .iter()
+        .filter(|geoloc| previous.map_or(true, |previous| geoloc.created_at > previous))
+        .map(|geoloc| (Point::new(geoloc.latitude, geoloc.longitude), geoloc.created_at))
+        .collect();
+    let latest = match geolocs.iter().max_by_key(|geoloc| geoloc.created_at) {
+        Some(latest) => latest.clone(),
+        None => return,
+    };
+    if stored && previous.map_or(true, |previous| latest.created_at > previous) {
+        remember(cache, &latest).await;
+    }
+
+    let pool_track = pool.clone();
+    let user_id = user_id.to_string();
+    let tracked = block(move || {
+        let user = find_user(&pool_track, &user_id)?;
+        let events = track(&pool_track, &user, &positions)?;
+        Ok::<_, ApiError>((user, events))
+    })
+    .await;
+    match tracked {
+        Ok((user, events)) => {
+            if let Some(family_id) = user.family_id {
+                if stored {
+                    let latest: GeolocResponse = latest.into();
+                    publish_of(pool, &family_id, Some(&user.id), ActivityKind::GeolocCreated, &latest).await;
+                }
+                for event in events {
+                    let event: EventResponse = event.into();
+                    publish(pool, &family_id, ActivityKind::EventCreated, &event).await;
+                    request_acknowledgements(pool, &event).await;
+                }
+            }
+        }
+        Err(error) => log::warn!("Could not track the positions of {}: {:?}", latest.user_id, error),
     }
-    .into();
-    let geoloc = block(move || create(&pool, &new_geoloc)).await?;
-    respond_json(geoloc.into())
 }
 
 impl From<Geoloc> for GeolocResponse {
@@ -72,7 +544,12 @@ impl From<Geoloc> for GeolocResponse {
             latitude: geoloc.latitude,
             longitude: geoloc.longitude,
             user_id: Uuid::parse_str(&geoloc.user_id).unwrap(),
-            created_at: geoloc.created_at
+            created_at: geoloc.created_at,
+            accuracy: geoloc.accuracy,
+            speed: geoloc.speed,
+            heading: geoloc.heading,
+            altitude: geoloc.altitude,
+            battery: geoloc.battery,
         }
     }
 }
@@ -0,0 +1,248 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::acknowledgement::request_acknowledgements;
+use crate::handlers::event::EventResponse;
+use crate::helpers::respond_json;
+use crate::models::activity::ActivityKind;
+use crate::models::event::{Event, EventKind, NewEvent};
+use crate::models::family::find_family;
+use crate::models::handover::{confirm, create, find_by_code, new_code, HandoverCode};
+use crate::models::pickup_person::find as find_pickup_person;
+use crate::models::place::find_place;
+use crate::models::place_staff::is_staff;
+use crate::models::subscription::{day_name, find_subscription};
+use crate::models::user::{find_user, get_adults_by_family_id, AuthUser};
+use crate::notify::{send_to_users, Notification};
+use crate::stream::publish;
+use actix_web::web::{block, Data, Json, Path};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct HandoverResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    pub child_id: Uuid,
+    pub pickup_person_id: Option<Uuid>,
+    pub pickup_name: String,
+    /// Shown by the pickup person, `/api/v1/handover/code/{code}`
+    pub code: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub confirmed_by: Option<Uuid>,
+    pub event_id: Option<Uuid>,
+    pub created_by: Uuid,
+}
+
+/// What anyone holding the code sees, the route is public so nothing
+/// beyond the pickup person's name
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct HandoverPreviewResponse {
+    pub child_first_name: String,
+    pub pickup_name: String,
+    pub place_name: String,
+    pub day: NaiveDate,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CreateHandoverRequest {
+    pub subscription_id: Uuid,
+    pub day: NaiveDate,
+    pub pickup_person_id: Uuid,
+}
+
+/// Issue a one-time code for a pickup person to collect a child
+///
+/// Adults of the family can issue codes for anyone allowed, a pickup
+/// person with an account for themselves. The code is valid until the end
+/// of the family's day.
+pub async fn create_handover(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<CreateHandoverRequest>,
+) -> Result<Json<HandoverResponse>, ApiError> {
+    let handover = block(move || {
+        let subscription = find_subscription(&pool, &params.subscription_id.to_string())?;
+        let person = find_pickup_person(&pool, params.pickup_person_id)?;
+        let caller = find_user(&pool, &user.id)?;
+        let allowed = (caller.belongs_to(&subscription.family_id) && caller.is_adult())
+            || person.user_id.as_ref() == Some(&caller.id);
+        if !allowed {
+            return Err(ApiError::Unauthorized(
+                "Only an adult of the family or the pickup person can ask for a handover code".into(),
+            ));
+        }
+
+        let family = find_family(&pool, &subscription.family_id)?;
+        let mut errors = vec![];
+        if subscription.child_id.as_ref() != Some(&person.child_id) {
+            errors.push("the pickup person isn't allowed to collect the child of this subscription".into());
+        }
+        if !subscription.runs_on(params.day) {
+            errors.push(format!("subscription doesn't run on {}", params.day));
+        }
+        if params.day < family.today() {
+            errors.push("day must not be in the past".into());
+        }
+        if !person.is_valid_on(params.day) {
+            errors.push(format!("{} isn't allowed to collect the child on {}", person.name, params.day));
+        }
+        if !errors.is_empty() {
+            return Err(ApiError::ValidationError(errors));
+        }
+
+        create(
+            &pool,
+            &HandoverCode {
+                id: Uuid::new_v4().to_string(),
+                family_id: family.id.clone(),
+                subscription_id: subscription.id,
+                day: params.day,
+                child_id: person.child_id,
+                pickup_person_id: Some(person.id),
+                pickup_name: person.name,
+                code: new_code(),
+                expires_at: family.day_bounds(params.day).1,
+                used_at: None,
+                confirmed_by: None,
+                event_id: None,
+                created_by: caller.id,
+                created_at: Utc::now().naive_utc(),
+            },
+        )
+    })
+    .await?;
+    respond_json(handover.into())
+}
+
+/// See who a code is for, for whoever hands the child over
+pub async fn get_handover(code: Path<String>, pool: Data<PoolType>) -> Result<Json<HandoverPreviewResponse>, ApiError> {
+    let preview = block(move || {
+        let handover = find_by_code(&pool, &code)?;
+        let child = find_user(&pool, &handover.child_id)?;
+        let subscription = find_subscription(&pool, &handover.subscription_id)?;
+        let place = find_place(&pool, &subscription.place_id)?;
+        Ok::<_, ApiError>(HandoverPreviewResponse {
+            child_first_name: child.first_name,
+            pickup_name: handover.pickup_name,
+            place_name: place.name,
+            day: handover.day,
+            expires_at: handover.expires_at,
+            used_at: handover.used_at,
+        })
+    })
+    .await?;
+    respond_json(preview)
+}
+
+/// Hand the child over to the holder of a code
+///
+/// Records the pickup event with who took the child, once. Only a member
+/// of the family or the staff of the place can confirm, never the pickup
+/// person themselves.
+pub async fn confirm_handover(
+    user: AuthUser,
+    code: Path<String>,
+    pool: Data<PoolType>,
+) -> Result<Json<HandoverResponse>, ApiError> {
+    let pool_confirm = pool.clone();
+    let (handover, event) = block(move || {
+        let handover = find_by_code(&pool_confirm, &code)?;
+        let now = Utc::now().naive_utc();
+        if !handover.is_usable_at(now) {
+            return Err(ApiError::BadRequest("handover code was already used or expired".into()));
+        }
+        let family = find_family(&pool_confirm, &handover.family_id)?;
+        if family.today() != handover.day {
+            return Err(ApiError::BadRequest(format!("handover code is for {}", handover.day)));
+        }
+        let subscription = find_subscription(&pool_confirm, &handover.subscription_id)?;
+        let person = match &handover.pickup_person_id {
+            Some(pickup_person_id) => Some(find_pickup_person(&pool_confirm, Uuid::parse_str(pickup_person_id)?)?),
+            None => None,
+        };
+        let confirmer = find_user(&pool_confirm, &user.id)?;
+        let holder = person.as_ref().and_then(|person| person.user_id.as_ref()) == Some(&confirmer.id);
+        let allowed = confirmer.belongs_to(&family.id) || is_staff(&pool_confirm, &subscription.place_id, &confirmer.id)?;
+        if holder || !allowed {
+            return Err(ApiError::Unauthorized(
+                "Only a member of the family or the staff of the place can confirm a handover".into(),
+            ));
+        }
+        let actor = confirmer.id.clone();
+        let event: Event = NewEvent {
+            id: Uuid::new_v4().to_string(),
+            family_id: family.id.clone(),
+            subscription_id: subscription.id.clone(),
+            place_id: subscription.place_id.clone(),
+            user_id: handover.child_id.clone(),
+            day: day_name(handover.day).into(),
+            message: format!("Picked up by {}", handover.pickup_name),
+            created_by: actor.clone(),
+            updated_by: actor,
+            kind: EventKind::Pickup,
+            actor_id: person.and_then(|person| person.user_id),
+            occurred_at: now,
+        }
+        .into();
+        confirm(&pool_confirm, &handover.id, &confirmer.id, &event)?;
+        let handover = find_by_code(&pool_confirm, &handover.code)?;
+        Ok::<_, ApiError>((handover, event))
+    })
+    .await?;
+
+    let event: EventResponse = event.into();
+    publish(&pool, &handover.family_id, ActivityKind::EventCreated, &event).await;
+    request_acknowledgements(&pool, &event).await;
+    notify_adults(&pool, &handover).await;
+    respond_json(handover.into())
+}
+
+/// Tell the adults of the family who took the child
+async fn notify_adults(pool: &Data<PoolType>, handover: &HandoverCode) {
+    let pool = pool.clone();
+    let (family_id, child_id) = (handover.family_id.clone(), handover.child_id.clone());
+    let found = block(move || {
+        let child = find_user(&pool, &child_id)?;
+        let adults = get_adults_by_family_id(&pool, &family_id)?;
+        Ok::<_, ApiError>((child, adults))
+    })
+    .await;
+    match found {
+        Ok((child, adults)) => {
+            let body = format!("{} was picked up by {}", child.first_name, handover.pickup_name);
+            let notification = Notification::new("Child picked up", &body).data(json!({
+                "handover_id": handover.id,
+                "event_id": handover.event_id,
+            }));
+            send_to_users(&adults, notification).await;
+        }
+        Err(error) => log::warn!("Could not notify the handover {}: {:?}", handover.id, error),
+    }
+}
+
+impl From<HandoverCode> for HandoverResponse {
+    fn from(handover: HandoverCode) -> Self {
+        HandoverResponse {
+            id: Uuid::parse_str(&handover.id).unwrap(),
+            family_id: Uuid::parse_str(&handover.family_id).unwrap(),
+            subscription_id: Uuid::parse_str(&handover.subscription_id).unwrap(),
+            day: handover.day,
+            child_id: Uuid::parse_str(&handover.child_id).unwrap(),
+            pickup_person_id: handover.pickup_person_id.map(|id| Uuid::parse_str(&id).unwrap()),
+            pickup_name: handover.pickup_name,
+            code: handover.code,
+            expires_at: handover.expires_at,
+            used_at: handover.used_at,
+            confirmed_by: handover.confirmed_by.map(|id| Uuid::parse_str(&id).unwrap()),
+            event_id: handover.event_id.map(|id| Uuid::parse_str(&id).unwrap()),
+            created_by: Uuid::parse_str(&handover.created_by).unwrap(),
+        }
+    }
+}
@@ -5,4 +5,22 @@ pub mod family;
 pub mod place;
 pub mod subscription;
 pub mod event;
-pub mod geoloc;
\ No newline at end of file
+pub mod geoloc;
+pub mod cover_request;
+pub mod stream;
+pub mod owntracks;
+pub mod trip;
+pub mod export;
+pub mod sharing;
+pub mod eta;
+pub mod escalation;
+pub mod sos;
+pub mod pickup_person;
+pub mod handover;
+pub mod emergency_contact;
+pub mod child_card;
+pub mod chat;
+pub mod acknowledgement;
+pub mod attachment;
+pub mod change;
+pub mod audit;
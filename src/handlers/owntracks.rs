@@ -0,0 +1,386 @@
+//! OwnTracks HTTP mode
+//!
+//! The app posts one JSON message per request and authenticates with HTTP
+//! basic auth, email and password. See
+//! https://owntracks.org/booklet/tech/http/ and
+//! https://owntracks.org/booklet/tech/json/ for the formats.
+
+use crate::audit::record;
+use crate::auth::hash;
+use crate::cache::Cache;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::auth::login_failed;
+use crate::handlers::geoloc::{ingest, GeolocPoint};
+use crate::models::audit::ClientInfo;
+use crate::models::geoloc::find_latest;
+use crate::models::place::{
+    create, find_all_by_family_id as find_places, find_by_name, update, NewPlace, UpdatePlace, DEFAULT_RADIUS,
+};
+use crate::models::presence::find_all_by_user_ids as find_presences;
+use crate::models::sharing::{find_all_by_user_ids as find_sharings, Visibility};
+use crate::models::sos::find_tracked_user_ids;
+use crate::models::user::{find_by_auth, find_user, get_members_by_family_id, User};
+use actix_web::web::{block, Data, Json};
+use actix_web::HttpRequest;
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "_type", rename_all = "lowercase")]
+pub enum OwnTracksMessage {
+    Location(OwnTracksLocation),
+    Transition(OwnTracksTransition),
+    Waypoint(OwnTracksWaypoint),
+    Waypoints { waypoints: Vec<OwnTracksWaypoint> },
+    /// Cards, commands and the like, accepted and ignored
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OwnTracksLocation {
+    pub lat: f64,
+    pub lon: f64,
+    /// Unix time of the fix
+    pub tst: i64,
+    pub acc: Option<f64>,
+    pub alt: Option<f64>,
+    /// km/h
+    pub vel: Option<f64>,
+    pub cog: Option<f64>,
+    pub batt: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OwnTracksTransition {
+    pub lat: f64,
+    pub lon: f64,
+    pub tst: i64,
+    pub acc: Option<f64>,
+    /// enter or leave, our own geofences decide
+    pub event: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OwnTracksWaypoint {
+    /// Name of the region, matched with the name of a place
+    pub desc: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// Radius in meters
+    pub rad: Option<f64>,
+}
+
+/// What the app shows for each other family member, and commands for it
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "_type", rename_all = "lowercase")]
+pub enum OwnTracksReply {
+    Location {
+        tid: String,
+        topic: String,
+        lat: f64,
+        lon: f64,
+        tst: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        acc: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        batt: Option<i32>,
+    },
+    Card {
+        tid: String,
+        topic: String,
+        name: String,
+    },
+    /// Remote configuration, only honored when the app allows it
+    Cmd {
+        action: String,
+        configuration: Value,
+    },
+}
+
+/// Switch the app to move mode, reporting every `interval` seconds, while
+/// an emergency alert of the user is tracked
+fn sampling(interval: i32) -> OwnTracksReply {
+    OwnTracksReply::Cmd {
+        action: "setConfiguration".into(),
+        configuration: json!({
+            "_type": "configuration",
+            "monitoring": 2,
+            "locatorInterval": interval,
+        }),
+    }
+}
+
+/// Parse `Authorization: Basic` into an email and password
+fn basic_auth(request: &HttpRequest) -> Option<(String, String)> {
+    let header = request.headers().get("Authorization")?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+/// Two letters the app shows on the map
+fn tracker_id(user: &User) -> String {
+    user.first_name
+        .chars()
+        .take(1)
+        .chain(user.last_name.chars().take(1))
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn topic(user: &User) -> String {
+    format!("owntracks/{}/mama", user.id)
+}
+
+fn point(lat: f64, lon: f64, tst: i64, acc: Option<f64>) -> GeolocPoint {
+    GeolocPoint {
+        latitude: lat,
+        longitude: lon,
+        recorded_at: Some(NaiveDateTime::from_timestamp(tst, 0)),
+        accuracy: acc,
+        speed: None,
+        heading: None,
+        altitude: None,
+        battery: None,
+    }
+}
+
+/// Create the place a waypoint names, or move its geofence
+fn save_waypoint(pool: &PoolType, user: &User, family_id: &str, waypoint: &OwnTracksWaypoint) -> Result<(), ApiError> {
+    match find_by_name(pool, family_id, &waypoint.desc)? {
+        Some(place) => {
+            let update_place = UpdatePlace {
+                id: place.id,
+                name: place.name,
+                updated_by: user.id.clone(),
+                family_id: place.family_id,
+                latitude: Some(waypoint.lat),
+                longitude: Some(waypoint.lon),
+                radius: waypoint.rad.or(place.radius),
+                polygon: place.polygon,
+                address: place.address,
+            };
+            update(pool, &update_place)?;
+        }
+        None => {
+            let new_place = NewPlace {
+                id: Uuid::new_v4().to_string(),
+                name: waypoint.desc.clone(),
+                family_id: family_id.to_string(),
+                created_by: user.id.clone(),
+                updated_by: user.id.clone(),
+                latitude: Some(waypoint.lat),
+                longitude: Some(waypoint.lon),
+                radius: waypoint.rad,
+                polygon: None,
+                address: None,
+            };
+            create(pool, &new_place.into())?;
+        }
+    }
+    Ok(())
+}
+
+/// The card and latest position of every other member of the family
+///
+/// Members sharing only places are shown at the center of the place they
+/// are inside, the fence radius as accuracy. Members not sharing only get
+/// their card.
+fn friends(pool: &PoolType, user: &User, family_id: &str) -> Result<Vec<OwnTracksReply>, ApiError> {
+    let members = get_members_by_family_id(pool, family_id)?;
+    let member_ids: Vec<String> = members.iter().map(|member| member.id.clone()).collect();
+    let sharings = find_sharings(pool, &member_ids)?;
+    let tracked = find_tracked_user_ids(pool, &member_ids)?;
+    let places = find_places(pool, family_id)?;
+    let conn = pool.get()?;
+    let presences = find_presences(&conn, &member_ids)?;
+
+    let mut friends = vec![];
+    for member in members {
+        if member.id == user.id {
+            continue;
+        }
+        friends.push(OwnTracksReply::Card {
+            tid: tracker_id(&member),
+            topic: topic(&member),
+            name: format!("{} {}", member.first_name, member.last_name),
+        });
+        let visibility = match sharings.get(&member.id) {
+            Some(sharing) if !tracked.contains(&member.id) => sharing.visibility(),
+            _ => Visibility::Precise,
+        };
+        let location = match visibility {
+            Visibility::Precise => find_latest(pool, &member.id)?
+                .map(|geoloc| (geoloc.latitude, geoloc.longitude, geoloc.created_at, geoloc.accuracy, geoloc.battery)),
+            Visibility::Place => {
+                let inside = presences
+                    .iter()
+                    .filter(|presence| presence.user_id == member.id)
+                    .max_by_key(|presence| presence.entered_at)
+                    .and_then(|presence| {
+                        let place = places.iter().find(|place| place.id == presence.place_id)?;
+                        Some((place, place.location()?, presence.entered_at))
+                    });
+                match inside {
+                    Some((place, center, entered_at)) => {
+                        // Still there as of the last point, or since entering
+                        let seen_at = find_latest(pool, &member.id)?
+                            .map(|geoloc| geoloc.created_at)
+                            .filter(|created_at| *created_at > entered_at)
+                            .unwrap_or(entered_at);
+                        let radius = place.radius.unwrap_or(DEFAULT_RADIUS);
+                        Some((center.latitude, center.longitude, seen_at, Some(radius), None))
+                    }
+                    None => None,
+                }
+            }
+            Visibility::Hidden => None,
+        };
+        if let Some((lat, lon, at, acc, batt)) = location {
+            friends.push(OwnTracksReply::Location {
+                tid: tracker_id(&member),
+                topic: topic(&member),
+                lat,
+                lon,
+                tst: at.timestamp(),
+                acc,
+                batt,
+            });
+        }
+    }
+    Ok(friends)
+}
+
+/// Receive an OwnTracks message, reply with the family members' positions,
+/// and the sampling interval to switch to while the user is tracked
+pub async fn owntracks(
+    request: HttpRequest,
+    client: ClientInfo,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    message: Json<OwnTracksMessage>,
+) -> Result<Json<Vec<OwnTracksReply>>, ApiError> {
+    let (email, password) = match basic_auth(&request) {
+        Some(credentials) => credentials,
+        None => {
+            let entry = login_failed(&pool, &client, None)
+                .await
+                .details(json!({ "path": request.path() }));
+            record(&pool, entry).await;
+            return Err(ApiError::Unauthorized("Basic authentication is required".into()));
+        }
+    };
+    let hashed = hash(&password);
+    let pool_auth = pool.clone();
+    let lookup_email = email.clone();
+    let user = match block(move || find_by_auth(&pool_auth, &lookup_email, &hashed)).await {
+        Ok(user) => user,
+        Err(error) => {
+            let entry = login_failed(&pool, &client, Some(email))
+                .await
+                .details(json!({ "path": request.path() }));
+            record(&pool, entry).await;
+            return Err(error.into());
+        }
+    };
+    let pool_user = pool.clone();
+    let user = block(move || find_user(&pool_user, &user.id.to_string())).await?;
+    let family_id = user
+        .family_id
+        .clone()
+        .ok_or_else(|| ApiError::BadRequest(format!("user {} doesn't belong to a family", user.id)))?;
+
+    let point = match message.into_inner() {
+        OwnTracksMessage::Location(location) => {
+            let mut point = point(location.lat, location.lon, location.tst, location.acc);
+            point.altitude = location.alt;
+            point.speed = location.vel.map(|vel| vel / 3.6);
+            point.heading = location.cog;
+            point.battery = location.batt;
+            Some(point)
+        }
+        OwnTracksMessage::Transition(transition) => {
+            Some(point(transition.lat, transition.lon, transition.tst, transition.acc))
+        }
+        OwnTracksMessage::Waypoint(waypoint) => {
+            let pool = pool.clone();
+            let (user, family_id) = (user.clone(), family_id.clone());
+            block(move || save_waypoint(&pool, &user, &family_id, &waypoint)).await?;
+            None
+        }
+        OwnTracksMessage::Waypoints { waypoints } => {
+            let pool = pool.clone();
+            let (user, family_id) = (user.clone(), family_id.clone());
+            block(move || {
+                waypoints
+                    .iter()
+                    .try_for_each(|waypoint| save_waypoint(&pool, &user, &family_id, waypoint))
+            })
+            .await?;
+            None
+        }
+        OwnTracksMessage::Other => None,
+    };
+
+    let mut sampling_interval = None;
+    if let Some(point) = point {
+        let errors = point.errors();
+        if !errors.is_empty() {
+            return Err(ApiError::ValidationError(errors));
+        }
+        let ingested = ingest(&pool, &cache, &user.id, vec![point.into_geoloc(&user.id)]).await?;
+        sampling_interval = ingested.sampling_interval;
+    }
+
+    let mut replies = block(move || friends(&pool, &user, &family_id)).await?;
+    replies.extend(sampling_interval.map(sampling));
+    Ok(Json(replies))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn it_parses_owntracks_messages() {
+        let location = json!({"_type": "location", "lat": 48.85, "lon": 2.35, "tst": 1602489600, "vel": 36, "tid": "NN"});
+        match serde_json::from_value::<OwnTracksMessage>(location).unwrap() {
+            OwnTracksMessage::Location(location) => assert_eq!(location.vel, Some(36.0)),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let card = json!({"_type": "card", "name": "Nelson"});
+        assert!(matches!(serde_json::from_value::<OwnTracksMessage>(card).unwrap(), OwnTracksMessage::Other));
+    }
+
+    #[test]
+    fn it_serializes_friends_the_way_the_app_expects() {
+        let friend = OwnTracksReply::Location {
+            tid: "NN".into(),
+            topic: "owntracks/1/mama".into(),
+            lat: 48.85,
+            lon: 2.35,
+            tst: 1602489600,
+            acc: None,
+            batt: Some(80),
+        };
+        let expected = json!({"_type": "location", "tid": "NN", "topic": "owntracks/1/mama", "lat": 48.85, "lon": 2.35, "tst": 1602489600, "batt": 80});
+        assert_eq!(serde_json::to_value(&friend).unwrap(), expected);
+    }
+
+    #[test]
+    fn it_asks_the_app_to_report_more_often() {
+        let expected = json!({
+            "_type": "cmd",
+            "action": "setConfiguration",
+            "configuration": {"_type": "configuration", "monitoring": 2, "locatorInterval": 15},
+        });
+        assert_eq!(serde_json::to_value(&sampling(15)).unwrap(), expected);
+    }
+}
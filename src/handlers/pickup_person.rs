@@ -0,0 +1,227 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::models::pickup_person::{
+    create, delete, find, find_all_by_family_id, update, PickupPerson, UpdatePickupPerson,
+};
+use crate::models::user::{find_user, AuthUser, Membership};
+use crate::validate::validate;
+use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use rayon::prelude::*;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PickupPersonResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub child_id: Uuid,
+    pub name: String,
+    pub phone: Option<String>,
+    pub photo_url: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+    pub updated_at: NaiveDateTime,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PickupPersonsResponse(pub Vec<PickupPersonResponse>);
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct CreatePickupPersonRequest {
+    pub child_id: Uuid,
+
+    #[validate(length(min = 1, max = 100, message = "name is required and must be at most 100 characters"))]
+    pub name: String,
+
+    #[validate(length(max = 32, message = "phone must be at most 32 characters"))]
+    pub phone: Option<String>,
+
+    #[validate(url(message = "photo_url must be a valid url"))]
+    pub photo_url: Option<String>,
+
+    /// When the person has an account, to let them ask for handover codes
+    pub user_id: Option<Uuid>,
+
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct UpdatePickupPersonRequest {
+    #[validate(length(min = 1, max = 100, message = "name is required and must be at most 100 characters"))]
+    pub name: String,
+
+    #[validate(length(max = 32, message = "phone must be at most 32 characters"))]
+    pub phone: Option<String>,
+
+    #[validate(url(message = "photo_url must be a valid url"))]
+    pub photo_url: Option<String>,
+
+    pub user_id: Option<Uuid>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+}
+
+fn validate_period(valid_from: Option<NaiveDate>, valid_until: Option<NaiveDate>) -> Result<(), ApiError> {
+    match (valid_from, valid_until) {
+        (Some(from), Some(until)) if until < from => Err(ApiError::ValidationError(vec![
+            "valid_until must not be before valid_from".into(),
+        ])),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get the people allowed to collect the children of a family
+pub async fn get_pickup_persons_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<PickupPersonsResponse>, ApiError> {
+    let persons = block(move || {
+        let family_id = path.family_id.to_string();
+        Membership::Member.of(&pool, &user, &family_id)?;
+        find_all_by_family_id(&pool, &family_id)
+    })
+    .await?;
+    respond_json(PickupPersonsResponse(persons.into_par_iter().map(|person| person.into()).collect()))
+}
+
+/// Get a pickup person
+pub async fn get_pickup_person(
+    user: AuthUser,
+    pickup_person_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<PickupPersonResponse>, ApiError> {
+    let person = block(move || {
+        let person = find(&pool, *pickup_person_id)?;
+        Membership::Member.of(&pool, &user, &person.family_id)?;
+        Ok::<_, ApiError>(person)
+    })
+    .await?;
+    respond_json(person.into())
+}
+
+/// Allow someone to collect a child, only an adult of the child's family can
+pub async fn create_pickup_person(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<CreatePickupPersonRequest>,
+) -> Result<Json<PickupPersonResponse>, ApiError> {
+    validate(&params)?;
+    validate_period(params.valid_from, params.valid_until)?;
+
+    let person = block(move || {
+        let child = find_user(&pool, &params.child_id.to_string())?;
+        let family_id = child
+            .family_id
+            .clone()
+            .ok_or_else(|| ApiError::BadRequest(format!("user {} doesn't belong to a family", child.id)))?;
+        let adult = Membership::Adult.of(&pool, &user, &family_id)?;
+        if let Some(user_id) = params.user_id {
+            find_user(&pool, &user_id.to_string())?;
+        }
+        let now = Utc::now().naive_utc();
+        let params = params.into_inner();
+        create(
+            &pool,
+            &PickupPerson {
+                id: Uuid::new_v4().to_string(),
+                family_id,
+                child_id: child.id,
+                name: params.name,
+                phone: params.phone,
+                photo_url: params.photo_url,
+                user_id: params.user_id.map(|user_id| user_id.to_string()),
+                valid_from: params.valid_from,
+                valid_until: params.valid_until,
+                created_by: adult.id.clone(),
+                created_at: now,
+                updated_by: adult.id,
+                updated_at: now,
+            },
+        )
+    })
+    .await?;
+    respond_json(person.into())
+}
+
+/// Change who a pickup person is or when they may collect the child
+pub async fn update_pickup_person(
+    user: AuthUser,
+    pickup_person_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<UpdatePickupPersonRequest>,
+) -> Result<Json<PickupPersonResponse>, ApiError> {
+    validate(&params)?;
+    validate_period(params.valid_from, params.valid_until)?;
+
+    let person = block(move || {
+        let person = find(&pool, *pickup_person_id)?;
+        let adult = Membership::Adult.of(&pool, &user, &person.family_id)?;
+        if let Some(user_id) = params.user_id {
+            find_user(&pool, &user_id.to_string())?;
+        }
+        let params = params.into_inner();
+        update(
+            &pool,
+            &UpdatePickupPerson {
+                id: person.id,
+                name: params.name,
+                phone: params.phone,
+                photo_url: params.photo_url,
+                user_id: params.user_id.map(|user_id| user_id.to_string()),
+                valid_from: params.valid_from,
+                valid_until: params.valid_until,
+                updated_by: adult.id,
+                updated_at: Utc::now().naive_utc(),
+            },
+        )
+    })
+    .await?;
+    respond_json(person.into())
+}
+
+/// Stop allowing someone to collect a child
+pub async fn delete_pickup_person(
+    user: AuthUser,
+    pickup_person_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    block(move || {
+        let person = find(&pool, *pickup_person_id)?;
+        Membership::Adult.of(&pool, &user, &person.family_id)?;
+        delete(&pool, *pickup_person_id)
+    })
+    .await?;
+    respond_ok()
+}
+
+impl From<PickupPerson> for PickupPersonResponse {
+    fn from(person: PickupPerson) -> Self {
+        PickupPersonResponse {
+            id: Uuid::parse_str(&person.id).unwrap(),
+            family_id: Uuid::parse_str(&person.family_id).unwrap(),
+            child_id: Uuid::parse_str(&person.child_id).unwrap(),
+            name: person.name,
+            phone: person.phone,
+            photo_url: person.photo_url,
+            user_id: person.user_id.map(|user_id| Uuid::parse_str(&user_id).unwrap()),
+            valid_from: person.valid_from,
+            valid_until: person.valid_until,
+            updated_at: person.updated_at,
+            created_by: Uuid::parse_str(&person.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&person.updated_by).unwrap(),
+        }
+    }
+}
@@ -1,9 +1,15 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::geo::{validate_location, Point};
+use crate::handlers::change::ChangesResponse;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::place::{create, delete, find, get_all_by_family_id, get_all, update, NewPlace, UpdatePlace, Place};
+use crate::models::change::{find_all as find_changes, Entity};
+use crate::models::place::{create, delete, find, find_place, find_with_deleted, get_all_by_family_id, get_all, restore, update, NewPlace, UpdatePlace, Place};
+use crate::models::place_staff::{create as create_staff, delete as delete_staff, find_all_by_place_id as find_staff, PlaceStaff};
+use crate::models::user::{find_user, AuthUser, Membership};
 use crate::validate::validate;
 use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use chrono::{NaiveDateTime, Utc};
 use rayon::prelude::*;
 use serde::Serialize;
 use uuid::Uuid;
@@ -14,6 +20,13 @@ pub struct PlaceResponse {
     pub id: Uuid,
     pub name: String,
     pub family_id: Uuid,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius: Option<f64>,
+    pub polygon: Option<Vec<Point>>,
+    pub address: Option<String>,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -31,7 +44,19 @@ pub struct CreatePlaceRequest {
         min = 3,
         message = "family_id is required and must be at least 3 characters"
     ))]
-    pub family_id: String
+    pub family_id: String,
+
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+
+    /// Radius of the geofence around the center, in meters
+    pub radius: Option<f64>,
+
+    /// Geofence outline, takes precedence over the radius
+    pub polygon: Option<Vec<Point>>,
+
+    #[validate(length(max = 255, message = "address must be at most 255 characters"))]
+    pub address: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
@@ -46,7 +71,40 @@ pub struct UpdatePlaceRequest {
         min = 3,
         message = "family_id is required and must be at least 3 characters"
     ))]
-    pub family_id: String
+    pub family_id: String,
+
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+
+    /// Radius of the geofence around the center, in meters
+    pub radius: Option<f64>,
+
+    /// Geofence outline, takes precedence over the radius
+    pub polygon: Option<Vec<Point>>,
+
+    #[validate(length(max = 255, message = "address must be at most 255 characters"))]
+    pub address: Option<String>,
+}
+
+/// A geofence needs a positive radius and a polygon at least three valid vertices
+fn validate_geofence(radius: Option<f64>, polygon: &Option<Vec<Point>>) -> Result<(), ApiError> {
+    let mut errors = vec![];
+    if radius.map_or(false, |radius| radius <= 0.0) {
+        errors.push("radius must be positive".to_string());
+    }
+    if let Some(vertices) = polygon {
+        let valid = vertices
+            .iter()
+            .all(|vertex| validate_location(Some(vertex.latitude), Some(vertex.longitude)).is_ok());
+        if vertices.len() < 3 || !valid {
+            errors.push("polygon must have at least 3 valid vertices".to_string());
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(errors))
+    }
 }
 
 pub async fn get_place(
@@ -73,18 +131,26 @@ pub async fn get_places_by_family_id(path: Path<PathByFamilyID>, pool: Data<Pool
 }
 
 pub async fn create_place(
+    user: AuthUser,
     pool: Data<PoolType>,
     params: Json<CreatePlaceRequest>,
 ) -> Result<Json<PlaceResponse>, ApiError> {
     validate(&params)?;
+    validate_location(params.latitude, params.longitude)?;
+    validate_geofence(params.radius, &params.polygon)?;
 
     let place_id = Uuid::new_v4();
     let new_place: Place = NewPlace{
         id: place_id.to_string(),
         name: params.name.to_string(),
         family_id: params.family_id.to_string(),
-        created_by: place_id.to_string(),
-        updated_by: place_id.to_string(),
+        created_by: user.id.clone(),
+        updated_by: user.id,
+        latitude: params.latitude,
+        longitude: params.longitude,
+        radius: params.radius,
+        polygon: params.polygon.as_ref().map(|polygon| serde_json::to_string(polygon).unwrap()),
+        address: params.address.clone(),
     }
     .into();
     let place = block(move || create(&pool, &new_place)).await?;
@@ -92,36 +158,190 @@ pub async fn create_place(
 }
 
 pub async fn update_place(
+    user: AuthUser,
     place_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdatePlaceRequest>,
 ) -> Result<Json<PlaceResponse>, ApiError> {
     validate(&params)?;
+    validate_location(params.latitude, params.longitude)?;
+    validate_geofence(params.radius, &params.polygon)?;
 
+    let user_check = user.clone();
     let update_place = UpdatePlace {
         id: place_id.to_string(),
         name: params.name.to_string(),
         family_id: params.family_id.to_string(),
-        updated_by: place_id.to_string(),
+        updated_by: user.id,
+        latitude: params.latitude,
+        longitude: params.longitude,
+        radius: params.radius,
+        polygon: params.polygon.as_ref().map(|polygon| serde_json::to_string(polygon).unwrap()),
+        address: params.address.clone(),
     };
-    let place = block(move || update(&pool, &update_place)).await?;
+    let place = block(move || {
+        let current = find_place(&pool, &update_place.id)?;
+        Membership::Member.of(&pool, &user_check, &current.family_id)?;
+        Membership::Member.of(&pool, &user_check, &update_place.family_id)?;
+        update(&pool, &update_place)
+    })
+    .await?;
     respond_json(place.into())
 }
 
+/// Delete a place, it can be restored
 pub async fn delete_place(
+    user: AuthUser,
+    place_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    block(move || {
+        let place = find_place(&pool, &place_id.to_string())?;
+        Membership::Member.of(&pool, &user, &place.family_id)?;
+        delete(&pool, *place_id, &user.id)
+    })
+    .await?;
+    respond_ok()
+}
+
+/// Restore a deleted place
+pub async fn restore_place(
+    user: AuthUser,
+    place_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<PlaceResponse>, ApiError> {
+    let place = block(move || {
+        let place = find_with_deleted(&pool, &place_id.to_string())?;
+        Membership::Member.of(&pool, &user, &place.family_id)?;
+        restore(&pool, *place_id, &user.id)
+    })
+    .await?;
+    respond_json(place)
+}
+
+/// Get every change made to a place, oldest first, deleted places included
+pub async fn get_place_history(
+    user: AuthUser,
+    place_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<ChangesResponse>, ApiError> {
+    let changes = block(move || {
+        let place = find_with_deleted(&pool, &place_id.to_string())?;
+        Membership::Member.of(&pool, &user, &place.family_id)?;
+        find_changes(&pool, Entity::Place, &place.id)
+    })
+    .await?;
+    respond_json(changes.into())
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PlaceStaffResponse {
+    pub place_id: Uuid,
+    pub user_id: Uuid,
+    pub created_by: Uuid,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct PlaceStaffsResponse(pub Vec<PlaceStaffResponse>);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddPlaceStaffRequest {
+    pub user_id: Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct PathByPlaceStaff {
+    place_id: Uuid,
+    user_id: Uuid,
+}
+
+/// Get the staff of a place, who can confirm handovers there
+pub async fn get_place_staff(
+    user: AuthUser,
     place_id: Path<Uuid>,
     pool: Data<PoolType>,
+) -> Result<Json<PlaceStaffsResponse>, ApiError> {
+    let staff = block(move || {
+        let place = find_place(&pool, &place_id.to_string())?;
+        Membership::Member.of(&pool, &user, &place.family_id)?;
+        find_staff(&pool, &place.id)
+    })
+    .await?;
+    respond_json(staff.into())
+}
+
+/// Add someone with an account to the staff of a place, only an adult of
+/// the family can
+pub async fn add_place_staff(
+    user: AuthUser,
+    place_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<AddPlaceStaffRequest>,
+) -> Result<Json<PlaceStaffResponse>, ApiError> {
+    let staff = block(move || {
+        let place = find_place(&pool, &place_id.to_string())?;
+        let adult = Membership::Adult.of(&pool, &user, &place.family_id)?;
+        let staff_user = find_user(&pool, &params.user_id.to_string())?;
+        create_staff(
+            &pool,
+            &PlaceStaff {
+                place_id: place.id,
+                user_id: staff_user.id,
+                created_by: adult.id,
+                created_at: Utc::now().naive_utc(),
+            },
+        )
+    })
+    .await?;
+    respond_json(staff.into())
+}
+
+/// Remove someone from the staff of a place, only an adult of the family can
+pub async fn remove_place_staff(
+    user: AuthUser,
+    path: Path<PathByPlaceStaff>,
+    pool: Data<PoolType>,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *place_id)).await?;
+    block(move || {
+        let place = find_place(&pool, &path.place_id.to_string())?;
+        Membership::Adult.of(&pool, &user, &place.family_id)?;
+        delete_staff(&pool, &place.id, &path.user_id.to_string())
+    })
+    .await?;
     respond_ok()
 }
 
+impl From<PlaceStaff> for PlaceStaffResponse {
+    fn from(staff: PlaceStaff) -> Self {
+        PlaceStaffResponse {
+            place_id: Uuid::parse_str(&staff.place_id).unwrap(),
+            user_id: Uuid::parse_str(&staff.user_id).unwrap(),
+            created_by: Uuid::parse_str(&staff.created_by).unwrap(),
+            created_at: staff.created_at,
+        }
+    }
+}
+
+impl From<Vec<PlaceStaff>> for PlaceStaffsResponse {
+    fn from(staff: Vec<PlaceStaff>) -> Self {
+        PlaceStaffsResponse(staff.into_iter().map(|staff| staff.into()).collect())
+    }
+}
+
 impl From<Place> for PlaceResponse {
     fn from(place: Place) -> Self {
         PlaceResponse {
             id: Uuid::parse_str(&place.id).unwrap(),
             name: place.name.to_string(),
             family_id: Uuid::parse_str(&place.family_id).unwrap(),
+            latitude: place.latitude,
+            longitude: place.longitude,
+            radius: place.radius,
+            polygon: place.polygon(),
+            address: place.address.clone(),
+            created_by: Uuid::parse_str(&place.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&place.updated_by).unwrap(),
         }
     }
 }
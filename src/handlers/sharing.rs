@@ -0,0 +1,90 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::respond_json;
+use crate::models::activity::delete_geolocs_of_user;
+use crate::models::sharing::{find_sharing, save, Sharing, SharingMode, Visibility};
+use crate::models::user::{find_user, AuthUser};
+use actix_web::web::{block, Data, Json};
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SharingResponse {
+    pub user_id: Uuid,
+    pub mode: SharingMode,
+    pub paused_until: Option<NaiveDateTime>,
+    /// What the rest of the family sees right now
+    pub visibility: Visibility,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateSharingRequest {
+    pub mode: SharingMode,
+    /// UTC, required when paused
+    pub paused_until: Option<NaiveDateTime>,
+}
+
+impl UpdateSharingRequest {
+    fn errors(&self) -> Vec<String> {
+        let now = Utc::now().naive_utc();
+        match (self.mode, self.paused_until) {
+            (SharingMode::Paused, Some(until)) if until <= now => vec!["paused_until must be in the future".into()],
+            (SharingMode::Paused, None) => vec!["paused_until is required to pause sharing".into()],
+            (SharingMode::Paused, Some(_)) | (_, None) => vec![],
+            (_, Some(_)) => vec!["paused_until only applies to the paused mode".into()],
+        }
+    }
+}
+
+/// Get the location sharing settings of the caller
+pub async fn get_sharing(user: AuthUser, pool: Data<PoolType>) -> Result<Json<SharingResponse>, ApiError> {
+    let sharing = block(move || find_sharing(&pool, &user.id)).await?;
+    respond_json(sharing.into())
+}
+
+/// Change what the rest of the family sees of the caller's location
+///
+/// Positions the family's streams would replay are dropped as soon as
+/// sharing stops being precise.
+pub async fn update_sharing(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<UpdateSharingRequest>,
+) -> Result<Json<SharingResponse>, ApiError> {
+    let errors = params.errors();
+    if !errors.is_empty() {
+        return Err(ApiError::ValidationError(errors));
+    }
+
+    let sharing = Sharing {
+        user_id: user.id.clone(),
+        mode: params.mode.as_str().into(),
+        paused_until: params.paused_until,
+        updated_at: Utc::now().naive_utc(),
+    };
+    let sharing = block(move || {
+        let sharing = save(&pool, &sharing)?;
+        if sharing.visibility() != Visibility::Precise {
+            if let Some(family_id) = find_user(&pool, &user.id)?.family_id {
+                delete_geolocs_of_user(&pool, &family_id, &user.id)?;
+            }
+        }
+        Ok::<_, ApiError>(sharing)
+    })
+    .await?;
+    respond_json(sharing.into())
+}
+
+impl From<Sharing> for SharingResponse {
+    fn from(sharing: Sharing) -> Self {
+        SharingResponse {
+            user_id: Uuid::parse_str(&sharing.user_id).unwrap(),
+            mode: sharing.mode(),
+            paused_until: sharing.paused_until,
+            visibility: sharing.visibility(),
+            updated_at: sharing.updated_at,
+        }
+    }
+}
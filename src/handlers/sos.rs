@@ -0,0 +1,340 @@
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::acknowledgement::request_acknowledgements;
+use crate::geofence::match_occurrence;
+use crate::handlers::event::EventResponse;
+use crate::handlers::geoloc::{ingest, GeolocPoint};
+use crate::helpers::respond_json;
+use crate::models::activity::ActivityKind;
+use crate::models::event::{insert, Event, EventKind, NewEvent};
+use crate::models::family::find_family;
+use crate::models::geoloc::find_between;
+use crate::models::occurrence::{expand, get_assignments};
+use crate::models::sos::{
+    create, extend, find, find_all_by_family_id, find_by_token, find_open_by_user, new_token, resolve, Sos,
+};
+use crate::models::subscription::{day_name, find_all_by_family_id as find_subscriptions};
+use crate::models::user::{find_user, get_members_by_family_id, AuthUser, Membership, User};
+use crate::notify::{send_to_users, Notification, Priority};
+use crate::stream::publish;
+use actix_web::web::{block, Data, Json, Path};
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+/// Positions at most in a live view, the most recent ones
+const LIVE_POSITIONS: i64 = 500;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SosResponse {
+    pub id: Uuid,
+    pub family_id: Uuid,
+    pub user_id: Uuid,
+    pub event_id: Option<Uuid>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub message: Option<String>,
+    /// Secret of the read-only link, `/api/v1/sos/live/{token}`
+    pub token: String,
+    /// Seconds between positions the device should use while tracked
+    pub sampling_interval: i32,
+    pub tracking: bool,
+    pub expires_at: NaiveDateTime,
+    pub resolved_by: Option<Uuid>,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SosesResponse(pub Vec<SosResponse>);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct LivePositionResponse {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub created_at: NaiveDateTime,
+}
+
+/// What the read-only link shows, positions only while tracked
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SosLiveResponse {
+    pub first_name: String,
+    pub message: Option<String>,
+    pub raised_at: NaiveDateTime,
+    pub tracking: bool,
+    pub expires_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    /// Oldest first, from the alert on
+    pub positions: Vec<LivePositionResponse>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RaiseSosRequest {
+    #[serde(flatten)]
+    pub point: GeolocPoint,
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PathByFamilyID {
+    family_id: Uuid,
+}
+
+/// Get the emergency alerts of a family, most recent first
+pub async fn get_sos_by_family_id(
+    user: AuthUser,
+    path: Path<PathByFamilyID>,
+    pool: Data<PoolType>,
+) -> Result<Json<SosesResponse>, ApiError> {
+    let alerts = block(move || {
+        let family_id = path.family_id.to_string();
+        Membership::Member.of(&pool, &user, &family_id)?;
+        find_all_by_family_id(&pool, &family_id)
+    })
+    .await?;
+    respond_json(SosesResponse(alerts.into_iter().map(|sos| sos.into()).collect()))
+}
+
+/// Get an emergency alert
+pub async fn get_sos(user: AuthUser, sos_id: Path<Uuid>, pool: Data<PoolType>) -> Result<Json<SosResponse>, ApiError> {
+    let sos = block(move || {
+        let sos = find(&pool, *sos_id)?;
+        Membership::Member.of(&pool, &user, &sos.family_id)?;
+        Ok::<_, ApiError>(sos)
+    })
+    .await?;
+    respond_json(sos.into())
+}
+
+/// Raise an emergency alert from where the caller is
+///
+/// Every other member of the family is notified at high priority and the
+/// caller is live tracked, whatever their sharing settings. Raising again
+/// while an alert is open only records the new position.
+pub async fn raise_sos(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<RaiseSosRequest>,
+) -> Result<Json<SosResponse>, ApiError> {
+    let mut errors = params.point.errors();
+    if params.message.as_ref().map_or(false, |message| message.chars().count() > 150) {
+        errors.push("message must be at most 150 characters".into());
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::ValidationError(errors));
+    }
+
+    let RaiseSosRequest { point, message } = params.into_inner();
+    let pool_raise = pool.clone();
+    let user_id = user.id.clone();
+    let location = point.clone();
+    let (raised, sos, event, new) = block(move || {
+        let user = find_user(&pool_raise, &user_id)?;
+        let family_id = user
+            .family_id
+            .clone()
+            .ok_or_else(|| ApiError::BadRequest("Join a family before raising an alert".into()))?;
+        if let Some(sos) = find_open_by_user(&pool_raise, &user.id)? {
+            return Ok((user, sos, None, false));
+        }
+
+        let event = alert_event(&pool_raise, &user, &family_id, message.as_deref())?;
+        let now = Utc::now().naive_utc();
+        let sos = create(
+            &pool_raise,
+            &Sos {
+                id: Uuid::new_v4().to_string(),
+                family_id,
+                user_id: user.id.clone(),
+                event_id: event.as_ref().map(|event| event.id.clone()),
+                latitude: location.latitude,
+                longitude: location.longitude,
+                accuracy: location.accuracy,
+                message,
+                token: new_token(),
+                sampling_interval: CONFIG.sos_sampling_interval,
+                expires_at: now + Duration::minutes(CONFIG.sos_duration),
+                resolved_by: None,
+                resolved_at: None,
+                created_by: user.id.clone(),
+                created_at: now,
+                updated_by: user.id.clone(),
+                updated_at: now,
+            },
+        )?;
+        Ok::<_, ApiError>((user, sos, event, true))
+    })
+    .await?;
+
+    ingest(&pool, &cache, &user.id, vec![point.into_geoloc(&user.id)]).await?;
+
+    if let Some(event) = event {
+        let event: EventResponse = event.into();
+        publish(&pool, &sos.family_id, ActivityKind::EventCreated, &event).await;
+        request_acknowledgements(&pool, &event).await;
+    }
+    let response: SosResponse = sos.clone().into();
+    if new {
+        publish(&pool, &sos.family_id, ActivityKind::SosRaised, &response).await;
+        let body = match &sos.message {
+            Some(message) => format!("{} needs help: {}", raised.first_name, message),
+            None => format!("{} needs help", raised.first_name),
+        };
+        notify_members(&pool, &sos, Notification::new("Emergency", &body)).await;
+    }
+    respond_json(response)
+}
+
+/// Close an emergency alert, only an adult of the family can
+pub async fn resolve_sos(user: AuthUser, sos_id: Path<Uuid>, pool: Data<PoolType>) -> Result<Json<SosResponse>, ApiError> {
+    let pool_resolve = pool.clone();
+    let (resolver, sos) = block(move || {
+        let sos = find(&pool_resolve, *sos_id)?;
+        let resolver = Membership::Adult.of(&pool_resolve, &user, &sos.family_id)?;
+        let sos = resolve(&pool_resolve, *sos_id, &resolver.id)?;
+        Ok::<_, ApiError>((resolver, sos))
+    })
+    .await?;
+
+    let response: SosResponse = sos.clone().into();
+    publish(&pool, &sos.family_id, ActivityKind::SosResolved, &response).await;
+    let body = format!("{} resolved the emergency", resolver.first_name);
+    notify_members(&pool, &sos, Notification::new("Emergency resolved", &body)).await;
+    respond_json(response)
+}
+
+/// Keep tracking an open alert for another session length, only an adult
+/// of the family can
+pub async fn extend_sos(user: AuthUser, sos_id: Path<Uuid>, pool: Data<PoolType>) -> Result<Json<SosResponse>, ApiError> {
+    let pool_extend = pool.clone();
+    let sos = block(move || {
+        let sos = find(&pool_extend, *sos_id)?;
+        let adult = Membership::Adult.of(&pool_extend, &user, &sos.family_id)?;
+        let until = sos.expires_at.max(Utc::now().naive_utc()) + Duration::minutes(CONFIG.sos_duration);
+        extend(&pool_extend, *sos_id, until, &adult.id)
+    })
+    .await?;
+
+    let response: SosResponse = sos.clone().into();
+    publish(&pool, &sos.family_id, ActivityKind::SosUpdated, &response).await;
+    respond_json(response)
+}
+
+/// Follow an emergency alert from its read-only link, no account needed
+///
+/// Positions stop showing once the session expires or the alert is resolved.
+pub async fn get_sos_live(token: Path<String>, pool: Data<PoolType>) -> Result<Json<SosLiveResponse>, ApiError> {
+    let (sos, user, positions) = block(move || {
+        let sos = find_by_token(&pool, &token)?;
+        let user = find_user(&pool, &sos.user_id)?;
+        let positions = if sos.is_tracking() {
+            let now = Utc::now().naive_utc();
+            let mut positions = find_between(&pool, &sos.user_id, sos.created_at, now, Some((0, LIVE_POSITIONS)))?;
+            positions.reverse();
+            positions
+        } else {
+            vec![]
+        };
+        Ok::<_, ApiError>((sos, user, positions))
+    })
+    .await?;
+
+    respond_json(SosLiveResponse {
+        first_name: user.first_name,
+        message: sos.message.clone(),
+        raised_at: sos.created_at,
+        tracking: sos.is_tracking(),
+        expires_at: sos.expires_at,
+        resolved_at: sos.resolved_at,
+        positions: positions
+            .into_iter()
+            .map(|geoloc| LivePositionResponse {
+                latitude: geoloc.latitude,
+                longitude: geoloc.longitude,
+                accuracy: geoloc.accuracy,
+                created_at: geoloc.created_at,
+            })
+            .collect(),
+    })
+}
+
+/// Record an alert on the occurrence the user is on right now, if any
+fn alert_event(pool: &PoolType, user: &User, family_id: &str, message: Option<&str>) -> Result<Option<Event>, ApiError> {
+    let family = find_family(pool, family_id)?;
+    let today = family.today();
+    let subscriptions = find_subscriptions(pool, family_id)?;
+    let assignments = get_assignments(pool, family_id, today, today)?;
+    let occurrences = expand(&subscriptions, &assignments, today, today);
+    let now = Utc::now().naive_utc();
+    let occurrence = match match_occurrence(&occurrences, &user.id, family.to_local(now)) {
+        Some(occurrence) => occurrence,
+        None => return Ok(None),
+    };
+
+    let event: Event = NewEvent {
+        id: Uuid::new_v4().to_string(),
+        family_id: family_id.to_string(),
+        subscription_id: occurrence.subscription.id.clone(),
+        place_id: occurrence.subscription.place_id.clone(),
+        user_id: user.id.clone(),
+        day: day_name(today).into(),
+        message: message.map_or_else(|| "SOS".into(), |message| format!("SOS: {}", message)),
+        created_by: user.id.clone(),
+        updated_by: user.id.clone(),
+        kind: EventKind::Alert,
+        actor_id: Some(user.id.clone()),
+        occurred_at: now,
+    }
+    .into();
+    let conn = pool.get()?;
+    insert(&conn, &event)?;
+    Ok(Some(event))
+}
+
+/// Notify every member of the family but whoever raised the alert
+async fn notify_members(pool: &Data<PoolType>, sos: &Sos, notification: Notification) {
+    let pool = pool.clone();
+    let family_id = sos.family_id.clone();
+    match block(move || get_members_by_family_id(&pool, &family_id)).await {
+        Ok(members) => {
+            let others: Vec<_> = members.into_iter().filter(|member| member.id != sos.user_id).collect();
+            let notification = notification
+                .data(json!({ "sos_id": sos.id, "user_id": sos.user_id }))
+                .priority(Priority::High);
+            send_to_users(&others, notification).await;
+        }
+        Err(error) => log::warn!("Could not notify members: {:?}", error),
+    }
+}
+
+impl From<Sos> for SosResponse {
+    fn from(sos: Sos) -> Self {
+        SosResponse {
+            id: Uuid::parse_str(&sos.id).unwrap(),
+            family_id: Uuid::parse_str(&sos.family_id).unwrap(),
+            user_id: Uuid::parse_str(&sos.user_id).unwrap(),
+            event_id: sos.event_id.as_ref().map(|event_id| Uuid::parse_str(event_id).unwrap()),
+            latitude: sos.latitude,
+            longitude: sos.longitude,
+            accuracy: sos.accuracy,
+            message: sos.message.clone(),
+            tracking: sos.is_tracking(),
+            token: sos.token,
+            sampling_interval: sos.sampling_interval,
+            expires_at: sos.expires_at,
+            resolved_by: sos.resolved_by.map(|resolved_by| Uuid::parse_str(&resolved_by).unwrap()),
+            resolved_at: sos.resolved_at,
+            created_at: sos.created_at,
+            created_by: Uuid::parse_str(&sos.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&sos.updated_by).unwrap(),
+        }
+    }
+}
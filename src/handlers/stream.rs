@@ -0,0 +1,295 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::activity::{replay, ActivityMessage};
+use crate::models::user::{find_user, AuthUser};
+use crate::stream::{Broker, Connect, Disconnect, Dispatch, Seen};
+use actix::prelude::*;
+use actix_web::web::{block, Bytes, Data, Payload, Query};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use futures::channel::mpsc::{unbounded, UnboundedSender};
+use futures::StreamExt;
+use std::time::{Duration, Instant};
+
+/// How often the server pings WebSocket clients or sends SSE heartbeats
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long EventSource clients wait before reconnecting
+const SSE_RETRY: Duration = Duration::from_secs(3);
+
+/// How long without a pong before the client is considered gone
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    /// Id of the last activity received, to resume after a reconnect
+    pub cursor: Option<i64>,
+}
+
+/// A WebSocket client following the activities of its family
+pub struct FamilySocket {
+    family_id: String,
+    pool: Data<PoolType>,
+    /// Id given by the broker once connected
+    id: Option<usize>,
+    /// Where to resume from, the last activity the client received
+    cursor: Option<i64>,
+    seen: Seen,
+    heartbeat: Instant,
+}
+
+impl FamilySocket {
+    fn send(&mut self, ctx: &mut ws::WebsocketContext<Self>, activity: &ActivityMessage) {
+        if !self.seen.insert(activity.id) {
+            return;
+        }
+        ctx.text(serde_json::to_string(activity).unwrap_or_default());
+    }
+
+    fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |socket, ctx| {
+            if Instant::now().duration_since(socket.heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl Actor for FamilySocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    /// Register with the broker, then replay what was missed since the cursor
+    ///
+    /// The actor waits for both, so live activities received meanwhile queue
+    /// up and are sent after the replay, duplicates skipped.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat(ctx);
+
+        let setup = connect(
+            self.pool.clone(),
+            self.family_id.clone(),
+            ctx.address().recipient(),
+            self.cursor,
+        );
+        ctx.wait(setup.into_actor(self).map(|result, socket, ctx| match result {
+            Ok((id, missed)) => {
+                socket.id = Some(id);
+                for activity in missed {
+                    socket.send(ctx, &activity);
+                }
+            }
+            Err(error) => {
+                log::warn!("Could not start family stream: {:?}", error);
+                ctx.stop();
+            }
+        }));
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if let Some(id) = self.id {
+            Broker::from_registry().do_send(Disconnect {
+                family_id: self.family_id.clone(),
+                id,
+            });
+        }
+        Running::Stop
+    }
+}
+
+impl Handler<Dispatch> for FamilySocket {
+    type Result = ();
+
+    fn handle(&mut self, Dispatch(activity): Dispatch, ctx: &mut Self::Context) {
+        self.send(ctx, &activity);
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for FamilySocket {
+    fn handle(&mut self, message: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match message {
+            Ok(ws::Message::Ping(message)) => {
+                self.heartbeat = Instant::now();
+                ctx.pong(&message);
+            }
+            Ok(ws::Message::Pong(_)) => self.heartbeat = Instant::now(),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            // The stream only goes one way
+            Ok(_) => (),
+            Err(_) => ctx.stop(),
+        }
+    }
+}
+
+/// A Server-Sent Events client following the activities of its family
+///
+/// Frames are pushed to the response body through a channel, a closed
+/// channel means the client went away.
+pub struct FamilyEvents {
+    family_id: String,
+    pool: Data<PoolType>,
+    id: Option<usize>,
+    /// Where to resume from, the last event id the client received
+    cursor: Option<i64>,
+    seen: Seen,
+    sender: UnboundedSender<Bytes>,
+}
+
+impl FamilyEvents {
+    fn push(&mut self, ctx: &mut Context<Self>, frame: String) {
+        if self.sender.unbounded_send(Bytes::from(frame)).is_err() {
+            ctx.stop();
+        }
+    }
+
+    fn send(&mut self, ctx: &mut Context<Self>, activity: &ActivityMessage) {
+        if !self.seen.insert(activity.id) {
+            return;
+        }
+        let frame = format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            activity.id,
+            activity.kind,
+            serde_json::to_string(activity).unwrap_or_default()
+        );
+        self.push(ctx, frame);
+    }
+}
+
+impl Actor for FamilyEvents {
+    type Context = Context<Self>;
+
+    /// Same as the WebSocket: register, replay, then relay live activities
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.push(ctx, format!("retry: {}\n\n", SSE_RETRY.as_millis()));
+        // Comments keep proxies from closing an idle connection
+        ctx.run_interval(HEARTBEAT_INTERVAL, |events, ctx| events.push(ctx, ": heartbeat\n\n".into()));
+
+        let setup = connect(
+            self.pool.clone(),
+            self.family_id.clone(),
+            ctx.address().recipient(),
+            self.cursor,
+        );
+        ctx.wait(setup.into_actor(self).map(|result, events, ctx| match result {
+            Ok((id, missed)) => {
+                events.id = Some(id);
+                for activity in missed {
+                    events.send(ctx, &activity);
+                }
+            }
+            Err(error) => {
+                log::warn!("Could not start family events: {:?}", error);
+                ctx.stop();
+            }
+        }));
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if let Some(id) = self.id {
+            Broker::from_registry().do_send(Disconnect {
+                family_id: self.family_id.clone(),
+                id,
+            });
+        }
+        Running::Stop
+    }
+}
+
+impl Handler<Dispatch> for FamilyEvents {
+    type Result = ();
+
+    fn handle(&mut self, Dispatch(activity): Dispatch, ctx: &mut Self::Context) {
+        self.send(ctx, &activity);
+    }
+}
+
+/// Register a client with the broker, then load what it missed since its
+/// cursor, or the reset telling it to start over
+async fn connect(
+    pool: Data<PoolType>,
+    family_id: String,
+    recipient: Recipient<Dispatch>,
+    cursor: Option<i64>,
+) -> Result<(usize, Vec<ActivityMessage>), ApiError> {
+    let id = Broker::from_registry()
+        .send(Connect {
+            family_id: family_id.clone(),
+            recipient,
+        })
+        .await
+        .map_err(|error| ApiError::InternalServerError(error.to_string()))?;
+    let missed = match cursor {
+        Some(cursor) => block(move || replay(&pool, &family_id, cursor)).await?,
+        None => vec![],
+    };
+    Ok((id, missed))
+}
+
+/// The family of a user
+pub async fn family_of_user(pool: &Data<PoolType>, user_id: &str) -> Result<String, ApiError> {
+    let pool = pool.clone();
+    let user_id = user_id.to_string();
+    let user = block(move || find_user(&pool, &user_id)).await?;
+    let not_in_family = format!("user {} doesn't belong to a family", user.id);
+    user.family_id.ok_or(ApiError::BadRequest(not_in_family))
+}
+
+/// Stream the activities of the caller's family over a WebSocket
+pub async fn family_socket(
+    user: AuthUser,
+    query: Query<StreamQuery>,
+    request: HttpRequest,
+    payload: Payload,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, Error> {
+    let family_id = family_of_user(&pool, &user.id).await?;
+    let socket = FamilySocket {
+        family_id,
+        pool,
+        id: None,
+        cursor: query.cursor,
+        seen: Seen::default(),
+        heartbeat: Instant::now(),
+    };
+    ws::start(socket, &request, payload)
+}
+
+/// Stream the activities of the caller's family as Server-Sent Events
+///
+/// Resumes after the `Last-Event-ID` header, or the `cursor` parameter for
+/// clients that can't set headers.
+pub async fn family_events(
+    user: AuthUser,
+    query: Query<StreamQuery>,
+    request: HttpRequest,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let family_id = family_of_user(&pool, &user.id).await?;
+    let last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+
+    let (sender, receiver) = unbounded();
+    FamilyEvents {
+        family_id,
+        pool,
+        id: None,
+        cursor: last_event_id.or(query.cursor),
+        seen: Seen::default(),
+        sender,
+    }
+    .start();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("X-Accel-Buffering", "no")
+        .streaming(receiver.map(Ok::<_, Error>)))
+}
@@ -1,10 +1,18 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::handlers::change::ChangesResponse;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::subscription::{ get_all_by_family_id_and_user_id_and_days_without_user, get_all_by_family_id_and_user_id_and_days_events, get_all_by_family_id_and_user_id_and_days, get_all_by_family_id_and_place_id, create, delete, find, get_all_by_family_id, get_all, update, NewSubscription, UpdateSubscription, Subscription};
+use crate::models::subscription::{ get_all_by_family_id_and_user_id_and_days_without_user, get_all_by_family_id_and_user_id_and_days_events, get_all_by_family_id_and_user_id_and_days, get_all_by_family_id_and_place_id, create, delete, find, find_subscription, find_with_deleted, get_all_by_family_id, get_all, restore, update, NewSubscription, UpdateSubscription, Subscription};
+use crate::models::change::{find_all as find_changes, Entity};
+use crate::models::user::{AuthUser, Membership};
 use crate::validate::validate;
-use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use crate::conflict::{find_conflicts, Conflict, ConflictKind};
+use crate::models::activity::ActivityKind;
+use crate::stream::publish;
+use actix_web::web::{block, Data, HttpResponse, Json, Path, Query};
+use chrono::{Duration, NaiveDate, NaiveTime, Utc};
 use serde::Serialize;
+use serde_json::json;
 use uuid::Uuid;
 use validator::Validate;
 use rayon::prelude::*;
@@ -19,8 +27,35 @@ pub struct SubscriptionResponse {
     pub user_id: Uuid,
     pub place_id: Uuid,
     pub days: String,
+    pub child_id: Option<Uuid>,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
+/// A created or updated subscription along with the conflicts it causes
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SubscriptionConflictsResponse {
+    #[serde(flatten)]
+    pub subscription: SubscriptionResponse,
+    pub conflicts: Vec<ConflictResponse>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ConflictResponse {
+    pub kind: ConflictKind,
+    pub day: NaiveDate,
+    pub user_id: Uuid,
+    pub first_subscription_id: Uuid,
+    pub second_subscription_id: Uuid,
+    pub travel_minutes: Option<i64>,
+    pub gap_minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ConflictsResponse(pub Vec<ConflictResponse>);
+
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub struct SubscriptionsResponse(pub Vec<SubscriptionResponse>);
 
@@ -45,6 +80,15 @@ pub struct CreateSubscriptionRequest {
     pub place_id: String,
 
     pub days: String,
+
+    #[validate(length(
+        min = 3,
+        message = "child_id must be at least 3 characters"
+    ))]
+    pub child_id: Option<String>,
+
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
@@ -68,6 +112,15 @@ pub struct UpdateSubscriptionRequest {
     pub place_id: String,
 
     pub days: String,
+
+    #[validate(length(
+        min = 3,
+        message = "child_id must be at least 3 characters"
+    ))]
+    pub child_id: Option<String>,
+
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
 }
 
 pub async fn get_subscription(
@@ -161,14 +214,43 @@ pub async fn search_by_family_user_days_events(path: Path<PathByFamilyIDUserIDDa
 }
 
 
+/// Both ends of a window must be set together and in order
+fn validate_window(start_time: Option<NaiveTime>, end_time: Option<NaiveTime>) -> Result<(), ApiError> {
+    match (start_time, end_time) {
+        (None, None) => Ok(()),
+        (Some(start), Some(end)) if start < end => Ok(()),
+        _ => Err(ApiError::ValidationError(vec![
+            "start_time and end_time must be set together and start_time must be before end_time".into(),
+        ])),
+    }
+}
+
+/// Days ahead of today checked for conflicts when saving a subscription
+const CONFLICT_HORIZON: i64 = 14;
+
+/// Conflicts involving a subscription over the coming days
+fn subscription_conflicts(pool: &PoolType, subscription: &SubscriptionResponse) -> Result<Vec<ConflictResponse>, ApiError> {
+    let from = Utc::now().naive_utc().date();
+    let to = from + Duration::days(CONFLICT_HORIZON);
+    let subscription_id = subscription.id.to_string();
+    let conflicts = find_conflicts(pool, &subscription.family_id.to_string(), from, to)?
+        .into_iter()
+        .filter(|conflict| conflict.involves(&subscription_id))
+        .map(|conflict| conflict.into())
+        .collect();
+    Ok(conflicts)
+}
+
 pub async fn create_subscription(
+    user: AuthUser,
     pool: Data<PoolType>,
     params: Json<CreateSubscriptionRequest>,
-) -> Result<Json<SubscriptionResponse>, ApiError> {
+) -> Result<Json<SubscriptionConflictsResponse>, ApiError> {
     //println!("create_subscription");
     //println!("{:?}", params);
 
     validate(&params)?;
+    validate_window(params.start_time, params.end_time)?;
 
     let subscription_id = Uuid::new_v4();
     let new_subscription: Subscription = NewSubscription {
@@ -177,42 +259,145 @@ pub async fn create_subscription(
         user_id: params.user_id.to_string(),
         place_id: params.place_id.to_string(),
         days: params.days.to_string(),
-        created_by: subscription_id.to_string(),
-        updated_by: subscription_id.to_string(),
+        created_by: user.id.clone(),
+        updated_by: user.id,
+        child_id: params.child_id.clone(),
+        start_time: params.start_time,
+        end_time: params.end_time,
     }
     .into();
-    let subscription = block(move || create(&pool, &new_subscription)).await?;
-    respond_json(subscription.into())
+    let pool_publish = pool.clone();
+    let response = block(move || {
+        let subscription = create(&pool, &new_subscription)?;
+        let conflicts = subscription_conflicts(&pool, &subscription)?;
+        Ok(SubscriptionConflictsResponse { subscription, conflicts })
+    })
+    .await?;
+    publish_assignment(&pool_publish, &response.subscription).await;
+    respond_json(response)
 }
 
 pub async fn update_subscription(
+    user: AuthUser,
     sub_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdateSubscriptionRequest>,
-) -> Result<Json<SubscriptionResponse>, ApiError> {
+) -> Result<Json<SubscriptionConflictsResponse>, ApiError> {
     validate(&params)?;
+    validate_window(params.start_time, params.end_time)?;
 
+    let user_check = user.clone();
     let update_subscription= UpdateSubscription{
         id: sub_id.to_string(),
         family_id: params.family_id.to_string(),
         user_id: params.user_id.to_string(),
         place_id: params.place_id.to_string(),
         days: params.days.to_string(),
-        updated_by: sub_id.to_string(),
+        updated_by: user.id,
+        child_id: params.child_id.clone(),
+        start_time: params.start_time,
+        end_time: params.end_time,
     };
-    let subscription = block(move || update(&pool, &update_subscription)).await?;
-    respond_json(subscription.into())
+    let pool_publish = pool.clone();
+    let response = block(move || {
+        let current = find_subscription(&pool, &update_subscription.id)?;
+        Membership::Member.of(&pool, &user_check, &current.family_id)?;
+        Membership::Member.of(&pool, &user_check, &update_subscription.family_id)?;
+        let subscription = update(&pool, &update_subscription)?;
+        let conflicts = subscription_conflicts(&pool, &subscription)?;
+        Ok(SubscriptionConflictsResponse { subscription, conflicts })
+    })
+    .await?;
+    publish_assignment(&pool_publish, &response.subscription).await;
+    respond_json(response)
 }
 
-/// Delete a user
+/// Let the family's streams know who is now responsible for a subscription
+async fn publish_assignment(pool: &Data<PoolType>, subscription: &SubscriptionResponse) {
+    let payload = json!({
+        "subscription_id": subscription.id,
+        "day": null,
+        "user_id": subscription.user_id,
+    });
+    publish(pool, &subscription.family_id.to_string(), ActivityKind::AssignmentChanged, &payload).await;
+}
+
+#[derive(Deserialize)]
+pub struct ConflictsQuery {
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+}
+
+/// Longest range of days a conflict report can cover
+const MAX_REPORT_DAYS: i64 = 92;
+
+/// Report the conflicts in the schedule of a family
+///
+/// Covers the next two weeks unless `from` and `to` are given.
+pub async fn get_conflicts_by_family_id(
+    path: Path<PathByFamilyID>,
+    query: Query<ConflictsQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<ConflictsResponse>, ApiError> {
+    let from = query.from.unwrap_or_else(|| Utc::now().naive_utc().date());
+    let to = query.to.unwrap_or_else(|| from + Duration::days(CONFLICT_HORIZON));
+    if to < from || (to - from).num_days() > MAX_REPORT_DAYS {
+        let message = format!("to must be after from and at most {} days later", MAX_REPORT_DAYS);
+        return Err(ApiError::ValidationError(vec![message]));
+    }
+
+    let conflicts = block(move || find_conflicts(&pool, &path.family_id.to_string(), from, to)).await?;
+    respond_json(ConflictsResponse(conflicts.into_iter().map(|conflict| conflict.into()).collect()))
+}
+
+/// Delete a subscription, it can be restored
 pub async fn delete_subscription(
+    user: AuthUser,
     subscription_id: Path<Uuid>,
     pool: Data<PoolType>,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *subscription_id)).await?;
+    block(move || {
+        let subscription = find_subscription(&pool, &subscription_id.to_string())?;
+        Membership::Member.of(&pool, &user, &subscription.family_id)?;
+        delete(&pool, *subscription_id, &user.id)
+    })
+    .await?;
     respond_ok()
 }
 
+/// Restore a deleted subscription
+pub async fn restore_subscription(
+    user: AuthUser,
+    subscription_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<SubscriptionResponse>, ApiError> {
+    let pool_publish = pool.clone();
+    let subscription = block(move || {
+        let subscription = find_with_deleted(&pool, &subscription_id.to_string())?;
+        Membership::Member.of(&pool, &user, &subscription.family_id)?;
+        restore(&pool, *subscription_id, &user.id)
+    })
+    .await?;
+    publish_assignment(&pool_publish, &subscription).await;
+    respond_json(subscription)
+}
+
+/// Get every change made to a subscription, oldest first, deleted
+/// subscriptions included
+pub async fn get_subscription_history(
+    user: AuthUser,
+    subscription_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<ChangesResponse>, ApiError> {
+    let changes = block(move || {
+        let subscription = find_with_deleted(&pool, &subscription_id.to_string())?;
+        Membership::Member.of(&pool, &user, &subscription.family_id)?;
+        find_changes(&pool, Entity::Subscription, &subscription.id)
+    })
+    .await?;
+    respond_json(changes.into())
+}
+
 impl From<Subscription> for SubscriptionResponse {
     fn from(subscription: Subscription) -> Self {
         SubscriptionResponse {
@@ -221,6 +406,25 @@ impl From<Subscription> for SubscriptionResponse {
             user_id: Uuid::parse_str(&subscription.user_id).unwrap(),
             place_id: Uuid::parse_str(&subscription.place_id).unwrap(),
             days: subscription.days.to_string(),
+            child_id: subscription.child_id.map(|child_id| Uuid::parse_str(&child_id).unwrap()),
+            start_time: subscription.start_time,
+            end_time: subscription.end_time,
+            created_by: Uuid::parse_str(&subscription.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&subscription.updated_by).unwrap(),
+        }
+    }
+}
+
+impl From<Conflict> for ConflictResponse {
+    fn from(conflict: Conflict) -> Self {
+        ConflictResponse {
+            kind: conflict.kind,
+            day: conflict.day,
+            user_id: Uuid::parse_str(&conflict.user_id).unwrap(),
+            first_subscription_id: Uuid::parse_str(&conflict.subscription_ids.0).unwrap(),
+            second_subscription_id: Uuid::parse_str(&conflict.subscription_ids.1).unwrap(),
+            travel_minutes: conflict.travel_minutes,
+            gap_minutes: conflict.gap_minutes,
         }
     }
 }
@@ -244,22 +448,8 @@ pub struct SubscriptionsEventResponse(pub Vec<SubscriptionEventResponse>);
 impl From<(Subscription, Event)> for SubscriptionEventResponse {
     fn from((subscription, event): (Subscription, Event)) -> Self {
         SubscriptionEventResponse{
-            s: SubscriptionResponse {
-                id: Uuid::parse_str(&subscription.id).unwrap(),
-                family_id: Uuid::parse_str(&subscription.family_id).unwrap(),
-                user_id: Uuid::parse_str(&subscription.user_id).unwrap(),
-                place_id: Uuid::parse_str(&subscription.place_id).unwrap(),
-                days: subscription.days.to_string(),
-            },
-            e: EventResponse {
-                id: Uuid::parse_str(&event.id).unwrap(),
-                family_id: Uuid::parse_str(&event.family_id).unwrap(),
-                subscription_id: Uuid::parse_str(&event.subscription_id).unwrap(),
-                place_id: Uuid::parse_str(&event.place_id).unwrap(),
-                user_id: Uuid::parse_str(&event.user_id).unwrap(),
-                day: event.day.to_string(),
-                message: event.message.to_string()
-            }
+            s: subscription.into(),
+            e: event.into(),
         }
     }
 }
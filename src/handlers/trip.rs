@@ -0,0 +1,184 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::geoloc::resolve_range;
+use crate::helpers::respond_json;
+use crate::models::family::find_family;
+use crate::models::sharing::{visibility_for, Visibility};
+use crate::models::user::{find_user, AuthUser};
+use crate::trips::{find_trips, Movement, Segment, Settings, Stay};
+use actix_web::web::{block, Data, Json, Path, Query};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct TripPointResponse {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentResponse {
+    Stay {
+        latitude: f64,
+        longitude: f64,
+        started_at: NaiveDateTime,
+        ended_at: NaiveDateTime,
+        /// Seconds
+        duration: i64,
+        place_id: Option<Uuid>,
+    },
+    Movement {
+        started_at: NaiveDateTime,
+        ended_at: NaiveDateTime,
+        /// Seconds
+        duration: i64,
+        /// Meters
+        distance: f64,
+        start_place_id: Option<Uuid>,
+        end_place_id: Option<Uuid>,
+        points: Vec<TripPointResponse>,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct TripsResponse {
+    pub user_id: Uuid,
+    pub from: NaiveDateTime,
+    pub to: NaiveDateTime,
+    /// Meters moved over the whole range
+    pub distance: f64,
+    pub segments: Vec<SegmentResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TripQuery {
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub date: Option<NaiveDate>,
+    /// Meters of deviation dropped from movements
+    pub tolerance: Option<f64>,
+    /// Meters someone may wander while staying somewhere
+    pub stay_radius: Option<f64>,
+    /// Minutes within the stay radius before it counts as a stay
+    pub min_dwell: Option<i64>,
+}
+
+impl TripQuery {
+    fn settings(&self) -> Result<Settings, ApiError> {
+        let mut errors = vec![];
+        if self.tolerance.map_or(false, |tolerance| tolerance < 0.0) {
+            errors.push("tolerance must be positive".to_string());
+        }
+        if self.stay_radius.map_or(false, |stay_radius| stay_radius <= 0.0) {
+            errors.push("stay_radius must be greater than 0".to_string());
+        }
+        if self.min_dwell.map_or(false, |min_dwell| min_dwell < 1) {
+            errors.push("min_dwell must be at least 1 minute".to_string());
+        }
+        if !errors.is_empty() {
+            return Err(ApiError::ValidationError(errors));
+        }
+
+        let defaults = Settings::default();
+        Ok(Settings {
+            stay_radius: self.stay_radius.unwrap_or(defaults.stay_radius),
+            min_dwell: self.min_dwell.map_or(defaults.min_dwell, Duration::minutes),
+            tolerance: self.tolerance.unwrap_or(defaults.tolerance),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PathByUser {
+    user_id: Uuid,
+}
+
+/// Get the stays and movements of a user over a range, the current day by default
+pub async fn get_trips(
+    viewer: AuthUser,
+    path: Path<PathByUser>,
+    query: Query<TripQuery>,
+    pool: Data<PoolType>,
+) -> Result<Json<TripsResponse>, ApiError> {
+    let settings = query.settings()?;
+    let user_id = path.user_id;
+    let (from, to, segments) = block(move || {
+        let user = find_user(&pool, &user_id.to_string())?;
+        let family = match &user.family_id {
+            Some(family_id) => Some(find_family(&pool, family_id)?),
+            None => None,
+        };
+        let (from, to) = resolve_range(query.from, query.to, query.date, family.as_ref())?;
+        let segments = match visibility_for(&pool, &user.id, &viewer.id)? {
+            Visibility::Precise => find_trips(&pool, &user, from, to, &settings)?,
+            _ => vec![],
+        };
+        Ok::<_, ApiError>((from, to, segments))
+    })
+    .await?;
+
+    let distance = segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Movement(movement) => movement.distance,
+            Segment::Stay(_) => 0.0,
+        })
+        .sum();
+    respond_json(TripsResponse {
+        user_id,
+        from,
+        to,
+        distance,
+        segments: segments.into_iter().map(|segment| segment.into()).collect(),
+    })
+}
+
+fn place_uuid(place_id: Option<String>) -> Option<Uuid> {
+    place_id.map(|place_id| Uuid::parse_str(&place_id).unwrap())
+}
+
+impl From<Segment> for SegmentResponse {
+    fn from(segment: Segment) -> Self {
+        match segment {
+            Segment::Stay(Stay {
+                center,
+                started_at,
+                ended_at,
+                place_id,
+            }) => SegmentResponse::Stay {
+                latitude: center.latitude,
+                longitude: center.longitude,
+                started_at,
+                ended_at,
+                duration: (ended_at - started_at).num_seconds(),
+                place_id: place_uuid(place_id),
+            },
+            Segment::Movement(Movement {
+                points,
+                distance,
+                started_at,
+                ended_at,
+                start_place_id,
+                end_place_id,
+            }) => SegmentResponse::Movement {
+                started_at,
+                ended_at,
+                duration: (ended_at - started_at).num_seconds(),
+                distance,
+                start_place_id: place_uuid(start_place_id),
+                end_place_id: place_uuid(end_place_id),
+                points: points
+                    .into_iter()
+                    .map(|(point, created_at)| TripPointResponse {
+                        latitude: point.latitude,
+                        longitude: point.longitude,
+                        created_at,
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
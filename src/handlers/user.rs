@@ -1,11 +1,14 @@
+use crate::audit::record;
 use crate::database::PoolType;
 use crate::errors::ApiError;
 use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::{create, delete, find, get_all_by_family_id, get_all, update, NewUser, UpdateUser, User};
+use crate::models::audit::{AuditAction, ClientInfo, NewAuditEntry};
+use crate::models::user::{create, delete, find, find_user, get_all_by_family_id, get_all, update, AuthUser, NewUser, UpdateUser, User};
 use crate::validate::validate;
 use actix_web::web::{block, Data, HttpResponse, Json, Path};
 use rayon::prelude::*;
 use serde::Serialize;
+use serde_json::json;
 use uuid::Uuid;
 use validator::Validate;
 
@@ -18,6 +21,8 @@ pub struct UserResponse {
     pub family_id: Option<String>,
     pub role: Option<String>,
     pub token: String,
+    pub created_by: Uuid,
+    pub updated_by: Uuid,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
@@ -106,8 +111,9 @@ pub async fn get_users_by_family_id(path: Path<PathByFamilyID>, pool: Data<PoolT
     respond_json(users)
 }
 
-/// Create a user
+/// Create a user, by a logged-in member or by signing up
 pub async fn create_user(
+    user: Option<AuthUser>,
     pool: Data<PoolType>,
     params: Json<CreateUserRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
@@ -115,17 +121,17 @@ pub async fn create_user(
     validate(&params)?;
     println!("create_user2");
 
-    // temporarily use the new user's id for created_at/updated_at
-    // update when auth is added
+    // Users signing up create themselves
     let user_id = Uuid::new_v4();
+    let actor = user.map_or_else(|| user_id.to_string(), |user| user.id);
     let new_user: User = NewUser {
         id: user_id.to_string(),
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
         password: params.password.to_string(),
-        created_by: user_id.to_string(),
-        updated_by: user_id.to_string(),
+        created_by: actor.clone(),
+        updated_by: actor,
         token: params.token.to_string(),
     }
     .into();
@@ -134,38 +140,99 @@ pub async fn create_user(
 }
 
 /// Update a user
+///
+/// Changes of family and role are audited.
 pub async fn update_user(
+    user: AuthUser,
+    client: ClientInfo,
     user_id: Path<Uuid>,
     pool: Data<PoolType>,
     params: Json<UpdateUserRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
     validate(&params)?;
 
-    // temporarily use the user's id for updated_at
-    // update when auth is added
+    let actor_id = user.id.clone();
     let update_user = UpdateUser {
         id: user_id.to_string(),
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
-        updated_by: user_id.to_string(),
+        updated_by: user.id,
         family_id: params.family_id.clone(),
         role: params.role.clone(),
         token: params.token.clone(),
     };
-    let user = block(move || update(&pool, &update_user)).await?;
-    respond_json(user.into())
+    let update_pool = pool.clone();
+    let (before, updated) = block(move || {
+        let before = find_user(&update_pool, &update_user.id)?;
+        let updated = update(&update_pool, &update_user)?;
+        Ok((before, updated))
+    })
+    .await?;
+    for entry in membership_entries(&before, &updated, &actor_id, &client) {
+        record(&pool, entry).await;
+    }
+    respond_json(updated)
 }
 
 /// Delete a user
+///
+/// Leaving the family with the account is audited.
 pub async fn delete_user(
+    user: AuthUser,
+    client: ClientInfo,
     user_id: Path<Uuid>,
     pool: Data<PoolType>,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *user_id)).await?;
+    let delete_pool = pool.clone();
+    let deleted = block(move || {
+        let deleted = find_user(&delete_pool, &user_id.to_string())?;
+        delete(&delete_pool, *user_id)?;
+        Ok(deleted)
+    })
+    .await?;
+    if let Some(family_id) = &deleted.family_id {
+        let entry = NewAuditEntry::new(AuditAction::FamilyLeft, Some(&user.id), &client)
+            .family(Some(family_id))
+            .target(&deleted.id)
+            .details(json!({ "deleted": true }));
+        record(&pool, entry).await;
+    }
     respond_ok()
 }
 
+/// The audit entries for the family and role changes of an update
+fn membership_entries(
+    before: &User,
+    after: &UserResponse,
+    actor_id: &str,
+    client: &ClientInfo,
+) -> Vec<NewAuditEntry> {
+    let target = after.id.to_string();
+    let entry = |action, family_id: &Option<String>| {
+        NewAuditEntry::new(action, Some(actor_id), client)
+            .family(family_id.as_deref())
+            .target(&target)
+    };
+    let mut entries = vec![];
+    if before.family_id != after.family_id {
+        if before.family_id.is_some() {
+            entries.push(entry(AuditAction::FamilyLeft, &before.family_id));
+        }
+        if after.family_id.is_some() {
+            entries.push(entry(AuditAction::FamilyJoined, &after.family_id));
+        }
+    }
+    if before.role != after.role {
+        let family_id = after.family_id.as_ref().or_else(|| before.family_id.as_ref()).cloned();
+        entries.push(
+            entry(AuditAction::RoleChanged, &family_id)
+                .details(json!({ "from": before.role, "to": after.role })),
+        );
+    }
+    entries
+}
+
 impl From<User> for UserResponse {
     fn from(user: User) -> Self {
         UserResponse {
@@ -176,6 +243,8 @@ impl From<User> for UserResponse {
             role: user.role,
             family_id: user.family_id,
             token: user.token,
+            created_by: Uuid::parse_str(&user.created_by).unwrap(),
+            updated_by: Uuid::parse_str(&user.updated_by).unwrap(),
         }
     }
 }
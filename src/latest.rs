@@ -0,0 +1,61 @@
+//! Latest position of each user, cached in Redis
+//!
+//! The cache is written on ingest, only ever moving forward in time, and
+//! filled from the database on a miss. Without Redis every read goes to
+//! the database.
+
+use crate::cache::{delete, get, set, Cache};
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::geoloc::{find_latest, Geoloc};
+use actix_web::web::{block, Data};
+
+fn key(user_id: &str) -> String {
+    format!("latest_geoloc:{}", user_id)
+}
+
+/// Cache the latest position of a user
+///
+/// Failures are only logged, the next read falls back to the database.
+pub async fn remember(cache: &Option<Cache>, geoloc: &Geoloc) {
+    let cache = match cache {
+        Some(cache) => cache.clone(),
+        None => return,
+    };
+    let value = serde_json::to_string(geoloc).unwrap_or_default();
+    if let Err(error) = set(cache, &key(&geoloc.user_id), &value).await {
+        log::warn!("Could not cache the latest position of {}: {:?}", geoloc.user_id, error);
+    }
+}
+
+/// The latest position of a user, from the cache when there
+pub async fn latest(cache: &Option<Cache>, pool: &Data<PoolType>, user_id: &str) -> Result<Option<Geoloc>, ApiError> {
+    if let Some(cache) = cache {
+        // Misses come back empty, which doesn't parse
+        match get(cache.clone(), &key(user_id)).await {
+            Ok(value) => {
+                if let Ok(geoloc) = serde_json::from_str(&value) {
+                    return Ok(Some(geoloc));
+                }
+            }
+            Err(error) => log::warn!("Could not read the latest position of {}: {:?}", user_id, error),
+        }
+    }
+
+    let pool = pool.clone();
+    let user_id = user_id.to_string();
+    let geoloc = block(move || find_latest(&pool, &user_id)).await?;
+    if let Some(geoloc) = &geoloc {
+        remember(cache, geoloc).await;
+    }
+    Ok(geoloc)
+}
+
+/// Drop the cached position of a user, after their positions were deleted
+pub async fn forget(cache: &Option<Cache>, user_id: &str) {
+    if let Some(cache) = cache {
+        if let Err(error) = delete(cache.clone(), &key(user_id)).await {
+            log::warn!("Could not forget the latest position of {}: {:?}", user_id, error);
+        }
+    }
+}
@@ -11,28 +11,41 @@ extern crate validator_derive;
 
 use crate::server::server;
 
+mod audit;
 mod auth;
 mod cache;
 mod config;
+mod conflict;
 mod database;
 mod errors;
+mod escalation;
+mod eta;
+mod export;
 mod extractors;
+mod geo;
+mod geofence;
 pub mod handlers;
 mod helpers;
+mod latest;
 mod middleware;
 mod models;
+mod notify;
 mod routes;
 mod schema;
 mod server;
 mod state;
+mod stream;
+mod trips;
 mod tests;
 mod validate;
 mod scheduling;
+mod storage;
+mod media;
+mod multipart;
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
-    //scheduling
-    //scheduling::schedule(); 
+    scheduling::schedule();
 
     server().await
 }
@@ -0,0 +1,202 @@
+//! What uploaded files really are, and how they are cleaned up
+//!
+//! The type is sniffed from the content, never taken from the client.
+//! Photos lose their EXIF and XMP metadata, where phones record the
+//! location they were taken at, and get a small JPEG thumbnail.
+
+use image::imageops::FilterType;
+use image::io::Reader;
+use image::{GenericImageView, ImageOutputFormat};
+use std::io::Cursor;
+
+/// Pixels of the longest side of a thumbnail
+const THUMBNAIL_SIZE: u32 = 320;
+const THUMBNAIL_QUALITY: u8 = 80;
+
+/// Most pixels of an image decoded for a thumbnail, a small file can claim
+/// a huge size and take all the memory
+const MAX_PIXELS: u64 = 50_000_000;
+
+const JPEG: &str = "image/jpeg";
+const PNG: &str = "image/png";
+const GIF: &str = "image/gif";
+const PDF: &str = "application/pdf";
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+/// The type of a file from its first bytes, only the types accepted as
+/// attachments
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(JPEG)
+    } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(PNG)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(GIF)
+    } else if data.starts_with(b"%PDF-") {
+        Some(PDF)
+    } else {
+        None
+    }
+}
+
+pub fn is_image(content_type: &str) -> bool {
+    content_type.starts_with("image/")
+}
+
+/// A file without the metadata that could tell where it was made, as is
+/// for types without such metadata. None when a photo doesn't parse, its
+/// metadata can't be told apart then.
+pub fn strip_location(content_type: &str, data: Vec<u8>) -> Option<Vec<u8>> {
+    match content_type {
+        JPEG => strip_jpeg(&data),
+        PNG => strip_png(&data),
+        _ => Some(data),
+    }
+}
+
+/// Drop the APP1 segments, EXIF and XMP, of a JPEG
+fn strip_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = data.get(..2)?.to_vec();
+    let mut at = 2;
+    loop {
+        if *data.get(at)? != 0xFF {
+            return None;
+        }
+        let marker = *data.get(at + 1)?;
+        // Start of scan, the image data runs to the end
+        if marker == 0xDA {
+            stripped.extend_from_slice(&data[at..]);
+            return Some(stripped);
+        }
+        // The length counts its own two bytes
+        let length = u16::from_be_bytes([*data.get(at + 2)?, *data.get(at + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        let segment = data.get(at..at + 2 + length)?;
+        let payload = &segment[4..];
+        let location = marker == 0xE1 && (payload.starts_with(EXIF_HEADER) || payload.starts_with(XMP_HEADER));
+        if !location {
+            stripped.extend_from_slice(segment);
+        }
+        at += 2 + length;
+    }
+}
+
+/// Drop the eXIf chunks and XMP text of a PNG
+fn strip_png(data: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = data.get(..8)?.to_vec();
+    let mut at = 8;
+    while at < data.len() {
+        let length = u32::from_be_bytes([*data.get(at)?, *data.get(at + 1)?, *data.get(at + 2)?, *data.get(at + 3)?]) as usize;
+        // Length, type, data and CRC
+        let chunk = data.get(at..at + 12 + length)?;
+        let kind = &chunk[4..8];
+        let location = kind == b"eXIf" || (kind == b"iTXt" && chunk[8..].starts_with(XMP_KEYWORD));
+        if !location {
+            stripped.extend_from_slice(chunk);
+        }
+        at += 12 + length;
+    }
+    Some(stripped)
+}
+
+/// A JPEG thumbnail of an image with its size, None when the image can't
+/// be decoded or is too large to
+pub fn thumbnail(data: &[u8]) -> Option<(Vec<u8>, u32, u32)> {
+    // The header tells the size without decoding the pixels
+    let (width, height) = Reader::new(Cursor::new(data)).with_guessed_format().ok()?.into_dimensions().ok()?;
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        return None;
+    }
+    let image = image::load_from_memory(data).ok()?;
+    let (width, height) = image.dimensions();
+    let thumbnail = image.resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle);
+    let mut encoded = vec![];
+    thumbnail
+        .write_to(&mut encoded, ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY))
+        .ok()?;
+    Some((encoded, width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageBuffer, Rgb};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(width, height, Rgb([200, 100, 50])));
+        let mut encoded = vec![];
+        image.write_to(&mut encoded, ImageOutputFormat::Png).unwrap();
+        encoded
+    }
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    #[test]
+    fn it_sniffs_types_from_the_content() {
+        assert_eq!(sniff(&png(1, 1)), Some(PNG));
+        assert_eq!(sniff(b"%PDF-1.4"), Some(PDF));
+        assert_eq!(sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(JPEG));
+        assert_eq!(sniff(b"<html>"), None);
+    }
+
+    #[test]
+    fn it_strips_the_exif_of_a_jpeg() {
+        let mut exif = EXIF_HEADER.to_vec();
+        exif.extend_from_slice(b"GPS 48.85 2.35");
+        let jfif = segment(0xE0, b"JFIF\0\x01\x01");
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(&jfif);
+        jpeg.extend(segment(0xE1, &exif));
+        jpeg.extend(segment(0xDA, b"\x00"));
+        jpeg.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+
+        let stripped = strip_location(JPEG, jpeg).unwrap();
+        let mut expected = vec![0xFF, 0xD8];
+        expected.extend(&jfif);
+        expected.extend(segment(0xDA, b"\x00"));
+        expected.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+        assert_eq!(stripped, expected);
+    }
+
+    #[test]
+    fn it_strips_the_exif_of_a_png() {
+        let original = png(2, 2);
+        let mut with_exif = original[..33].to_vec();
+        with_exif.extend_from_slice(&4u32.to_be_bytes());
+        with_exif.extend_from_slice(b"eXIfMM\0*\0\0\0\0");
+        with_exif.extend_from_slice(&original[33..]);
+        assert_eq!(strip_location(PNG, with_exif), Some(original));
+    }
+
+    #[test]
+    fn it_rejects_photos_that_dont_parse() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x01]);
+        assert_eq!(strip_location(JPEG, jpeg), None);
+        assert_eq!(strip_location(JPEG, vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00]), None);
+
+        let truncated = png(2, 2)[..40].to_vec();
+        assert_eq!(strip_location(PNG, truncated), None);
+        assert_eq!(strip_location(PDF, b"%PDF-1.4".to_vec()), Some(b"%PDF-1.4".to_vec()));
+    }
+
+    #[test]
+    fn it_makes_thumbnails() {
+        let (thumbnail, width, height) = thumbnail(&png(640, 480)).unwrap();
+        assert_eq!((width, height), (640, 480));
+        assert_eq!(sniff(&thumbnail), Some(JPEG));
+        let decoded = image::load_from_memory(&thumbnail).unwrap();
+        assert_eq!(decoded.dimensions(), (320, 240));
+        assert!(super::thumbnail(b"%PDF-1.4").is_none());
+    }
+}
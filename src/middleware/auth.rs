@@ -1,15 +1,36 @@
+use crate::audit::record;
 use crate::auth::{decode_jwt, PrivateClaim};
+use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::extractors::client_info;
+use crate::models::audit::{AuditAction, NewAuditEntry};
 use actix_identity::RequestIdentity;
 use actix_service::{Service, Transform};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
     Error, HttpResponse,
 };
+use chrono::{Duration, NaiveDateTime, Utc};
 use futures::{Future, future::{ok, Ready}};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::Mutex;
 use std::task::{Context, Poll};
 
+/// Minutes during which the invalid token of a session is only audited once
+const INVALID_TOKEN_WINDOW: i64 = 10;
+
+/// Sessions remembered at most, past it invalid tokens go unaudited until
+/// the window of the oldest ends
+const INVALID_TOKEN_SESSIONS: usize = 10_000;
+
+lazy_static! {
+    /// When the invalid token of each session, by its hash, was last audited
+    static ref INVALID_TOKENS: Mutex<HashMap<Vec<u8>, NaiveDateTime>> = Mutex::new(HashMap::new());
+}
+
 pub struct Auth;
 
 impl<S, B> Transform<S> for Auth
@@ -52,8 +73,26 @@ where
         let is_logged_in = private_claim.is_ok();
         let unauthorized = !is_logged_in && !dont_need_auth(req.path());
 
+        // A token that does not verify has expired, or was forged or
+        // tampered with
+        let invalid_token = match req.app_data::<PoolType>() {
+            Some(pool) if !is_logged_in && !identity.is_empty() && first_in_window(&identity) => {
+                let entry = NewAuditEntry::new(
+                    AuditAction::InvalidToken,
+                    None,
+                    &client_info(req.peer_addr(), &req.connection_info(), req.headers()),
+                )
+                .details(json!({ "path": req.path() }));
+                Some((pool, entry))
+            }
+            _ => None,
+        };
+
         if unauthorized {
-            return Box::pin(async move {    
+            return Box::pin(async move {
+                if let Some((pool, entry)) = invalid_token {
+                    record(&pool, entry).await;
+                }
                 Ok(req.into_response(HttpResponse::Unauthorized().finish().into_body()))
             })
         }
@@ -61,16 +100,64 @@ where
         let fut = self.service.call(req);
 
         Box::pin(async move {
+            if let Some((pool, entry)) = invalid_token {
+                record(&pool, entry).await;
+            }
             let res = fut.await?;
             Ok(res)
         })
     }
 }
 
+/// Whether an invalid token is seen for the first time in its window, a
+/// client retrying with a stale cookie must not flood the audit log
+fn first_in_window(token: &str) -> bool {
+    let mut seen = match INVALID_TOKENS.lock() {
+        Ok(seen) => seen,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    remember(&mut seen, Sha256::digest(token.as_bytes()).to_vec(), Utc::now().naive_utc())
+}
+
+fn remember(seen: &mut HashMap<Vec<u8>, NaiveDateTime>, key: Vec<u8>, now: NaiveDateTime) -> bool {
+    let since = now - Duration::minutes(INVALID_TOKEN_WINDOW);
+    if seen.get(&key).map_or(false, |last| *last > since) {
+        return false;
+    }
+    if seen.len() >= INVALID_TOKEN_SESSIONS {
+        seen.retain(|_, last| *last > since);
+        if seen.len() >= INVALID_TOKEN_SESSIONS {
+            return false;
+        }
+    }
+    seen.insert(key, now);
+    true
+}
+
 fn dont_need_auth(path: &str) -> bool {
     path == "/api/v1/auth/login" ||
     path == "/api/v1/subscription/search_by_family_place/7c372cea-240b-4a68-a076-2d84f426596c/b8405db4-f339-4f8d-82d0-caff997bf154" ||
     path == "/api/v1/event" ||
+    path == "/api/v1/owntracks" ||
+    path.starts_with("/api/v1/sos/live/") ||
+    path.starts_with("/api/v1/handover/code/") ||
     path == "/api/v1/subscription/search_by_family_user_days/7c372cea-240b-4a68-a076-2d84f426596c/ca0dcfba-7df8-4e7c-bc9f-4b244857081d/lundi"  ||
     path == "/api/v1/user"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_audits_an_invalid_token_once_per_window() {
+        let mut seen = HashMap::new();
+        let now = Utc::now().naive_utc();
+        let key = b"session".to_vec();
+
+        assert!(remember(&mut seen, key.clone(), now));
+        assert!(!remember(&mut seen, key.clone(), now + Duration::minutes(1)));
+        assert!(remember(&mut seen, b"other".to_vec(), now + Duration::minutes(1)));
+        assert!(remember(&mut seen, key, now + Duration::minutes(INVALID_TOKEN_WINDOW + 1)));
+    }
+}
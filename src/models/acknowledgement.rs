@@ -0,0 +1,267 @@
+//! Who has seen an event, one record per event and recipient
+//!
+//! Recipients are the adults of the family but whoever posted the event.
+//! Families pick the event kinds that must be acknowledged, those are
+//! notified again until someone does, a few times at most.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::event::{Event, EventKind};
+use crate::schema::{acknowledgements, mandatory_acknowledgements};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Integer, Timestamp, Varchar};
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+#[table_name = "acknowledgements"]
+pub struct Acknowledgement {
+    pub event_id: String,
+    pub user_id: String,
+    pub family_id: String,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    /// Last time the recipient was notified about the event
+    pub notified_at: NaiveDateTime,
+    /// Times the recipient was notified again
+    pub reminders: i32,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+#[table_name = "mandatory_acknowledgements"]
+pub struct MandatoryAcknowledgement {
+    pub family_id: String,
+    pub kind: String,
+    pub updated_by: String,
+    /// When the kind became mandatory, kept as long as it stays so
+    pub updated_at: NaiveDateTime,
+}
+
+/// Whether to notify the recipients of an event again: nobody acknowledged
+/// it, the last notification is old enough and reminders are left
+pub fn is_due(acknowledgements: &[Acknowledgement], now: NaiveDateTime, interval: Duration, max_reminders: i32) -> bool {
+    !acknowledgements.is_empty()
+        && acknowledgements.iter().all(|acknowledgement| {
+            acknowledgement.acknowledged_at.is_none()
+                && acknowledgement.reminders < max_reminders
+                && acknowledgement.notified_at + interval <= now
+        })
+}
+
+/// Get the recipients of an event, acknowledged or not
+pub fn find_all_by_event_id(pool: &PoolType, _event_id: &str) -> Result<Vec<Acknowledgement>, ApiError> {
+    use crate::schema::acknowledgements::dsl::*;
+
+    let conn = pool.get()?;
+    let all = acknowledgements
+        .filter(event_id.eq(_event_id.to_string()))
+        .order(created_at.asc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Record the recipients of a new event, notified now
+pub fn request(pool: &PoolType, event: &Event, user_ids: &[String]) -> Result<Vec<Acknowledgement>, ApiError> {
+    use crate::schema::acknowledgements::dsl::acknowledgements;
+
+    let now = Utc::now().naive_utc();
+    let requested: Vec<Acknowledgement> = user_ids
+        .iter()
+        .map(|user_id| Acknowledgement {
+            event_id: event.id.clone(),
+            user_id: user_id.clone(),
+            family_id: event.family_id.clone(),
+            acknowledged_at: None,
+            notified_at: now,
+            reminders: 0,
+            created_at: now,
+        })
+        .collect();
+    let conn = pool.get()?;
+    diesel::insert_into(acknowledgements)
+        .values(&requested)
+        .on_conflict_do_nothing()
+        .execute(&conn)?;
+    Ok(requested)
+}
+
+/// Acknowledge an event, acknowledging again keeps the first time
+pub fn acknowledge(pool: &PoolType, _event_id: &str, _user_id: &str) -> Result<Acknowledgement, ApiError> {
+    use crate::schema::acknowledgements::dsl::*;
+
+    let conn = pool.get()?;
+    diesel::update(acknowledgements)
+        .filter(event_id.eq(_event_id.to_string()))
+        .filter(user_id.eq(_user_id.to_string()))
+        .filter(acknowledged_at.is_null())
+        .set(acknowledged_at.eq(Some(Utc::now().naive_utc())))
+        .execute(&conn)?;
+    acknowledgements
+        .filter(event_id.eq(_event_id.to_string()))
+        .filter(user_id.eq(_user_id.to_string()))
+        .first::<Acknowledgement>(&conn)
+        .map_err(|_| ApiError::BadRequest(format!("event {} wasn't sent to user {}", _event_id, _user_id)))
+}
+
+/// Get the event kinds a family must acknowledge
+pub fn find_mandatory_kinds(pool: &PoolType, _family_id: &str) -> Result<Vec<EventKind>, ApiError> {
+    use crate::schema::mandatory_acknowledgements::dsl::*;
+
+    let conn = pool.get()?;
+    let kinds = mandatory_acknowledgements
+        .select(kind)
+        .filter(family_id.eq(_family_id.to_string()))
+        .load::<String>(&conn)?;
+    Ok(kinds.iter().filter_map(|_kind| _kind.parse().ok()).collect())
+}
+
+/// Replace the event kinds a family must acknowledge
+pub fn save_mandatory_kinds(
+    pool: &PoolType,
+    _family_id: &str,
+    kinds: &[EventKind],
+    actor: &str,
+) -> Result<Vec<EventKind>, ApiError> {
+    use crate::schema::mandatory_acknowledgements::dsl::*;
+
+    let now = Utc::now().naive_utc();
+    let rows: Vec<MandatoryAcknowledgement> = kinds
+        .iter()
+        .map(|_kind| MandatoryAcknowledgement {
+            family_id: _family_id.to_string(),
+            kind: _kind.as_str().into(),
+            updated_by: actor.to_string(),
+            updated_at: now,
+        })
+        .collect();
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let keep: Vec<String> = rows.iter().map(|row| row.kind.clone()).collect();
+        diesel::delete(mandatory_acknowledgements)
+            .filter(family_id.eq(_family_id.to_string()))
+            .filter(kind.ne_all(keep))
+            .execute(&conn)?;
+        // Kinds that stay mandatory keep the time they became so
+        diesel::insert_into(mandatory_acknowledgements)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(&conn)?;
+        Ok(())
+    })?;
+    find_mandatory_kinds(pool, _family_id)
+}
+
+/// An event waiting for an acknowledgement
+#[derive(QueryableByName)]
+struct Pending {
+    #[sql_type = "Varchar"]
+    event_id: String,
+}
+
+/// Events of a mandatory kind nobody acknowledged, due for a reminder, with
+/// their recipients
+///
+/// Only events posted since their kind became mandatory are reminded, making
+/// a kind mandatory doesn't remind the family of its whole history.
+pub fn find_due(
+    pool: &PoolType,
+    now: NaiveDateTime,
+    interval: Duration,
+    max_reminders: i32,
+) -> Result<Vec<(Event, Vec<Acknowledgement>)>, ApiError> {
+    use crate::schema::events;
+
+    let conn = pool.get()?;
+    let pending: Vec<Pending> = diesel::sql_query(
+        "SELECT DISTINCT acknowledgements.event_id
+        FROM acknowledgements
+        JOIN events ON events.id = acknowledgements.event_id
+        JOIN mandatory_acknowledgements ON mandatory_acknowledgements.family_id = events.family_id
+            AND mandatory_acknowledgements.kind = events.kind
+        WHERE acknowledgements.acknowledged_at IS NULL
+        AND acknowledgements.notified_at <= $1
+        AND acknowledgements.reminders < $2
+        AND events.deleted_at IS NULL
+        AND events.created_at >= mandatory_acknowledgements.updated_at",
+    )
+    .bind::<Timestamp, _>(now - interval)
+    .bind::<Integer, _>(max_reminders)
+    .load(&conn)?;
+    let event_ids: Vec<String> = pending.into_iter().map(|pending| pending.event_id).collect();
+
+    // Every recipient must be due, not only the pending ones
+    let all: Vec<Acknowledgement> = acknowledgements::table
+        .filter(acknowledgements::event_id.eq_any(&event_ids))
+        .load(&conn)?;
+    let mut by_event: HashMap<String, Vec<Acknowledgement>> = HashMap::new();
+    for acknowledgement in all {
+        by_event
+            .entry(acknowledgement.event_id.clone())
+            .or_default()
+            .push(acknowledgement);
+    }
+    by_event.retain(|_, recipients| is_due(recipients, now, interval, max_reminders));
+
+    let due_ids: Vec<&String> = by_event.keys().collect();
+    let due_events: Vec<Event> = events::table.filter(events::id.eq_any(due_ids)).load(&conn)?;
+    let due = due_events
+        .into_iter()
+        .map(|event| {
+            let recipients = by_event.remove(&event.id).unwrap_or_default();
+            (event, recipients)
+        })
+        .collect();
+    Ok(due)
+}
+
+/// Record that the recipients of an event who didn't acknowledge were
+/// notified again
+pub fn remind(pool: &PoolType, _event_id: &str, now: NaiveDateTime) -> Result<(), ApiError> {
+    use crate::schema::acknowledgements::dsl::*;
+
+    let conn = pool.get()?;
+    diesel::update(acknowledgements)
+        .filter(event_id.eq(_event_id.to_string()))
+        .filter(acknowledged_at.is_null())
+        .set((notified_at.eq(now), reminders.eq(reminders + 1)))
+        .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 10, 26).and_hms(16, minute, 0)
+    }
+
+    fn acknowledgement(user_id: &str, acknowledged_at: Option<NaiveDateTime>, reminders: i32) -> Acknowledgement {
+        Acknowledgement {
+            event_id: "event".into(),
+            user_id: user_id.into(),
+            family_id: "family".into(),
+            acknowledged_at,
+            notified_at: at(0),
+            reminders,
+            created_at: at(0),
+        }
+    }
+
+    #[test]
+    fn it_reminds_when_nobody_acknowledged_after_the_interval() {
+        let pending = vec![acknowledgement("mum", None, 0), acknowledgement("dad", None, 0)];
+        assert!(!is_due(&pending, at(5), Duration::minutes(10), 3));
+        assert!(is_due(&pending, at(10), Duration::minutes(10), 3));
+        assert!(!is_due(&[], at(10), Duration::minutes(10), 3));
+    }
+
+    #[test]
+    fn it_stops_reminding_once_someone_acknowledged_or_reminders_ran_out() {
+        let acknowledged = vec![acknowledgement("mum", Some(at(2)), 0), acknowledgement("dad", None, 0)];
+        assert!(!is_due(&acknowledged, at(30), Duration::minutes(10), 3));
+        let exhausted = vec![acknowledgement("mum", None, 3)];
+        assert!(!is_due(&exhausted, at(30), Duration::minutes(10), 3));
+    }
+}
@@ -0,0 +1,233 @@
+//! The activity log of a family, what real-time streams replay on resume
+//!
+//! Every change pushed to the streams is stored first, its id is the cursor
+//! clients send back to resume after a reconnect.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::family::find_by_retention;
+use crate::models::geoloc::PURGE_BATCH;
+use crate::schema::activities;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use serde_json::Value;
+
+/// Most activities replayed on resume, a client further behind is told to
+/// reset and fetch the current state again
+pub const MAX_REPLAY: i64 = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable)]
+#[table_name = "activities"]
+pub struct Activity {
+    pub id: i64,
+    pub family_id: String,
+    pub kind: String,
+    pub payload: String,
+    pub created_at: NaiveDateTime,
+    /// The user a position belongs to, so it can be forgotten
+    pub user_id: Option<String>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "activities"]
+pub struct NewActivity {
+    pub family_id: String,
+    pub kind: String,
+    pub payload: String,
+    pub user_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityKind {
+    EventCreated,
+    EventUpdated,
+    GeolocCreated,
+    AssignmentChanged,
+    EscalationChanged,
+    SosRaised,
+    SosUpdated,
+    SosResolved,
+    ChatMessageCreated,
+    ChatRead,
+    EventAcknowledged,
+    /// Sent instead of the replay to a client too far behind, never stored
+    Reset,
+}
+
+impl ActivityKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActivityKind::EventCreated => "event_created",
+            ActivityKind::EventUpdated => "event_updated",
+            ActivityKind::GeolocCreated => "geoloc_created",
+            ActivityKind::AssignmentChanged => "assignment_changed",
+            ActivityKind::EscalationChanged => "escalation_changed",
+            ActivityKind::SosRaised => "sos_raised",
+            ActivityKind::SosUpdated => "sos_updated",
+            ActivityKind::SosResolved => "sos_resolved",
+            ActivityKind::ChatMessageCreated => "chat_message_created",
+            ActivityKind::ChatRead => "chat_read",
+            ActivityKind::EventAcknowledged => "event_acknowledged",
+            ActivityKind::Reset => "reset",
+        }
+    }
+}
+
+/// What is sent to clients for each activity
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ActivityMessage {
+    pub id: i64,
+    pub family_id: String,
+    pub kind: String,
+    pub payload: Value,
+    pub created_at: NaiveDateTime,
+}
+
+/// Store an activity, the returned id is its cursor
+pub fn record(pool: &PoolType, new_activity: &NewActivity) -> Result<Activity, ApiError> {
+    use crate::schema::activities::dsl::*;
+
+    let conn = pool.get()?;
+    let activity = diesel::insert_into(activities)
+        .values(new_activity)
+        .get_result(&conn)?;
+    Ok(activity)
+}
+
+/// Get the activities of a family after a cursor, oldest first, None when
+/// there are more than `MAX_REPLAY` of them
+pub fn get_since(pool: &PoolType, _family_id: &str, cursor: i64) -> Result<Option<Vec<Activity>>, ApiError> {
+    use crate::schema::activities::dsl::*;
+
+    let conn = pool.get()?;
+    let all: Vec<Activity> = activities
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(id.gt(cursor))
+        .order(id.asc())
+        .limit(MAX_REPLAY + 1)
+        .load(&conn)?;
+    if all.len() as i64 > MAX_REPLAY {
+        return Ok(None);
+    }
+    Ok(Some(all))
+}
+
+/// What a client resuming after a cursor missed, or a reset when it missed
+/// too much to replay
+///
+/// The reset carries the id of the latest activity, the client resumes from
+/// there once it fetched the current state again.
+pub fn replay(pool: &PoolType, _family_id: &str, cursor: i64) -> Result<Vec<ActivityMessage>, ApiError> {
+    use crate::schema::activities::dsl::*;
+
+    if let Some(missed) = get_since(pool, _family_id, cursor)? {
+        return Ok(missed.into_iter().map(ActivityMessage::from).collect());
+    }
+    let conn = pool.get()?;
+    let latest: Option<i64> = activities
+        .filter(family_id.eq(_family_id.to_string()))
+        .select(diesel::dsl::max(id))
+        .first(&conn)?;
+    Ok(vec![ActivityMessage {
+        id: latest.unwrap_or(cursor),
+        family_id: _family_id.to_string(),
+        kind: ActivityKind::Reset.as_str().into(),
+        payload: Value::Null,
+        created_at: Utc::now().naive_utc(),
+    }])
+}
+
+impl From<Activity> for ActivityMessage {
+    fn from(activity: Activity) -> Self {
+        ActivityMessage {
+            id: activity.id,
+            family_id: activity.family_id,
+            kind: activity.kind,
+            payload: serde_json::from_str(&activity.payload).unwrap_or(Value::Null),
+            created_at: activity.created_at,
+        }
+    }
+}
+
+/// Delete a batch of activities of a kind older than the retention of
+/// their family, returns how many were deleted
+///
+/// Limited to `PURGE_BATCH` like positions, see `geoloc::delete_expired`.
+pub fn delete_expired(
+    pool: &PoolType,
+    _kind: ActivityKind,
+    now: NaiveDateTime,
+    default_days: i64,
+) -> Result<usize, ApiError> {
+    use crate::schema::activities::dsl::*;
+
+    let mut expired: Vec<i64> = vec![];
+    for (days, family_ids) in find_by_retention(pool, default_days)? {
+        let remaining = PURGE_BATCH - expired.len() as i64;
+        if remaining <= 0 {
+            break;
+        }
+        let conn = pool.get()?;
+        expired.extend(
+            activities
+                .select(id)
+                .filter(kind.eq(_kind.as_str()))
+                .filter(family_id.eq_any(family_ids))
+                .filter(created_at.lt(now - Duration::days(days)))
+                .limit(remaining)
+                .load::<i64>(&conn)?,
+        );
+    }
+    if expired.is_empty() {
+        return Ok(0);
+    }
+
+    let conn = pool.get()?;
+    Ok(diesel::delete(activities.filter(id.eq_any(expired))).execute(&conn)?)
+}
+
+/// Delete the positions of a user the activity log of a family still holds
+pub fn delete_geolocs_of_user(pool: &PoolType, _family_id: &str, _user_id: &str) -> Result<usize, ApiError> {
+    use crate::schema::activities::dsl::*;
+
+    let conn = pool.get()?;
+    let deleted = diesel::delete(
+        activities
+            .filter(family_id.eq(_family_id.to_string()))
+            .filter(kind.eq(ActivityKind::GeolocCreated.as_str()))
+            .filter(user_id.eq(_user_id.to_string())),
+    )
+    .execute(&conn)?;
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(payload: &str) -> Activity {
+        Activity {
+            id: 42,
+            family_id: "family".into(),
+            kind: ActivityKind::EventCreated.as_str().into(),
+            payload: payload.into(),
+            created_at: Utc::now().naive_utc(),
+            user_id: None,
+        }
+    }
+
+    #[test]
+    fn it_decodes_the_payload_of_an_activity() {
+        let message: ActivityMessage = activity(r#"{"kind":"pickup"}"#).into();
+        assert_eq!(message.id, 42);
+        assert_eq!(message.kind, "event_created");
+        assert_eq!(message.payload["kind"], "pickup");
+    }
+
+    #[test]
+    fn it_tolerates_a_malformed_payload() {
+        let message: ActivityMessage = activity("not json").into();
+        assert_eq!(message.payload, Value::Null);
+    }
+}
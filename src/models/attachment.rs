@@ -0,0 +1,105 @@
+//! Files attached to an event or a chat message
+//!
+//! The content lives in the storage under `storage_key`, images also have
+//! a thumbnail there.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::attachments;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "attachments"]
+pub struct Attachment {
+    pub id: String,
+    pub family_id: String,
+    pub event_id: Option<String>,
+    pub message_id: Option<String>,
+    /// As uploaded, only used to name downloads
+    pub filename: String,
+    /// Sniffed from the content
+    pub content_type: String,
+    pub size: i64,
+    pub storage_key: String,
+    pub thumbnail_key: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Where the content of an attachment is stored, grouped by family
+pub fn storage_key(family_id: &str, attachment_id: &str) -> String {
+    format!("{}/{}", family_id, attachment_id)
+}
+
+pub fn thumbnail_key(family_id: &str, attachment_id: &str) -> String {
+    format!("{}/{}.thumbnail.jpg", family_id, attachment_id)
+}
+
+/// Get the attachments of an event, oldest first
+pub fn find_all_by_event_id(pool: &PoolType, _event_id: &str) -> Result<Vec<Attachment>, ApiError> {
+    use crate::schema::attachments::dsl::*;
+
+    let conn = pool.get()?;
+    let all = attachments
+        .filter(event_id.eq(_event_id.to_string()))
+        .order(created_at.asc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Get the attachments of a chat message, oldest first
+pub fn find_all_by_message_id(pool: &PoolType, _message_id: &str) -> Result<Vec<Attachment>, ApiError> {
+    use crate::schema::attachments::dsl::*;
+
+    let conn = pool.get()?;
+    let all = attachments
+        .filter(message_id.eq(_message_id.to_string()))
+        .order(created_at.asc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Find an attachment or error out
+pub fn find(pool: &PoolType, attachment_id: Uuid) -> Result<Attachment, ApiError> {
+    use crate::schema::attachments::dsl::{attachments, id};
+
+    let not_found = format!("attachment {} not found", attachment_id);
+    let conn = pool.get()?;
+    attachments
+        .filter(id.eq(attachment_id.to_string()))
+        .first::<Attachment>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+pub fn create(pool: &PoolType, new_attachment: &Attachment) -> Result<Attachment, ApiError> {
+    use crate::schema::attachments::dsl::attachments;
+
+    let conn = pool.get()?;
+    diesel::insert_into(attachments).values(new_attachment).execute(&conn)?;
+    Ok(new_attachment.clone())
+}
+
+pub fn delete(pool: &PoolType, attachment_id: &str) -> Result<(), ApiError> {
+    use crate::schema::attachments::dsl::{attachments, id};
+
+    let conn = pool.get()?;
+    diesel::delete(attachments).filter(id.eq(attachment_id.to_string())).execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_attachments_by_family() {
+        let family_id = "00000000-0000-0000-0000-000000000001";
+        let attachment_id = "00000000-0000-0000-0000-000000000002";
+        assert_eq!(storage_key(family_id, attachment_id), format!("{}/{}", family_id, attachment_id));
+        assert!(thumbnail_key(family_id, attachment_id).starts_with(&storage_key(family_id, attachment_id)));
+    }
+}
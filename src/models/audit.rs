@@ -0,0 +1,385 @@
+//! The audit log of security-sensitive actions
+//!
+//! Entries are only ever appended, the table rejects updates and deletes.
+//! Each one carries the hash of the previous entry of its family, and its
+//! own hash covers that and all its fields, so altering or removing an
+//! entry breaks the chain from there on. Entries not tied to a family, like
+//! failed logins of unknown emails, form a chain of their own.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::audit_entries;
+use chrono::{NaiveDateTime, Timelike, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Nullable, Text};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// The previous hash of the first entry of a chain
+pub const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+const USER_AGENT_LENGTH: usize = 255;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    /// A request came with a session token that could not be verified
+    InvalidToken,
+    RoleChanged,
+    FamilyJoined,
+    FamilyLeft,
+    LocationExported,
+    ChildCardViewed,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::InvalidToken => "invalid_token",
+            AuditAction::RoleChanged => "role_changed",
+            AuditAction::FamilyJoined => "family_joined",
+            AuditAction::FamilyLeft => "family_left",
+            AuditAction::LocationExported => "location_exported",
+            AuditAction::ChildCardViewed => "child_card_viewed",
+        }
+    }
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = ApiError;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "login" => Ok(AuditAction::Login),
+            "login_failed" => Ok(AuditAction::LoginFailed),
+            "invalid_token" => Ok(AuditAction::InvalidToken),
+            "role_changed" => Ok(AuditAction::RoleChanged),
+            "family_joined" => Ok(AuditAction::FamilyJoined),
+            "family_left" => Ok(AuditAction::FamilyLeft),
+            "location_exported" => Ok(AuditAction::LocationExported),
+            "child_card_viewed" => Ok(AuditAction::ChildCardViewed),
+            _ => Err(ApiError::BadRequest(format!("unknown audit action {}", action))),
+        }
+    }
+}
+
+/// Where a request came from
+///
+/// Simply add "client: ClientInfo" to a handler to extract it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable)]
+#[table_name = "audit_entries"]
+pub struct AuditEntry {
+    pub id: i64,
+    pub family_id: Option<String>,
+    /// None when nobody could be identified, like a failed login
+    pub actor_id: Option<String>,
+    pub action: String,
+    /// Who or what the action was about, a user id or an email
+    pub target: Option<String>,
+    /// JSON
+    pub details: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEntry {
+    pub fn details(&self) -> Value {
+        self.details
+            .as_deref()
+            .and_then(|details| serde_json::from_str(details).ok())
+            .unwrap_or(Value::Null)
+    }
+
+    /// The hash the entry should have, given the one it follows
+    fn digest(&self, prev_hash: &str) -> String {
+        digest(
+            prev_hash,
+            &self.family_id,
+            &self.actor_id,
+            &self.action,
+            &self.target,
+            &self.details,
+            &self.ip_address,
+            &self.user_agent,
+            self.created_at,
+        )
+    }
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "audit_entries"]
+pub struct NewAuditEntry {
+    pub family_id: Option<String>,
+    pub actor_id: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub details: Option<String>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl NewAuditEntry {
+    pub fn new(action: AuditAction, actor_id: Option<&str>, client: &ClientInfo) -> Self {
+        let now = Utc::now().naive_utc();
+        NewAuditEntry {
+            family_id: None,
+            actor_id: actor_id.map(str::to_string),
+            action: action.as_str().into(),
+            target: None,
+            details: None,
+            ip_address: client.ip_address.clone(),
+            user_agent: client
+                .user_agent
+                .as_ref()
+                .map(|user_agent| user_agent.chars().take(USER_AGENT_LENGTH).collect()),
+            // The database keeps microseconds, the hash must cover what is stored
+            created_at: now.with_nanosecond(now.nanosecond() / 1000 * 1000).unwrap_or(now),
+            prev_hash: GENESIS.into(),
+            hash: String::new(),
+        }
+    }
+
+    pub fn family(mut self, family_id: Option<&str>) -> Self {
+        self.family_id = family_id.map(str::to_string);
+        self
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = Some(details.to_string());
+        self
+    }
+
+    /// Chain the entry after the one with the given hash
+    fn chain(&mut self, prev_hash: &str) {
+        self.prev_hash = prev_hash.to_string();
+        self.hash = digest(
+            prev_hash,
+            &self.family_id,
+            &self.actor_id,
+            &self.action,
+            &self.target,
+            &self.details,
+            &self.ip_address,
+            &self.user_agent,
+            self.created_at,
+        );
+    }
+}
+
+/// SHA-256 of the previous hash and every field, hex encoded
+#[allow(clippy::too_many_arguments)]
+fn digest(
+    prev_hash: &str,
+    family_id: &Option<String>,
+    actor_id: &Option<String>,
+    action: &str,
+    target: &Option<String>,
+    details: &Option<String>,
+    ip_address: &Option<String>,
+    user_agent: &Option<String>,
+    created_at: NaiveDateTime,
+) -> String {
+    // A JSON array keeps the fields apart whatever they contain
+    let fields = json!([
+        prev_hash,
+        family_id,
+        actor_id,
+        action,
+        target,
+        details,
+        ip_address,
+        user_agent,
+        created_at.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+    ]);
+    Sha256::digest(fields.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Append an entry at the end of the chain of its family
+pub fn append(pool: &PoolType, mut new_entry: NewAuditEntry) -> Result<AuditEntry, ApiError> {
+    use crate::schema::audit_entries::dsl::*;
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        // Appends to a chain are serialized, two entries must not follow the
+        // same one, other chains go on
+        diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext(coalesce($1, '')))")
+            .bind::<Nullable<Text>, _>(new_entry.family_id.clone())
+            .execute(&conn)?;
+
+        let mut last = audit_entries.select(hash).order(id.desc()).into_boxed();
+        last = match &new_entry.family_id {
+            Some(_family_id) => last.filter(family_id.eq(_family_id.clone())),
+            None => last.filter(family_id.is_null()),
+        };
+        let last_hash: Option<String> = last.first(&conn).optional()?;
+        new_entry.chain(last_hash.as_deref().unwrap_or(GENESIS));
+
+        let entry = diesel::insert_into(audit_entries)
+            .values(&new_entry)
+            .get_result(&conn)?;
+        Ok(entry)
+    })
+}
+
+/// What to narrow the entries of a family down to
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub actor_id: Option<String>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+}
+
+/// Get a page of the entries of a family, newest first
+pub fn find_all_by_family_id(
+    pool: &PoolType,
+    _family_id: &str,
+    filter: &AuditFilter,
+    offset: i64,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, ApiError> {
+    use crate::schema::audit_entries::dsl::*;
+
+    let conn = pool.get()?;
+    let mut query = audit_entries
+        .filter(family_id.eq(_family_id.to_string()))
+        .into_boxed();
+    if let Some(_action) = filter.action {
+        query = query.filter(action.eq(_action.as_str()));
+    }
+    if let Some(_actor_id) = &filter.actor_id {
+        query = query.filter(actor_id.eq(_actor_id.clone()));
+    }
+    if let Some(from) = filter.from {
+        query = query.filter(created_at.ge(from));
+    }
+    if let Some(to) = filter.to {
+        query = query.filter(created_at.lt(to));
+    }
+    let entries = query
+        .order(id.desc())
+        .offset(offset)
+        .limit(limit)
+        .load(&conn)?;
+    Ok(entries)
+}
+
+/// Get the whole chain of a family, oldest first
+pub fn find_chain(pool: &PoolType, _family_id: &str) -> Result<Vec<AuditEntry>, ApiError> {
+    use crate::schema::audit_entries::dsl::*;
+
+    let conn = pool.get()?;
+    let entries = audit_entries
+        .filter(family_id.eq(_family_id.to_string()))
+        .order(id.asc())
+        .load(&conn)?;
+    Ok(entries)
+}
+
+/// Check a chain, oldest first, returns the id of the first entry that does
+/// not follow the previous one or whose hash does not match its fields
+pub fn verify(entries: &[AuditEntry]) -> Result<(), i64> {
+    let mut prev_hash = GENESIS;
+    for entry in entries {
+        if entry.prev_hash != prev_hash || entry.digest(prev_hash) != entry.hash {
+            return Err(entry.id);
+        }
+        prev_hash = &entry.hash;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain(count: i64) -> Vec<AuditEntry> {
+        let client = ClientInfo {
+            ip_address: Some("192.0.2.1".into()),
+            user_agent: Some("Mozilla/5.0".into()),
+        };
+        let mut prev_hash = GENESIS.to_string();
+        (1..=count)
+            .map(|entry_id| {
+                let mut new_entry = NewAuditEntry::new(AuditAction::Login, Some("user"), &client)
+                    .family(Some("family"))
+                    .details(json!({ "entry": entry_id }));
+                new_entry.chain(&prev_hash);
+                prev_hash = new_entry.hash.clone();
+                AuditEntry {
+                    id: entry_id,
+                    family_id: new_entry.family_id,
+                    actor_id: new_entry.actor_id,
+                    action: new_entry.action,
+                    target: new_entry.target,
+                    details: new_entry.details,
+                    ip_address: new_entry.ip_address,
+                    user_agent: new_entry.user_agent,
+                    created_at: new_entry.created_at,
+                    prev_hash: new_entry.prev_hash,
+                    hash: new_entry.hash,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_verifies_an_untouched_chain() {
+        let entries = chain(3);
+        assert_eq!(entries[0].prev_hash, GENESIS);
+        assert_eq!(entries[1].prev_hash, entries[0].hash);
+        assert_eq!(verify(&entries), Ok(()));
+        assert_eq!(verify(&[]), Ok(()));
+    }
+
+    #[test]
+    fn it_finds_where_a_chain_was_tampered_with() {
+        let mut entries = chain(3);
+        entries[1].actor_id = Some("someone else".into());
+        assert_eq!(verify(&entries), Err(2));
+
+        let mut entries = chain(3);
+        entries.remove(1);
+        assert_eq!(verify(&entries), Err(3));
+    }
+
+    #[test]
+    fn it_parses_audit_actions() {
+        let action: AuditAction = "location_exported".parse().unwrap();
+        assert_eq!(action, AuditAction::LocationExported);
+        assert_eq!(action.as_str(), "location_exported");
+        assert!("dropped_table".parse::<AuditAction>().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! The history of events, subscriptions and places
+//!
+//! Every write to one of them records the row before and after it, as JSON,
+//! with who made it, in the same transaction. Deletes are soft so a deleted
+//! row keeps its history and can be restored.
+
+use crate::database::{ConnectionType, PoolType};
+use crate::errors::ApiError;
+use crate::schema::changes;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// Columns that change with every write, left out of the changed fields
+const BOOKKEEPING: [&str; 2] = ["updated_by", "updated_at"];
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Entity {
+    Event,
+    Subscription,
+    Place,
+}
+
+impl Entity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Entity::Event => "event",
+            Entity::Subscription => "subscription",
+            Entity::Place => "place",
+        }
+    }
+}
+
+impl FromStr for Entity {
+    type Err = ApiError;
+
+    fn from_str(entity: &str) -> Result<Self, Self::Err> {
+        match entity {
+            "event" => Ok(Entity::Event),
+            "subscription" => Ok(Entity::Subscription),
+            "place" => Ok(Entity::Place),
+            _ => Err(ApiError::BadRequest(format!("unknown entity {}", entity))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeAction {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+}
+
+impl ChangeAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeAction::Created => "created",
+            ChangeAction::Updated => "updated",
+            ChangeAction::Deleted => "deleted",
+            ChangeAction::Restored => "restored",
+        }
+    }
+}
+
+impl fmt::Display for ChangeAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for ChangeAction {
+    type Err = ApiError;
+
+    fn from_str(action: &str) -> Result<Self, Self::Err> {
+        match action {
+            "created" => Ok(ChangeAction::Created),
+            "updated" => Ok(ChangeAction::Updated),
+            "deleted" => Ok(ChangeAction::Deleted),
+            "restored" => Ok(ChangeAction::Restored),
+            _ => Err(ApiError::BadRequest(format!("unknown change action {}", action))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable)]
+#[table_name = "changes"]
+pub struct Change {
+    pub id: i64,
+    pub family_id: String,
+    pub entity: String,
+    pub entity_id: String,
+    pub action: String,
+    /// The row as JSON, None for a creation
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub changed_by: String,
+    pub changed_at: NaiveDateTime,
+}
+
+impl Change {
+    pub fn entity(&self) -> Entity {
+        self.entity.parse().unwrap_or(Entity::Event)
+    }
+
+    pub fn action(&self) -> ChangeAction {
+        self.action.parse().unwrap_or(ChangeAction::Updated)
+    }
+
+    pub fn changed_fields(&self) -> Vec<String> {
+        changed_fields(self.before.as_deref(), self.after.as_deref())
+    }
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "changes"]
+pub struct NewChange {
+    pub family_id: String,
+    pub entity: String,
+    pub entity_id: String,
+    pub action: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub changed_by: String,
+}
+
+impl NewChange {
+    pub fn new<T: Serialize>(
+        entity: Entity,
+        entity_id: &str,
+        family_id: &str,
+        action: ChangeAction,
+        before: Option<&T>,
+        after: Option<&T>,
+        changed_by: &str,
+    ) -> Self {
+        let to_json = |row: Option<&T>| row.and_then(|row| serde_json::to_string(row).ok());
+        NewChange {
+            family_id: family_id.to_string(),
+            entity: entity.as_str().into(),
+            entity_id: entity_id.to_string(),
+            action: action.as_str().into(),
+            before: to_json(before),
+            after: to_json(after),
+            changed_by: changed_by.to_string(),
+        }
+    }
+}
+
+/// The fields whose value differs between two versions of a row, a
+/// missing version having none
+pub fn changed_fields(before: Option<&str>, after: Option<&str>) -> Vec<String> {
+    let parse = |row: Option<&str>| match row.and_then(|row| serde_json::from_str(row).ok()) {
+        Some(Value::Object(fields)) => fields,
+        _ => Default::default(),
+    };
+    let (before, after) = (parse(before), parse(after));
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter(|name| !BOOKKEEPING.contains(&name.as_str()))
+        .filter(|name| before.get(*name) != after.get(*name))
+        .cloned()
+        .collect()
+}
+
+/// Record a change, inside the transaction of the write
+pub fn record(conn: &ConnectionType, new_change: &NewChange) -> Result<(), ApiError> {
+    use crate::schema::changes::dsl::changes;
+
+    diesel::insert_into(changes).values(new_change).execute(conn)?;
+    Ok(())
+}
+
+/// Get the history of a row, oldest first
+pub fn find_all(pool: &PoolType, _entity: Entity, _entity_id: &str) -> Result<Vec<Change>, ApiError> {
+    use crate::schema::changes::dsl::*;
+
+    let conn = pool.get()?;
+    let all = changes
+        .filter(entity.eq(_entity.as_str()))
+        .filter(entity_id.eq(_entity_id.to_string()))
+        .order(id.asc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_lists_the_changed_fields() {
+        let before = r#"{"day":"jeudi","message":"","updated_by":"a","deleted_at":null}"#;
+        let after = r#"{"day":"vendredi","message":"","updated_by":"b","deleted_at":null}"#;
+        assert_eq!(changed_fields(Some(before), Some(after)), vec!["day"]);
+        assert_eq!(changed_fields(Some(before), Some(before)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn it_lists_every_field_of_a_creation() {
+        let after = r#"{"name":"School","updated_at":"2020-10-28T08:00:00"}"#;
+        assert_eq!(changed_fields(None, Some(after)), vec!["name"]);
+    }
+}
@@ -0,0 +1,303 @@
+//! Family conversations, each thread about the family at large or about
+//! one event, subscription or place
+//!
+//! A member's read receipt is the time they last read a thread up to,
+//! everything posted by others after it is unread for them.
+
+use crate::database::{ConnectionType, PoolType};
+use crate::errors::ApiError;
+use crate::schema::{chat_messages, chat_reads, chat_threads};
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Varchar};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// What a thread is about, at most one thing
+#[derive(Clone, Debug, PartialEq)]
+pub enum Topic {
+    Family,
+    Event(String),
+    Subscription(String),
+    Place(String),
+}
+
+impl Topic {
+    pub fn from_ids(
+        event_id: Option<String>,
+        subscription_id: Option<String>,
+        place_id: Option<String>,
+    ) -> Result<Self, ApiError> {
+        match (event_id, subscription_id, place_id) {
+            (None, None, None) => Ok(Topic::Family),
+            (Some(event_id), None, None) => Ok(Topic::Event(event_id)),
+            (None, Some(subscription_id), None) => Ok(Topic::Subscription(subscription_id)),
+            (None, None, Some(place_id)) => Ok(Topic::Place(place_id)),
+            _ => Err(ApiError::ValidationError(vec![
+                "a thread is about at most one of event_id, subscription_id and place_id".into(),
+            ])),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "chat_threads"]
+pub struct Thread {
+    pub id: String,
+    pub family_id: String,
+    pub title: Option<String>,
+    pub event_id: Option<String>,
+    pub subscription_id: Option<String>,
+    pub place_id: Option<String>,
+    pub last_message_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "chat_messages"]
+pub struct Message {
+    pub id: String,
+    pub thread_id: String,
+    /// The author
+    pub user_id: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl Message {
+    pub fn new(thread_id: &str, user_id: &str, body: &str) -> Self {
+        Message {
+            id: Uuid::new_v4().to_string(),
+            thread_id: thread_id.to_string(),
+            user_id: user_id.to_string(),
+            body: body.to_string(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable, AsChangeset)]
+#[table_name = "chat_reads"]
+pub struct Read {
+    pub thread_id: String,
+    pub user_id: String,
+    pub last_read_at: NaiveDateTime,
+}
+
+/// Whether a member has read a message, their own are read
+pub fn is_read_by(message: &Message, user_id: &str, last_read_at: Option<NaiveDateTime>) -> bool {
+    message.user_id == user_id || last_read_at.map_or(false, |last_read_at| message.created_at <= last_read_at)
+}
+
+/// Get the threads of a family, about a topic when given, most recently
+/// active first
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str, topic: &Topic) -> Result<Vec<Thread>, ApiError> {
+    use crate::schema::chat_threads::dsl::*;
+
+    let conn = pool.get()?;
+    let mut query = chat_threads.filter(family_id.eq(_family_id.to_string())).into_boxed();
+    query = match topic {
+        Topic::Family => query,
+        Topic::Event(_event_id) => query.filter(event_id.eq(_event_id.clone())),
+        Topic::Subscription(_subscription_id) => query.filter(subscription_id.eq(_subscription_id.clone())),
+        Topic::Place(_place_id) => query.filter(place_id.eq(_place_id.clone())),
+    };
+    let all = query
+        .order((last_message_at.desc().nulls_last(), created_at.desc()))
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Find a thread or error out
+pub fn find(pool: &PoolType, thread_id: Uuid) -> Result<Thread, ApiError> {
+    use crate::schema::chat_threads::dsl::{chat_threads, id};
+
+    let not_found = format!("thread {} not found", thread_id);
+    let conn = pool.get()?;
+    chat_threads
+        .filter(id.eq(thread_id.to_string()))
+        .first::<Thread>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Find a message or error out
+pub fn find_message(pool: &PoolType, message_id: Uuid) -> Result<Message, ApiError> {
+    use crate::schema::chat_messages::dsl::{chat_messages, id};
+
+    let not_found = format!("message {} not found", message_id);
+    let conn = pool.get()?;
+    chat_messages
+        .filter(id.eq(message_id.to_string()))
+        .first::<Message>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+pub fn create(pool: &PoolType, new_thread: &Thread) -> Result<Thread, ApiError> {
+    use crate::schema::chat_threads::dsl::chat_threads;
+
+    let conn = pool.get()?;
+    diesel::insert_into(chat_threads).values(new_thread).execute(&conn)?;
+    Ok(new_thread.clone())
+}
+
+/// Get a page of the messages of a thread, most recent first, the ones
+/// posted before a message when given
+pub fn find_messages(pool: &PoolType, _thread_id: &str, before: Option<&Message>, limit: i64) -> Result<Vec<Message>, ApiError> {
+    use crate::schema::chat_messages::dsl::*;
+
+    let conn = pool.get()?;
+    let mut query = chat_messages.filter(thread_id.eq(_thread_id.to_string())).into_boxed();
+    if let Some(before) = before {
+        // Messages posted the same microsecond are told apart by id
+        query = query.filter(
+            created_at
+                .lt(before.created_at)
+                .or(created_at.eq(before.created_at).and(id.lt(before.id.clone()))),
+        );
+    }
+    let page = query.order((created_at.desc(), id.desc())).limit(limit).load(&conn)?;
+    Ok(page)
+}
+
+/// Post a message, which its author has read along with the rest of the thread
+pub fn post(pool: &PoolType, new_message: &Message) -> Result<Message, ApiError> {
+    use crate::schema::chat_threads::dsl::{chat_threads, id, last_message_at, updated_at};
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        diesel::insert_into(chat_messages::table).values(new_message).execute(&conn)?;
+        diesel::update(chat_threads)
+            .filter(id.eq(new_message.thread_id.clone()))
+            .set((last_message_at.eq(Some(new_message.created_at)), updated_at.eq(new_message.created_at)))
+            .execute(&conn)?;
+        read_up_to(&conn, &new_message.thread_id, &new_message.user_id, new_message.created_at)?;
+        Ok(())
+    })?;
+    Ok(new_message.clone())
+}
+
+/// Move a member's read receipt forward, never back
+pub fn mark_read(pool: &PoolType, _thread_id: &str, _user_id: &str, at: NaiveDateTime) -> Result<Read, ApiError> {
+    let conn = pool.get()?;
+    read_up_to(&conn, _thread_id, _user_id, at)
+}
+
+fn read_up_to(conn: &ConnectionType, _thread_id: &str, _user_id: &str, at: NaiveDateTime) -> Result<Read, ApiError> {
+    use crate::schema::chat_reads::dsl::*;
+
+    let read = Read {
+        thread_id: _thread_id.to_string(),
+        user_id: _user_id.to_string(),
+        last_read_at: at,
+    };
+    conn.transaction::<_, ApiError, _>(|| {
+        let current = chat_reads
+            .filter(thread_id.eq(_thread_id.to_string()))
+            .filter(user_id.eq(_user_id.to_string()))
+            .first::<Read>(conn)
+            .optional()?;
+        match current {
+            Some(current) if current.last_read_at >= at => Ok(current),
+            Some(_) => {
+                diesel::update(chat_reads)
+                    .filter(thread_id.eq(_thread_id.to_string()))
+                    .filter(user_id.eq(_user_id.to_string()))
+                    .set(last_read_at.eq(at))
+                    .execute(conn)?;
+                Ok(read)
+            }
+            None => {
+                diesel::insert_into(chat_reads).values(&read).execute(conn)?;
+                Ok(read)
+            }
+        }
+    })
+}
+
+/// Get the read receipts of a thread, by member
+pub fn find_reads(pool: &PoolType, _thread_id: &str) -> Result<Vec<Read>, ApiError> {
+    use crate::schema::chat_reads::dsl::*;
+
+    let conn = pool.get()?;
+    let all = chat_reads.filter(thread_id.eq(_thread_id.to_string())).load(&conn)?;
+    Ok(all)
+}
+
+/// How many messages of a thread a member hasn't read
+#[derive(QueryableByName)]
+struct UnreadCount {
+    #[sql_type = "Varchar"]
+    thread_id: String,
+    #[sql_type = "Varchar"]
+    user_id: String,
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+/// Count what each member hasn't read in each thread, in one query, only
+/// the pairs with unread messages are returned
+fn count_unread(conn: &ConnectionType, thread_ids: &[String], user_ids: &[String]) -> Result<Vec<UnreadCount>, ApiError> {
+    let counts = diesel::sql_query(
+        "SELECT chat_messages.thread_id, members.user_id, COUNT(*) AS count
+        FROM chat_messages
+        JOIN unnest($2) AS members(user_id) ON members.user_id <> chat_messages.user_id
+        LEFT JOIN chat_reads ON chat_reads.thread_id = chat_messages.thread_id
+            AND chat_reads.user_id = members.user_id
+        WHERE chat_messages.thread_id = ANY($1)
+        AND (chat_reads.last_read_at IS NULL OR chat_messages.created_at > chat_reads.last_read_at)
+        GROUP BY chat_messages.thread_id, members.user_id",
+    )
+    .bind::<Array<Varchar>, _>(thread_ids)
+    .bind::<Array<Varchar>, _>(user_ids)
+    .load(conn)?;
+    Ok(counts)
+}
+
+/// How many messages of each thread a member hasn't read, by thread id
+pub fn unread_counts(pool: &PoolType, _thread_ids: &[String], _user_id: &str) -> Result<HashMap<String, i64>, ApiError> {
+    let conn = pool.get()?;
+    let counts = count_unread(&conn, _thread_ids, &[_user_id.to_string()])?
+        .into_iter()
+        .map(|unread| (unread.thread_id, unread.count))
+        .collect();
+    Ok(counts)
+}
+
+/// How many messages of a thread each member hasn't read, by user id
+pub fn unread_counts_by_member(pool: &PoolType, _thread_id: &str, _user_ids: &[String]) -> Result<HashMap<String, i64>, ApiError> {
+    let conn = pool.get()?;
+    let counts = count_unread(&conn, &[_thread_id.to_string()], _user_ids)?
+        .into_iter()
+        .map(|unread| (unread.user_id, unread.count))
+        .collect();
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    #[test]
+    fn it_keeps_threads_to_one_topic() {
+        assert_eq!(Topic::from_ids(None, None, None).unwrap(), Topic::Family);
+        assert_eq!(
+            Topic::from_ids(Some("event".into()), None, None).unwrap(),
+            Topic::Event("event".into())
+        );
+        assert!(Topic::from_ids(Some("event".into()), None, Some("place".into())).is_err());
+    }
+
+    #[test]
+    fn it_tells_who_read_a_message() {
+        let mut message = Message::new("thread", "author", "On my way");
+        message.created_at = NaiveDate::from_ymd(2020, 10, 25).and_hms(16, 0, 0);
+        assert!(is_read_by(&message, "author", None));
+        assert!(!is_read_by(&message, "reader", None));
+        assert!(!is_read_by(&message, "reader", Some(message.created_at - Duration::seconds(1))));
+        assert!(is_read_by(&message, "reader", Some(message.created_at)));
+    }
+}
@@ -0,0 +1,213 @@
+//! What to know about a child in an emergency, and who looked at it
+//!
+//! Parents edit the card. Other adults read it only while they are on duty
+//! for the child, and every read is recorded.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::emergency_contact::EmergencyContact;
+use crate::models::occurrence::ScheduledOccurrence;
+use crate::models::user::User;
+use crate::schema::{child_card_views, child_cards};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+
+/// Minutes around an occurrence's window its adult is on duty, to travel
+/// there and back
+const ON_DUTY_MARGIN: i64 = 60;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable, AsChangeset)]
+#[table_name = "child_cards"]
+#[primary_key(child_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct ChildCard {
+    pub child_id: String,
+    pub family_id: String,
+    pub birth_date: Option<NaiveDate>,
+    pub blood_type: Option<String>,
+    pub allergies: Option<String>,
+    pub medications: Option<String>,
+    pub conditions: Option<String>,
+    pub doctor_name: Option<String>,
+    pub doctor_phone: Option<String>,
+    pub notes: Option<String>,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+impl ChildCard {
+    /// The card of a child nobody filled in yet
+    pub fn empty(child: &User, family_id: &str) -> Self {
+        ChildCard {
+            child_id: child.id.clone(),
+            family_id: family_id.to_string(),
+            birth_date: None,
+            blood_type: None,
+            allergies: None,
+            medications: None,
+            conditions: None,
+            doctor_name: None,
+            doctor_phone: None,
+            notes: None,
+            updated_by: child.id.clone(),
+            updated_at: child.created_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "child_card_views"]
+pub struct CardView {
+    pub id: String,
+    pub family_id: String,
+    pub child_id: String,
+    pub viewer_id: String,
+    /// card or summary
+    pub format: String,
+    pub viewed_at: NaiveDateTime,
+}
+
+/// Whether an adult is on duty for a child at a local time of the family
+pub fn on_duty(occurrences: &[ScheduledOccurrence], user_id: &str, child_id: &str, now: NaiveDateTime) -> bool {
+    occurrences.iter().any(|occurrence| {
+        if occurrence.user_id != user_id || occurrence.subscription.child_id.as_deref() != Some(child_id) {
+            return false;
+        }
+        match occurrence.window() {
+            Some((start, end)) => {
+                let margin = Duration::minutes(ON_DUTY_MARGIN);
+                start - margin <= now && now <= end + margin
+            }
+            None => occurrence.day == now.date(),
+        }
+    })
+}
+
+/// A plain text summary of a card, to print or hand over
+pub fn summary(child: &User, card: &ChildCard, contacts: &[EmergencyContact]) -> String {
+    let mut lines = vec![format!("{} {}", child.first_name, child.last_name), String::new()];
+    let fields = [
+        ("Birth date", card.birth_date.map(|date| date.format("%Y-%m-%d").to_string())),
+        ("Blood type", card.blood_type.clone()),
+        ("Allergies", card.allergies.clone()),
+        ("Medications", card.medications.clone()),
+        ("Conditions", card.conditions.clone()),
+        ("Doctor", card.doctor_name.clone()),
+        ("Doctor's phone", card.doctor_phone.clone()),
+        ("Notes", card.notes.clone()),
+    ];
+    for (label, value) in fields.iter() {
+        lines.push(format!("{}: {}", label, value.as_deref().unwrap_or("-")));
+    }
+    lines.push(String::new());
+    lines.push("Emergency contacts:".into());
+    if contacts.is_empty() {
+        lines.push("-".into());
+    }
+    for contact in contacts {
+        lines.push(format!("{}. {} {}", contact.position, contact.name, contact.phone));
+    }
+    lines.push(String::new());
+    lines.push(format!("Updated {}", card.updated_at.format("%Y-%m-%d %H:%M UTC")));
+    lines.join("\n") + "\n"
+}
+
+/// Get the card of a child, empty when nobody filled it in
+pub fn find_card(pool: &PoolType, child: &User, _family_id: &str) -> Result<ChildCard, ApiError> {
+    use crate::schema::child_cards::dsl::*;
+
+    let conn = pool.get()?;
+    let card = child_cards
+        .filter(child_id.eq(child.id.clone()))
+        .first::<ChildCard>(&conn)
+        .optional()?;
+    Ok(card.unwrap_or_else(|| ChildCard::empty(child, _family_id)))
+}
+
+/// Create or replace the card of a child
+pub fn save(pool: &PoolType, card: &ChildCard) -> Result<ChildCard, ApiError> {
+    use crate::schema::child_cards::dsl::*;
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let updated = diesel::update(child_cards)
+            .filter(child_id.eq(card.child_id.clone()))
+            .set(card)
+            .execute(&conn)?;
+        if updated == 0 {
+            diesel::insert_into(child_cards).values(card).execute(&conn)?;
+        }
+        Ok(card.clone())
+    })
+}
+
+pub fn record_view(pool: &PoolType, view: &CardView) -> Result<(), ApiError> {
+    use crate::schema::child_card_views::dsl::child_card_views;
+
+    let conn = pool.get()?;
+    diesel::insert_into(child_card_views).values(view).execute(&conn)?;
+    Ok(())
+}
+
+/// Get who looked at the card of a child, most recent first
+pub fn find_views(pool: &PoolType, _child_id: &str) -> Result<Vec<CardView>, ApiError> {
+    use crate::schema::child_card_views::dsl::*;
+
+    let conn = pool.get()?;
+    let all = child_card_views
+        .filter(child_id.eq(_child_id.to_string()))
+        .order(viewed_at.desc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::subscription::Subscription;
+    use chrono::NaiveTime;
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd(2020, 10, 22)
+    }
+
+    fn occurrence(start: Option<NaiveTime>, end: Option<NaiveTime>) -> ScheduledOccurrence {
+        ScheduledOccurrence {
+            subscription: Subscription {
+                id: "subscription".into(),
+                family_id: "family".into(),
+                place_id: "place".into(),
+                user_id: "adult".into(),
+                days: "jeudi".into(),
+                created_by: "adult".into(),
+                created_at: day().and_hms(0, 0, 0),
+                updated_by: "adult".into(),
+                updated_at: day().and_hms(0, 0, 0),
+                child_id: Some("child".into()),
+                start_time: start,
+                end_time: end,
+                deleted_at: None,
+                deleted_by: None,
+            },
+            day: day(),
+            user_id: "nanny".into(),
+        }
+    }
+
+    #[test]
+    fn it_puts_the_assigned_adult_on_duty_around_the_window() {
+        let occurrences = vec![occurrence(Some(NaiveTime::from_hms(16, 30, 0)), Some(NaiveTime::from_hms(17, 0, 0)))];
+        assert!(on_duty(&occurrences, "nanny", "child", day().and_hms(15, 30, 0)));
+        assert!(on_duty(&occurrences, "nanny", "child", day().and_hms(18, 0, 0)));
+        assert!(!on_duty(&occurrences, "nanny", "child", day().and_hms(18, 1, 0)));
+        assert!(!on_duty(&occurrences, "adult", "child", day().and_hms(16, 45, 0)));
+        assert!(!on_duty(&occurrences, "nanny", "other", day().and_hms(16, 45, 0)));
+    }
+
+    #[test]
+    fn it_puts_the_assigned_adult_on_duty_all_day_without_a_window() {
+        let occurrences = vec![occurrence(None, None)];
+        assert!(on_duty(&occurrences, "nanny", "child", day().and_hms(8, 0, 0)));
+        assert!(!on_duty(&occurrences, "nanny", "child", day().succ().and_hms(8, 0, 0)));
+    }
+}
@@ -0,0 +1,221 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::cover_request::CoverRequestsResponse;
+use crate::models::occurrence::reassign;
+use crate::schema::cover_requests;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+pub const STATUS_OPEN: &str = "open";
+pub const STATUS_CLAIMED: &str = "claimed";
+pub const STATUS_EXPIRED: &str = "expired";
+pub const STATUS_CANCELLED: &str = "cancelled";
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "cover_requests"]
+pub struct CoverRequest {
+    pub id: String,
+    pub family_id: String,
+    pub subscription_id: String,
+    pub day: NaiveDate,
+    pub requested_by: String,
+    pub claimed_by: Option<String>,
+    pub status: String,
+    pub message: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub escalated_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NewCoverRequest {
+    pub id: String,
+    pub family_id: String,
+    pub subscription_id: String,
+    pub day: NaiveDate,
+    pub requested_by: String,
+    pub message: Option<String>,
+    pub expires_at: NaiveDateTime,
+    pub created_by: String,
+    pub updated_by: String,
+}
+
+/// Get all cover requests of a family
+pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid) -> Result<CoverRequestsResponse, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    let all: Vec<CoverRequest> = cover_requests
+        .filter(family_id.eq(_family_id.to_string()))
+        .order(day.asc())
+        .load(&conn)?;
+
+    Ok(all.into())
+}
+
+/// Find the open cover request of an occurrence, if any
+pub fn find_open(pool: &PoolType, _subscription_id: &str, _day: NaiveDate) -> Result<Option<CoverRequest>, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    let cover_request = cover_requests
+        .filter(subscription_id.eq(_subscription_id.to_string()))
+        .filter(day.eq(_day))
+        .filter(status.eq(STATUS_OPEN))
+        .first::<CoverRequest>(&conn)
+        .optional()?;
+
+    Ok(cover_request)
+}
+
+/// Find a cover request or error out
+pub fn find(pool: &PoolType, cover_request_id: Uuid) -> Result<CoverRequest, ApiError> {
+    use crate::schema::cover_requests::dsl::{cover_requests, id};
+
+    let not_found = format!("cover request {} not found", cover_request_id);
+    let conn = pool.get()?;
+    cover_requests
+        .filter(id.eq(cover_request_id.to_string()))
+        .first::<CoverRequest>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Create a new cover request
+pub fn create(pool: &PoolType, new_cover_request: &CoverRequest) -> Result<CoverRequest, ApiError> {
+    use crate::schema::cover_requests::dsl::cover_requests;
+
+    let conn = pool.get()?;
+    diesel::insert_into(cover_requests).values(new_cover_request).execute(&conn)?;
+    Ok(new_cover_request.clone())
+}
+
+/// Claim an open cover request and reassign its occurrence to the claimer
+///
+/// Only the first claim succeeds: the status is checked and changed in a
+/// single UPDATE so concurrent claims can't both win.
+pub fn claim(pool: &PoolType, cover_request_id: Uuid, claimer_id: &str) -> Result<CoverRequest, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let now = Utc::now().naive_utc();
+        let claimed = diesel::update(cover_requests)
+            .filter(id.eq(cover_request_id.to_string()))
+            .filter(status.eq(STATUS_OPEN))
+            .filter(expires_at.gt(now))
+            .set((
+                status.eq(STATUS_CLAIMED),
+                claimed_by.eq(Some(claimer_id.to_string())),
+                updated_by.eq(claimer_id.to_string()),
+                updated_at.eq(now),
+            ))
+            .execute(&conn)?;
+        if claimed == 0 {
+            let no_longer_open = format!("cover request {} is no longer open", cover_request_id);
+            return Err(ApiError::BadRequest(no_longer_open));
+        }
+
+        let cover_request = cover_requests
+            .filter(id.eq(cover_request_id.to_string()))
+            .first::<CoverRequest>(&conn)?;
+        reassign(
+            &conn,
+            &cover_request.family_id,
+            &cover_request.subscription_id,
+            cover_request.day,
+            claimer_id,
+            claimer_id,
+        )?;
+        Ok(cover_request)
+    })
+}
+
+/// Cancel an open cover request
+pub fn cancel(pool: &PoolType, cover_request_id: Uuid, actor: &str) -> Result<CoverRequest, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    let cancelled = diesel::update(cover_requests)
+        .filter(id.eq(cover_request_id.to_string()))
+        .filter(status.eq(STATUS_OPEN))
+        .set((
+            status.eq(STATUS_CANCELLED),
+            updated_by.eq(actor.to_string()),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
+        .execute(&conn)?;
+    if cancelled == 0 {
+        let no_longer_open = format!("cover request {} is no longer open", cover_request_id);
+        return Err(ApiError::BadRequest(no_longer_open));
+    }
+    find(pool, cover_request_id)
+}
+
+/// Mark open cover requests past their expiry as expired and return them
+pub fn expire_due(pool: &PoolType, actor: &str) -> Result<Vec<CoverRequest>, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    // Selected and flagged at once, a request claimed meanwhile is left alone
+    let expired = diesel::update(
+        cover_requests
+            .filter(status.eq(STATUS_OPEN))
+            .filter(expires_at.le(now)),
+    )
+    .set((
+        status.eq(STATUS_EXPIRED),
+        updated_by.eq(actor.to_string()),
+        updated_at.eq(now),
+    ))
+    .get_results(&conn)?;
+    Ok(expired)
+}
+
+/// Flag open cover requests getting close to their expiry as escalated and
+/// return them
+pub fn escalate_due(pool: &PoolType, before: NaiveDateTime, actor: &str) -> Result<Vec<CoverRequest>, ApiError> {
+    use crate::schema::cover_requests::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    // Selected and flagged at once, a request claimed meanwhile is left alone
+    let escalated = diesel::update(
+        cover_requests
+            .filter(status.eq(STATUS_OPEN))
+            .filter(escalated_at.is_null())
+            .filter(expires_at.le(before)),
+    )
+    .set((
+        escalated_at.eq(Some(now)),
+        updated_by.eq(actor.to_string()),
+        updated_at.eq(now),
+    ))
+    .get_results(&conn)?;
+    Ok(escalated)
+}
+
+impl From<NewCoverRequest> for CoverRequest {
+    fn from(cover_request: NewCoverRequest) -> Self {
+        CoverRequest {
+            id: cover_request.id,
+            family_id: cover_request.family_id,
+            subscription_id: cover_request.subscription_id,
+            day: cover_request.day,
+            requested_by: cover_request.requested_by,
+            claimed_by: None,
+            status: STATUS_OPEN.into(),
+            message: cover_request.message,
+            expires_at: cover_request.expires_at,
+            escalated_at: None,
+            created_by: cover_request.created_by,
+            created_at: Utc::now().naive_utc(),
+            updated_by: cover_request.updated_by,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+}
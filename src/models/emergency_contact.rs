@@ -0,0 +1,111 @@
+//! People to reach when none of the family's adults answer
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::emergency_contacts;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "emergency_contacts"]
+pub struct EmergencyContact {
+    pub id: String,
+    pub family_id: String,
+    pub name: String,
+    pub phone: String,
+    /// Set when the contact has an account, so they can get notifications
+    pub user_id: Option<String>,
+    /// Contacts are reached in ascending position
+    pub position: i32,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+    /// Set when the contact is for one child only, a doctor or a nanny
+    pub child_id: Option<String>,
+}
+
+impl EmergencyContact {
+    /// Whether to reach the contact about a child, or about no child in particular
+    pub fn is_for(&self, child_id: Option<&str>) -> bool {
+        match (&self.child_id, child_id) {
+            (None, _) => true,
+            (Some(own), Some(child_id)) => own == child_id,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
+#[table_name = "emergency_contacts"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct UpdateEmergencyContact {
+    pub id: String,
+    pub name: String,
+    pub phone: String,
+    pub user_id: Option<String>,
+    pub position: i32,
+    pub child_id: Option<String>,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Get the emergency contacts of a family, in the order they are reached
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<EmergencyContact>, ApiError> {
+    use crate::schema::emergency_contacts::dsl::*;
+
+    let conn = pool.get()?;
+    let all = emergency_contacts
+        .filter(family_id.eq(_family_id.to_string()))
+        .order((position.asc(), name.asc()))
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Get the contacts to reach about a child, the family's and the child's own
+pub fn find_all_for_child(pool: &PoolType, _family_id: &str, _child_id: &str) -> Result<Vec<EmergencyContact>, ApiError> {
+    let all = find_all_by_family_id(pool, _family_id)?;
+    Ok(all.into_iter().filter(|contact| contact.is_for(Some(_child_id))).collect())
+}
+
+/// Find an emergency contact or error out
+pub fn find(pool: &PoolType, contact_id: Uuid) -> Result<EmergencyContact, ApiError> {
+    use crate::schema::emergency_contacts::dsl::{emergency_contacts, id};
+
+    let not_found = format!("emergency contact {} not found", contact_id);
+    let conn = pool.get()?;
+    emergency_contacts
+        .filter(id.eq(contact_id.to_string()))
+        .first::<EmergencyContact>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+pub fn create(pool: &PoolType, new_contact: &EmergencyContact) -> Result<EmergencyContact, ApiError> {
+    use crate::schema::emergency_contacts::dsl::emergency_contacts;
+
+    let conn = pool.get()?;
+    diesel::insert_into(emergency_contacts).values(new_contact).execute(&conn)?;
+    Ok(new_contact.clone())
+}
+
+pub fn update(pool: &PoolType, update_contact: &UpdateEmergencyContact) -> Result<EmergencyContact, ApiError> {
+    use crate::schema::emergency_contacts::dsl::{emergency_contacts, id};
+
+    let conn = pool.get()?;
+    diesel::update(emergency_contacts)
+        .filter(id.eq(update_contact.id.clone()))
+        .set(update_contact)
+        .execute(&conn)?;
+    find(pool, Uuid::parse_str(&update_contact.id)?)
+}
+
+pub fn delete(pool: &PoolType, contact_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::emergency_contacts::dsl::{emergency_contacts, id};
+
+    let conn = pool.get()?;
+    diesel::delete(emergency_contacts)
+        .filter(id.eq(contact_id.to_string()))
+        .execute(&conn)?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! Occurrences whose pickup went wrong, and who was alerted about it
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::escalation::EscalationsResponse;
+use crate::schema::escalations;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    /// The adult on duty isn't at the place once the window started
+    Late,
+    /// The window is over and the child was neither picked up nor gone
+    Missed,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Late => "late",
+            Reason::Missed => "missed",
+        }
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Reason {
+    type Err = ApiError;
+
+    fn from_str(reason: &str) -> Result<Self, Self::Err> {
+        match reason {
+            "late" => Ok(Reason::Late),
+            "missed" => Ok(Reason::Missed),
+            _ => Err(ApiError::BadRequest(format!("unknown escalation reason {}", reason))),
+        }
+    }
+}
+
+/// Who was alerted last, each level reaches further
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Assigned,
+    Adults,
+    Contacts,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Assigned => "assigned",
+            Level::Adults => "adults",
+            Level::Contacts => "contacts",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Level {
+    type Err = ApiError;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "assigned" => Ok(Level::Assigned),
+            "adults" => Ok(Level::Adults),
+            "contacts" => Ok(Level::Contacts),
+            _ => Err(ApiError::BadRequest(format!("unknown escalation level {}", level))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "escalations"]
+pub struct Escalation {
+    pub id: String,
+    pub family_id: String,
+    pub subscription_id: String,
+    pub day: NaiveDate,
+    pub reason: String,
+    pub level: String,
+    /// When the current level was alerted
+    pub notified_at: NaiveDateTime,
+    pub acknowledged_by: Option<String>,
+    pub acknowledged_at: Option<NaiveDateTime>,
+    /// Set once the pickup went through, or the adult made it
+    pub resolved_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Escalation {
+    pub fn reason(&self) -> Reason {
+        self.reason.parse().unwrap_or(Reason::Missed)
+    }
+
+    pub fn level(&self) -> Level {
+        self.level.parse().unwrap_or(Level::Assigned)
+    }
+
+    /// Still alerting people
+    pub fn is_open(&self) -> bool {
+        self.acknowledged_at.is_none() && self.resolved_at.is_none()
+    }
+}
+
+/// Get the escalations of a family, most recent first
+pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid) -> Result<EscalationsResponse, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let all: Vec<Escalation> = escalations
+        .filter(family_id.eq(_family_id.to_string()))
+        .order(created_at.desc())
+        .load(&conn)?;
+    Ok(all.into())
+}
+
+/// Get the escalations of a family on a day
+pub fn find_all_by_day(pool: &PoolType, _family_id: &str, _day: NaiveDate) -> Result<Vec<Escalation>, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let all = escalations
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(day.eq(_day))
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Find an escalation or error out
+pub fn find(pool: &PoolType, escalation_id: Uuid) -> Result<Escalation, ApiError> {
+    use crate::schema::escalations::dsl::{escalations, id};
+
+    let not_found = format!("escalation {} not found", escalation_id);
+    let conn = pool.get()?;
+    escalations
+        .filter(id.eq(escalation_id.to_string()))
+        .first::<Escalation>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Start escalating an occurrence, its assigned adult is alerted first
+pub fn create(
+    pool: &PoolType,
+    _family_id: &str,
+    _subscription_id: &str,
+    _day: NaiveDate,
+    _reason: Reason,
+    actor: &str,
+) -> Result<Escalation, ApiError> {
+    use crate::schema::escalations::dsl::escalations;
+
+    let now = Utc::now().naive_utc();
+    let escalation = Escalation {
+        id: Uuid::new_v4().to_string(),
+        family_id: _family_id.to_string(),
+        subscription_id: _subscription_id.to_string(),
+        day: _day,
+        reason: _reason.as_str().into(),
+        level: Level::Assigned.as_str().into(),
+        notified_at: now,
+        acknowledged_by: None,
+        acknowledged_at: None,
+        resolved_at: None,
+        created_by: actor.to_string(),
+        created_at: now,
+        updated_by: actor.to_string(),
+        updated_at: now,
+    };
+    let conn = pool.get()?;
+    diesel::insert_into(escalations).values(&escalation).execute(&conn)?;
+    Ok(escalation)
+}
+
+/// Alert the next level of an open escalation
+///
+/// None when it was acknowledged or resolved meanwhile, nobody is to be
+/// alerted then.
+pub fn advance(pool: &PoolType, escalation_id: &str, _level: Level, actor: &str) -> Result<Option<Escalation>, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let advanced = diesel::update(escalations)
+        .filter(id.eq(escalation_id.to_string()))
+        .filter(acknowledged_at.is_null())
+        .filter(resolved_at.is_null())
+        .set((
+            level.eq(_level.as_str()),
+            notified_at.eq(now),
+            updated_by.eq(actor.to_string()),
+            updated_at.eq(now),
+        ))
+        .get_result(&conn)
+        .optional()?;
+    Ok(advanced)
+}
+
+/// Start over from the assigned adult for another reason, forgetting how
+/// the previous one ended
+pub fn restart(pool: &PoolType, escalation_id: &str, _reason: Reason, actor: &str) -> Result<Escalation, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    diesel::update(escalations)
+        .filter(id.eq(escalation_id.to_string()))
+        .set((
+            reason.eq(_reason.as_str()),
+            level.eq(Level::Assigned.as_str()),
+            notified_at.eq(now),
+            acknowledged_by.eq(None::<String>),
+            acknowledged_at.eq(None::<NaiveDateTime>),
+            resolved_at.eq(None::<NaiveDateTime>),
+            updated_by.eq(actor.to_string()),
+            updated_at.eq(now),
+        ))
+        .execute(&conn)?;
+    Ok(escalations.filter(id.eq(escalation_id.to_string())).first(&conn)?)
+}
+
+/// Stop an escalation because someone is on it
+///
+/// Only the first acknowledgement counts, later ones are rejected.
+pub fn acknowledge(pool: &PoolType, escalation_id: Uuid, user_id: &str) -> Result<Escalation, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let acknowledged = diesel::update(escalations)
+        .filter(id.eq(escalation_id.to_string()))
+        .filter(acknowledged_at.is_null())
+        .filter(resolved_at.is_null())
+        .set((
+            acknowledged_by.eq(Some(user_id.to_string())),
+            acknowledged_at.eq(Some(now)),
+            updated_by.eq(user_id.to_string()),
+            updated_at.eq(now),
+        ))
+        .execute(&conn)?;
+    if acknowledged == 0 {
+        let closed = format!("escalation {} is no longer open", escalation_id);
+        return Err(ApiError::BadRequest(closed));
+    }
+    find(pool, escalation_id)
+}
+
+/// Close escalations whose occurrence no longer needs anyone
+pub fn resolve(pool: &PoolType, escalation_ids: &[String], actor: &str) -> Result<usize, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let resolved = diesel::update(escalations)
+        .filter(id.eq_any(escalation_ids))
+        .filter(resolved_at.is_null())
+        .set((resolved_at.eq(Some(now)), updated_by.eq(actor.to_string()), updated_at.eq(now)))
+        .execute(&conn)?;
+    Ok(resolved)
+}
+
+/// Close the escalations of a family left over from days before
+pub fn resolve_before(pool: &PoolType, _family_id: &str, before: NaiveDate, actor: &str) -> Result<usize, ApiError> {
+    use crate::schema::escalations::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let resolved = diesel::update(escalations)
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(day.lt(before))
+        .filter(resolved_at.is_null())
+        .set((resolved_at.eq(Some(now)), updated_by.eq(actor.to_string()), updated_at.eq(now)))
+        .execute(&conn)?;
+    Ok(resolved)
+}
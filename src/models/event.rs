@@ -1,12 +1,16 @@
-use crate::database::PoolType;
+use crate::database::{ConnectionType, PoolType};
 use crate::errors::ApiError;
 use crate::handlers::event::{EventResponse, EventsResponse};
 use crate::schema::events;
 use chrono::{NaiveDateTime, Utc, NaiveDate};
 use diesel::prelude::*;
 use uuid::Uuid;
+use crate::models::change::{record, ChangeAction, Entity, NewChange};
+use crate::models::family::{load_family, Family};
 use crate::models::subscription::Subscription;
 use diesel::dsl::sql;
+use std::fmt;
+use std::str::FromStr;
 
 
 #[derive(Clone, Debug, Serialize, Associations, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
@@ -24,6 +28,128 @@ pub struct Event {
     pub created_at: NaiveDateTime,
     pub updated_by: String,
     pub updated_at: NaiveDateTime,
+    pub kind: String,
+    pub actor_id: Option<String>,
+    pub occurred_at: NaiveDateTime,
+    /// Set when the event is deleted, it can still be restored
+    pub deleted_at: Option<NaiveDateTime>,
+    pub deleted_by: Option<String>,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        self.kind.parse().unwrap_or(EventKind::Note)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    DropOff,
+    Pickup,
+    Late,
+    Absent,
+    Note,
+    Alert,
+    Arrival,
+    Departure,
+}
+
+impl EventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::DropOff => "drop_off",
+            EventKind::Pickup => "pickup",
+            EventKind::Late => "late",
+            EventKind::Absent => "absent",
+            EventKind::Note => "note",
+            EventKind::Alert => "alert",
+            EventKind::Arrival => "arrival",
+            EventKind::Departure => "departure",
+        }
+    }
+}
+
+impl fmt::Display for EventKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = ApiError;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "drop_off" => Ok(EventKind::DropOff),
+            "pickup" => Ok(EventKind::Pickup),
+            "late" => Ok(EventKind::Late),
+            "absent" => Ok(EventKind::Absent),
+            "note" => Ok(EventKind::Note),
+            "alert" => Ok(EventKind::Alert),
+            "arrival" => Ok(EventKind::Arrival),
+            "departure" => Ok(EventKind::Departure),
+            _ => Err(ApiError::BadRequest(format!("unknown event kind {}", kind))),
+        }
+    }
+}
+
+/// Where an occurrence stands, given the events posted for it
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OccurrenceStatus {
+    Scheduled,
+    DroppedOff,
+    PickedUp,
+    Absent,
+}
+
+impl OccurrenceStatus {
+    /// The status after an event of the given kind, or an error when the
+    /// event makes no sense at this point of the occurrence
+    ///
+    /// Notes, alerts and geofence crossings can be posted at any time,
+    /// lateness only while the child hasn't been picked up yet.
+    pub fn next(self, kind: EventKind) -> Result<OccurrenceStatus, ApiError> {
+        use OccurrenceStatus::*;
+
+        match (self, kind) {
+            (_, EventKind::Note) | (_, EventKind::Alert) => Ok(self),
+            (_, EventKind::Arrival) | (_, EventKind::Departure) => Ok(self),
+            (Scheduled, EventKind::Late) | (DroppedOff, EventKind::Late) => Ok(self),
+            (Scheduled, EventKind::DropOff) => Ok(DroppedOff),
+            (Scheduled, EventKind::Pickup) | (DroppedOff, EventKind::Pickup) => Ok(PickedUp),
+            (Scheduled, EventKind::Absent) => Ok(Absent),
+            _ => Err(ApiError::BadRequest(format!(
+                "{} is not allowed once the occurrence is {:?}",
+                kind, self
+            ))),
+        }
+    }
+}
+
+/// Replay the events of an occurrence in order and return its status
+pub fn replay(events: &[Event]) -> Result<OccurrenceStatus, ApiError> {
+    let mut sorted: Vec<&Event> = events.iter().collect();
+    sorted.sort_by_key(|event| event.occurred_at);
+    sorted
+        .iter()
+        .try_fold(OccurrenceStatus::Scheduled, |status, event| status.next(event.kind()))
+}
+
+/// The status of an occurrence as stored, for reads
+///
+/// Unlike `replay` it never fails, events that don't fit the lifecycle at
+/// their point, like ones written before it was enforced, are skipped.
+pub fn status_of(events: &[Event]) -> OccurrenceStatus {
+    let mut sorted: Vec<&Event> = events.iter().collect();
+    sorted.sort_by_key(|event| event.occurred_at);
+    sorted.iter().fold(OccurrenceStatus::Scheduled, |status, event| {
+        status.next(event.kind()).unwrap_or_else(|_| {
+            log::warn!("event {} doesn't fit occurrence status {:?}", event.id, status);
+            status
+        })
+    })
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,6 +163,9 @@ pub struct NewEvent {
     pub message: String,
     pub created_by: String,
     pub updated_by: String,
+    pub kind: EventKind,
+    pub actor_id: Option<String>,
+    pub occurred_at: NaiveDateTime,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
@@ -50,28 +179,40 @@ pub struct UpdateEvent {
     pub day: String,
     pub message: String,
     pub updated_by: String,
+    pub kind: String,
+    pub actor_id: Option<String>,
+    pub occurred_at: NaiveDateTime,
 }
 
-pub fn get_all(pool: &PoolType) -> Result<EventsResponse, ApiError> {
+pub fn get_all(pool: &PoolType, _kind: Option<EventKind>) -> Result<EventsResponse, ApiError> {
     use crate::schema::events::dsl::*;
 
     let conn = pool.get()?;
-    let all = events.load(&conn)?;
+    let mut query = events.filter(deleted_at.is_null()).into_boxed();
+    if let Some(_kind) = _kind {
+        query = query.filter(kind.eq(_kind.as_str()));
+    }
+    let all = query.load(&conn)?;
 
     Ok(all.into())
 }
 
-pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid, _day: &String) -> Result<EventsResponse, ApiError> {
+pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid, _day: &String, _kind: Option<EventKind>) -> Result<EventsResponse, ApiError> {
     use crate::schema::events::dsl::*;
 
     println!("passage get_all_by_family_id");
     println!("{:?}", _family_id);
 
     let conn = pool.get()?;
-    let all = events
+    let mut query = events
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(day.eq(_day.to_string()))
-        .load(&conn)?;
+        .into_boxed();
+    if let Some(_kind) = _kind {
+        query = query.filter(kind.eq(_kind.as_str()));
+    }
+    let all = query.load(&conn)?;
     
     println!("passage get_all_by_family_id");
     println!("{:?}", _family_id);
@@ -85,6 +226,7 @@ pub fn get_all_by_family_user_place_sub(
     _place_id: Uuid, 
     _user_id: Uuid, 
     _subscription_id: Uuid, 
+    _kind: Option<EventKind>,
 ) -> Result<EventsResponse, ApiError> {
     use crate::schema::events::dsl::*;
 
@@ -92,14 +234,19 @@ pub fn get_all_by_family_user_place_sub(
     println!("dt {:?}", dt);
 
     let conn = pool.get()?;
-    let all: Vec<Event> = events
+    let mut query = events
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(place_id.eq(_place_id.to_string()))
         .filter(user_id.eq(_user_id.to_string()))
         .filter(subscription_id.eq(_subscription_id.to_string()))
         //.filter(created_at.gt(NaiveDate::from_ymd(dt.year(), dt.month(), dt.day()).and_hms(0, 0, 0)))
         .filter(sql(r#""events"."created_at" > CURRENT_DATE + interval '1 hour'"#))
-        .load(&conn)?;
+        .into_boxed();
+    if let Some(_kind) = _kind {
+        query = query.filter(kind.eq(_kind.as_str()));
+    }
+    let all: Vec<Event> = query.load(&conn)?;
 
         println!("**********************************");
         println!("get_all_by_family_user_place_sub");
@@ -111,46 +258,194 @@ pub fn get_all_by_family_user_place_sub(
 
 
 
+/// Get the events of one occurrence: a subscription on a given day of its
+/// family
+pub fn get_all_by_occurrence(
+    pool: &PoolType,
+    family: &Family,
+    _subscription_id: &str,
+    _day: NaiveDate,
+) -> Result<Vec<Event>, ApiError> {
+    let conn = pool.get()?;
+    find_all_by_occurrence(&conn, _subscription_id, family.day_bounds(_day))
+}
+
+fn find_all_by_occurrence(
+    conn: &ConnectionType,
+    _subscription_id: &str,
+    (start, end): (NaiveDateTime, NaiveDateTime),
+) -> Result<Vec<Event>, ApiError> {
+    use crate::schema::events::dsl::*;
+
+    let all = events
+        .filter(subscription_id.eq(_subscription_id.to_string()))
+        .filter(deleted_at.is_null())
+        .filter(occurred_at.ge(start))
+        .filter(occurred_at.lt(end))
+        .order(occurred_at.asc())
+        .load(conn)?;
+
+    Ok(all)
+}
+
 pub fn find(pool: &PoolType, event_id: Uuid) -> Result<EventResponse, ApiError> {
+    Ok(find_event(pool, &event_id.to_string())?.into())
+}
+
+pub fn find_event(pool: &PoolType, event_id: &str) -> Result<Event, ApiError> {
     use crate::schema::events::dsl::*;
 
     let not_found = format!("event {} not found", event_id);
     let conn = pool.get()?;
-    let event = events
+    events
         .filter(id.eq(event_id.to_string()))
+        .filter(deleted_at.is_null())
         .first::<Event>(&conn)
-        .map_err(|_| ApiError::NotFound(not_found))?;
+        .map_err(|_| ApiError::NotFound(not_found))
+}
 
-    Ok(event.into())
+/// Find an event, deleted or not
+pub fn find_with_deleted(pool: &PoolType, event_id: &str) -> Result<Event, ApiError> {
+    use crate::schema::events::dsl::*;
+
+    let not_found = format!("event {} not found", event_id);
+    let conn = pool.get()?;
+    events
+        .filter(id.eq(event_id.to_string()))
+        .first::<Event>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
 }
 
+/// Create an event, if it fits the lifecycle of its occurrence
 pub fn create(pool: &PoolType, new_event: &Event) -> Result<EventResponse, ApiError> {
-    use crate::schema::events::dsl::*;
     let conn = pool.get()?;
 
-    diesel::insert_into(events).values(new_event).execute(&conn)?;
+    conn.transaction::<_, ApiError, _>(|| {
+        check_transition(&conn, new_event)?;
+        insert(&conn, new_event)
+    })?;
     Ok(new_event.clone().into())
 }
 
+/// Insert an event and record its creation, inside the caller's
+/// transaction if any
+pub fn insert(conn: &ConnectionType, new_event: &Event) -> Result<(), ApiError> {
+    use crate::schema::events::dsl::*;
+
+    diesel::insert_into(events).values(new_event).execute(conn)?;
+    let change = NewChange::new(
+        Entity::Event,
+        &new_event.id,
+        &new_event.family_id,
+        ChangeAction::Created,
+        None,
+        Some(new_event),
+        &new_event.created_by,
+    );
+    record(conn, &change)
+}
+
+/// Update an event, if its new version fits the lifecycle of its
+/// occurrence, keeping the previous version in its history
 pub fn update(pool: &PoolType, update_event: &UpdateEvent) -> Result<EventResponse, ApiError> {
     use crate::schema::events::dsl::*;
 
+    let not_found = format!("event {} not found", update_event.id);
     let conn = pool.get()?;
-    diesel::update(events)
-        .filter(id.eq(update_event.id.clone()))
-        .set(update_event)
-        .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_event.id)?)
+    let event = conn.transaction::<_, ApiError, _>(|| {
+        let before = events
+            .filter(id.eq(update_event.id.clone()))
+            .filter(deleted_at.is_null())
+            .first::<Event>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found))?;
+        let candidate = Event {
+            subscription_id: update_event.subscription_id.clone(),
+            kind: update_event.kind.clone(),
+            occurred_at: update_event.occurred_at,
+            ..before.clone()
+        };
+        check_transition(&conn, &candidate)?;
+        diesel::update(events)
+            .filter(id.eq(update_event.id.clone()))
+            .set(update_event)
+            .execute(&conn)?;
+        let after = events.filter(id.eq(update_event.id.clone())).first::<Event>(&conn)?;
+        let change = NewChange::new(
+            Entity::Event,
+            &after.id,
+            &after.family_id,
+            ChangeAction::Updated,
+            Some(&before),
+            Some(&after),
+            &update_event.updated_by,
+        );
+        record(&conn, &change)?;
+        Ok(after)
+    })?;
+    Ok(event.into())
 }
 
-pub fn delete(pool: &PoolType, event_id: Uuid) -> Result<(), ApiError> {
+/// Delete an event, it stays in the database and can be restored
+pub fn delete(pool: &PoolType, event_id: Uuid, actor: &str) -> Result<(), ApiError> {
+    set_deleted(pool, &event_id.to_string(), actor, true).map(|_| ())
+}
+
+/// Restore a deleted event
+pub fn restore(pool: &PoolType, event_id: Uuid, actor: &str) -> Result<EventResponse, ApiError> {
+    Ok(set_deleted(pool, &event_id.to_string(), actor, false)?.into())
+}
+
+fn set_deleted(pool: &PoolType, event_id: &str, actor: &str, deleted: bool) -> Result<Event, ApiError> {
     use crate::schema::events::dsl::*;
 
+    let not_found = format!("event {} not found", event_id);
+    let now = Utc::now().naive_utc();
     let conn = pool.get()?;
-    diesel::delete(events)
-        .filter(id.eq(event_id.to_string()))
-        .execute(&conn)?;
-    Ok(())
+    conn.transaction::<_, ApiError, _>(|| {
+        let before = events
+            .filter(id.eq(event_id.to_string()))
+            .first::<Event>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found.clone()))?;
+        match (before.deleted_at.is_some(), deleted) {
+            (true, true) => return Err(ApiError::NotFound(not_found)),
+            (false, false) => return Err(ApiError::BadRequest(format!("event {} is not deleted", event_id))),
+            _ => (),
+        }
+        let (at, by) = if deleted { (Some(now), Some(actor.to_string())) } else { (None, None) };
+        diesel::update(events)
+            .filter(id.eq(event_id.to_string()))
+            .set((deleted_at.eq(at), deleted_by.eq(by)))
+            .execute(&conn)?;
+        let after = events.filter(id.eq(event_id.to_string())).first::<Event>(&conn)?;
+        let action = if deleted { ChangeAction::Deleted } else { ChangeAction::Restored };
+        let change = NewChange::new(Entity::Event, &after.id, &after.family_id, action, Some(&before), Some(&after), actor);
+        record(&conn, &change)?;
+        Ok(after)
+    })
+}
+
+/// Check that an event is a valid step in the lifecycle of its occurrence
+///
+/// The other events of the occurrence are replayed with this one, an event
+/// being updated is replaced by its new version. Meant for the transaction
+/// writing the event, the subscription stays locked until it ends so
+/// concurrent writes are checked one after the other. The occurrence is the
+/// day of the event on the family's clock.
+pub fn check_transition(conn: &ConnectionType, event: &Event) -> Result<OccurrenceStatus, ApiError> {
+    use crate::schema::subscriptions::dsl::{id, subscriptions};
+
+    subscriptions
+        .filter(id.eq(event.subscription_id.clone()))
+        .select(id)
+        .for_update()
+        .first::<String>(conn)
+        .optional()?;
+    let family = load_family(conn, &event.family_id)?;
+    let day = family.to_local(event.occurred_at).date();
+    let mut occurrence = find_all_by_occurrence(conn, &event.subscription_id, family.day_bounds(day))?;
+    occurrence.retain(|other| other.id != event.id);
+    occurrence.push(event.clone());
+    replay(&occurrence)
 }
 
 impl From<NewEvent> for Event {
@@ -167,6 +462,80 @@ impl From<NewEvent> for Event {
             created_at: Utc::now().naive_utc(),
             updated_by: event.updated_by,
             updated_at: Utc::now().naive_utc(),
+            kind: event.kind.as_str().into(),
+            actor_id: event.actor_id,
+            occurred_at: event.occurred_at,
+            deleted_at: None,
+            deleted_by: None,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind, minute: u32) -> Event {
+        NewEvent {
+            id: Uuid::new_v4().to_string(),
+            family_id: "family".into(),
+            subscription_id: "subscription".into(),
+            place_id: "place".into(),
+            user_id: "user".into(),
+            day: "lundi".into(),
+            message: "".into(),
+            created_by: "".into(),
+            updated_by: "".into(),
+            kind,
+            actor_id: None,
+            occurred_at: NaiveDate::from_ymd(2020, 10, 12).and_hms(8, minute, 0),
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_parses_event_kinds() {
+        assert_eq!("drop_off".parse::<EventKind>().unwrap(), EventKind::DropOff);
+        assert_eq!(EventKind::Pickup.to_string(), "pickup");
+        assert!("dropped".parse::<EventKind>().is_err());
+    }
+
+    #[test]
+    fn it_replays_a_valid_occurrence() {
+        let events = vec![
+            event(EventKind::Pickup, 30),
+            event(EventKind::Late, 5),
+            event(EventKind::DropOff, 10),
+            event(EventKind::Note, 20),
+        ];
+        assert_eq!(replay(&events).unwrap(), OccurrenceStatus::PickedUp);
+        assert_eq!(replay(&[]).unwrap(), OccurrenceStatus::Scheduled);
+    }
+
+    #[test]
+    fn it_rejects_invalid_transitions() {
+        let picked_up_twice = vec![event(EventKind::Pickup, 0), event(EventKind::Pickup, 1)];
+        assert!(replay(&picked_up_twice).is_err());
+
+        let absent_then_dropped = vec![event(EventKind::Absent, 0), event(EventKind::DropOff, 1)];
+        assert!(replay(&absent_then_dropped).is_err());
+
+        let late_after_pickup = vec![event(EventKind::Pickup, 0), event(EventKind::Late, 1)];
+        assert!(replay(&late_after_pickup).is_err());
+    }
+
+    #[test]
+    fn it_skips_stored_events_that_dont_fit() {
+        let picked_up_twice = vec![event(EventKind::DropOff, 0), event(EventKind::Pickup, 1), event(EventKind::Pickup, 2)];
+        assert_eq!(status_of(&picked_up_twice), OccurrenceStatus::PickedUp);
+
+        let absent_then_dropped = vec![event(EventKind::Absent, 0), event(EventKind::DropOff, 1)];
+        assert_eq!(status_of(&absent_then_dropped), OccurrenceStatus::Absent);
+    }
+
+    #[test]
+    fn it_allows_notes_and_alerts_at_any_time() {
+        let events = vec![event(EventKind::Absent, 0), event(EventKind::Alert, 1), event(EventKind::Note, 2)];
+        assert_eq!(replay(&events).unwrap(), OccurrenceStatus::Absent);
+    }
+}
@@ -1,9 +1,12 @@
-use crate::database::PoolType;
+use crate::config::CONFIG;
+use crate::database::{ConnectionType, PoolType};
 use crate::errors::ApiError;
 use crate::handlers::family::{FamilyResponse, FamiliesResponse};
 use crate::schema::families;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
 use diesel::prelude::*;
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
@@ -16,6 +19,61 @@ pub struct Family {
     pub created_at: NaiveDateTime,
     pub updated_by: String,
     pub updated_at: NaiveDateTime,
+    /// IANA name, days of the family start and end in this timezone
+    pub timezone: String,
+    /// Days positions are kept, the system default when None
+    pub location_retention_days: Option<i32>,
+}
+
+/// Timezone of families that didn't pick one
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+
+impl Family {
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// The current date for the family
+    pub fn today(&self) -> NaiveDate {
+        Utc::now().with_timezone(&self.tz()).date().naive_local()
+    }
+
+    /// How long positions of the members are kept
+    pub fn location_retention(&self) -> Duration {
+        self.location_retention_days
+            .map_or_else(|| Duration::days(CONFIG.location_retention_days), |days| Duration::days(days.into()))
+    }
+
+    /// A time on the family's clock in UTC
+    pub fn to_utc(&self, local: NaiveDateTime) -> NaiveDateTime {
+        to_utc(self.tz(), local)
+    }
+
+    /// A UTC time on the family's clock
+    pub fn to_local(&self, utc: NaiveDateTime) -> NaiveDateTime {
+        Utc.from_utc_datetime(&utc).with_timezone(&self.tz()).naive_local()
+    }
+
+    /// Start and end in UTC of a day of the family
+    pub fn day_bounds(&self, date: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+        day_bounds(self.tz(), date)
+    }
+}
+
+/// A local time of a timezone in UTC
+///
+/// Times repeated when clocks go back take their first instant, times in a
+/// DST gap the instant an hour later.
+pub fn to_utc(tz: Tz, local: NaiveDateTime) -> NaiveDateTime {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local + Duration::hours(1))).earliest())
+        .map_or(local, |time| time.naive_utc())
+}
+
+/// Start and end in UTC of a day in a timezone
+pub fn day_bounds(tz: Tz, date: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+    (to_utc(tz, date.and_hms(0, 0, 0)), to_utc(tz, date.succ().and_hms(0, 0, 0)))
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -25,6 +83,8 @@ pub struct NewFamily{
     pub code: String,
     pub created_by: String,
     pub updated_by: String,
+    pub timezone: String,
+    pub location_retention_days: Option<i32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
@@ -34,15 +94,15 @@ pub struct UpdateFamily {
     pub nom: String,
     pub code: String,
     pub updated_by: String,
+    /// Left unchanged when None
+    pub timezone: Option<String>,
+    /// Left unchanged when None, back to the system retention when Some(None)
+    pub location_retention_days: Option<Option<i32>>,
 }
 
 /// Get all families
 pub fn get_all(pool: &PoolType) -> Result<FamiliesResponse, ApiError> {
-    use crate::schema::families::dsl::*;
-    let conn = pool.get()?;
-    let all_families = families.load(&conn)?;
-
-    Ok(all_families.into())
+    Ok(find_families(pool)?.into())
 }
 
 pub fn find_by_code(pool: &PoolType, _code: &String) -> Result<FamilyResponse, ApiError> {
@@ -58,6 +118,47 @@ pub fn find_by_code(pool: &PoolType, _code: &String) -> Result<FamilyResponse, A
     Ok(family.into())
 }
 
+/// Find the family row itself
+pub fn find_family(pool: &PoolType, family_id: &str) -> Result<Family, ApiError> {
+    let conn = pool.get()?;
+    load_family(&conn, family_id)
+}
+
+/// Find the family row itself, inside the caller's transaction if any
+pub fn load_family(conn: &ConnectionType, family_id: &str) -> Result<Family, ApiError> {
+    use crate::schema::families::dsl::{id, families};
+
+    let not_found = format!("Family {} not found", family_id);
+    families
+        .filter(id.eq(family_id.to_string()))
+        .first::<Family>(conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// The ids of the families by how many days they keep positions, the
+/// default always among them for families without a retention of their own
+pub fn find_by_retention(pool: &PoolType, default_days: i64) -> Result<BTreeMap<i64, Vec<String>>, ApiError> {
+    use crate::schema::families::dsl::{families, id, location_retention_days};
+
+    let conn = pool.get()?;
+    let all: Vec<(String, Option<i32>)> = families.select((id, location_retention_days)).load(&conn)?;
+    let mut by_retention = BTreeMap::new();
+    by_retention.insert(default_days, vec![]);
+    for (family_id, days) in all {
+        let days = days.map_or(default_days, i64::from);
+        by_retention.entry(days).or_insert_with(Vec::new).push(family_id);
+    }
+    Ok(by_retention)
+}
+
+/// Get the family rows themselves
+pub fn find_families(pool: &PoolType) -> Result<Vec<Family>, ApiError> {
+    use crate::schema::families::dsl::families;
+
+    let conn = pool.get()?;
+    Ok(families.load(&conn)?)
+}
+
 /// Find a family by the family's id or error out
 pub fn find(pool: &PoolType, family_id: Uuid) -> Result<FamilyResponse, ApiError> {
     use crate::schema::families::dsl::{id, families};
@@ -114,6 +215,28 @@ impl From<NewFamily> for Family {
             created_at: Utc::now().naive_utc(),
             updated_by: family.updated_by,
             updated_at: Utc::now().naive_utc(),
+            timezone: family.timezone,
+            location_retention_days: family.location_retention_days,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_gets_the_bounds_of_a_day_in_a_timezone() {
+        let date = NaiveDate::from_ymd(2020, 10, 12);
+        let (start, end) = day_bounds(Tz::Europe__Paris, date);
+        assert_eq!(start, NaiveDate::from_ymd(2020, 10, 11).and_hms(22, 0, 0));
+        assert_eq!(end, date.and_hms(22, 0, 0));
+    }
+
+    #[test]
+    fn it_handles_daylight_saving_time() {
+        // Clocks go back on the night of October 25th in Paris
+        let (start, end) = day_bounds(Tz::Europe__Paris, NaiveDate::from_ymd(2020, 10, 25));
+        assert_eq!(end - start, Duration::hours(25));
+    }
+}
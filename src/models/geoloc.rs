@@ -1,11 +1,9 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
-use crate::handlers::geoloc::{GeolocResponse, GeolocsResponse};
 use crate::schema::geolocs;
-use chrono::{NaiveDateTime, Utc};
+use crate::models::family::find_by_retention;
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel::prelude::*;
-use uuid::Uuid;
-use diesel::dsl::sql;
 
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
@@ -15,16 +13,35 @@ pub struct Geoloc {
     pub user_id: String,
     pub latitude: f64,
     pub longitude: f64,
+    /// When the position was taken, by the device clock when it sent one
     pub created_at: NaiveDateTime,
+    /// Meters
+    pub accuracy: Option<f64>,
+    /// Meters per second
+    pub speed: Option<f64>,
+    /// Degrees clockwise from north
+    pub heading: Option<f64>,
+    /// Meters
+    pub altitude: Option<f64>,
+    /// Percent
+    pub battery: Option<i32>,
+    /// When the server got the position
+    pub received_at: NaiveDateTime,
 }
 
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct NewGeoloc{
     pub id: String,
     pub user_id: String,
     pub latitude: f64,
     pub longitude: f64,
+    pub recorded_at: Option<NaiveDateTime>,
+    pub accuracy: Option<f64>,
+    pub speed: Option<f64>,
+    pub heading: Option<f64>,
+    pub altitude: Option<f64>,
+    pub battery: Option<i32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
@@ -36,39 +53,305 @@ pub struct UpdateGeoloc {
     pub longitude: f64,
 }
 
-pub fn find_today(pool: &PoolType, _user_id: Uuid) -> Result<GeolocsResponse, ApiError> {
+/// Get the positions of a user between two times, most recent first
+///
+/// Paginated when given an offset and a limit.
+pub fn find_between(
+    pool: &PoolType,
+    _user_id: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    page: Option<(i64, i64)>,
+) -> Result<Vec<Geoloc>, ApiError> {
     use crate::schema::geolocs::dsl::*;
 
-    let not_found = format!("geoloc {} not found", _user_id);
     let conn = pool.get()?;
-    let geoloc = geolocs
+    let mut query = geolocs
         .filter(user_id.eq(_user_id.to_string()))
-        .filter(sql(r#""geolocs"."created_at" > CURRENT_DATE + interval '1 hour'"#))
+        .filter(created_at.ge(from))
+        .filter(created_at.lt(to))
         .order(created_at.desc())
-        //.first::<Geoloc>(&conn)
-        .load(&conn)?;
+        .into_boxed();
+    if let Some((offset, limit)) = page {
+        query = query.offset(offset).limit(limit);
+    }
+    Ok(query.load(&conn)?)
+}
+
+/// Where to resume reading a range, the time and id of the last position read
+pub type Cursor = (NaiveDateTime, String);
+
+/// Get at most `limit` positions of a user between two times, oldest
+/// first, after a cursor when reading a range chunk by chunk
+pub fn find_chunk(
+    pool: &PoolType,
+    _user_id: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    after: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<Geoloc>, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let conn = pool.get()?;
+    let mut query = geolocs
+        .filter(user_id.eq(_user_id.to_string()))
+        .filter(created_at.ge(from))
+        .filter(created_at.lt(to))
+        .into_boxed();
+    // Concurrent uploads may store two positions at the same time, they are
+    // told apart by id
+    if let Some((after_at, after_id)) = after {
+        query = query.filter(created_at.gt(after_at).or(created_at.eq(after_at).and(id.gt(after_id))));
+    }
+    Ok(query.order((created_at.asc(), id.asc())).limit(limit).load(&conn)?)
+}
 
-    Ok(geoloc.into())
+/// The indexes of at most `max_points` positions out of `count`, evenly
+/// spread over the track, always keeping the first and the last
+fn kept_indexes(count: usize, max_points: usize) -> Vec<usize> {
+    if count <= max_points || max_points == 0 {
+        return (0..count).collect();
+    }
+    if max_points == 1 {
+        return vec![0];
+    }
+    (0..max_points).map(|index| index * (count - 1) / (max_points - 1)).collect()
 }
 
-/// Create a new geoloc
-pub fn create(pool: &PoolType, new_geoloc: &Geoloc) -> Result<GeolocResponse, ApiError> {
+/// Positions read at once while downsampling a range
+const DOWNSAMPLE_CHUNK: i64 = 1000;
+
+/// Get the positions of a user between two times downsampled to at most
+/// `max_points`, see `kept_indexes`, most recent first
+///
+/// The range is read chunk by chunk, oldest first, so only the kept
+/// positions are ever held however many there are.
+pub fn find_downsampled(
+    pool: &PoolType,
+    _user_id: &str,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    max_points: usize,
+) -> Result<Vec<Geoloc>, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let count: i64 = {
+        let conn = pool.get()?;
+        geolocs
+            .filter(user_id.eq(_user_id.to_string()))
+            .filter(created_at.ge(from))
+            .filter(created_at.lt(to))
+            .count()
+            .get_result(&conn)?
+    };
+    let mut keep = kept_indexes(count as usize, max_points).into_iter().peekable();
+
+    let mut kept = vec![];
+    let mut index = 0;
+    let mut after = None;
+    while keep.peek().is_some() {
+        let chunk = find_chunk(pool, _user_id, from, to, after, DOWNSAMPLE_CHUNK)?;
+        let last = match chunk.last() {
+            Some(last) => (last.created_at, last.id.clone()),
+            None => break,
+        };
+        for geoloc in chunk {
+            if keep.peek() == Some(&index) {
+                keep.next();
+                kept.push(geoloc);
+            }
+            index += 1;
+        }
+        after = Some(last);
+    }
+    kept.reverse();
+    Ok(kept)
+}
+
+/// The most recent position of a user
+pub fn find_latest(pool: &PoolType, _user_id: &str) -> Result<Option<Geoloc>, ApiError> {
     use crate::schema::geolocs::dsl::*;
 
     let conn = pool.get()?;
-    diesel::insert_into(geolocs).values(new_geoloc).execute(&conn)?;
-    Ok(new_geoloc.clone().into())
+    let latest = geolocs
+        .filter(user_id.eq(_user_id.to_string()))
+        .order(created_at.desc())
+        .first::<Geoloc>(&conn)
+        .optional()?;
+    Ok(latest)
 }
 
+/// The time of the most recent position of a user
+pub fn find_latest_at(pool: &PoolType, _user_id: &str) -> Result<Option<NaiveDateTime>, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let conn = pool.get()?;
+    let latest = geolocs
+        .filter(user_id.eq(_user_id.to_string()))
+        .select(diesel::dsl::max(created_at))
+        .first(&conn)?;
+    Ok(latest)
+}
+
+/// The position of a user stored for a time
+pub fn find_at(pool: &PoolType, _user_id: &str, at: NaiveDateTime) -> Result<Geoloc, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let not_found = format!("no position of user {} at {}", _user_id, at);
+    let conn = pool.get()?;
+    geolocs
+        .filter(user_id.eq(_user_id.to_string()))
+        .filter(created_at.eq(at))
+        .first::<Geoloc>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Insert the positions of a user in one transaction, skipping the ones
+/// already stored for the same time. Returns the inserted positions, oldest
+/// first.
+pub fn create_batch(pool: &PoolType, _user_id: &str, batch: Vec<Geoloc>) -> Result<Vec<Geoloc>, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let mut batch = dedupe(batch);
+    let (first, last) = match (batch.first(), batch.last()) {
+        (Some(first), Some(last)) => (first.created_at, last.created_at),
+        _ => return Ok(vec![]),
+    };
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let existing: Vec<NaiveDateTime> = geolocs
+            .filter(user_id.eq(_user_id.to_string()))
+            .filter(created_at.between(first, last))
+            .select(created_at)
+            .load(&conn)?;
+        batch.retain(|geoloc| !existing.contains(&geoloc.created_at));
+        diesel::insert_into(geolocs).values(&batch).execute(&conn)?;
+        Ok(batch)
+    })
+}
+
+/// Most positions deleted per statement, so purging never holds long locks
+pub const PURGE_BATCH: i64 = 1000;
+
+/// Delete a batch of positions of a user, the ones taken before a time or
+/// all of them. Returns how many were deleted, less than `PURGE_BATCH` once
+/// there are none left.
+pub fn delete_batch(pool: &PoolType, _user_id: &str, before: Option<NaiveDateTime>) -> Result<usize, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+
+    let conn = pool.get()?;
+    let mut query = geolocs
+        .filter(user_id.eq(_user_id.to_string()))
+        .select(id)
+        .into_boxed();
+    if let Some(before) = before {
+        query = query.filter(created_at.lt(before));
+    }
+    let ids: Vec<String> = query.limit(PURGE_BATCH).load(&conn)?;
+    if ids.is_empty() {
+        return Ok(0);
+    }
+    Ok(diesel::delete(geolocs.filter(id.eq_any(ids))).execute(&conn)?)
+}
+
+/// Delete a batch of positions older than the retention of their user's
+/// family, or the default for users without one. Returns the user of each
+/// deleted position, less than `PURGE_BATCH` once there are none left.
+pub fn delete_expired(pool: &PoolType, now: NaiveDateTime, default_days: i64) -> Result<Vec<String>, ApiError> {
+    use crate::schema::geolocs::dsl::*;
+    use crate::schema::users;
+
+    let mut expired: Vec<(String, String)> = vec![];
+    for (days, family_ids) in find_by_retention(pool, default_days)? {
+        let remaining = PURGE_BATCH - expired.len() as i64;
+        if remaining <= 0 {
+            break;
+        }
+        let mut members = users::table
+            .select(users::id)
+            .filter(users::family_id.eq_any(family_ids))
+            .into_boxed();
+        if days == default_days {
+            members = members.or_filter(users::family_id.is_null());
+        }
+        let conn = pool.get()?;
+        expired.extend(
+            geolocs
+                .select((id, user_id))
+                .filter(user_id.eq_any(members))
+                .filter(created_at.lt(now - Duration::days(days)))
+                .limit(remaining)
+                .load::<(String, String)>(&conn)?,
+        );
+    }
+    if expired.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let conn = pool.get()?;
+    let (ids, user_ids): (Vec<String>, Vec<String>) = expired.into_iter().unzip();
+    diesel::delete(geolocs.filter(id.eq_any(ids))).execute(&conn)?;
+    Ok(user_ids)
+}
+
+/// Sort positions by time and keep one per timestamp
+pub fn dedupe(mut batch: Vec<Geoloc>) -> Vec<Geoloc> {
+    batch.sort_by_key(|geoloc| geoloc.created_at);
+    batch.dedup_by_key(|geoloc| geoloc.created_at);
+    batch
+}
 
 impl From<NewGeoloc> for Geoloc {
     fn from(geoloc: NewGeoloc) -> Self {
+        let now = Utc::now().naive_utc();
         Geoloc {
             id: geoloc.id,
             user_id: geoloc.user_id,
             latitude: geoloc.latitude,
             longitude: geoloc.longitude,
-            created_at: Utc::now().naive_utc(),
+            created_at: geoloc.recorded_at.unwrap_or(now),
+            accuracy: geoloc.accuracy,
+            speed: geoloc.speed,
+            heading: geoloc.heading,
+            altitude: geoloc.altitude,
+            battery: geoloc.battery,
+            received_at: now,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    fn geoloc(second: u32) -> Geoloc {
+        NewGeoloc {
+            id: Uuid::new_v4().to_string(),
+            user_id: "user".into(),
+            latitude: 48.8566,
+            longitude: 2.3522,
+            recorded_at: Some(NaiveDate::from_ymd(2020, 10, 12).and_hms(8, 0, second)),
+            ..NewGeoloc::default()
+        }
+        .into()
+    }
+
+    #[test]
+    fn it_downsamples_a_track() {
+        assert_eq!(kept_indexes(10, 4), vec![0, 3, 6, 9]);
+        assert_eq!(kept_indexes(10, 20).len(), 10);
+        assert_eq!(kept_indexes(10, 1), vec![0]);
+        assert_eq!(kept_indexes(3, 0), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn it_dedupes_positions_by_time() {
+        let batch = dedupe(vec![geoloc(30), geoloc(10), geoloc(30), geoloc(20)]);
+        let seconds: Vec<_> = batch.iter().map(|geoloc| geoloc.created_at.time().format("%S").to_string()).collect();
+        assert_eq!(seconds, vec!["10", "20", "30"]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,127 @@
+//! One-time codes a pickup person shows to get the child handed over
+//!
+//! A code is for one occurrence and one person, and expires at the end of
+//! the family's day. Confirming it records the pickup event and who took
+//! the child, once.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::event::{check_transition, insert, Event};
+use crate::schema::handover_codes;
+use chrono::{NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+
+/// No 0/O nor 1/I, codes are read out loud and typed by hand
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "handover_codes"]
+pub struct HandoverCode {
+    pub id: String,
+    pub family_id: String,
+    pub subscription_id: String,
+    pub day: NaiveDate,
+    pub child_id: String,
+    /// None once the pickup person was deleted
+    pub pickup_person_id: Option<String>,
+    /// Kept so the handover still says who took the child
+    pub pickup_name: String,
+    pub code: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    /// The member who confirmed, None when confirmed without an account
+    pub confirmed_by: Option<String>,
+    /// The pickup event recorded on confirmation
+    pub event_id: Option<String>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl HandoverCode {
+    pub fn is_usable_at(&self, at: NaiveDateTime) -> bool {
+        self.used_at.is_none() && at < self.expires_at
+    }
+}
+
+/// A fresh code, 8 characters easy to read out
+pub fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0, CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+/// A code as typed, whatever the case and separators
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// Find a handover from its code
+pub fn find_by_code(pool: &PoolType, _code: &str) -> Result<HandoverCode, ApiError> {
+    use crate::schema::handover_codes::dsl::*;
+
+    let conn = pool.get()?;
+    handover_codes
+        .filter(code.eq(normalize(_code)))
+        .first::<HandoverCode>(&conn)
+        .map_err(|_| ApiError::NotFound("handover code not found".into()))
+}
+
+pub fn create(pool: &PoolType, new_handover: &HandoverCode) -> Result<HandoverCode, ApiError> {
+    use crate::schema::handover_codes::dsl::handover_codes;
+
+    let conn = pool.get()?;
+    diesel::insert_into(handover_codes).values(new_handover).execute(&conn)?;
+    Ok(new_handover.clone())
+}
+
+/// Use a code and record the pickup event together, unless the code was
+/// used or expired in the meantime or the child can't be picked up anymore
+pub fn confirm(pool: &PoolType, handover_id: &str, _confirmed_by: &str, event: &Event) -> Result<(), ApiError> {
+    use crate::schema::handover_codes::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    conn.transaction::<_, ApiError, _>(|| {
+        check_transition(&conn, event)?;
+        insert(&conn, event)?;
+        let confirmed = diesel::update(handover_codes)
+            .filter(id.eq(handover_id.to_string()))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(now))
+            .set((
+                used_at.eq(Some(now)),
+                confirmed_by.eq(Some(_confirmed_by.to_string())),
+                event_id.eq(Some(event.id.clone())),
+            ))
+            .execute(&conn)?;
+        if confirmed == 0 {
+            return Err(ApiError::BadRequest("handover code was already used or expired".into()));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_makes_codes_easy_to_read_out() {
+        let code = new_code();
+        assert_eq!(code.len(), CODE_LENGTH);
+        assert!(code.bytes().all(|c| CODE_ALPHABET.contains(&c)));
+        assert_ne!(code, new_code());
+    }
+
+    #[test]
+    fn it_normalizes_typed_codes() {
+        assert_eq!(normalize("abcd-efgh"), "ABCDEFGH");
+        assert_eq!(normalize(" AB CD EF GH "), "ABCDEFGH");
+    }
+}
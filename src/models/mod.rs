@@ -1,6 +1,23 @@
 pub mod user;
 pub mod family;
 pub mod place;
+pub mod place_staff;
 pub mod subscription;
 pub mod event;
 pub mod geoloc;
+pub mod occurrence;
+pub mod cover_request;
+pub mod activity;
+pub mod presence;
+pub mod sharing;
+pub mod emergency_contact;
+pub mod escalation;
+pub mod sos;
+pub mod pickup_person;
+pub mod handover;
+pub mod child_card;
+pub mod chat;
+pub mod acknowledgement;
+pub mod attachment;
+pub mod change;
+pub mod audit;
@@ -0,0 +1,197 @@
+//! An occurrence is a subscription on one given day.
+//!
+//! Occurrences are not stored unless the responsible adult of that day
+//! differs from the subscription's, in which case a row overrides the
+//! assignment for that single day.
+
+use crate::database::{ConnectionType, PoolType};
+use crate::errors::ApiError;
+use crate::models::subscription::Subscription;
+use crate::schema::occurrences;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "occurrences"]
+pub struct Occurrence {
+    pub id: String,
+    pub family_id: String,
+    pub subscription_id: String,
+    pub day: NaiveDate,
+    pub user_id: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+/// A subscription expanded on one day, with the adult responsible that day
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledOccurrence {
+    pub subscription: Subscription,
+    pub day: NaiveDate,
+    pub user_id: String,
+}
+
+impl ScheduledOccurrence {
+    pub fn window(&self) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        self.subscription.window_on(self.day)
+    }
+}
+
+/// Expand subscriptions into occurrences between two dates (inclusive),
+/// applying the assignment overrides
+pub fn expand(
+    subscriptions: &[Subscription],
+    assignments: &[Occurrence],
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Vec<ScheduledOccurrence> {
+    let mut expanded = vec![];
+    let mut day = from;
+    while day <= to {
+        for subscription in subscriptions.iter().filter(|subscription| subscription.runs_on(day)) {
+            let user_id = assignments
+                .iter()
+                .find(|occurrence| occurrence.subscription_id == subscription.id && occurrence.day == day)
+                .map_or_else(|| subscription.user_id.clone(), |occurrence| occurrence.user_id.clone());
+            expanded.push(ScheduledOccurrence {
+                subscription: subscription.clone(),
+                day,
+                user_id,
+            });
+        }
+        day = day + Duration::days(1);
+    }
+    expanded
+}
+
+/// Get the assignment overrides of a family between two dates (inclusive)
+pub fn get_assignments(
+    pool: &PoolType,
+    _family_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<Occurrence>, ApiError> {
+    use crate::schema::occurrences::dsl::*;
+
+    let conn = pool.get()?;
+    let all = occurrences
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(day.between(from, to))
+        .load(&conn)?;
+
+    Ok(all)
+}
+
+/// Find the assignment override of a subscription on a given day
+pub fn find_assignment(
+    pool: &PoolType,
+    _subscription_id: &str,
+    _day: NaiveDate,
+) -> Result<Option<Occurrence>, ApiError> {
+    use crate::schema::occurrences::dsl::*;
+
+    let conn = pool.get()?;
+    let occurrence = occurrences
+        .filter(subscription_id.eq(_subscription_id.to_string()))
+        .filter(day.eq(_day))
+        .first::<Occurrence>(&conn)
+        .optional()?;
+
+    Ok(occurrence)
+}
+
+/// The adult responsible for a subscription on a given day
+pub fn assigned_user_id(pool: &PoolType, subscription: &Subscription, day: NaiveDate) -> Result<String, ApiError> {
+    let assignment = find_assignment(pool, &subscription.id, day)?;
+    Ok(assignment.map_or_else(|| subscription.user_id.clone(), |occurrence| occurrence.user_id))
+}
+
+/// Assign another adult to a subscription for a single day
+///
+/// Takes a connection so it can run inside the caller's transaction.
+pub fn reassign(
+    conn: &ConnectionType,
+    _family_id: &str,
+    _subscription_id: &str,
+    _day: NaiveDate,
+    _user_id: &str,
+    actor: &str,
+) -> Result<Occurrence, ApiError> {
+    use crate::schema::occurrences::dsl::*;
+
+    diesel::delete(occurrences)
+        .filter(subscription_id.eq(_subscription_id.to_string()))
+        .filter(day.eq(_day))
+        .execute(conn)?;
+
+    let now = Utc::now().naive_utc();
+    let occurrence = Occurrence {
+        id: Uuid::new_v4().to_string(),
+        family_id: _family_id.to_string(),
+        subscription_id: _subscription_id.to_string(),
+        day: _day,
+        user_id: _user_id.to_string(),
+        created_by: actor.to_string(),
+        created_at: now,
+        updated_by: actor.to_string(),
+        updated_at: now,
+    };
+    diesel::insert_into(occurrences).values(&occurrence).execute(conn)?;
+    Ok(occurrence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(days: &str, user_id: &str) -> Subscription {
+        Subscription {
+            id: Uuid::new_v4().to_string(),
+            family_id: "family".into(),
+            place_id: "place".into(),
+            user_id: user_id.into(),
+            days: days.into(),
+            created_by: "".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "".into(),
+            updated_at: Utc::now().naive_utc(),
+            child_id: None,
+            start_time: None,
+            end_time: None,
+            deleted_at: None,
+            deleted_by: None,
+        }
+    }
+
+    #[test]
+    fn it_expands_subscriptions_with_overrides() {
+        let monday = NaiveDate::from_ymd(2020, 10, 12);
+        let next_monday = NaiveDate::from_ymd(2020, 10, 19);
+        let subscription = subscription("lundi,mercredi", "mom");
+        let assignment = Occurrence {
+            id: Uuid::new_v4().to_string(),
+            family_id: "family".into(),
+            subscription_id: subscription.id.clone(),
+            day: next_monday,
+            user_id: "dad".into(),
+            created_by: "".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "".into(),
+            updated_at: Utc::now().naive_utc(),
+        };
+
+        let expanded = expand(&[subscription], &[assignment], monday, next_monday);
+        let days: Vec<_> = expanded.iter().map(|occurrence| (occurrence.day, occurrence.user_id.as_str())).collect();
+        assert_eq!(
+            days,
+            vec![
+                (monday, "mom"),
+                (NaiveDate::from_ymd(2020, 10, 14), "mom"),
+                (next_monday, "dad"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! People allowed to collect a child, members of the family or not
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::pickup_persons;
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "pickup_persons"]
+pub struct PickupPerson {
+    pub id: String,
+    pub family_id: String,
+    pub child_id: String,
+    pub name: String,
+    pub phone: Option<String>,
+    pub photo_url: Option<String>,
+    /// Set when the person has an account, a member of the family or not
+    pub user_id: Option<String>,
+    /// First day the person may collect the child, no limit when None
+    pub valid_from: Option<NaiveDate>,
+    /// Last day the person may collect the child, no limit when None
+    pub valid_until: Option<NaiveDate>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+impl PickupPerson {
+    pub fn is_valid_on(&self, day: NaiveDate) -> bool {
+        self.valid_from.map_or(true, |from| from <= day) && self.valid_until.map_or(true, |until| day <= until)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
+#[table_name = "pickup_persons"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct UpdatePickupPerson {
+    pub id: String,
+    pub name: String,
+    pub phone: Option<String>,
+    pub photo_url: Option<String>,
+    pub user_id: Option<String>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Get the people allowed to collect the children of a family
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<PickupPerson>, ApiError> {
+    use crate::schema::pickup_persons::dsl::*;
+
+    let conn = pool.get()?;
+    let all = pickup_persons
+        .filter(family_id.eq(_family_id.to_string()))
+        .order((child_id.asc(), name.asc()))
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Find a pickup person or error out
+pub fn find(pool: &PoolType, pickup_person_id: Uuid) -> Result<PickupPerson, ApiError> {
+    use crate::schema::pickup_persons::dsl::{id, pickup_persons};
+
+    let not_found = format!("pickup person {} not found", pickup_person_id);
+    let conn = pool.get()?;
+    pickup_persons
+        .filter(id.eq(pickup_person_id.to_string()))
+        .first::<PickupPerson>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+pub fn create(pool: &PoolType, new_pickup_person: &PickupPerson) -> Result<PickupPerson, ApiError> {
+    use crate::schema::pickup_persons::dsl::pickup_persons;
+
+    let conn = pool.get()?;
+    diesel::insert_into(pickup_persons).values(new_pickup_person).execute(&conn)?;
+    Ok(new_pickup_person.clone())
+}
+
+pub fn update(pool: &PoolType, update_pickup_person: &UpdatePickupPerson) -> Result<PickupPerson, ApiError> {
+    use crate::schema::pickup_persons::dsl::{id, pickup_persons};
+
+    let conn = pool.get()?;
+    diesel::update(pickup_persons)
+        .filter(id.eq(update_pickup_person.id.clone()))
+        .set(update_pickup_person)
+        .execute(&conn)?;
+    find(pool, Uuid::parse_str(&update_pickup_person.id)?)
+}
+
+/// Delete a pickup person, the handovers they made keep their name
+pub fn delete(pool: &PoolType, pickup_person_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::pickup_persons::dsl::{id, pickup_persons};
+
+    let conn = pool.get()?;
+    diesel::delete(pickup_persons)
+        .filter(id.eq(pickup_person_id.to_string()))
+        .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn person(valid_from: Option<NaiveDate>, valid_until: Option<NaiveDate>) -> PickupPerson {
+        PickupPerson {
+            id: "person".into(),
+            family_id: "family".into(),
+            child_id: "child".into(),
+            name: "Grandma".into(),
+            phone: None,
+            photo_url: None,
+            user_id: None,
+            valid_from,
+            valid_until,
+            created_by: "parent".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "parent".into(),
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn it_tells_if_a_person_may_collect_on_a_day() {
+        let day = NaiveDate::from_ymd(2020, 10, 23);
+        assert!(person(None, None).is_valid_on(day));
+        assert!(person(Some(day), Some(day)).is_valid_on(day));
+        assert!(!person(Some(day.succ()), None).is_valid_on(day));
+        assert!(!person(None, Some(day.pred())).is_valid_on(day));
+    }
+}
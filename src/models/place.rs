@@ -1,6 +1,8 @@
 use crate::database::PoolType;
 use crate::errors::ApiError;
+use crate::geo::{Geofence, Point};
 use crate::handlers::place::{PlaceResponse, PlacesResponse};
+use crate::models::change::{record, ChangeAction, Entity, NewChange};
 use crate::schema::places;
 use chrono::{NaiveDateTime, Utc};
 use diesel::prelude::*;
@@ -16,6 +18,43 @@ pub struct Place {
     pub updated_by: String,
     pub updated_at: NaiveDateTime,
     pub family_id: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius: Option<f64>,
+    /// JSON array of points
+    pub polygon: Option<String>,
+    pub address: Option<String>,
+    /// Set when the place is deleted, it can still be restored
+    pub deleted_at: Option<NaiveDateTime>,
+    pub deleted_by: Option<String>,
+}
+
+/// Radius of places that have a center but no radius, in meters
+pub const DEFAULT_RADIUS: f64 = 100.0;
+
+impl Place {
+    pub fn location(&self) -> Option<Point> {
+        match (self.latitude, self.longitude) {
+            (Some(latitude), Some(longitude)) => Some(Point::new(latitude, longitude)),
+            _ => None,
+        }
+    }
+
+    pub fn polygon(&self) -> Option<Vec<Point>> {
+        self.polygon
+            .as_ref()
+            .and_then(|polygon| serde_json::from_str(polygon).ok())
+    }
+
+    /// The polygon when there is one, otherwise a circle around the center
+    pub fn geofence(&self) -> Option<Geofence> {
+        match self.polygon() {
+            Some(vertices) if vertices.len() >= 3 => Some(Geofence::Polygon(vertices)),
+            _ => self
+                .location()
+                .map(|center| Geofence::Circle(center, self.radius.unwrap_or(DEFAULT_RADIUS))),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -25,23 +64,34 @@ pub struct NewPlace{
     pub family_id: String,
     pub created_by: String,
     pub updated_by: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius: Option<f64>,
+    pub polygon: Option<String>,
+    pub address: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
 #[table_name = "places"]
+#[changeset_options(treat_none_as_null = "true")]
 pub struct UpdatePlace {
     pub id: String,
     pub name: String,
     pub updated_by: String,
     pub family_id: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub radius: Option<f64>,
+    pub polygon: Option<String>,
+    pub address: Option<String>,
 }
 
 /// Get all places
 pub fn get_all(pool: &PoolType) -> Result<PlacesResponse, ApiError> {
-    use crate::schema::places::dsl::places;
+    use crate::schema::places::dsl::{deleted_at, places};
 
     let conn = pool.get()?;
-    let all_places = places.load(&conn)?;
+    let all_places = places.filter(deleted_at.is_null()).load(&conn)?;
 
     Ok(all_places.into())
 }
@@ -53,23 +103,68 @@ pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid) -> Result<PlacesR
     let conn = pool.get()?;
     let all_places = places
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .load(&conn)?;
 
     Ok(all_places.into())
 }
 
+/// Get the place rows of a family
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<Place>, ApiError> {
+    use crate::schema::places::dsl::*;
+
+    let conn = pool.get()?;
+    let all_places = places
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
+        .load(&conn)?;
+
+    Ok(all_places)
+}
+
+/// Find a place of a family by its name
+pub fn find_by_name(pool: &PoolType, _family_id: &str, _name: &str) -> Result<Option<Place>, ApiError> {
+    use crate::schema::places::dsl::*;
+
+    let conn = pool.get()?;
+    let place = places
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(name.eq(_name.to_string()))
+        .filter(deleted_at.is_null())
+        .first::<Place>(&conn)
+        .optional()?;
+
+    Ok(place)
+}
+
+/// Find the place row itself
+pub fn find_place(pool: &PoolType, place_id: &str) -> Result<Place, ApiError> {
+    use crate::schema::places::dsl::{deleted_at, id, places};
+
+    let not_found = format!("Place {} not found", place_id);
+    let conn = pool.get()?;
+    places
+        .filter(id.eq(place_id.to_string()))
+        .filter(deleted_at.is_null())
+        .first::<Place>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
 /// Find a place 
 pub fn find(pool: &PoolType, place_id: Uuid) -> Result<PlaceResponse, ApiError> {
+    Ok(find_place(pool, &place_id.to_string())?.into())
+}
+
+/// Find a place, deleted or not
+pub fn find_with_deleted(pool: &PoolType, place_id: &str) -> Result<Place, ApiError> {
     use crate::schema::places::dsl::{id, places};
 
     let not_found = format!("Place {} not found", place_id);
     let conn = pool.get()?;
-    let place = places
+    places
         .filter(id.eq(place_id.to_string()))
         .first::<Place>(&conn)
-        .map_err(|_| ApiError::NotFound(not_found))?;
-
-    Ok(place.into())
+        .map_err(|_| ApiError::NotFound(not_found))
 }
 
 /// Create a new place
@@ -77,31 +172,91 @@ pub fn create(pool: &PoolType, new_place: &Place) -> Result<PlaceResponse, ApiEr
     use crate::schema::places::dsl::places;
 
     let conn = pool.get()?;
-    diesel::insert_into(places).values(new_place).execute(&conn)?;
+    conn.transaction::<_, ApiError, _>(|| {
+        diesel::insert_into(places).values(new_place).execute(&conn)?;
+        let change = NewChange::new(
+            Entity::Place,
+            &new_place.id,
+            &new_place.family_id,
+            ChangeAction::Created,
+            None,
+            Some(new_place),
+            &new_place.created_by,
+        );
+        record(&conn, &change)
+    })?;
     Ok(new_place.clone().into())
 }
 
-/// Update a place
+/// Update a place, keeping the previous version in its history
 pub fn update(pool: &PoolType, update_place: &UpdatePlace) -> Result<PlaceResponse, ApiError> {
-    use crate::schema::places::dsl::{id, places};
+    use crate::schema::places::dsl::{deleted_at, id, places};
 
+    let not_found = format!("Place {} not found", update_place.id);
     let conn = pool.get()?;
-    diesel::update(places)
-        .filter(id.eq(update_place.id.clone()))
-        .set(update_place)
-        .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_place.id)?)
+    let place = conn.transaction::<_, ApiError, _>(|| {
+        let before = places
+            .filter(id.eq(update_place.id.clone()))
+            .filter(deleted_at.is_null())
+            .first::<Place>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found))?;
+        diesel::update(places)
+            .filter(id.eq(update_place.id.clone()))
+            .set(update_place)
+            .execute(&conn)?;
+        let after = places.filter(id.eq(update_place.id.clone())).first::<Place>(&conn)?;
+        let change = NewChange::new(
+            Entity::Place,
+            &after.id,
+            &after.family_id,
+            ChangeAction::Updated,
+            Some(&before),
+            Some(&after),
+            &update_place.updated_by,
+        );
+        record(&conn, &change)?;
+        Ok(after)
+    })?;
+    Ok(place.into())
 }
 
-/// Delete a place
-pub fn delete(pool: &PoolType, place_id: Uuid) -> Result<(), ApiError> {
-    use crate::schema::places::dsl::{id, places};
+/// Delete a place, it stays in the database and can be restored
+pub fn delete(pool: &PoolType, place_id: Uuid, actor: &str) -> Result<(), ApiError> {
+    set_deleted(pool, &place_id.to_string(), actor, true).map(|_| ())
+}
 
+/// Restore a deleted place
+pub fn restore(pool: &PoolType, place_id: Uuid, actor: &str) -> Result<PlaceResponse, ApiError> {
+    Ok(set_deleted(pool, &place_id.to_string(), actor, false)?.into())
+}
+
+fn set_deleted(pool: &PoolType, place_id: &str, actor: &str, deleted: bool) -> Result<Place, ApiError> {
+    use crate::schema::places::dsl::{deleted_at, deleted_by, id, places};
+
+    let not_found = format!("Place {} not found", place_id);
+    let now = Utc::now().naive_utc();
     let conn = pool.get()?;
-    diesel::delete(places)
-        .filter(id.eq(place_id.to_string()))
-        .execute(&conn)?;
-    Ok(())
+    conn.transaction::<_, ApiError, _>(|| {
+        let before = places
+            .filter(id.eq(place_id.to_string()))
+            .first::<Place>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found.clone()))?;
+        match (before.deleted_at.is_some(), deleted) {
+            (true, true) => return Err(ApiError::NotFound(not_found)),
+            (false, false) => return Err(ApiError::BadRequest(format!("Place {} is not deleted", place_id))),
+            _ => (),
+        }
+        let (at, by) = if deleted { (Some(now), Some(actor.to_string())) } else { (None, None) };
+        diesel::update(places)
+            .filter(id.eq(place_id.to_string()))
+            .set((deleted_at.eq(at), deleted_by.eq(by)))
+            .execute(&conn)?;
+        let after = places.filter(id.eq(place_id.to_string())).first::<Place>(&conn)?;
+        let action = if deleted { ChangeAction::Deleted } else { ChangeAction::Restored };
+        let change = NewChange::new(Entity::Place, &after.id, &after.family_id, action, Some(&before), Some(&after), actor);
+        record(&conn, &change)?;
+        Ok(after)
+    })
 }
 
 impl From<NewPlace> for Place {
@@ -114,6 +269,13 @@ impl From<NewPlace> for Place {
             updated_by: place.updated_by,
             updated_at: Utc::now().naive_utc(),
             family_id: place.family_id,
+            latitude: place.latitude,
+            longitude: place.longitude,
+            radius: place.radius,
+            polygon: place.polygon,
+            address: place.address,
+            deleted_at: None,
+            deleted_by: None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,67 @@
+//! Staff of a place, a teacher or a nanny at the club, who can confirm the
+//! handover of a child there without being a member of the family
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::place_staff;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+#[table_name = "place_staff"]
+pub struct PlaceStaff {
+    pub place_id: String,
+    pub user_id: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Get the staff of a place
+pub fn find_all_by_place_id(pool: &PoolType, _place_id: &str) -> Result<Vec<PlaceStaff>, ApiError> {
+    use crate::schema::place_staff::dsl::*;
+
+    let conn = pool.get()?;
+    let all = place_staff
+        .filter(place_id.eq(_place_id.to_string()))
+        .order(created_at.asc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Whether a user is on the staff of a place
+pub fn is_staff(pool: &PoolType, _place_id: &str, _user_id: &str) -> Result<bool, ApiError> {
+    use crate::schema::place_staff::dsl::*;
+
+    let conn = pool.get()?;
+    let found: i64 = place_staff
+        .filter(place_id.eq(_place_id.to_string()))
+        .filter(user_id.eq(_user_id.to_string()))
+        .count()
+        .get_result(&conn)?;
+    Ok(found > 0)
+}
+
+/// Add a user to the staff of a place, adding them twice changes nothing
+pub fn create(pool: &PoolType, new_staff: &PlaceStaff) -> Result<PlaceStaff, ApiError> {
+    use crate::schema::place_staff::dsl::place_staff;
+
+    let conn = pool.get()?;
+    diesel::insert_into(place_staff)
+        .values(new_staff)
+        .on_conflict_do_nothing()
+        .execute(&conn)?;
+    Ok(new_staff.clone())
+}
+
+pub fn delete(pool: &PoolType, _place_id: &str, _user_id: &str) -> Result<(), ApiError> {
+    use crate::schema::place_staff::dsl::*;
+
+    let conn = pool.get()?;
+    diesel::delete(
+        place_staff
+            .filter(place_id.eq(_place_id.to_string()))
+            .filter(user_id.eq(_user_id.to_string())),
+    )
+    .execute(&conn)?;
+    Ok(())
+}
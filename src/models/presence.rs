@@ -0,0 +1,61 @@
+//! Who is currently inside which place, as last detected from geolocs
+
+use crate::database::ConnectionType;
+use crate::errors::ApiError;
+use crate::schema::presences;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct Presence {
+    pub id: String,
+    pub user_id: String,
+    pub place_id: String,
+    pub entered_at: NaiveDateTime,
+}
+
+/// Get the places a user is currently inside
+pub fn find_all_by_user_id(conn: &ConnectionType, _user_id: &str) -> Result<Vec<Presence>, ApiError> {
+    use crate::schema::presences::dsl::*;
+
+    let all = presences
+        .filter(user_id.eq(_user_id.to_string()))
+        .load(conn)?;
+    Ok(all)
+}
+
+/// Get the places any of the users are currently inside
+pub fn find_all_by_user_ids(conn: &ConnectionType, _user_ids: &[String]) -> Result<Vec<Presence>, ApiError> {
+    use crate::schema::presences::dsl::*;
+
+    let all = presences
+        .filter(user_id.eq_any(_user_ids))
+        .load(conn)?;
+    Ok(all)
+}
+
+/// Record that a user entered a place
+pub fn enter(conn: &ConnectionType, _user_id: &str, _place_id: &str, at: NaiveDateTime) -> Result<(), ApiError> {
+    use crate::schema::presences::dsl::*;
+
+    let presence = Presence {
+        id: Uuid::new_v4().to_string(),
+        user_id: _user_id.to_string(),
+        place_id: _place_id.to_string(),
+        entered_at: at,
+    };
+    diesel::insert_into(presences).values(&presence).execute(conn)?;
+    Ok(())
+}
+
+/// Record that a user left a place
+pub fn leave(conn: &ConnectionType, _user_id: &str, _place_id: &str) -> Result<(), ApiError> {
+    use crate::schema::presences::dsl::*;
+
+    diesel::delete(presences)
+        .filter(user_id.eq(_user_id.to_string()))
+        .filter(place_id.eq(_place_id.to_string()))
+        .execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,246 @@
+//! What a user lets the rest of the family see of their location
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::sos::sampling_interval;
+use crate::models::user::{find_user, User};
+use crate::schema::location_sharing;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SharingMode {
+    Off,
+    /// Off until `paused_until`, precise afterwards
+    Paused,
+    /// Only the place the user is inside, if any
+    PlaceOnly,
+    Precise,
+}
+
+impl SharingMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SharingMode::Off => "off",
+            SharingMode::Paused => "paused",
+            SharingMode::PlaceOnly => "place_only",
+            SharingMode::Precise => "precise",
+        }
+    }
+}
+
+impl fmt::Display for SharingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for SharingMode {
+    type Err = ApiError;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "off" => Ok(SharingMode::Off),
+            "paused" => Ok(SharingMode::Paused),
+            "place_only" => Ok(SharingMode::PlaceOnly),
+            "precise" => Ok(SharingMode::Precise),
+            _ => Err(ApiError::BadRequest(format!("unknown sharing mode {}", mode))),
+        }
+    }
+}
+
+/// What others get to see of a user's location at a given time
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Nothing, and nothing is recorded
+    Hidden,
+    /// The place the user is inside, positions are only used to detect it
+    Place,
+    Precise,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable, AsChangeset)]
+#[table_name = "location_sharing"]
+#[primary_key(user_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct Sharing {
+    pub user_id: String,
+    pub mode: String,
+    pub paused_until: Option<NaiveDateTime>,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Sharing {
+    /// Users who never changed their settings share precisely
+    pub fn default_for(user_id: &str) -> Self {
+        Sharing {
+            user_id: user_id.to_string(),
+            mode: SharingMode::Precise.as_str().into(),
+            paused_until: None,
+            updated_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn mode(&self) -> SharingMode {
+        self.mode.parse().unwrap_or(SharingMode::Precise)
+    }
+
+    pub fn visibility_at(&self, at: NaiveDateTime) -> Visibility {
+        match self.mode() {
+            SharingMode::Off => Visibility::Hidden,
+            SharingMode::Paused if self.paused_until.map_or(false, |until| at < until) => Visibility::Hidden,
+            SharingMode::Paused => Visibility::Precise,
+            SharingMode::PlaceOnly => Visibility::Place,
+            SharingMode::Precise => Visibility::Precise,
+        }
+    }
+
+    pub fn visibility(&self) -> Visibility {
+        self.visibility_at(Utc::now().naive_utc())
+    }
+}
+
+/// Get the sharing settings of a user
+pub fn find_sharing(pool: &PoolType, _user_id: &str) -> Result<Sharing, ApiError> {
+    use crate::schema::location_sharing::dsl::*;
+
+    let conn = pool.get()?;
+    let sharing = location_sharing
+        .filter(user_id.eq(_user_id.to_string()))
+        .first::<Sharing>(&conn)
+        .optional()?;
+    Ok(sharing.unwrap_or_else(|| Sharing::default_for(_user_id)))
+}
+
+/// Get the sharing settings of users, by user id
+pub fn find_all_by_user_ids(pool: &PoolType, _user_ids: &[String]) -> Result<HashMap<String, Sharing>, ApiError> {
+    use crate::schema::location_sharing::dsl::*;
+
+    let conn = pool.get()?;
+    let all: Vec<Sharing> = location_sharing.filter(user_id.eq_any(_user_ids)).load(&conn)?;
+    let mut by_user: HashMap<String, Sharing> = all
+        .into_iter()
+        .map(|sharing| (sharing.user_id.clone(), sharing))
+        .collect();
+    for _user_id in _user_ids {
+        by_user
+            .entry(_user_id.clone())
+            .or_insert_with(|| Sharing::default_for(_user_id));
+    }
+    Ok(by_user)
+}
+
+/// What a viewer gets to see of a user's location, see `visible_to`
+pub fn visibility_for(pool: &PoolType, _user_id: &str, viewer_id: &str) -> Result<Visibility, ApiError> {
+    if _user_id == viewer_id {
+        return Ok(Visibility::Precise);
+    }
+    let user = find_user(pool, _user_id)?;
+    let viewer = find_user(pool, viewer_id)?;
+    let tracked = sampling_interval(pool, _user_id)?.is_some();
+    let sharing = find_sharing(pool, _user_id)?;
+    Ok(visible_to(&user, &viewer, tracked, &sharing, Utc::now().naive_utc()))
+}
+
+/// Users see all of their own location and nobody outside their family sees
+/// any of it. Within the family, everyone sees all of a user tracked for an
+/// emergency, and what the user shares otherwise.
+fn visible_to(user: &User, viewer: &User, tracked: bool, sharing: &Sharing, now: NaiveDateTime) -> Visibility {
+    if user.id == viewer.id {
+        return Visibility::Precise;
+    }
+    // Users without a family are strangers to everybody
+    let same_family = user.family_id.as_deref().map_or(false, |family_id| viewer.belongs_to(family_id));
+    if !same_family {
+        return Visibility::Hidden;
+    }
+    if tracked {
+        return Visibility::Precise;
+    }
+    sharing.visibility_at(now)
+}
+
+/// Create or replace the sharing settings of a user
+pub fn save(pool: &PoolType, sharing: &Sharing) -> Result<Sharing, ApiError> {
+    use crate::schema::location_sharing::dsl::*;
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        let updated = diesel::update(location_sharing)
+            .filter(user_id.eq(sharing.user_id.clone()))
+            .set(sharing)
+            .execute(&conn)?;
+        if updated == 0 {
+            diesel::insert_into(location_sharing).values(sharing).execute(&conn)?;
+        }
+        Ok(sharing.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    fn sharing(mode: SharingMode, paused_until: Option<NaiveDateTime>) -> Sharing {
+        Sharing {
+            mode: mode.as_str().into(),
+            paused_until,
+            ..Sharing::default_for("00000000-0000-0000-0000-000000000000")
+        }
+    }
+
+    #[test]
+    fn it_tells_what_others_see() {
+        let now = NaiveDate::from_ymd(2020, 10, 20).and_hms(8, 0, 0);
+        assert_eq!(sharing(SharingMode::Off, None).visibility_at(now), Visibility::Hidden);
+        assert_eq!(sharing(SharingMode::PlaceOnly, None).visibility_at(now), Visibility::Place);
+        assert_eq!(sharing(SharingMode::Precise, None).visibility_at(now), Visibility::Precise);
+    }
+
+    fn user(id: &str, family_id: Option<&str>) -> User {
+        User {
+            id: id.into(),
+            first_name: "".into(),
+            last_name: "".into(),
+            email: "".into(),
+            password: "".into(),
+            created_by: "".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "".into(),
+            updated_at: Utc::now().naive_utc(),
+            family_id: family_id.map(Into::into),
+            role: None,
+            token: "".into(),
+        }
+    }
+
+    #[test]
+    fn it_hides_the_location_from_other_families() {
+        let now = NaiveDate::from_ymd(2020, 10, 20).and_hms(8, 0, 0);
+        let precise = sharing(SharingMode::Precise, None);
+        let kid = user("kid", Some("family"));
+        let mom = user("mom", Some("family"));
+        let stranger = user("stranger", Some("other"));
+        let loner = user("loner", None);
+
+        assert_eq!(visible_to(&kid, &mom, false, &precise, now), Visibility::Precise);
+        assert_eq!(visible_to(&kid, &stranger, false, &precise, now), Visibility::Hidden);
+        assert_eq!(visible_to(&kid, &stranger, true, &precise, now), Visibility::Hidden);
+        assert_eq!(visible_to(&loner, &user("other loner", None), false, &precise, now), Visibility::Hidden);
+        assert_eq!(visible_to(&loner, &loner, false, &precise, now), Visibility::Precise);
+    }
+
+    #[test]
+    fn it_resumes_sharing_after_a_pause() {
+        let now = NaiveDate::from_ymd(2020, 10, 20).and_hms(8, 0, 0);
+        let paused = sharing(SharingMode::Paused, Some(now + Duration::hours(1)));
+        assert_eq!(paused.visibility_at(now), Visibility::Hidden);
+        assert_eq!(paused.visibility_at(now + Duration::hours(1)), Visibility::Precise);
+    }
+}
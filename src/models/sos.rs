@@ -0,0 +1,219 @@
+//! Emergency alerts, each with a live tracking session of whoever raised it
+//!
+//! The session is time boxed: past `expires_at` the sampling hint and the
+//! shared link stop, adults can extend it. The alert itself stays open
+//! until an adult resolves it.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::sos_alerts;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use rand::Rng;
+use std::collections::HashSet;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "sos_alerts"]
+pub struct Sos {
+    pub id: String,
+    pub family_id: String,
+    /// Who raised the alert, and is tracked
+    pub user_id: String,
+    /// The alert event of the occurrence the user was on, if any
+    pub event_id: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub accuracy: Option<f64>,
+    pub message: Option<String>,
+    /// Secret of the read-only link
+    pub token: String,
+    /// Seconds between positions asked from the device while tracked
+    pub sampling_interval: i32,
+    pub expires_at: NaiveDateTime,
+    pub resolved_by: Option<String>,
+    pub resolved_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Sos {
+    /// Whether the user is live tracked at a given time
+    pub fn is_tracking_at(&self, at: NaiveDateTime) -> bool {
+        self.resolved_at.is_none() && at < self.expires_at
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.is_tracking_at(Utc::now().naive_utc())
+    }
+}
+
+/// Get the alerts of a family, most recent first
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<Sos>, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    let all = sos_alerts
+        .filter(family_id.eq(_family_id.to_string()))
+        .order(created_at.desc())
+        .load(&conn)?;
+    Ok(all)
+}
+
+/// Find an alert or error out
+pub fn find(pool: &PoolType, sos_id: Uuid) -> Result<Sos, ApiError> {
+    use crate::schema::sos_alerts::dsl::{id, sos_alerts};
+
+    let not_found = format!("alert {} not found", sos_id);
+    let conn = pool.get()?;
+    sos_alerts
+        .filter(id.eq(sos_id.to_string()))
+        .first::<Sos>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Find an alert from the secret of its link
+pub fn find_by_token(pool: &PoolType, _token: &str) -> Result<Sos, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    sos_alerts
+        .filter(token.eq(_token.to_string()))
+        .first::<Sos>(&conn)
+        .map_err(|_| ApiError::NotFound("alert not found".into()))
+}
+
+/// Find the alert a user raised and nobody resolved yet
+pub fn find_open_by_user(pool: &PoolType, _user_id: &str) -> Result<Option<Sos>, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    let sos = sos_alerts
+        .filter(user_id.eq(_user_id.to_string()))
+        .filter(resolved_at.is_null())
+        .order(created_at.desc())
+        .first::<Sos>(&conn)
+        .optional()?;
+    Ok(sos)
+}
+
+/// Seconds between positions a user's device should use, when tracked
+pub fn sampling_interval(pool: &PoolType, _user_id: &str) -> Result<Option<i32>, ApiError> {
+    let sos = find_open_by_user(pool, _user_id)?;
+    Ok(sos.filter(Sos::is_tracking).map(|sos| sos.sampling_interval))
+}
+
+/// Users among some who are live tracked right now, their positions are
+/// shared with the family whatever their sharing settings
+pub fn find_tracked_user_ids(pool: &PoolType, _user_ids: &[String]) -> Result<HashSet<String>, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    let tracked = sos_alerts
+        .select(user_id)
+        .filter(user_id.eq_any(_user_ids))
+        .filter(resolved_at.is_null())
+        .filter(expires_at.gt(Utc::now().naive_utc()))
+        .load::<String>(&conn)?;
+    Ok(tracked.into_iter().collect())
+}
+
+/// A fresh secret for a read-only link, 64 hex characters
+pub fn new_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn create(pool: &PoolType, new_sos: &Sos) -> Result<Sos, ApiError> {
+    use crate::schema::sos_alerts::dsl::sos_alerts;
+
+    let conn = pool.get()?;
+    diesel::insert_into(sos_alerts).values(new_sos).execute(&conn)?;
+    Ok(new_sos.clone())
+}
+
+/// Close an alert, which ends its tracking session
+pub fn resolve(pool: &PoolType, sos_id: Uuid, actor: &str) -> Result<Sos, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let resolved = diesel::update(sos_alerts)
+        .filter(id.eq(sos_id.to_string()))
+        .filter(resolved_at.is_null())
+        .set((
+            resolved_by.eq(Some(actor.to_string())),
+            resolved_at.eq(Some(now)),
+            updated_by.eq(actor.to_string()),
+            updated_at.eq(now),
+        ))
+        .execute(&conn)?;
+    if resolved == 0 {
+        return Err(ApiError::BadRequest(format!("alert {} is already resolved", sos_id)));
+    }
+    find(pool, sos_id)
+}
+
+/// Track an open alert until a later time
+pub fn extend(pool: &PoolType, sos_id: Uuid, until: NaiveDateTime, actor: &str) -> Result<Sos, ApiError> {
+    use crate::schema::sos_alerts::dsl::*;
+
+    let conn = pool.get()?;
+    let now = Utc::now().naive_utc();
+    let extended = diesel::update(sos_alerts)
+        .filter(id.eq(sos_id.to_string()))
+        .filter(resolved_at.is_null())
+        .set((expires_at.eq(until), updated_by.eq(actor.to_string()), updated_at.eq(now)))
+        .execute(&conn)?;
+    if extended == 0 {
+        return Err(ApiError::BadRequest(format!("alert {} is already resolved", sos_id)));
+    }
+    find(pool, sos_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    fn sos(resolved_at: Option<NaiveDateTime>) -> Sos {
+        let raised_at = NaiveDate::from_ymd(2020, 10, 22).and_hms(16, 0, 0);
+        Sos {
+            id: "sos".into(),
+            family_id: "family".into(),
+            user_id: "user".into(),
+            event_id: None,
+            latitude: 48.8566,
+            longitude: 2.3522,
+            accuracy: None,
+            message: None,
+            token: new_token(),
+            sampling_interval: 5,
+            expires_at: raised_at + Duration::hours(1),
+            resolved_by: resolved_at.map(|_| "adult".into()),
+            resolved_at,
+            created_by: "user".into(),
+            created_at: raised_at,
+            updated_by: "user".into(),
+            updated_at: raised_at,
+        }
+    }
+
+    #[test]
+    fn it_tracks_until_expiry_or_resolution() {
+        let raised_at = NaiveDate::from_ymd(2020, 10, 22).and_hms(16, 0, 0);
+        assert!(sos(None).is_tracking_at(raised_at + Duration::minutes(59)));
+        assert!(!sos(None).is_tracking_at(raised_at + Duration::minutes(60)));
+        assert!(!sos(Some(raised_at)).is_tracking_at(raised_at + Duration::minutes(1)));
+    }
+
+    #[test]
+    fn it_makes_unguessable_tokens() {
+        let token = new_token();
+        assert_eq!(token.len(), 64);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, new_token());
+    }
+}
@@ -3,9 +3,10 @@ use crate::errors::ApiError;
 use crate::handlers::subscription::{SubscriptionsEventResponse, SubscriptionResponse, SubscriptionsResponse};
 use crate::schema::subscriptions;
 use crate::schema::events;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use diesel::prelude::*;
 use uuid::Uuid;
+use crate::models::change::{record, ChangeAction, Entity, NewChange};
 use crate::models::event::Event;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
@@ -19,6 +20,35 @@ pub struct Subscription {
     pub created_at: NaiveDateTime,
     pub updated_by: String,
     pub updated_at: NaiveDateTime,
+    pub child_id: Option<String>,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    /// Set when the subscription is deleted, it can still be restored
+    pub deleted_at: Option<NaiveDateTime>,
+    pub deleted_by: Option<String>,
+}
+
+/// Day names as stored in `subscriptions.days`, starting on monday
+pub const DAY_NAMES: [&str; 7] = ["lundi", "mardi", "mercredi", "jeudi", "vendredi", "samedi", "dimanche"];
+
+/// The name of a date's weekday, as used in `days`
+pub fn day_name(date: NaiveDate) -> &'static str {
+    DAY_NAMES[date.weekday().num_days_from_monday() as usize]
+}
+
+impl Subscription {
+    /// Does the subscription take place on the given date
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        self.days.contains(day_name(date))
+    }
+
+    /// The time window of the subscription on a given date, when it has one
+    pub fn window_on(&self, date: NaiveDate) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        match (self.start_time, self.end_time) {
+            (Some(start), Some(end)) => Some((date.and_time(start), date.and_time(end))),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -30,10 +60,14 @@ pub struct NewSubscription {
     pub days: String,
     pub created_by: String,
     pub updated_by: String,
+    pub child_id: Option<String>,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, AsChangeset)]
 #[table_name = "subscriptions"]
+#[changeset_options(treat_none_as_null = "true")]
 pub struct UpdateSubscription {
     pub id: String,
     pub family_id: String,
@@ -41,6 +75,9 @@ pub struct UpdateSubscription {
     pub user_id: String,
     pub days: String,
     pub updated_by: String,
+    pub child_id: Option<String>,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
 }
 
 pub fn get_all_by_family_id_and_place_id(pool: &PoolType, _family_id: Uuid, _place_id: Uuid) -> Result<SubscriptionsResponse, ApiError> {
@@ -51,16 +88,17 @@ pub fn get_all_by_family_id_and_place_id(pool: &PoolType, _family_id: Uuid, _pla
     let conn = pool.get()?;
     let all = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(place_id.eq(_place_id.to_string()))
         .load(&conn)?;
     Ok(all.into())
 }
 
 pub fn get_all(pool: &PoolType) -> Result<SubscriptionsResponse, ApiError> {
-    use crate::schema::subscriptions::dsl::subscriptions;
+    use crate::schema::subscriptions::dsl::{deleted_at, subscriptions};
 
     let conn = pool.get()?;
-    let all = subscriptions.load(&conn)?;
+    let all = subscriptions.filter(deleted_at.is_null()).load(&conn)?;
 
     Ok(all.into())
 }
@@ -71,11 +109,25 @@ pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid) -> Result<Subscri
     let conn = pool.get()?;
     let all = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .load(&conn)?;
 
     Ok(all.into())
 }
 
+/// Get the subscription rows of a family, used to expand the schedule
+pub fn find_all_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<Subscription>, ApiError> {
+    use crate::schema::subscriptions::dsl::*;
+
+    let conn = pool.get()?;
+    let all = subscriptions
+        .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
+        .load(&conn)?;
+
+    Ok(all)
+}
+
 pub fn get_all_by_family_id_and_user_id_and_days_events(pool: &PoolType, _family_id: Uuid, _user_id: Uuid, _days: &String) -> Result<SubscriptionsEventResponse, ApiError> {
     use crate::schema::subscriptions::dsl::*;
     println!("passage get_all_by_family_id_and_user_id_and_days_events");
@@ -99,6 +151,7 @@ pub fn get_all_by_family_id_and_user_id_and_days_events(pool: &PoolType, _family
     
     let all2: Vec<Subscription> = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(user_id.eq(_user_id.to_string()))
         .filter(days.like(day2))
         .load(&conn)?;
@@ -106,9 +159,11 @@ pub fn get_all_by_family_id_and_user_id_and_days_events(pool: &PoolType, _family
 
     let all: Vec<(Subscription, Event)> = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(user_id.eq(_user_id.to_string()))
         .filter(days.like(day.clone()))
         .inner_join(events::table)
+        .filter(events::deleted_at.is_null())
         .load(&conn)?;
 
     //0011000
@@ -133,6 +188,7 @@ pub fn get_all_by_family_id_and_user_id_and_days(pool: &PoolType, _family_id: Uu
 
     let all: Vec<Subscription> = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(user_id.eq(_user_id.to_string()))
         .filter(days.like(day.clone()))
         .load(&conn)?;
@@ -157,6 +213,7 @@ pub fn get_all_by_family_id_and_user_id_and_days_without_user(pool: &PoolType, _
 
     let all: Vec<Subscription> = subscriptions
         .filter(family_id.eq(_family_id.to_string()))
+        .filter(deleted_at.is_null())
         .filter(days.like(day.clone()))
         .load(&conn)?;
 
@@ -164,46 +221,138 @@ pub fn get_all_by_family_id_and_user_id_and_days_without_user(pool: &PoolType, _
 }
 
 
-pub fn find(pool: &PoolType, subscription_id: Uuid) -> Result<SubscriptionResponse, ApiError> {
+/// Find the subscription row itself, used by occurrence-level features
+pub fn find_subscription(pool: &PoolType, subscription_id: &str) -> Result<Subscription, ApiError> {
+    use crate::schema::subscriptions::dsl::{deleted_at, id, subscriptions};
+
+    let not_found = format!("subscription {} not found", subscription_id);
+    let conn = pool.get()?;
+    subscriptions
+        .filter(id.eq(subscription_id.to_string()))
+        .filter(deleted_at.is_null())
+        .first::<Subscription>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Find a subscription, deleted or not
+pub fn find_with_deleted(pool: &PoolType, subscription_id: &str) -> Result<Subscription, ApiError> {
     use crate::schema::subscriptions::dsl::{id, subscriptions};
 
     let not_found = format!("subscription {} not found", subscription_id);
     let conn = pool.get()?;
-    let subscription = subscriptions
+    subscriptions
         .filter(id.eq(subscription_id.to_string()))
         .first::<Subscription>(&conn)
-        .map_err(|_| ApiError::NotFound(not_found))?;
+        .map_err(|_| ApiError::NotFound(not_found))
+}
 
-    Ok(subscription.into())
+pub fn find(pool: &PoolType, subscription_id: Uuid) -> Result<SubscriptionResponse, ApiError> {
+    Ok(find_subscription(pool, &subscription_id.to_string())?.into())
 }
 
 pub fn create(pool: &PoolType, new_subscription: &Subscription) -> Result<SubscriptionResponse, ApiError> {
     use crate::schema::subscriptions::dsl::subscriptions;
     let conn = pool.get()?;
 
-    diesel::insert_into(subscriptions).values(new_subscription).execute(&conn)?;
+    conn.transaction::<_, ApiError, _>(|| {
+        diesel::insert_into(subscriptions).values(new_subscription).execute(&conn)?;
+        let change = NewChange::new(
+            Entity::Subscription,
+            &new_subscription.id,
+            &new_subscription.family_id,
+            ChangeAction::Created,
+            None,
+            Some(new_subscription),
+            &new_subscription.created_by,
+        );
+        record(&conn, &change)
+    })?;
     Ok(new_subscription.clone().into())
 }
 
+/// Update a subscription, keeping the previous version in its history
 pub fn update(pool: &PoolType, update_subscription: &UpdateSubscription) -> Result<SubscriptionResponse, ApiError> {
-    use crate::schema::subscriptions::dsl::{id, subscriptions};
+    use crate::schema::subscriptions::dsl::{deleted_at, id, subscriptions};
 
+    let not_found = format!("subscription {} not found", update_subscription.id);
     let conn = pool.get()?;
-    diesel::update(subscriptions)
-        .filter(id.eq(update_subscription.id.clone()))
-        .set(update_subscription)
-        .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_subscription.id)?)
+    let subscription = conn.transaction::<_, ApiError, _>(|| {
+        let before = subscriptions
+            .filter(id.eq(update_subscription.id.clone()))
+            .filter(deleted_at.is_null())
+            .first::<Subscription>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found))?;
+        diesel::update(subscriptions)
+            .filter(id.eq(update_subscription.id.clone()))
+            .set(update_subscription)
+            .execute(&conn)?;
+        let after = subscriptions
+            .filter(id.eq(update_subscription.id.clone()))
+            .first::<Subscription>(&conn)?;
+        let change = NewChange::new(
+            Entity::Subscription,
+            &after.id,
+            &after.family_id,
+            ChangeAction::Updated,
+            Some(&before),
+            Some(&after),
+            &update_subscription.updated_by,
+        );
+        record(&conn, &change)?;
+        Ok(after)
+    })?;
+    Ok(subscription.into())
 }
 
-pub fn delete(pool: &PoolType, subscription_id: Uuid) -> Result<(), ApiError> {
-    use crate::schema::subscriptions::dsl::{id, subscriptions};
+/// Delete a subscription, it stays in the database and can be restored
+pub fn delete(pool: &PoolType, subscription_id: Uuid, actor: &str) -> Result<(), ApiError> {
+    set_deleted(pool, &subscription_id.to_string(), actor, true).map(|_| ())
+}
 
+/// Restore a deleted subscription
+pub fn restore(pool: &PoolType, subscription_id: Uuid, actor: &str) -> Result<SubscriptionResponse, ApiError> {
+    Ok(set_deleted(pool, &subscription_id.to_string(), actor, false)?.into())
+}
+
+fn set_deleted(pool: &PoolType, subscription_id: &str, actor: &str, deleted: bool) -> Result<Subscription, ApiError> {
+    use crate::schema::subscriptions::dsl::{deleted_at, deleted_by, id, subscriptions};
+
+    let not_found = format!("subscription {} not found", subscription_id);
+    let now = Utc::now().naive_utc();
     let conn = pool.get()?;
-    diesel::delete(subscriptions)
-        .filter(id.eq(subscription_id.to_string()))
-        .execute(&conn)?;
-    Ok(())
+    conn.transaction::<_, ApiError, _>(|| {
+        let before = subscriptions
+            .filter(id.eq(subscription_id.to_string()))
+            .first::<Subscription>(&conn)
+            .map_err(|_| ApiError::NotFound(not_found.clone()))?;
+        match (before.deleted_at.is_some(), deleted) {
+            (true, true) => return Err(ApiError::NotFound(not_found)),
+            (false, false) => {
+                return Err(ApiError::BadRequest(format!("subscription {} is not deleted", subscription_id)))
+            }
+            _ => (),
+        }
+        let (at, by) = if deleted { (Some(now), Some(actor.to_string())) } else { (None, None) };
+        diesel::update(subscriptions)
+            .filter(id.eq(subscription_id.to_string()))
+            .set((deleted_at.eq(at), deleted_by.eq(by)))
+            .execute(&conn)?;
+        let after = subscriptions
+            .filter(id.eq(subscription_id.to_string()))
+            .first::<Subscription>(&conn)?;
+        let action = if deleted { ChangeAction::Deleted } else { ChangeAction::Restored };
+        let change = NewChange::new(
+            Entity::Subscription,
+            &after.id,
+            &after.family_id,
+            action,
+            Some(&before),
+            Some(&after),
+            actor,
+        );
+        record(&conn, &change)?;
+        Ok(after)
+    })
 }
 
 impl From<NewSubscription> for Subscription {
@@ -218,6 +367,56 @@ impl From<NewSubscription> for Subscription {
             created_at: Utc::now().naive_utc(),
             updated_by: subscription.updated_by,
             updated_at: Utc::now().naive_utc(),
+            child_id: subscription.child_id,
+            start_time: subscription.start_time,
+            end_time: subscription.end_time,
+            deleted_at: None,
+            deleted_by: None,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(days: &str) -> Subscription {
+        Subscription {
+            id: Uuid::new_v4().to_string(),
+            family_id: Uuid::new_v4().to_string(),
+            place_id: Uuid::new_v4().to_string(),
+            user_id: Uuid::new_v4().to_string(),
+            days: days.into(),
+            created_by: "".into(),
+            created_at: Utc::now().naive_utc(),
+            updated_by: "".into(),
+            updated_at: Utc::now().naive_utc(),
+            child_id: None,
+            start_time: Some(NaiveTime::from_hms(8, 0, 0)),
+            end_time: Some(NaiveTime::from_hms(8, 30, 0)),
+            deleted_at: None,
+            deleted_by: None,
+        }
+    }
+
+    #[test]
+    fn it_runs_on_the_listed_days() {
+        let subscription = subscription("lundi,jeudi");
+        // 2020-10-12 is a monday
+        assert!(subscription.runs_on(NaiveDate::from_ymd(2020, 10, 12)));
+        assert!(!subscription.runs_on(NaiveDate::from_ymd(2020, 10, 13)));
+        assert!(subscription.runs_on(NaiveDate::from_ymd(2020, 10, 15)));
+    }
+
+    #[test]
+    fn it_gets_the_window_on_a_day() {
+        let day = NaiveDate::from_ymd(2020, 10, 12);
+        let mut subscription = subscription("lundi");
+        let (start, end) = subscription.window_on(day).unwrap();
+        assert_eq!(start, day.and_hms(8, 0, 0));
+        assert_eq!(end, day.and_hms(8, 30, 0));
+
+        subscription.end_time = None;
+        assert_eq!(subscription.window_on(day), None);
+    }
+}
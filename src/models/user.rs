@@ -54,6 +54,55 @@ pub struct AuthUser {
     pub email: String,
 }
 
+/// Role given to the children of a family, every other member is an adult
+pub const CHILD_ROLE: &str = "child";
+
+/// Role of the adults responsible for the children, members without a role
+/// are parents too; other adults, a nanny or grandparents, help with pickups
+pub const PARENT_ROLE: &str = "parent";
+
+/// The actor of writes made on public routes without a logged-in user
+pub const ANONYMOUS: &str = "anonymous";
+
+impl User {
+    pub fn is_adult(&self) -> bool {
+        self.role.as_deref() != Some(CHILD_ROLE)
+    }
+
+    pub fn is_parent(&self) -> bool {
+        self.role.is_none() || self.role.as_deref() == Some(PARENT_ROLE)
+    }
+
+    pub fn belongs_to(&self, family: &str) -> bool {
+        self.family_id.as_deref() == Some(family)
+    }
+}
+
+/// How closely a user must belong to a family to be let through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Membership {
+    Member,
+    Adult,
+    Parent,
+}
+
+impl Membership {
+    /// The user behind a session, as long as they belong to the family
+    /// closely enough
+    pub fn of(self, pool: &PoolType, user: &AuthUser, family_id: &str) -> Result<User, ApiError> {
+        let user = find_user(pool, &user.id)?;
+        let (allowed, who) = match self {
+            Membership::Member => (user.belongs_to(family_id), "members"),
+            Membership::Adult => (user.belongs_to(family_id) && user.is_adult(), "an adult"),
+            Membership::Parent => (user.belongs_to(family_id) && user.is_parent(), "a parent"),
+        };
+        if !allowed {
+            return Err(ApiError::Unauthorized(format!("Only {} of the family can do this", who)));
+        }
+        Ok(user)
+    }
+}
+
 /// Get all users
 pub fn get_all(pool: &PoolType) -> Result<UsersResponse, ApiError> {
     use crate::schema::users::dsl::users;
@@ -76,6 +125,36 @@ pub fn get_all_by_family_id(pool: &PoolType, _family_id: Uuid) -> Result<UsersRe
     Ok(all_users.into())
 }
 
+/// Get the members of a family
+pub fn get_members_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<User>, ApiError> {
+    use crate::schema::users::dsl::*;
+
+    let conn = pool.get()?;
+    let all_users = users
+        .filter(family_id.eq(_family_id.to_string()))
+        .load(&conn)?;
+
+    Ok(all_users)
+}
+
+/// Get the adults of a family
+pub fn get_adults_by_family_id(pool: &PoolType, _family_id: &str) -> Result<Vec<User>, ApiError> {
+    let members = get_members_by_family_id(pool, _family_id)?;
+    Ok(members.into_iter().filter(User::is_adult).collect())
+}
+
+/// Find the user row itself, used for permission checks
+pub fn find_user(pool: &PoolType, user_id: &str) -> Result<User, ApiError> {
+    use crate::schema::users::dsl::{id, users};
+
+    let not_found = format!("User {} not found", user_id);
+    let conn = pool.get()?;
+    users
+        .filter(id.eq(user_id.to_string()))
+        .first::<User>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
 /// Find a user by the user's id or error out
 pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
     use crate::schema::users::dsl::{id, users};
@@ -107,6 +186,18 @@ pub fn find_by_auth(
     Ok(user.into())
 }
 
+/// Find a user by email, when there is no id to go by
+pub fn find_by_email(pool: &PoolType, user_email: &str) -> Result<User, ApiError> {
+    use crate::schema::users::dsl::{email, users};
+
+    let not_found = format!("User {} not found", user_email);
+    let conn = pool.get()?;
+    users
+        .filter(email.eq(user_email.to_string()))
+        .first::<User>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
 /// Create a new user
 pub fn create(pool: &PoolType, new_user: &User) -> Result<UserResponse, ApiError> {
     use crate::schema::users::dsl::users;
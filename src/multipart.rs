@@ -0,0 +1,137 @@
+//! Just enough of multipart/form-data to receive uploaded files
+
+use crate::errors::ApiError;
+
+/// A field of a multipart body
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+/// The boundary given in a multipart Content-Type header
+pub fn boundary(content_type: &str) -> Result<String, ApiError> {
+    let mut params = content_type.split(';').map(str::trim);
+    let mime = params.next().unwrap_or_default();
+    if !mime.eq_ignore_ascii_case("multipart/form-data") {
+        return Err(ApiError::BadRequest("expected a multipart/form-data body".into()));
+    }
+    params
+        .filter_map(|param| {
+            let mut split = param.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(key), Some(value)) if key.eq_ignore_ascii_case("boundary") => {
+                    Some(value.trim_matches('"').to_string())
+                }
+                _ => None,
+            }
+        })
+        .find(|value| !value.is_empty())
+        .ok_or_else(|| ApiError::BadRequest("missing multipart boundary".into()))
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if from > haystack.len() {
+        return None;
+    }
+    haystack[from..]
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| from + position)
+}
+
+/// The value of a parameter of a Content-Disposition header
+fn param(disposition: &str, key: &str) -> Option<String> {
+    disposition.split(';').map(str::trim).find_map(|param| {
+        let mut split = param.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(name), Some(value)) if name.eq_ignore_ascii_case(key) => Some(value.trim_matches('"').to_string()),
+            _ => None,
+        }
+    })
+}
+
+fn part(raw: &[u8]) -> Result<Part, ApiError> {
+    let invalid = || ApiError::BadRequest("invalid multipart body".into());
+    let end = find(raw, b"\r\n\r\n", 0).ok_or_else(invalid)?;
+    let headers = std::str::from_utf8(&raw[..end]).map_err(|_| invalid())?;
+    let mut disposition = None;
+    let mut content_type = None;
+    for header in headers.split("\r\n") {
+        let mut split = header.splitn(2, ':');
+        let (name, value) = (split.next().unwrap_or_default().trim(), split.next().unwrap_or_default().trim());
+        if name.eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-type") {
+            content_type = Some(value.to_string());
+        }
+    }
+    let disposition = disposition.ok_or_else(invalid)?;
+    Ok(Part {
+        name: param(&disposition, "name").ok_or_else(invalid)?,
+        filename: param(&disposition, "filename"),
+        content_type,
+        data: raw[end + 4..].to_vec(),
+    })
+}
+
+/// The parts of a multipart body
+pub fn parse(body: &[u8], boundary: &str) -> Result<Vec<Part>, ApiError> {
+    let invalid = || ApiError::BadRequest("invalid multipart body".into());
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut at = find(body, &delimiter, 0).ok_or_else(invalid)? + delimiter.len();
+    let mut parts = vec![];
+    loop {
+        // The closing delimiter is followed by "--"
+        if body[at..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[at..].starts_with(b"\r\n") {
+            return Err(invalid());
+        }
+        let end = find(body, &separator, at + 2).ok_or_else(invalid)?;
+        parts.push(part(&body[at + 2..end])?);
+        at = end + separator.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_the_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=abc").unwrap(), "abc");
+        assert_eq!(boundary("multipart/form-data; boundary=\"a b\"").unwrap(), "a b");
+        assert!(boundary("application/json").is_err());
+        assert!(boundary("multipart/form-data").is_err());
+    }
+
+    #[test]
+    fn it_parses_parts() {
+        let body = b"preamble\r\n--xyz\r\n\
+Content-Disposition: form-data; name=\"caption\"\r\n\r\n\
+At the park\r\n--xyz\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"park.png\"\r\n\
+Content-Type: image/png\r\n\r\n\
+\x89PNG\r\n--x\r\n--xyz--\r\n";
+        let parts = parse(body, "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "caption");
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].data, b"At the park");
+        assert_eq!(parts[1].filename.as_deref(), Some("park.png"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(parts[1].data, b"\x89PNG\r\n--x");
+    }
+
+    #[test]
+    fn it_rejects_truncated_bodies() {
+        let body = b"--xyz\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\ndata";
+        assert!(parse(body, "xyz").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Push notifications sent through Firebase Cloud Messaging
+//!
+//! Users register their device token when they sign up (`users.token`).
+//! People without an account, like emergency contacts, get a text message
+//! through the gateway at `SMS_URL` instead.
+//! Notifications are best effort: failures are logged and never bubble up
+//! to the request that triggered them.
+
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use crate::models::user::User;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+const FCM_URL: &str = "https://fcm.googleapis.com/fcm/send";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    Normal,
+    High,
+}
+
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub title: String,
+    pub body: String,
+    pub data: Value,
+    pub priority: Priority,
+}
+
+impl Notification {
+    pub fn new(title: &str, body: &str) -> Self {
+        Notification {
+            title: title.into(),
+            body: body.into(),
+            data: json!({}),
+            priority: Priority::Normal,
+        }
+    }
+
+    pub fn data(mut self, data: Value) -> Self {
+        self.data = data;
+        self
+    }
+
+    pub fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Build the FCM payload for a set of device tokens
+fn payload(tokens: &[String], notification: &Notification) -> Value {
+    let priority = match notification.priority {
+        Priority::Normal => "normal",
+        Priority::High => "high",
+    };
+    json!({
+        "registration_ids": tokens,
+        "priority": priority,
+        "notification": {
+            "title": notification.title,
+            "body": notification.body,
+        },
+        "data": notification.data,
+    })
+}
+
+/// Send a notification to a list of device tokens
+pub async fn send(tokens: Vec<String>, notification: &Notification) -> Result<(), ApiError> {
+    let key = match &CONFIG.fcm_key {
+        Some(key) if !key.is_empty() => key,
+        _ => {
+            log::debug!("FCM_KEY is not set, skipping notification {:?}", notification.title);
+            return Ok(());
+        }
+    };
+    if tokens.is_empty() {
+        return Ok(());
+    }
+
+    Client::new()
+        .post(FCM_URL)
+        .header(AUTHORIZATION, format!("key={}", key))
+        .header(CONTENT_TYPE, "application/json")
+        .json(&payload(&tokens, notification))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| ApiError::InternalServerError(format!("Could not send notification: {}", e)))?;
+    Ok(())
+}
+
+/// Notify a list of users, logging rather than returning failures
+pub async fn send_to_users(users: &[User], notification: Notification) {
+    let tokens = users.iter().map(|user| user.token.clone()).collect();
+    if let Err(error) = send(tokens, &notification).await {
+        log::warn!("{:?}", error);
+    }
+}
+
+/// Build the gateway payload for a text message
+fn sms_payload(phone: &str, body: &str) -> Value {
+    json!({
+        "to": phone,
+        "body": body,
+    })
+}
+
+/// Send a text message to each phone number
+pub async fn send_sms(phones: &[String], body: &str) -> Result<(), ApiError> {
+    let url = match &CONFIG.sms_url {
+        Some(url) if !url.is_empty() => url,
+        _ => {
+            log::debug!("SMS_URL is not set, skipping text message {:?}", body);
+            return Ok(());
+        }
+    };
+
+    let client = Client::new();
+    for phone in phones {
+        let mut request = client.post(url).header(CONTENT_TYPE, "application/json");
+        if let Some(key) = &CONFIG.sms_key {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", key));
+        }
+        request
+            .json(&sms_payload(phone, body))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| ApiError::InternalServerError(format!("Could not send text message: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Text a list of phone numbers, logging rather than returning failures
+pub async fn send_to_phones(phones: &[String], body: &str) {
+    if let Err(error) = send_sms(phones, body).await {
+        log::warn!("{:?}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_builds_a_high_priority_payload() {
+        let notification = Notification::new("title", "body")
+            .data(json!({"id": "123"}))
+            .priority(Priority::High);
+        let payload = payload(&["token".to_string()], &notification);
+        assert_eq!(payload["priority"], "high");
+        assert_eq!(payload["registration_ids"][0], "token");
+        assert_eq!(payload["notification"]["title"], "title");
+        assert_eq!(payload["data"]["id"], "123");
+    }
+
+    #[test]
+    fn it_builds_a_text_message_payload() {
+        let payload = sms_payload("+33600000000", "body");
+        assert_eq!(payload["to"], "+33600000000");
+        assert_eq!(payload["body"], "body");
+    }
+}
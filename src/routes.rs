@@ -6,10 +6,27 @@ use crate::handlers::{
     health::get_health,
     user::{get_users_by_family_id, create_user, delete_user, get_user, get_users, update_user},
     family::{get_family_by_code, create_family, delete_family, get_family, get_families, update_family},
-    place::{get_places_by_family_id, create_place, delete_place, get_place, get_places, update_place},
-    subscription::{search_by_family_user_days_without_user, search_by_family_user_days_events,search_by_family_user_days, get_subscriptions_by_family_id_and_place_id, get_subscriptions_by_family_id, create_subscription, delete_subscription, get_subscription, get_subscriptions, update_subscription},
-    event::{get_events_by_family_place_user_user, get_events_by_family_id, create_event, delete_event, get_event, get_events, update_event},
-    geoloc::{get_geolocs_by_day, create_geoloc},
+    place::{get_place_staff, add_place_staff, remove_place_staff, get_places_by_family_id, create_place, delete_place, restore_place, get_place_history, get_place, get_places, update_place},
+    subscription::{get_conflicts_by_family_id, search_by_family_user_days_without_user, search_by_family_user_days_events,search_by_family_user_days, get_subscriptions_by_family_id_and_place_id, get_subscriptions_by_family_id, create_subscription, delete_subscription, restore_subscription, get_subscription_history, get_subscription, get_subscriptions, update_subscription},
+    event::{get_occurrence_status, get_events_by_family_place_user_user, get_events_by_family_id, create_event, delete_event, restore_event, get_event_history, get_event, get_events, update_event},
+    geoloc::{wipe_geolocs, get_latest_by_family, get_geolocs_by_day, create_geoloc, create_geoloc_batch},
+    stream::{family_events, family_socket},
+    trip::get_trips,
+    sharing::{get_sharing, update_sharing},
+    export::export_geolocs,
+    eta::get_eta,
+    escalation::{get_escalations_by_family_id, get_escalation, acknowledge_escalation},
+    sos::{get_sos_by_family_id, get_sos, raise_sos, resolve_sos, extend_sos, get_sos_live},
+    pickup_person::{get_pickup_persons_by_family_id, get_pickup_person, create_pickup_person, update_pickup_person, delete_pickup_person},
+    handover::{create_handover, get_handover, confirm_handover},
+    emergency_contact::{get_emergency_contacts_by_family_id, get_emergency_contact, create_emergency_contact, update_emergency_contact, delete_emergency_contact},
+    child_card::{get_child_card, update_child_card, get_child_card_summary, get_child_card_views},
+    acknowledgement::{get_acknowledgements, acknowledge_event, get_mandatory_kinds, update_mandatory_kinds},
+    chat::{get_threads_by_family_id, get_thread, create_thread, get_messages, post_message, read_thread, get_read_receipts},
+    attachment::{get_attachment, delete_attachment, download_attachment, download_thumbnail, get_event_attachments, upload_event_attachment, get_message_attachments, upload_message_attachment},
+    audit::{get_audit_entries, verify_audit_entries},
+    owntracks::owntracks,
+    cover_request::{get_cover_requests_by_family_id, get_cover_request, create_cover_request, claim_cover_request, cancel_cover_request},
 };
 use crate::middleware::auth::Auth as AuthMiddleware;
 use actix_files::Files;
@@ -56,6 +73,11 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                         .route("/{id}", web::get().to(get_place))
                         .route("/{id}", web::put().to(update_place))
                         .route("/{id}", web::delete().to(delete_place))
+                        .route("/{id}/restore", web::post().to(restore_place))
+                        .route("/{id}/history", web::get().to(get_place_history))
+                        .route("/{id}/staff", web::get().to(get_place_staff))
+                        .route("/{id}/staff", web::post().to(add_place_staff))
+                        .route("/{place_id}/staff/{user_id}", web::delete().to(remove_place_staff))
                         .route("", web::get().to(get_places))
                         .route("", web::post().to(create_place))
                         .route("search_by_family/{family_id}", web::get().to(get_places_by_family_id)),
@@ -66,13 +88,16 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                         .route("/{id}", web::get().to(get_subscription))
                         .route("/{id}", web::put().to(update_subscription))
                         .route("/{id}", web::delete().to(delete_subscription))
+                        .route("/{id}/restore", web::post().to(restore_subscription))
+                        .route("/{id}/history", web::get().to(get_subscription_history))
                         .route("", web::get().to(get_subscriptions))
                         .route("", web::post().to(create_subscription))
                         .route("search_by_family/{family_id}", web::get().to(get_subscriptions_by_family_id))
                         .route("search_by_family_place/{family_id}/{place_id}", web::get().to(get_subscriptions_by_family_id_and_place_id))
                         .route("search_by_family_user_days/{family_id}/{user_id}/{days}", web::get().to(search_by_family_user_days))
                         .route("search_by_family_user_days_events/{family_id}/{user_id}/{days}", web::get().to(search_by_family_user_days_events))
-                        .route("search_by_family_user_days_without_user/{family_id}/{days}", web::get().to(search_by_family_user_days_without_user)),
+                        .route("search_by_family_user_days_without_user/{family_id}/{days}", web::get().to(search_by_family_user_days_without_user))
+                        .route("conflicts/{family_id}", web::get().to(get_conflicts_by_family_id)),
                 )
                 // Event routes
                 .service(
@@ -80,16 +105,135 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
                         .route("/{id}", web::get().to(get_event))
                         .route("/{id}", web::put().to(update_event))
                         .route("/{id}", web::delete().to(delete_event))
+                        .route("/{id}/restore", web::post().to(restore_event))
+                        .route("/{id}/history", web::get().to(get_event_history))
                         .route("", web::get().to(get_events))
                         .route("", web::post().to(create_event))
                         .route("search_by_family/{family_id}", web::get().to(get_events_by_family_id))
+                        .route("status/{subscription_id}/{day}", web::get().to(get_occurrence_status))
+                        .route("eta/{subscription_id}/{day}", web::get().to(get_eta))
                         .route("search_by_family_place_user_sub/{family_id}/{subscription_id}/{place_id}/{user_id}", web::get().to(get_events_by_family_place_user_user)),
                 )
                 // Geoloc routes
                 .service(
                     web::scope("/geoloc")
                         .route("", web::post().to(create_geoloc))
+                        .route("", web::delete().to(wipe_geolocs))
+                        .route("/batch", web::post().to(create_geoloc_batch))
                         .route("search_by_user_id/{user_id}", web::get().to(get_geolocs_by_day))
+                        .route("latest_by_family/{family_id}", web::get().to(get_latest_by_family))
+                        .route("trips/{user_id}", web::get().to(get_trips))
+                        .route("export/{user_id}", web::get().to(export_geolocs))
+                )
+                // Location sharing settings of the caller
+                .service(
+                    web::scope("/sharing")
+                        .route("", web::get().to(get_sharing))
+                        .route("", web::put().to(update_sharing)),
+                )
+                // Cover request routes
+                .service(
+                    web::scope("/cover_request")
+                        .route("/{id}", web::get().to(get_cover_request))
+                        .route("", web::post().to(create_cover_request))
+                        .route("/{id}/claim", web::post().to(claim_cover_request))
+                        .route("/{id}/cancel", web::post().to(cancel_cover_request))
+                        .route("search_by_family/{family_id}", web::get().to(get_cover_requests_by_family_id)),
+                )
+                // Escalation routes
+                .service(
+                    web::scope("/escalation")
+                        .route("/{id}", web::get().to(get_escalation))
+                        .route("/{id}/acknowledge", web::post().to(acknowledge_escalation))
+                        .route("search_by_family/{family_id}", web::get().to(get_escalations_by_family_id)),
+                )
+                // Emergency alerts, the live link needs no account
+                .service(
+                    web::scope("/sos")
+                        .route("/{id}", web::get().to(get_sos))
+                        .route("", web::post().to(raise_sos))
+                        .route("/{id}/resolve", web::post().to(resolve_sos))
+                        .route("/{id}/extend", web::post().to(extend_sos))
+                        .route("live/{token}", web::get().to(get_sos_live))
+                        .route("search_by_family/{family_id}", web::get().to(get_sos_by_family_id)),
+                )
+                // Pickup person routes
+                .service(
+                    web::scope("/pickup_person")
+                        .route("/{id}", web::get().to(get_pickup_person))
+                        .route("/{id}", web::put().to(update_pickup_person))
+                        .route("/{id}", web::delete().to(delete_pickup_person))
+                        .route("", web::post().to(create_pickup_person))
+                        .route("search_by_family/{family_id}", web::get().to(get_pickup_persons_by_family_id)),
+                )
+                // Handover routes, the code routes need no account
+                .service(
+                    web::scope("/handover")
+                        .route("", web::post().to(create_handover))
+                        .route("code/{code}", web::get().to(get_handover))
+                        .route("code/{code}/confirm", web::post().to(confirm_handover)),
+                )
+                // Emergency contact routes
+                .service(
+                    web::scope("/emergency_contact")
+                        .route("/{id}", web::get().to(get_emergency_contact))
+                        .route("/{id}", web::put().to(update_emergency_contact))
+                        .route("/{id}", web::delete().to(delete_emergency_contact))
+                        .route("", web::post().to(create_emergency_contact))
+                        .route("search_by_family/{family_id}", web::get().to(get_emergency_contacts_by_family_id)),
+                )
+                // Child card routes
+                .service(
+                    web::scope("/child_card")
+                        .route("/{child_id}", web::get().to(get_child_card))
+                        .route("/{child_id}", web::put().to(update_child_card))
+                        .route("/{child_id}/summary", web::get().to(get_child_card_summary))
+                        .route("/{child_id}/views", web::get().to(get_child_card_views)),
+                )
+                // Chat routes
+                .service(
+                    web::scope("/chat")
+                        .route("/{id}", web::get().to(get_thread))
+                        .route("", web::post().to(create_thread))
+                        .route("/{id}/messages", web::get().to(get_messages))
+                        .route("/{id}/messages", web::post().to(post_message))
+                        .route("/{id}/read", web::post().to(read_thread))
+                        .route("/{id}/reads", web::get().to(get_read_receipts))
+                        .route("search_by_family/{family_id}", web::get().to(get_threads_by_family_id)),
+                )
+                // Acknowledgement routes
+                .service(
+                    web::scope("/acknowledgement")
+                        .route("event/{event_id}", web::get().to(get_acknowledgements))
+                        .route("event/{event_id}", web::post().to(acknowledge_event))
+                        .route("settings/{family_id}", web::get().to(get_mandatory_kinds))
+                        .route("settings/{family_id}", web::put().to(update_mandatory_kinds)),
+                )
+                // Attachment routes
+                .service(
+                    web::scope("/attachment")
+                        .route("/{id}", web::get().to(get_attachment))
+                        .route("/{id}", web::delete().to(delete_attachment))
+                        .route("/{id}/download", web::get().to(download_attachment))
+                        .route("/{id}/thumbnail", web::get().to(download_thumbnail))
+                        .route("event/{event_id}", web::get().to(get_event_attachments))
+                        .route("event/{event_id}", web::post().to(upload_event_attachment))
+                        .route("message/{message_id}", web::get().to(get_message_attachments))
+                        .route("message/{message_id}", web::post().to(upload_message_attachment)),
+                )
+                // Audit log routes
+                .service(
+                    web::scope("/audit")
+                        .route("/{family_id}", web::get().to(get_audit_entries))
+                        .route("/{family_id}/verify", web::get().to(verify_audit_entries)),
+                )
+                // OwnTracks HTTP mode, authenticated with basic auth
+                .route("/owntracks", web::post().to(owntracks))
+                // Real-time stream routes
+                .service(
+                    web::scope("/stream")
+                        .route("/ws", web::get().to(family_socket))
+                        .route("/sse", web::get().to(family_events)),
                 )
         )
         // Serve secure static files from the static-private folder
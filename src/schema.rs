@@ -1,3 +1,190 @@
+table! {
+    acknowledgements (event_id, user_id) {
+        event_id -> Varchar,
+        user_id -> Varchar,
+        family_id -> Varchar,
+        acknowledged_at -> Nullable<Timestamp>,
+        notified_at -> Timestamp,
+        reminders -> Int4,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    activities (id) {
+        id -> Int8,
+        family_id -> Varchar,
+        kind -> Varchar,
+        payload -> Text,
+        created_at -> Timestamp,
+        user_id -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    attachments (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        event_id -> Nullable<Varchar>,
+        message_id -> Nullable<Varchar>,
+        filename -> Varchar,
+        content_type -> Varchar,
+        size -> Int8,
+        storage_key -> Varchar,
+        thumbnail_key -> Nullable<Varchar>,
+        width -> Nullable<Int4>,
+        height -> Nullable<Int4>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    changes (id) {
+        id -> Int8,
+        family_id -> Varchar,
+        entity -> Varchar,
+        entity_id -> Varchar,
+        action -> Varchar,
+        before -> Nullable<Text>,
+        after -> Nullable<Text>,
+        changed_by -> Varchar,
+        changed_at -> Timestamp,
+    }
+}
+
+table! {
+    audit_entries (id) {
+        id -> Int8,
+        family_id -> Nullable<Varchar>,
+        actor_id -> Nullable<Varchar>,
+        action -> Varchar,
+        target -> Nullable<Varchar>,
+        details -> Nullable<Text>,
+        ip_address -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        prev_hash -> Varchar,
+        hash -> Varchar,
+    }
+}
+
+table! {
+    chat_messages (id) {
+        id -> Varchar,
+        thread_id -> Varchar,
+        user_id -> Varchar,
+        body -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    chat_reads (thread_id, user_id) {
+        thread_id -> Varchar,
+        user_id -> Varchar,
+        last_read_at -> Timestamp,
+    }
+}
+
+table! {
+    chat_threads (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        title -> Nullable<Varchar>,
+        event_id -> Nullable<Varchar>,
+        subscription_id -> Nullable<Varchar>,
+        place_id -> Nullable<Varchar>,
+        last_message_at -> Nullable<Timestamp>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    child_card_views (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        child_id -> Varchar,
+        viewer_id -> Varchar,
+        format -> Varchar,
+        viewed_at -> Timestamp,
+    }
+}
+
+table! {
+    child_cards (child_id) {
+        child_id -> Varchar,
+        family_id -> Varchar,
+        birth_date -> Nullable<Date>,
+        blood_type -> Nullable<Varchar>,
+        allergies -> Nullable<Text>,
+        medications -> Nullable<Text>,
+        conditions -> Nullable<Text>,
+        doctor_name -> Nullable<Varchar>,
+        doctor_phone -> Nullable<Varchar>,
+        notes -> Nullable<Text>,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    cover_requests (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        subscription_id -> Varchar,
+        day -> Date,
+        requested_by -> Varchar,
+        claimed_by -> Nullable<Varchar>,
+        status -> Varchar,
+        message -> Nullable<Varchar>,
+        expires_at -> Timestamp,
+        escalated_at -> Nullable<Timestamp>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    emergency_contacts (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        name -> Varchar,
+        phone -> Varchar,
+        user_id -> Nullable<Varchar>,
+        position -> Int4,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+        child_id -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    escalations (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        subscription_id -> Varchar,
+        day -> Date,
+        reason -> Varchar,
+        level -> Varchar,
+        notified_at -> Timestamp,
+        acknowledged_by -> Nullable<Varchar>,
+        acknowledged_at -> Nullable<Timestamp>,
+        resolved_at -> Nullable<Timestamp>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     events (id) {
         id -> Varchar,
@@ -11,6 +198,11 @@ table! {
         created_at -> Timestamp,
         updated_by -> Varchar,
         updated_at -> Timestamp,
+        kind -> Varchar,
+        actor_id -> Nullable<Varchar>,
+        occurred_at -> Timestamp,
+        deleted_at -> Nullable<Timestamp>,
+        deleted_by -> Nullable<Varchar>,
     }
 }
 
@@ -23,6 +215,8 @@ table! {
         created_at -> Timestamp,
         updated_by -> Varchar,
         updated_at -> Timestamp,
+        timezone -> Varchar,
+        location_retention_days -> Nullable<Int4>,
     }
 }
 
@@ -33,6 +227,90 @@ table! {
         latitude -> Float8,
         longitude -> Float8,
         created_at -> Timestamp,
+        accuracy -> Nullable<Float8>,
+        speed -> Nullable<Float8>,
+        heading -> Nullable<Float8>,
+        altitude -> Nullable<Float8>,
+        battery -> Nullable<Int4>,
+        received_at -> Timestamp,
+    }
+}
+
+table! {
+    location_sharing (user_id) {
+        user_id -> Varchar,
+        mode -> Varchar,
+        paused_until -> Nullable<Timestamp>,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    handover_codes (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        subscription_id -> Varchar,
+        day -> Date,
+        child_id -> Varchar,
+        pickup_person_id -> Nullable<Varchar>,
+        pickup_name -> Varchar,
+        code -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        confirmed_by -> Nullable<Varchar>,
+        event_id -> Nullable<Varchar>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    mandatory_acknowledgements (family_id, kind) {
+        family_id -> Varchar,
+        kind -> Varchar,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    occurrences (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        subscription_id -> Varchar,
+        day -> Date,
+        user_id -> Varchar,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    pickup_persons (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        child_id -> Varchar,
+        name -> Varchar,
+        phone -> Nullable<Varchar>,
+        photo_url -> Nullable<Varchar>,
+        user_id -> Nullable<Varchar>,
+        valid_from -> Nullable<Date>,
+        valid_until -> Nullable<Date>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    place_staff (place_id, user_id) {
+        place_id -> Varchar,
+        user_id -> Varchar,
+        created_by -> Varchar,
+        created_at -> Timestamp,
     }
 }
 
@@ -45,6 +323,44 @@ table! {
         updated_by -> Varchar,
         updated_at -> Timestamp,
         family_id -> Varchar,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        radius -> Nullable<Float8>,
+        polygon -> Nullable<Text>,
+        address -> Nullable<Varchar>,
+        deleted_at -> Nullable<Timestamp>,
+        deleted_by -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    presences (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        place_id -> Varchar,
+        entered_at -> Timestamp,
+    }
+}
+
+table! {
+    sos_alerts (id) {
+        id -> Varchar,
+        family_id -> Varchar,
+        user_id -> Varchar,
+        event_id -> Nullable<Varchar>,
+        latitude -> Float8,
+        longitude -> Float8,
+        accuracy -> Nullable<Float8>,
+        message -> Nullable<Varchar>,
+        token -> Varchar,
+        sampling_interval -> Int4,
+        expires_at -> Timestamp,
+        resolved_by -> Nullable<Varchar>,
+        resolved_at -> Nullable<Timestamp>,
+        created_by -> Varchar,
+        created_at -> Timestamp,
+        updated_by -> Varchar,
+        updated_at -> Timestamp,
     }
 }
 
@@ -59,6 +375,11 @@ table! {
         created_at -> Timestamp,
         updated_by -> Varchar,
         updated_at -> Timestamp,
+        child_id -> Nullable<Varchar>,
+        start_time -> Nullable<Time>,
+        end_time -> Nullable<Time>,
+        deleted_at -> Nullable<Timestamp>,
+        deleted_by -> Nullable<Varchar>,
     }
 }
 
@@ -79,22 +400,85 @@ table! {
     }
 }
 
+joinable!(acknowledgements -> events (event_id));
+joinable!(acknowledgements -> families (family_id));
+joinable!(acknowledgements -> users (user_id));
+joinable!(activities -> families (family_id));
+joinable!(attachments -> chat_messages (message_id));
+joinable!(attachments -> events (event_id));
+joinable!(attachments -> families (family_id));
+joinable!(changes -> families (family_id));
+joinable!(chat_messages -> chat_threads (thread_id));
+joinable!(chat_messages -> users (user_id));
+joinable!(chat_reads -> chat_threads (thread_id));
+joinable!(chat_reads -> users (user_id));
+joinable!(chat_threads -> events (event_id));
+joinable!(chat_threads -> families (family_id));
+joinable!(chat_threads -> places (place_id));
+joinable!(chat_threads -> subscriptions (subscription_id));
+joinable!(child_card_views -> families (family_id));
+joinable!(child_cards -> families (family_id));
+joinable!(child_cards -> users (child_id));
+joinable!(cover_requests -> families (family_id));
+joinable!(cover_requests -> subscriptions (subscription_id));
+joinable!(emergency_contacts -> families (family_id));
+joinable!(emergency_contacts -> users (user_id));
+joinable!(escalations -> families (family_id));
+joinable!(escalations -> subscriptions (subscription_id));
+joinable!(escalations -> users (acknowledged_by));
 joinable!(events -> families (family_id));
 joinable!(events -> places (place_id));
 joinable!(events -> subscriptions (subscription_id));
 joinable!(events -> users (user_id));
 joinable!(geolocs -> users (user_id));
+joinable!(handover_codes -> events (event_id));
+joinable!(handover_codes -> families (family_id));
+joinable!(handover_codes -> pickup_persons (pickup_person_id));
+joinable!(handover_codes -> subscriptions (subscription_id));
+joinable!(mandatory_acknowledgements -> families (family_id));
+joinable!(occurrences -> families (family_id));
+joinable!(occurrences -> subscriptions (subscription_id));
+joinable!(location_sharing -> users (user_id));
+joinable!(occurrences -> users (user_id));
+joinable!(pickup_persons -> families (family_id));
+joinable!(place_staff -> places (place_id));
+joinable!(place_staff -> users (user_id));
 joinable!(places -> families (family_id));
+joinable!(presences -> places (place_id));
+joinable!(presences -> users (user_id));
+joinable!(sos_alerts -> events (event_id));
+joinable!(sos_alerts -> families (family_id));
 joinable!(subscriptions -> families (family_id));
 joinable!(subscriptions -> places (place_id));
 joinable!(subscriptions -> users (user_id));
 joinable!(users -> families (family_id));
 
 allow_tables_to_appear_in_same_query!(
+    acknowledgements,
+    activities,
+    attachments,
+    audit_entries,
+    changes,
+    chat_messages,
+    chat_reads,
+    chat_threads,
+    child_card_views,
+    child_cards,
+    cover_requests,
+    emergency_contacts,
+    escalations,
     events,
     families,
     geolocs,
+    handover_codes,
+    location_sharing,
+    mandatory_acknowledgements,
+    occurrences,
+    pickup_persons,
+    place_staff,
     places,
+    presences,
+    sos_alerts,
     subscriptions,
     users,
 );
@@ -0,0 +1,229 @@
+//! Where attachments are kept, on the local filesystem or in an
+//! S3-compatible bucket (AWS, MinIO...)
+//!
+//! Storages are blocking, call them from `block`. Keys are paths relative
+//! to the root of the storage.
+
+use crate::config::{Config, CONFIG};
+use crate::errors::ApiError;
+use chrono::{NaiveDateTime, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::blocking::Client;
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), ApiError>;
+    fn get(&self, key: &str) -> Result<Vec<u8>, ApiError>;
+    fn delete(&self, key: &str) -> Result<(), ApiError>;
+}
+
+lazy_static! {
+    pub static ref STORAGE: Box<dyn Storage> = from_config(&CONFIG);
+}
+
+/// The storage the configuration asks for
+pub fn from_config(config: &Config) -> Box<dyn Storage> {
+    match config.attachment_storage.as_str() {
+        "s3" => Box::new(S3Storage {
+            endpoint: config.s3_endpoint.clone().expect("S3_ENDPOINT is required with the s3 storage"),
+            bucket: config.s3_bucket.clone().expect("S3_BUCKET is required with the s3 storage"),
+            region: config.s3_region.clone(),
+            access_key: config.s3_access_key.clone().unwrap_or_default(),
+            secret_key: config.s3_secret_key.clone().unwrap_or_default(),
+            client: Client::new(),
+        }),
+        _ => Box::new(LocalStorage {
+            root: PathBuf::from(&config.attachment_dir),
+        }),
+    }
+}
+
+fn storage_error(key: &str, error: impl std::fmt::Display) -> ApiError {
+    ApiError::InternalServerError(format!("storage failed on {}: {}", key, error))
+}
+
+/// Files under a directory
+pub struct LocalStorage {
+    pub root: PathBuf,
+}
+
+impl LocalStorage {
+    fn path(&self, key: &str) -> Result<PathBuf, ApiError> {
+        if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+            return Err(ApiError::BadRequest(format!("invalid storage key {}", key)));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, data: &[u8], _content_type: &str) -> Result<(), ApiError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|error| storage_error(key, error))?;
+        }
+        fs::write(&path, data).map_err(|error| storage_error(key, error))
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, ApiError> {
+        fs::read(self.path(key)?).map_err(|_| ApiError::NotFound(format!("file {} not found", key)))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ApiError> {
+        match fs::remove_file(self.path(key)?) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(storage_error(key, error)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Objects of a bucket, path-style so MinIO works as is, requests signed
+/// with AWS Signature Version 4
+pub struct S3Storage {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    pub client: Client,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any size");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The key requests of a day are signed with
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    let key = hmac(&key, region);
+    let key = hmac(&key, service);
+    hmac(&key, "aws4_request")
+}
+
+/// Percent-encode a path, keeping the slashes
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+impl S3Storage {
+    /// Headers signing a request on an object, `host`, `x-amz-date`,
+    /// `x-amz-content-sha256` and `authorization`
+    fn sign(&self, method: &Method, host: &str, path: &str, payload: &[u8], at: NaiveDateTime) -> Vec<(String, String)> {
+        let amz_date = at.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = at.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(payload));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            method, path, host, payload_hash, amz_date, signed_headers, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signature = hex(&hmac(
+            &signing_key(&self.secret_key, &date, &self.region, "s3"),
+            &string_to_sign,
+        ));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+        vec![
+            ("host".into(), host.into()),
+            ("x-amz-date".into(), amz_date),
+            ("x-amz-content-sha256".into(), payload_hash),
+            ("authorization".into(), authorization),
+        ]
+    }
+
+    fn request(&self, method: Method, key: &str, payload: Vec<u8>, content_type: Option<&str>) -> Result<Vec<u8>, ApiError> {
+        let path = encode_path(&format!("/{}/{}", self.bucket, key));
+        let url = Url::parse(&format!("{}{}", self.endpoint.trim_end_matches('/'), path))
+            .map_err(|error| storage_error(key, error))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(storage_error(key, "S3_ENDPOINT has no host")),
+        };
+
+        let mut request = self.client.request(method.clone(), url);
+        for (name, value) in self.sign(&method, &host, &path, &payload, Utc::now().naive_utc()) {
+            request = request.header(name.as_str(), value);
+        }
+        if let Some(content_type) = content_type {
+            request = request.header("content-type", content_type);
+        }
+        let response = request.body(payload).send().map_err(|error| storage_error(key, error))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Err(ApiError::NotFound(format!("file {} not found", key))),
+            status if status.is_success() => Ok(response.bytes().map_err(|error| storage_error(key, error))?.to_vec()),
+            status => Err(storage_error(key, status)),
+        }
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&self, key: &str, data: &[u8], content_type: &str) -> Result<(), ApiError> {
+        self.request(Method::PUT, key, data.to_vec(), Some(content_type))?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, ApiError> {
+        self.request(Method::GET, key, vec![], None)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), ApiError> {
+        match self.request(Method::DELETE, key, vec![], None) {
+            Err(ApiError::NotFound(_)) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn it_derives_the_aws_signing_key() {
+        // Example from the AWS Signature Version 4 documentation
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+        assert_eq!(hex(&key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+    }
+
+    #[test]
+    fn it_encodes_object_paths() {
+        assert_eq!(encode_path("/bucket/family/a b.jpg"), "/bucket/family/a%20b.jpg");
+    }
+
+    #[test]
+    fn it_stores_files_locally() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let storage = LocalStorage { root: root.clone() };
+        storage.put("family/file", b"data", "text/plain").unwrap();
+        assert_eq!(storage.get("family/file").unwrap(), b"data");
+        storage.delete("family/file").unwrap();
+        assert!(storage.get("family/file").is_err());
+        assert!(storage.put("../escape", b"data", "text/plain").is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
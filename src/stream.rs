@@ -0,0 +1,253 @@
+//! Real-time fan-out of family activities
+//!
+//! Activities are recorded first, then published on a Redis channel every
+//! server instance subscribes to, each instance forwarding them to its own
+//! connected clients. Without Redis they only reach the clients of this
+//! instance. Clients that miss messages resume from their last cursor.
+
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::activity::{record, ActivityKind, ActivityMessage, NewActivity};
+use actix::prelude::*;
+use actix_redis::{Command, RedisActor};
+use actix_web::web::{block, Data};
+use futures::StreamExt;
+use redis_async::resp::FromResp;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::ToSocketAddrs;
+
+/// Activity ids a client remembers having been sent
+const SEEN_CAPACITY: usize = 1000;
+
+/// Redis channel carrying the activities of every family
+const CHANNEL: &str = "family_activities";
+
+/// Seconds to wait before subscribing again after losing Redis
+const RECONNECT: u64 = 5;
+
+/// An activity to forward to the connected clients of its family
+#[derive(Clone, Debug, Message)]
+#[rtype(result = "()")]
+pub struct Dispatch(pub ActivityMessage);
+
+/// Register a client for the activities of a family, returns its id
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Connect {
+    pub family_id: String,
+    pub recipient: Recipient<Dispatch>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub family_id: String,
+    pub id: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Publish(ActivityMessage);
+
+/// Keeps track of the clients connected to this instance, per family
+#[derive(Default)]
+pub struct Broker {
+    clients: HashMap<String, HashMap<usize, Recipient<Dispatch>>>,
+    next_id: usize,
+    redis: Option<Addr<RedisActor>>,
+}
+
+impl Broker {
+    fn dispatch(&mut self, activity: &ActivityMessage) {
+        if let Some(clients) = self.clients.get_mut(&activity.family_id) {
+            clients.retain(|_, client| client.do_send(Dispatch(activity.clone())).is_ok());
+        }
+    }
+}
+
+impl Actor for Broker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if CONFIG.redis_url.is_empty() {
+            return;
+        }
+        self.redis = Some(RedisActor::start(&CONFIG.redis_url));
+        actix_rt::spawn(subscribe(ctx.address()));
+    }
+}
+
+impl Supervised for Broker {}
+
+impl SystemService for Broker {}
+
+impl Handler<Connect> for Broker {
+    type Result = usize;
+
+    fn handle(&mut self, connect: Connect, _: &mut Self::Context) -> Self::Result {
+        self.next_id += 1;
+        self.clients
+            .entry(connect.family_id)
+            .or_insert_with(HashMap::new)
+            .insert(self.next_id, connect.recipient);
+        self.next_id
+    }
+}
+
+impl Handler<Disconnect> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, disconnect: Disconnect, _: &mut Self::Context) {
+        if let Some(clients) = self.clients.get_mut(&disconnect.family_id) {
+            clients.remove(&disconnect.id);
+            if clients.is_empty() {
+                self.clients.remove(&disconnect.family_id);
+            }
+        }
+    }
+}
+
+impl Handler<Dispatch> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, Dispatch(activity): Dispatch, _: &mut Self::Context) {
+        self.dispatch(&activity);
+    }
+}
+
+impl Handler<Publish> for Broker {
+    type Result = ();
+
+    fn handle(&mut self, Publish(activity): Publish, ctx: &mut Self::Context) {
+        let redis = match &self.redis {
+            Some(redis) => redis,
+            None => return self.dispatch(&activity),
+        };
+        let payload = serde_json::to_string(&activity).unwrap_or_default();
+        let published = redis.send(Command(resp_array!["PUBLISH", CHANNEL, payload]));
+        ctx.spawn(published.into_actor(self).map(move |result, broker, _| {
+            // Better reach this instance's clients than nobody
+            if !matches!(result, Ok(Ok(_))) {
+                log::warn!("Could not publish activity {} to Redis", activity.id);
+                broker.dispatch(&activity);
+            }
+        }));
+    }
+}
+
+/// Forward the activities published on Redis to the broker, forever
+async fn subscribe(broker: Addr<Broker>) {
+    loop {
+        if let Err(error) = listen(&broker).await {
+            log::warn!("Activity subscription failed: {:?}", error);
+        }
+        actix_rt::time::delay_for(std::time::Duration::from_secs(RECONNECT)).await;
+    }
+}
+
+fn cache_error<E: std::fmt::Debug>(error: E) -> ApiError {
+    ApiError::CacheError(format!("{:?}", error))
+}
+
+async fn listen(broker: &Addr<Broker>) -> Result<(), ApiError> {
+    let address = CONFIG
+        .redis_url
+        .to_socket_addrs()
+        .map_err(cache_error)?
+        .next()
+        .ok_or_else(|| ApiError::CacheError(format!("Could not resolve {}", CONFIG.redis_url)))?;
+    let connection = redis_async::client::pubsub_connect(&address).await.map_err(cache_error)?;
+    let mut messages = connection.subscribe(CHANNEL).await.map_err(cache_error)?;
+
+    while let Some(message) = messages.next().await {
+        let payload = String::from_resp(message.map_err(cache_error)?).map_err(cache_error)?;
+        match serde_json::from_str::<ActivityMessage>(&payload) {
+            Ok(activity) => broker.do_send(Dispatch(activity)),
+            Err(error) => log::warn!("Ignoring malformed activity: {:?}", error),
+        }
+    }
+    Ok(())
+}
+
+/// Record an activity of a family and push it to its streams
+///
+/// Failures are only logged, the change itself has already been made.
+pub async fn publish<T: Serialize>(pool: &Data<PoolType>, family_id: &str, kind: ActivityKind, payload: &T) {
+    publish_of(pool, family_id, None, kind, payload).await
+}
+
+/// Same as `publish` for an activity about a user, like a position, so it
+/// can be deleted along with the user's data
+pub async fn publish_of<T: Serialize>(
+    pool: &Data<PoolType>,
+    family_id: &str,
+    user_id: Option<&str>,
+    kind: ActivityKind,
+    payload: &T,
+) {
+    let new_activity = NewActivity {
+        family_id: family_id.to_string(),
+        kind: kind.as_str().into(),
+        payload: serde_json::to_string(payload).unwrap_or_default(),
+        user_id: user_id.map(str::to_string),
+    };
+    let pool = pool.clone();
+    match block(move || record(&pool, &new_activity)).await {
+        Ok(activity) => Broker::from_registry().do_send(Publish(activity.into())),
+        Err(error) => log::warn!("Could not record activity: {:?}", error),
+    }
+}
+
+/// The activities recently sent to a client, to skip the live copies of
+/// those it was just replayed
+///
+/// Ids are given at insert but committed in any order, so a live activity
+/// can come with a lower id than one already sent and still be new.
+#[derive(Debug, Default)]
+pub struct Seen {
+    ids: HashSet<i64>,
+    order: VecDeque<i64>,
+}
+
+impl Seen {
+    /// Remember an id, false if it was already sent
+    pub fn insert(&mut self, id: i64) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_skips_activities_already_sent_in_any_order() {
+        let mut seen = Seen::default();
+        assert!(seen.insert(12));
+        assert!(seen.insert(10));
+        assert!(!seen.insert(12));
+        assert!(seen.insert(11));
+    }
+
+    #[test]
+    fn it_forgets_the_oldest_ids() {
+        let mut seen = Seen::default();
+        for id in 0..=SEEN_CAPACITY as i64 {
+            seen.insert(id);
+        }
+        assert_eq!(seen.ids.len(), SEEN_CAPACITY);
+        assert!(seen.insert(0));
+        assert!(!seen.insert(SEEN_CAPACITY as i64));
+    }
+}
@@ -0,0 +1,240 @@
+//! Splitting geolocs into stays and movements
+//!
+//! A stay is a run of geolocs within a radius of its first one lasting at
+//! least a dwell time. Whatever lies between two stays is a movement, which
+//! starts where the previous stay ended and ends where the next one starts.
+
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::geo::{simplify, Geofence, Point};
+use crate::models::geoloc::find_between;
+use crate::models::place::find_all_by_family_id as find_places;
+use crate::models::user::User;
+use chrono::{Duration, NaiveDateTime};
+
+/// Meters someone may wander while still staying somewhere, by default
+pub const STAY_RADIUS: f64 = 100.0;
+
+/// Minutes within the stay radius before it counts as a stay, by default
+pub const MIN_DWELL: i64 = 5;
+
+/// Meters of deviation dropped when simplifying movements, by default
+pub const TOLERANCE: f64 = 10.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub stay_radius: f64,
+    pub min_dwell: Duration,
+    pub tolerance: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            stay_radius: STAY_RADIUS,
+            min_dwell: Duration::minutes(MIN_DWELL),
+            tolerance: TOLERANCE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stay {
+    /// Mean position of the geolocs of the stay
+    pub center: Point,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+    pub place_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movement {
+    /// Simplified path
+    pub points: Vec<(Point, NaiveDateTime)>,
+    /// Meters along the path before simplification
+    pub distance: f64,
+    pub started_at: NaiveDateTime,
+    pub ended_at: NaiveDateTime,
+    pub start_place_id: Option<String>,
+    pub end_place_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Stay(Stay),
+    Movement(Movement),
+}
+
+/// The place whose geofence contains a point, if any
+fn place_at(fences: &[(String, Geofence)], point: &Point) -> Option<String> {
+    fences
+        .iter()
+        .find(|(_, fence)| fence.contains(point, 0.0))
+        .map(|(place_id, _)| place_id.clone())
+}
+
+fn stay(fences: &[(String, Geofence)], positions: &[(Point, NaiveDateTime)]) -> Stay {
+    let count = positions.len() as f64;
+    let center = Point::new(
+        positions.iter().map(|(point, _)| point.latitude).sum::<f64>() / count,
+        positions.iter().map(|(point, _)| point.longitude).sum::<f64>() / count,
+    );
+    Stay {
+        center,
+        started_at: positions[0].1,
+        ended_at: positions[positions.len() - 1].1,
+        place_id: place_at(fences, &center),
+    }
+}
+
+fn movement(fences: &[(String, Geofence)], positions: &[(Point, NaiveDateTime)], tolerance: f64) -> Movement {
+    let points: Vec<Point> = positions.iter().map(|(point, _)| *point).collect();
+    let distance = points.windows(2).map(|pair| pair[0].distance(&pair[1])).sum();
+    let (first, last) = (positions[0], positions[positions.len() - 1]);
+    Movement {
+        points: simplify(&points, tolerance).into_iter().map(|index| positions[index]).collect(),
+        distance,
+        started_at: first.1,
+        ended_at: last.1,
+        start_place_id: place_at(fences, &first.0),
+        end_place_id: place_at(fences, &last.0),
+    }
+}
+
+/// Split chronological positions into stays and movements
+pub fn segment(
+    fences: &[(String, Geofence)],
+    positions: &[(Point, NaiveDateTime)],
+    settings: &Settings,
+) -> Vec<Segment> {
+    let mut segments = vec![];
+    // Positions since the last stay, starting with its last one
+    let mut moving: Vec<(Point, NaiveDateTime)> = vec![];
+    let mut start = 0;
+
+    while start < positions.len() {
+        let anchor = positions[start].0;
+        let end = positions[start..]
+            .iter()
+            .position(|(point, _)| anchor.distance(point) > settings.stay_radius)
+            .map_or(positions.len(), |length| start + length);
+
+        if positions[end - 1].1 - positions[start].1 < settings.min_dwell {
+            moving.push(positions[start]);
+            start += 1;
+            continue;
+        }
+
+        moving.push(positions[start]);
+        if moving.len() > 1 {
+            segments.push(Segment::Movement(movement(fences, &moving, settings.tolerance)));
+        }
+        segments.push(Segment::Stay(stay(fences, &positions[start..end])));
+        moving = vec![positions[end - 1]];
+        start = end;
+    }
+
+    if moving.len() > 1 {
+        segments.push(Segment::Movement(movement(fences, &moving, settings.tolerance)));
+    }
+    segments
+}
+
+/// The stays and movements of a user over a range
+pub fn find_trips(
+    pool: &PoolType,
+    user: &User,
+    from: NaiveDateTime,
+    to: NaiveDateTime,
+    settings: &Settings,
+) -> Result<Vec<Segment>, ApiError> {
+    let fences: Vec<(String, Geofence)> = match &user.family_id {
+        Some(family_id) => find_places(pool, family_id)?
+            .into_iter()
+            .filter_map(|place| place.geofence().map(|fence| (place.id, fence)))
+            .collect(),
+        None => vec![],
+    };
+    // Geolocs come most recent first
+    let positions: Vec<_> = find_between(pool, &user.id, from, to, None)?
+        .into_iter()
+        .rev()
+        .map(|geoloc| (Point::new(geoloc.latitude, geoloc.longitude), geoloc.created_at))
+        .collect();
+    Ok(segment(&fences, &positions, settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn at(minutes: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 10, 19).and_hms(8, 0, 0) + Duration::minutes(minutes)
+    }
+
+    fn home() -> (String, Geofence) {
+        ("home".into(), Geofence::Circle(Point::new(48.0, 2.0), 100.0))
+    }
+
+    /// Ten minutes at home, ten minutes walking north 1.1 km, ten minutes at school
+    fn commute() -> Vec<(Point, NaiveDateTime)> {
+        let mut positions = vec![];
+        for minute in 0..=10 {
+            positions.push((Point::new(48.0, 2.0 + 0.00001 * minute as f64), at(minute)));
+        }
+        for minute in 1..10 {
+            positions.push((Point::new(48.0 + 0.001 * minute as f64, 2.0), at(10 + minute)));
+        }
+        for minute in 0..=10 {
+            positions.push((Point::new(48.01, 2.0), at(20 + minute)));
+        }
+        positions
+    }
+
+    #[test]
+    fn it_splits_stays_and_movements() {
+        let segments = segment(&[home()], &commute(), &Settings::default());
+        assert_eq!(segments.len(), 3);
+        match (&segments[0], &segments[1], &segments[2]) {
+            (Segment::Stay(first), Segment::Movement(movement), Segment::Stay(last)) => {
+                assert_eq!((first.started_at, first.ended_at), (at(0), at(10)));
+                assert_eq!(first.place_id.as_deref(), Some("home"));
+                assert_eq!((movement.started_at, movement.ended_at), (at(10), at(20)));
+                assert_eq!(movement.start_place_id.as_deref(), Some("home"));
+                assert_eq!(movement.end_place_id, None);
+                assert!((movement.distance - 1112.0).abs() < 5.0);
+                assert_eq!((last.started_at, last.ended_at), (at(20), at(30)));
+                assert_eq!(last.place_id, None);
+            }
+            segments => panic!("unexpected segments {:?}", segments),
+        }
+    }
+
+    #[test]
+    fn it_simplifies_movements() {
+        let segments = segment(&[], &commute(), &Settings::default());
+        match &segments[1] {
+            Segment::Movement(movement) => {
+                assert_eq!(movement.points.len(), 2);
+                assert_eq!(movement.points[0].1, at(10));
+                assert_eq!(movement.points[1].1, at(20));
+            }
+            segment => panic!("unexpected segment {:?}", segment),
+        }
+    }
+
+    #[test]
+    fn it_keeps_short_pauses_in_movements() {
+        let settings = Settings {
+            min_dwell: Duration::minutes(15),
+            ..Settings::default()
+        };
+        let segments = segment(&[], &commute(), &settings);
+        assert_eq!(segments.len(), 1);
+        match &segments[0] {
+            Segment::Movement(movement) => assert_eq!((movement.started_at, movement.ended_at), (at(0), at(30))),
+            segment => panic!("unexpected segment {:?}", segment),
+        }
+    }
+}